@@ -0,0 +1,58 @@
+//! Timings of the bit packing of `dot_tree::bitcodec`, without a benchmark
+//! framework: each case runs for a fixed amount of iterations, and prints
+//! the time taken by one of them. Run with `cargo bench --bench bitcodec`.
+
+use dot_tree::BitOrder;
+use std::hint::black_box;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1_000_000;
+
+/// Run `f` [`ITERATIONS`] times and print how long one run took.
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up the caches and the branch predictor first.
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<40} {:>10.1} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    for order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+        let mut buf = [0_u8; 16];
+
+        for (width, offset) in [(8, 0), (13, 5), (64, 3)] {
+            bench(
+                &format!("{:?} pack_u64_at {}@{}", order, width, offset),
+                || {
+                    order.pack_u64_at(black_box(&mut buf), black_box(offset), width, 0x5555);
+                },
+            );
+            bench(
+                &format!("{:?} unpack_u64 {}@{}", order, width, offset),
+                || {
+                    black_box(order.unpack_u64(black_box(&buf), black_box(offset), width));
+                },
+            );
+        }
+
+        let bits = vec![true; 100];
+        bench(&format!("{:?} pack_bits_at 100@3", order), || {
+            order.pack_bits_at(black_box(&mut buf), black_box(3), black_box(&bits));
+        });
+        bench(&format!("{:?} unpack_bits_at 100@3", order), || {
+            black_box(order.unpack_bits_at(black_box(&buf), black_box(3), 100));
+        });
+    }
+}
@@ -0,0 +1,169 @@
+//! Annotations: small byte payloads attached to positions outside of the
+//! schema, e.g. debug labels or where a node came from.
+//!
+//! The annotations are kept in a log next to the tree file (with the
+//! `.annotations` extension), replayed when the tree is opened. Each entry is
+//! the position (16 bytes), the length of the annotation (2 bytes) and the
+//! annotation. Entries without an annotation remove the one of the position.
+
+use crate::{sidecar_path, Storage, Tree, TreeFileError, TreeOpenMode};
+use std::fs;
+
+/// The size in bytes of the longest annotation.
+pub const MAX_ANNOTATION_SIZE: usize = u16::MAX as usize;
+
+/// The size in bytes of each entry of the log, before its annotation.
+const ANNOTATION_ENTRY_HEADER_SIZE: usize = 18;
+
+/// The size in bytes below which the log is never compacted.
+const ANNOTATION_COMPACT_MIN_SIZE: u64 = 1 << 20;
+
+impl Tree {
+    /// Attach `annotation` to `position`, replacing the one it had. An empty
+    /// annotation removes it, and the log is removed with the last
+    /// annotation.
+    ///
+    /// Annotations belong to positions rather than nodes: they're kept when
+    /// the node is disabled or moved, and they aren't versioned with
+    /// persistent trees.
+    pub fn annotate(&mut self, position: u128, annotation: &[u8]) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty() {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        if annotation.len() > MAX_ANNOTATION_SIZE {
+            return Err(TreeFileError::AnnotationTooLarge);
+        };
+
+        if annotation.is_empty() {
+            if self.annotations.remove(&position).is_none() {
+                return Ok(());
+            };
+
+            if self.annotations.is_empty() {
+                self.annotation_log = None;
+                let _ = fs::remove_file(sidecar_path(&self.path, "annotations"));
+                return Ok(());
+            };
+        } else {
+            self.annotations.insert(position, annotation.to_vec());
+        };
+
+        if self.annotation_log.is_none() {
+            self.annotation_log = Some(self.open_sidecar("annotations", true)?);
+        };
+        if self
+            .append_annotation(&encode_entry(position, annotation))
+            .is_err()
+        {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        self.sync()
+    }
+
+    /// The annotation attached to `position`, if it has one.
+    pub fn annotation(&self, position: u128) -> Option<&[u8]> {
+        self.annotations.get(&position).map(Vec::as_slice)
+    }
+
+    /// Open the log and replay it, if the tree has annotations. A new tree
+    /// drops the annotations of the tree file it replaced.
+    pub(crate) fn open_annotations(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "annotations");
+
+        if create {
+            let _ = fs::remove_file(path);
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        };
+
+        let log = self.open_sidecar("annotations", false)?;
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        // An entry cut short by a crash is dropped, as its write never
+        // finished.
+        let mut offset = 0;
+        while offset + ANNOTATION_ENTRY_HEADER_SIZE <= bytes.len() {
+            let position = u128::from_be_bytes(bytes[offset..offset + 16].try_into().unwrap());
+            let len = u16::from_be_bytes([bytes[offset + 16], bytes[offset + 17]]) as usize;
+
+            let start = offset + ANNOTATION_ENTRY_HEADER_SIZE;
+            let annotation = match bytes.get(start..start + len) {
+                Some(annotation) => annotation,
+                None => break,
+            };
+            match annotation.is_empty() {
+                true => self.annotations.remove(&position),
+                false => self.annotations.insert(position, annotation.to_vec()),
+            };
+
+            offset = start + len;
+        }
+
+        self.annotation_log = Some(log);
+
+        Ok(())
+    }
+
+    /// Append an entry to the log, compacting it first if most of it is
+    /// made of replaced entries.
+    fn append_annotation(&mut self, entry: &[u8]) -> std::io::Result<()> {
+        let log = match &self.annotation_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        let size = log.size()?;
+        let live: usize = self
+            .annotations
+            .values()
+            .map(|annotation| ANNOTATION_ENTRY_HEADER_SIZE + annotation.len())
+            .sum();
+        if size >= ANNOTATION_COMPACT_MIN_SIZE && size > 4 * live as u64 {
+            return self.compact_annotations();
+        };
+
+        log.write_at(size, entry)
+    }
+
+    /// Rewrite the log with one entry per annotation. The new log is written
+    /// next to it and renamed over it, so a crash leaves one of them whole.
+    fn compact_annotations(&mut self) -> std::io::Result<()> {
+        let mut bytes = vec![];
+        for (position, annotation) in &self.annotations {
+            bytes.extend(encode_entry(*position, annotation));
+        }
+
+        let temp = sidecar_path(&self.path, "annotations.compact");
+        fs::write(&temp, bytes)?;
+        fs::rename(&temp, sidecar_path(&self.path, "annotations"))?;
+        self.annotation_log = Some(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(sidecar_path(&self.path, "annotations"))?,
+        );
+
+        Ok(())
+    }
+}
+
+fn encode_entry(position: u128, annotation: &[u8]) -> Vec<u8> {
+    let mut entry = position.to_be_bytes().to_vec();
+    entry.extend((annotation.len() as u16).to_be_bytes());
+    entry.extend(annotation);
+
+    entry
+}
@@ -0,0 +1,65 @@
+//! The writer id and last-modified time kept in each node of trees with the
+//! attribution feature.
+
+use crate::{bitcodec, Feature, NodeError, Tree};
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Who wrote a node last, and when.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attribution {
+    /// The writer id of the tree that wrote the node, as set with
+    /// [`set_writer_id`](Tree::set_writer_id), truncated to its subitem.
+    pub writer: u64,
+
+    /// When the node was written, to the second. Times that don't fit in
+    /// their subitem wrap around.
+    pub modified: SystemTime,
+}
+
+impl Tree {
+    /// Who wrote a node last, and when. Fails with
+    /// [`MissingFeature`](NodeError::MissingFeature) if the tree doesn't
+    /// have the attribution feature.
+    pub fn attribution(&self, position: u128) -> Result<Attribution, NodeError> {
+        if !self.features.contains(&Feature::Attribution) {
+            return Err(NodeError::MissingFeature);
+        };
+
+        let node = self.read_node(position)?;
+        let count = node.subitems.len();
+
+        Ok(Attribution {
+            writer: bitcodec::bits_to_u64(&node.subitems[count - 2]),
+            modified: UNIX_EPOCH
+                + Duration::from_secs(bitcodec::bits_to_u64(&node.subitems[count - 1])),
+        })
+    }
+
+    /// The subitems of a node about to be written, with the writer id and
+    /// the current time in the last two if the tree has the attribution
+    /// feature.
+    pub(crate) fn stamped<'a>(&self, subitems: &'a [Vec<bool>]) -> Cow<'a, [Vec<bool>]> {
+        if !self.features.contains(&Feature::Attribution) {
+            return Cow::Borrowed(subitems);
+        };
+
+        let seconds = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+
+        let mut stamped = subitems.to_vec();
+        let count = self.subitems.len();
+        for (index, value) in [(count - 2, self.writer_id as u64), (count - 1, seconds)] {
+            let width = self.subitems[index];
+            let mask = match width {
+                64 => u64::MAX,
+                width => (1 << width) - 1,
+            };
+            stamped[index] = bitcodec::u64_to_bits(value & mask, width);
+        }
+
+        Cow::Owned(stamped)
+    }
+}
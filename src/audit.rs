@@ -0,0 +1,180 @@
+//! The audit log: an append-only record of every change made to a tree with
+//! the audit feature, kept next to the tree file.
+
+use crate::{bitcodec, Feature, NodeData, NodeError, Operation, Storage, Tree, TreeFileError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The size in bytes of each entry of the audit log.
+const AUDIT_ENTRY_SIZE: u64 = 45;
+
+/// A change recorded in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// When the change was made.
+    pub time: SystemTime,
+
+    /// The operation that made the change.
+    pub operation: Operation,
+
+    /// The node written, for node writes.
+    pub position: Option<u128>,
+
+    /// The digest of the node before the write. `None` if it wasn't stored.
+    pub before: Option<u64>,
+
+    /// The digest of the node after the write.
+    pub after: Option<u64>,
+
+    /// The writer id of the tree that made the change, as set with
+    /// [`set_writer_id`](Tree::set_writer_id).
+    pub writer: u32,
+}
+
+impl AuditEntry {
+    fn decode(entry: &[u8; AUDIT_ENTRY_SIZE as usize]) -> Option<Self> {
+        let number = |bytes: &[u8]| {
+            let mut array = [0_u8; 8];
+            array.copy_from_slice(bytes);
+            u64::from_be_bytes(array)
+        };
+
+        let operation = match entry[8] {
+            0 => Operation::ReadNode,
+            1 => Operation::WriteNode,
+            2 => Operation::Flush,
+            3 => Operation::Gc,
+            4 => Operation::Verify,
+            5 => Operation::Rollback,
+            _ => return None,
+        };
+
+        let mut position = [0_u8; 16];
+        position.copy_from_slice(&entry[9..25]);
+        let mut writer = [0_u8; 4];
+        writer.copy_from_slice(&entry[41..45]);
+
+        Some(Self {
+            time: UNIX_EPOCH + Duration::from_millis(number(&entry[0..8])),
+            operation,
+            position: u128::from_be_bytes(position).checked_sub(1),
+            before: Some(number(&entry[25..33])).filter(|digest| *digest != 0),
+            after: Some(number(&entry[33..41])).filter(|digest| *digest != 0),
+            writer: bitcodec::u8_array_to_u32(&writer),
+        })
+    }
+
+    fn encode(&self) -> [u8; AUDIT_ENTRY_SIZE as usize] {
+        let millis = match self.time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as u64,
+            Err(_) => 0,
+        };
+        let position = self.position.map_or(0, |position| position + 1);
+
+        let mut entry = [0_u8; AUDIT_ENTRY_SIZE as usize];
+        entry[0..8].copy_from_slice(&millis.to_be_bytes());
+        entry[8] = self.operation as u8;
+        entry[9..25].copy_from_slice(&position.to_be_bytes());
+        entry[25..33].copy_from_slice(&self.before.unwrap_or(0).to_be_bytes());
+        entry[33..41].copy_from_slice(&self.after.unwrap_or(0).to_be_bytes());
+        entry[41..45].copy_from_slice(&bitcodec::u32_to_u8_array(self.writer));
+        entry
+    }
+}
+
+impl NodeData {
+    /// The digest of the node kept in the audit log: the 64-bit FNV-1a hash
+    /// of whether it's enabled (one byte) followed by each of its subitems
+    /// packed into bytes. Never `0`, which marks a missing node.
+    pub fn digest(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |byte: u8| {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        };
+
+        feed(self.enabled as u8);
+        for subitem in &self.subitems {
+            for byte in bitcodec::bits_to_bytes(subitem) {
+                feed(byte);
+            }
+        }
+
+        hash.max(1)
+    }
+}
+
+impl Tree {
+    /// Set the id recorded as the writer of the changes made through the
+    /// tree. Defaults to `0`.
+    pub fn set_writer_id(&mut self, id: u32) {
+        self.writer_id = id;
+        self.record_writer_id(id);
+    }
+
+    /// The writer id recorded for the changes made through the tree.
+    pub fn writer_id(&self) -> u32 {
+        self.writer_id
+    }
+
+    /// Every entry of the audit log, from the oldest to the newest.
+    pub fn audit_iter(&self) -> Result<impl Iterator<Item = AuditEntry> + '_, TreeFileError> {
+        let log = match &self.audit {
+            Some(log) => log,
+            None => return Err(TreeFileError::MissingFeature),
+        };
+
+        let entries = match log.size() {
+            Ok(size) => size / AUDIT_ENTRY_SIZE,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        Ok((0..entries).map_while(move |index| {
+            let mut entry = [0_u8; AUDIT_ENTRY_SIZE as usize];
+            log.read_at(index * AUDIT_ENTRY_SIZE, &mut entry).ok()?;
+            AuditEntry::decode(&entry)
+        }))
+    }
+
+    /// Open (or create) the audit log if the tree has the audit feature.
+    pub(crate) fn open_audit(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Audit) {
+            return Ok(());
+        };
+
+        self.audit = Some(self.open_sidecar("audit", create)?);
+
+        Ok(())
+    }
+
+    /// Append an entry to the audit log, if the tree has one.
+    pub(crate) fn record_audit(
+        &self,
+        operation: Operation,
+        position: Option<u128>,
+        before: Option<&NodeData>,
+        after: Option<&NodeData>,
+    ) -> Result<(), NodeError> {
+        let log = match &self.audit {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        let entry = AuditEntry {
+            time: SystemTime::now(),
+            operation,
+            position,
+            before: before.map(NodeData::digest),
+            after: after.map(NodeData::digest),
+            writer: self.writer_id,
+        };
+
+        let end = match log.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        match log.write_at(end, &entry.encode()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+}
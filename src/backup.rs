@@ -0,0 +1,333 @@
+//! Differential backups: the blocks of the tree file changed since an earlier
+//! backup, tracked in a revision table kept next to the tree file. The tree
+//! a backup is applied to keeps the revision it was restored up to, in a file
+//! next to its own.
+
+use crate::cache::PAGE_SIZE;
+use crate::{
+    read_headers, sidecar_path, storage, write_dirty, Feature, Storage, Tree, TreeFileError,
+    TreeOpenMode,
+};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+/// The size in bytes of each entry of the revision table, and of its
+/// header.
+const REVISION_SIZE: u64 = 8;
+
+/// The size in bytes of the header of a backup.
+const BACKUP_HEADER_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum BackupError {
+    /// The backup couldn't be written.
+    Write,
+
+    /// The backup couldn't be read.
+    Read,
+
+    /// The backup is truncated, or isn't a backup of a tree file.
+    Invalid,
+
+    /// The tree wasn't restored up to the revision the backup is based on.
+    BaseMismatch,
+
+    /// The tree couldn't be read or written.
+    Tree(TreeFileError),
+}
+
+impl From<TreeFileError> for BackupError {
+    fn from(error: TreeFileError) -> Self {
+        BackupError::Tree(error)
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, BackupError> {
+    let mut bytes = [0_u8; 8];
+    match reader.read_exact(&mut bytes) {
+        Ok(_) => Ok(u64::from_be_bytes(bytes)),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Err(BackupError::Invalid),
+        Err(_) => Err(BackupError::Read),
+    }
+}
+
+/// The size of the block at `offset` of a file of `size` bytes.
+fn block_len(offset: u64, size: u64) -> usize {
+    PAGE_SIZE.min(size - offset) as usize
+}
+
+impl Tree {
+    /// Write the blocks of the tree file changed since `base_revision` to
+    /// `writer`, and return the revision of the tree in the backup. Later
+    /// backups pass it as their base to only hold the blocks changed since.
+    ///
+    /// The first backup of a tree starts tracking the changed blocks, so it
+    /// holds every block whatever its base. Base revision `0` always makes a
+    /// full backup. Trees with files kept next to the tree file (persistent,
+    /// occupancy, level stats and audit, or subtree hashes and subitem
+    /// indexes) can't be backed up.
+    pub fn backup_diff(
+        &mut self,
+        base_revision: u64,
+        mut writer: impl Write,
+    ) -> Result<u64, BackupError> {
+        self.check_backup_features()?;
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+        };
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(BackupError::Tree(TreeFileError::FileNotOpened)),
+        };
+        let blocks = size.div_ceil(PAGE_SIZE);
+
+        let table = match &self.revisions {
+            Some(table) => table,
+            None => {
+                let table = self.open_sidecar("revisions", true)?;
+                if table.write_at(0, &1_u64.to_be_bytes()).is_err() {
+                    return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+                };
+                self.revisions.insert(table)
+            }
+        };
+
+        let revision = match read_revision(table, 0) {
+            Ok(revision) => revision,
+            Err(_) => return Err(BackupError::Tree(TreeFileError::Corrupted)),
+        };
+        if base_revision >= revision {
+            return Err(BackupError::Tree(TreeFileError::UnexistentVersion));
+        };
+
+        // The headers are always in the backup, as they're also written
+        // without going through the table.
+        let mut changed = vec![];
+        let mut entries = vec![];
+        for block in 0..blocks {
+            // Missing entries and gaps in the table hold `0`.
+            let written = match read_revision(table, block + 1) {
+                Ok(0) | Err(_) => revision,
+                Ok(written) => written,
+            };
+            if block == 0 || written > base_revision {
+                changed.push(block);
+            };
+            entries.extend(written.to_be_bytes());
+        }
+
+        let mut header = [0_u8; BACKUP_HEADER_SIZE];
+        header[0..8].copy_from_slice(&base_revision.to_be_bytes());
+        header[8..16].copy_from_slice(&revision.to_be_bytes());
+        header[16..24].copy_from_slice(&size.to_be_bytes());
+        header[24..32].copy_from_slice(&(changed.len() as u64).to_be_bytes());
+        if writer.write_all(&header).is_err() {
+            return Err(BackupError::Write);
+        };
+
+        for block in changed {
+            let offset = block * PAGE_SIZE;
+            let mut bytes = vec![0_u8; block_len(offset, size)];
+            if self.storage.read_at(offset, &mut bytes).is_err() {
+                return Err(BackupError::Tree(TreeFileError::Corrupted));
+            };
+
+            if writer.write_all(&block.to_be_bytes()).is_err() || writer.write_all(&bytes).is_err()
+            {
+                return Err(BackupError::Write);
+            };
+        }
+
+        if writer.flush().is_err() {
+            return Err(BackupError::Write);
+        };
+
+        // The blocks written from now on belong to the next revision, and
+        // the gaps filled so later backups leave them out.
+        if table.write_at(REVISION_SIZE, &entries).is_err()
+            || table.write_at(0, &(revision + 1).to_be_bytes()).is_err()
+        {
+            return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+        };
+
+        Ok(revision)
+    }
+
+    /// Restore a backup made with [`backup_diff`](Tree::backup_diff), and
+    /// return its revision. Backups apply on top of a tree restored up to
+    /// their base revision, so a full backup goes first, followed by each
+    /// differential backup in order.
+    ///
+    /// Fails with [`BaseMismatch`](BackupError::BaseMismatch) if a
+    /// differential backup doesn't follow the last backup applied to the
+    /// tree, and with [`SchemaMismatch`](TreeFileError::SchemaMismatch) if
+    /// the backup is of a tree with other features, subitems, bit order or
+    /// layout.
+    pub fn apply_backup(&mut self, mut reader: impl Read) -> Result<u64, BackupError> {
+        self.check_backup_features()?;
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+        };
+
+        let base_revision = read_u64(&mut reader)?;
+        let revision = read_u64(&mut reader)?;
+        let size = read_u64(&mut reader)?;
+        let count = read_u64(&mut reader)?;
+
+        // Full backups apply to any tree.
+        if base_revision != 0 && base_revision != self.restored_revision()? {
+            return Err(BackupError::BaseMismatch);
+        };
+
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let block = read_u64(&mut reader)?;
+            let offset = match block.checked_mul(PAGE_SIZE) {
+                Some(offset) if offset < size => offset,
+                _ => return Err(BackupError::Invalid),
+            };
+
+            let mut bytes = vec![0_u8; block_len(offset, size)];
+            match reader.read_exact(&mut bytes) {
+                Ok(_) => (),
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(BackupError::Invalid)
+                }
+                Err(_) => return Err(BackupError::Read),
+            };
+            blocks.push((offset, bytes));
+        }
+
+        // The backup is checked whole before the tree is changed.
+        let headers = match blocks.first() {
+            Some((0, bytes)) => storage::ByteStorage(bytes.clone()),
+            _ => return Err(BackupError::Invalid),
+        };
+        let options = match read_headers(&headers) {
+            Ok(options) => options,
+            Err(_) => return Err(BackupError::Invalid),
+        };
+        if options.features != self.features
+            || options.subitems != self.subitems
+            || options.bit_order != self.bit_order
+            || options.layout != self.layout
+        {
+            return Err(BackupError::Tree(TreeFileError::SchemaMismatch));
+        };
+
+        if self.set_storage_size(size).is_err() {
+            return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+        };
+        self.unpin_all();
+
+        for (offset, bytes) in blocks {
+            if self.bulk(|tree| tree.write_bytes(offset, &bytes)).is_err() {
+                return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+            };
+        }
+
+        // The backup's headers are copied as they are, but the tree is still
+        // open for writing.
+        write_dirty(&*self.storage, true)?;
+
+        let restored = self.open_sidecar("restored", true)?;
+        if restored.write_at(0, &revision.to_be_bytes()).is_err() {
+            return Err(BackupError::Tree(TreeFileError::MissingPermissions));
+        };
+
+        Ok(revision)
+    }
+
+    /// The revision of the last backup applied to the tree, or `0` if none
+    /// was.
+    fn restored_revision(&self) -> Result<u64, TreeFileError> {
+        if !sidecar_path(&self.path, "restored").exists() {
+            return Ok(0);
+        };
+
+        match read_revision(&self.open_sidecar("restored", false)?, 0) {
+            Ok(revision) => Ok(revision),
+            Err(_) => Err(TreeFileError::Corrupted),
+        }
+    }
+
+    /// Fail for trees with files kept next to the tree file, which backups
+    /// and patches don't carry.
+    pub(crate) fn check_backup_features(&self) -> Result<(), TreeFileError> {
+        for feature in [
+            Feature::Persistent,
+            Feature::Occupancy,
+            Feature::LevelStats,
+            Feature::Audit,
+        ] {
+            if self.features.contains(&feature) {
+                return Err(TreeFileError::UnsupportedFeature);
+            };
+        }
+
+        if self.merkle.is_some() || self.index_log.is_some() {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        Ok(())
+    }
+
+    /// Open the revision table if a backup of the tree was made. A new tree
+    /// drops the table, and the restored revision, of the tree file it
+    /// replaced.
+    pub(crate) fn open_revisions(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "revisions");
+
+        if create {
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(sidecar_path(&self.path, "restored"));
+        } else if path.exists() {
+            self.revisions = Some(self.open_sidecar("revisions", false)?);
+        };
+
+        Ok(())
+    }
+
+    /// Record that the blocks holding `len` bytes at `offset` were written
+    /// in the current revision, if the tree has a revision table.
+    pub(crate) fn mark_revisions(&self, offset: u64, len: usize) -> io::Result<()> {
+        let table = match &self.revisions {
+            Some(table) => table,
+            None => return Ok(()),
+        };
+
+        let revision = read_revision(table, 0)?.to_be_bytes();
+        let first = offset / PAGE_SIZE;
+        let end = (offset + len as u64).div_ceil(PAGE_SIZE).max(first + 1);
+        let entries: Vec<u8> = (first..end).flat_map(|_| revision).collect();
+
+        // Gaps left in the table read as written in the current revision.
+        table.write_at((first + 1) * REVISION_SIZE, &entries)
+    }
+
+    /// Drop the entries of the blocks past `size` from the revision table,
+    /// and of the block cut by it, so the blocks read as written in the
+    /// current revision if the tree file grows again.
+    pub(crate) fn truncate_revisions(&self, size: u64) -> io::Result<()> {
+        let table = match &self.revisions {
+            Some(table) => table,
+            None => return Ok(()),
+        };
+
+        let entries = size / PAGE_SIZE;
+        if table.size()? > (entries + 1) * REVISION_SIZE {
+            table.set_size((entries + 1) * REVISION_SIZE)?;
+        };
+
+        Ok(())
+    }
+}
+
+fn read_revision(table: &File, index: u64) -> io::Result<u64> {
+    let mut bytes = [0_u8; 8];
+    table.read_at(index * REVISION_SIZE, &mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
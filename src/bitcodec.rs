@@ -0,0 +1,264 @@
+//! Bit packing used by the tree file format. Every function here is what the
+//! crate itself uses to read and write tree files, so tools working on raw
+//! node regions get the exact same packing.
+//!
+//! Unless stated otherwise, bits are packed most significant bit first, and
+//! multi-bit numbers are stored with their most significant bit first.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The order in which bits are packed into each byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BitOrder {
+    /// The first bit is the most significant bit of the byte.
+    #[default]
+    MsbFirst,
+
+    /// The first bit is the least significant bit of the byte.
+    LsbFirst,
+}
+
+impl BitOrder {
+    /// The mask selecting bit `bit_offset` of a buffer in its byte.
+    pub(crate) fn mask(self, bit_offset: usize) -> u8 {
+        match self {
+            BitOrder::MsbFirst => 0x80 >> (bit_offset % 8),
+            BitOrder::LsbFirst => 1 << (bit_offset % 8),
+        }
+    }
+
+    /// Write `bits` into `buf` starting at `bit_offset`, keeping every other
+    /// bit of the buffer.
+    ///
+    /// Panics if the buffer is shorter than `bit_offset + bits.len()` bits.
+    pub fn pack_bits_at(self, buf: &mut [u8], bit_offset: usize, bits: &[bool]) {
+        for (i, &bit) in bits.iter().enumerate() {
+            let mask = self.mask(bit_offset + i);
+            let byte = &mut buf[(bit_offset + i) / 8];
+
+            if bit {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        }
+    }
+
+    /// Read `len` bits from `buf` starting at `bit_offset`.
+    ///
+    /// Panics if the buffer is shorter than `bit_offset + len` bits.
+    pub fn unpack_bits_at(self, buf: &[u8], bit_offset: usize, len: usize) -> Vec<bool> {
+        (bit_offset..bit_offset + len)
+            .map(|i| buf[i / 8] & self.mask(i) != 0)
+            .collect()
+    }
+
+    /// Write the lowest `width` bits of `value` into `buf` starting at
+    /// `bit_offset`, most significant bit first.
+    ///
+    /// Panics if `width` is over 64 or the buffer is too short.
+    pub fn pack_u64_at(self, buf: &mut [u8], bit_offset: usize, width: u32, value: u64) {
+        self.pack_bits_at(buf, bit_offset, &u64_to_bits(value, width));
+    }
+
+    /// Read a `width`-bit number from `buf` starting at `bit_offset`, most
+    /// significant bit first. Doesn't allocate.
+    ///
+    /// Panics if `width` is over 64 or the buffer is too short.
+    pub fn unpack_u64(self, buf: &[u8], bit_offset: usize, width: u32) -> u64 {
+        assert!(width <= 64, "Width is over 64 bits");
+
+        (bit_offset..bit_offset + width as usize).fold(0, |result, i| {
+            (result << 1) | (buf[i / 8] & self.mask(i) != 0) as u64
+        })
+    }
+
+    /// Pack bits into bytes. The last byte is padded with `0`s.
+    pub fn bits_to_bytes(self, bits: &[bool]) -> Vec<u8> {
+        let mut result = vec![0_u8; bits.len().div_ceil(8)];
+        self.pack_bits_at(&mut result, 0, bits);
+
+        result
+    }
+
+    /// Unpack every bit of `bytes`.
+    pub fn bytes_to_bits(self, bytes: &[u8]) -> Vec<bool> {
+        self.unpack_bits_at(bytes, 0, bytes.len() * 8)
+    }
+}
+
+/// Write `bits` into `buf` starting at `bit_offset`, keeping every other bit
+/// of the buffer.
+///
+/// Panics if the buffer is shorter than `bit_offset + bits.len()` bits.
+pub fn pack_bits_at(buf: &mut [u8], bit_offset: usize, bits: &[bool]) {
+    BitOrder::MsbFirst.pack_bits_at(buf, bit_offset, bits)
+}
+
+/// Read `len` bits from `buf` starting at `bit_offset`.
+///
+/// Panics if the buffer is shorter than `bit_offset + len` bits.
+pub fn unpack_bits_at(buf: &[u8], bit_offset: usize, len: usize) -> Vec<bool> {
+    BitOrder::MsbFirst.unpack_bits_at(buf, bit_offset, len)
+}
+
+/// Write the lowest `width` bits of `value` into `buf` starting at
+/// `bit_offset`.
+///
+/// Panics if `width` is over 64 or the buffer is too short.
+pub fn pack_u64_at(buf: &mut [u8], bit_offset: usize, width: u32, value: u64) {
+    BitOrder::MsbFirst.pack_u64_at(buf, bit_offset, width, value)
+}
+
+/// Read a `width`-bit number from `buf` starting at `bit_offset`.
+///
+/// Panics if `width` is over 64 or the buffer is too short.
+pub fn unpack_u64(buf: &[u8], bit_offset: usize, width: u32) -> u64 {
+    BitOrder::MsbFirst.unpack_u64(buf, bit_offset, width)
+}
+
+/// Pack bits into bytes. The last byte is padded with `0`s.
+pub fn bits_to_bytes(bits: &[bool]) -> Vec<u8> {
+    BitOrder::MsbFirst.bits_to_bytes(bits)
+}
+
+/// Unpack every bit of `bytes`.
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    BitOrder::MsbFirst.bytes_to_bits(bytes)
+}
+
+/// Interpret up to 64 bits as a number.
+pub fn bits_to_u64(bits: &[bool]) -> u64 {
+    let mut result: u64 = 0;
+
+    for &bit in bits {
+        result = (result << 1) | (bit as u64);
+    }
+
+    result
+}
+
+/// The lowest `width` bits of a number.
+///
+/// Panics if `width` is over 64.
+pub fn u64_to_bits(number: u64, width: u32) -> Vec<bool> {
+    assert!(width <= 64, "Width is over 64 bits");
+
+    (0..width).rev().map(|i| (number >> i) & 1 == 1).collect()
+}
+
+/// Store a number in 4 bytes.
+pub fn u32_to_u8_array(number: u32) -> [u8; 4] {
+    number.to_be_bytes()
+}
+
+/// Read a number stored in 4 bytes.
+pub fn u8_array_to_u32(bytes: &[u8; 4]) -> u32 {
+    u32::from_be_bytes(*bytes)
+}
+
+/// Store a number in 8 bytes.
+pub fn u64_to_u8_array(number: u64) -> [u8; 8] {
+    number.to_be_bytes()
+}
+
+/// Read a number stored in 8 bytes.
+pub fn u8_array_to_u64(bytes: &[u8; 8]) -> u64 {
+    u64::from_be_bytes(*bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [BitOrder; 2] = [BitOrder::MsbFirst, BitOrder::LsbFirst];
+
+    /// A pseudo-random sequence, so every run checks the same values.
+    fn values(count: usize) -> Vec<u64> {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packs_bits_in_each_order() {
+        let mut buf = [0_u8; 2];
+        BitOrder::MsbFirst.pack_bits_at(&mut buf, 3, &[true, false, true]);
+        assert_eq!(buf, [0b0001_0100, 0]);
+
+        let mut buf = [0_u8; 2];
+        BitOrder::LsbFirst.pack_bits_at(&mut buf, 3, &[true, false, true]);
+        assert_eq!(buf, [0b0010_1000, 0]);
+
+        let mut buf = [0_u8; 2];
+        BitOrder::MsbFirst.pack_u64_at(&mut buf, 6, 4, 0b1011);
+        assert_eq!(buf, [0b0000_0010, 0b1100_0000]);
+    }
+
+    #[test]
+    fn round_trips_numbers_at_every_offset() {
+        for order in ORDERS {
+            for (index, value) in values(64 * 16).into_iter().enumerate() {
+                let width = (index % 64) as u32 + 1;
+                let offset = index / 64;
+                let value = value & (u64::MAX >> (64 - width));
+
+                let mut buf = [0_u8; 11];
+                order.pack_u64_at(&mut buf, offset, width, value);
+                assert_eq!(order.unpack_u64(&buf, offset, width), value);
+                assert_eq!(
+                    bits_to_u64(&order.unpack_bits_at(&buf, offset, width as usize)),
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_bits_around_a_write() {
+        for order in ORDERS {
+            for offset in 0..16 {
+                let mut buf = [0xff_u8; 4];
+                order.pack_bits_at(&mut buf, offset, &[false; 9]);
+
+                let bits = order.bytes_to_bits(&buf);
+                for (index, bit) in bits.into_iter().enumerate() {
+                    assert_eq!(bit, !(offset..offset + 9).contains(&index));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let bytes: Vec<u8> = values(32).into_iter().map(|value| value as u8).collect();
+        for order in ORDERS {
+            assert_eq!(order.bits_to_bytes(&order.bytes_to_bits(&bytes)), bytes);
+        }
+
+        let bits = [true, false, true];
+        assert_eq!(bits_to_bytes(&bits), vec![0b1010_0000]);
+        assert_eq!(BitOrder::LsbFirst.bits_to_bytes(&bits), vec![0b0000_0101]);
+        assert_eq!(bytes_to_bits(&[0b1010_0000])[..3], bits);
+    }
+
+    #[test]
+    fn round_trips_integers() {
+        for value in values(16) {
+            assert_eq!(bits_to_u64(&u64_to_bits(value, 64)), value);
+            assert_eq!(u8_array_to_u64(&u64_to_u8_array(value)), value);
+            assert_eq!(
+                u8_array_to_u32(&u32_to_u8_array(value as u32)),
+                value as u32
+            );
+        }
+        assert_eq!(u64_to_bits(5, 4), vec![false, true, false, true]);
+    }
+}
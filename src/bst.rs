@@ -0,0 +1,277 @@
+//! Balanced binary search trees of `(key, value)` records, stored as trees
+//! whose nodes have a 64-bit key subitem followed by a 64-bit value subitem.
+
+use crate::{
+    bitcodec, positions, NodeError, Storage, SubitemKey, Tree, TreeFileError, TreeOpenMode,
+};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The amount of records sorted in memory at once.
+const RUN_SIZE: usize = 1 << 20;
+
+/// The size in bytes of a record in a sorted run.
+const RECORD_SIZE: usize = 16;
+
+/// Why a search tree couldn't be built.
+#[derive(Debug)]
+pub enum BuildError {
+    /// A temporary file couldn't be written or read.
+    TemporaryFile,
+
+    /// The tree file couldn't be created.
+    Tree(TreeFileError),
+
+    /// A node couldn't be written.
+    Node(NodeError),
+}
+
+/// Build a balanced search tree in `dest` from records in any order. The
+/// records are sorted in runs that fit in memory, stored in `tmp_dir`, and
+/// merged while the tree is written, so the input can be larger than RAM.
+///
+/// The tree is complete: every level is full except the deepest, which is
+/// filled from the left. Records with equal keys are kept in input order.
+pub fn build_from_unsorted(
+    records: impl IntoIterator<Item = (u64, u64)>,
+    tmp_dir: &Path,
+    dest: &'static str,
+) -> Result<Tree, BuildError> {
+    let mut runs = vec![];
+    let result = build(records, tmp_dir, dest, &mut runs);
+
+    for run in runs {
+        let _ = fs::remove_file(run);
+    }
+
+    result
+}
+
+fn build(
+    records: impl IntoIterator<Item = (u64, u64)>,
+    tmp_dir: &Path,
+    dest: &'static str,
+    runs: &mut Vec<PathBuf>,
+) -> Result<Tree, BuildError> {
+    let mut count: u128 = 0;
+    let mut run = Vec::with_capacity(RUN_SIZE);
+    for record in records {
+        run.push(record);
+        count += 1;
+
+        if run.len() == RUN_SIZE {
+            runs.push(write_run(&mut run, tmp_dir, runs.len())?);
+        };
+    }
+    if !run.is_empty() {
+        runs.push(write_run(&mut run, tmp_dir, runs.len())?);
+    };
+
+    let mut tree = match Tree::create(dest, TreeOpenMode::ReadWrite, vec![], vec![64, 64]) {
+        Ok(tree) => tree,
+        Err(error) => return Err(BuildError::Tree(error)),
+    };
+
+    // The records are merged into one sorted file, so the record of each
+    // position can be read by its rank while the nodes are written parent
+    // first, without leaving gaps in the tree file.
+    let sorted = merge_runs(runs, tmp_dir)?;
+    runs.push(sorted.clone());
+    let sorted = match File::open(sorted) {
+        Ok(file) => file,
+        Err(_) => return Err(BuildError::TemporaryFile),
+    };
+
+    let mut record = [0_u8; RECORD_SIZE];
+    for position in 0..count {
+        let rank = in_order_rank(position, count);
+        if sorted
+            .read_at(rank as u64 * RECORD_SIZE as u64, &mut record)
+            .is_err()
+        {
+            return Err(BuildError::TemporaryFile);
+        };
+
+        let (key, value) = record.split_at(8);
+        let subitems = [
+            bitcodec::u64_to_bits(u64::from_be_bytes(key.try_into().unwrap()), 64),
+            bitcodec::u64_to_bits(u64::from_be_bytes(value.try_into().unwrap()), 64),
+        ];
+        match tree.set_node_quiet(&subitems, &position, false, false) {
+            Ok(_) => (),
+            Err(error) => return Err(BuildError::Node(error)),
+        };
+    }
+
+    Ok(tree)
+}
+
+/// The value of a record of a search tree built by
+/// [`build_from_unsorted`], if there's one with `key`.
+pub fn search(tree: &Tree, key: u64) -> Result<Option<u64>, NodeError> {
+    let key = SubitemKey::new(&bitcodec::u64_to_bits(key, 64), false);
+    let mut position = 0;
+
+    loop {
+        let node = match tree.read_node(position) {
+            Ok(node) => node,
+            Err(NodeError::Unexistent) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let child = match key.cmp(&node.cmp_key(0, false)?) {
+            Ordering::Equal => return Ok(Some(bitcodec::bits_to_u64(&node.subitems[1]))),
+            Ordering::Less => positions::child(position, 0),
+            Ordering::Greater => positions::child(position, 1),
+        };
+        position = match child {
+            Some(child) => child,
+            None => return Ok(None),
+        };
+    }
+}
+
+/// The amount of nodes in the subtree of `position` in a complete tree of
+/// `count` nodes, 0 if there's no position.
+fn subtree_size(position: Option<u128>, count: u128) -> u128 {
+    let mut size = 0;
+
+    // The first and last positions of each level of the subtree.
+    let mut span = position.map(|position| (position, position));
+    while let Some((first, last)) = span.filter(|(first, _)| *first < count) {
+        size += last.min(count - 1) - first + 1;
+        span = positions::child(first, 0)
+            .map(|first| (first, positions::child(last, 1).unwrap_or(u128::MAX)));
+    }
+
+    size
+}
+
+/// The index of `position` in the in-order traversal of a complete tree of
+/// `count` nodes, which is the index of its record once they're sorted.
+fn in_order_rank(position: u128, count: u128) -> u128 {
+    let mut rank = subtree_size(positions::child(position, 0), count);
+
+    let mut current = position;
+    while current != 0 {
+        let parent = positions::parent(current);
+        if positions::child(parent, 1) == Some(current) {
+            rank += subtree_size(positions::child(parent, 0), count) + 1;
+        };
+        current = parent;
+    }
+
+    rank
+}
+
+/// Merge the sorted runs into one sorted file.
+fn merge_runs(runs: &[PathBuf], tmp_dir: &Path) -> Result<PathBuf, BuildError> {
+    let mut merged = Merge::new(runs)?;
+
+    let path = tmp_dir.join(format!("dot_tree-sorted-{}", std::process::id()));
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(_) => return Err(BuildError::TemporaryFile),
+    };
+
+    let mut writer = BufWriter::new(file);
+    while let Some((key, value)) = merged.next()? {
+        if writer.write_all(&key.to_be_bytes()).is_err()
+            || writer.write_all(&value.to_be_bytes()).is_err()
+        {
+            return Err(BuildError::TemporaryFile);
+        };
+    }
+
+    match writer.flush() {
+        Ok(_) => Ok(path),
+        Err(_) => Err(BuildError::TemporaryFile),
+    }
+}
+
+/// Sort a run of records and store it in a temporary file.
+fn write_run(
+    run: &mut Vec<(u64, u64)>,
+    tmp_dir: &Path,
+    index: usize,
+) -> Result<PathBuf, BuildError> {
+    run.sort_by_key(|(key, _)| *key);
+
+    let path = tmp_dir.join(format!("dot_tree-run-{}-{}", std::process::id(), index));
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(_) => return Err(BuildError::TemporaryFile),
+    };
+
+    let mut writer = BufWriter::new(file);
+    for (key, value) in run.drain(..) {
+        if writer.write_all(&key.to_be_bytes()).is_err()
+            || writer.write_all(&value.to_be_bytes()).is_err()
+        {
+            return Err(BuildError::TemporaryFile);
+        };
+    }
+
+    match writer.flush() {
+        Ok(_) => Ok(path),
+        Err(_) => Err(BuildError::TemporaryFile),
+    }
+}
+
+/// A k-way merge of sorted runs.
+struct Merge {
+    readers: Vec<BufReader<File>>,
+
+    /// The next record of each run, with the run's index to keep the input
+    /// order of equal keys.
+    heap: BinaryHeap<Reverse<(u64, usize, u64)>>,
+}
+
+impl Merge {
+    fn new(runs: &[PathBuf]) -> Result<Self, BuildError> {
+        let mut merge = Self {
+            readers: vec![],
+            heap: BinaryHeap::new(),
+        };
+
+        for (index, run) in runs.iter().enumerate() {
+            match File::open(run) {
+                Ok(file) => merge.readers.push(BufReader::new(file)),
+                Err(_) => return Err(BuildError::TemporaryFile),
+            };
+            merge.refill(index)?;
+        }
+
+        Ok(merge)
+    }
+
+    /// Read the next record of a run into the heap.
+    fn refill(&mut self, index: usize) -> Result<(), BuildError> {
+        let mut record = [0_u8; RECORD_SIZE];
+        match self.readers[index].read_exact(&mut record) {
+            Ok(_) => {
+                let (key, value) = record.split_at(8);
+                let key = u64::from_be_bytes(key.try_into().unwrap());
+                let value = u64::from_be_bytes(value.try_into().unwrap());
+                self.heap.push(Reverse((key, index, value)));
+                Ok(())
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(_) => Err(BuildError::TemporaryFile),
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, u64)>, BuildError> {
+        let Reverse((key, index, value)) = match self.heap.pop() {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        self.refill(index)?;
+
+        Ok(Some((key, value)))
+    }
+}
@@ -0,0 +1,327 @@
+//! Bundles: a single file holding everything needed to rebuild a tree and
+//! check it was rebuilt whole, e.g. to attach a tree to a support ticket or
+//! move it between machines.
+//!
+//! A bundle starts with its identifier (8 bytes) and format version (2
+//! bytes), followed by its sections in order. Each section is a tag (4
+//! bytes), the length of its contents (8 bytes), the contents and their
+//! 64-bit FNV-1a hash (8 bytes).
+//!
+//! - `META`: the version of the crate that wrote the bundle (its length in 1
+//!   byte and the string), when it was written (8 bytes, seconds since the
+//!   Unix epoch), the writer id (4 bytes) and the version of the tree that
+//!   was exported (8 bytes, `0` if it isn't persistent, or the version plus
+//!   one).
+//! - `SCHM`: the headers of the tree file, followed by its gap fill.
+//! - `STAT`: the amount of stored nodes and of enabled nodes (8 bytes each),
+//!   the amount of levels (4 bytes) and the size of the tree file (8 bytes).
+//! - `NODE`: every stored node, sorted by position, as its position (16
+//!   bytes), whether it's enabled (1 byte) and its subitems packed one after
+//!   the other.
+//! - `HASH`: the hash of the nodes (8 bytes), combining the position and
+//!   digest of every stored node.
+
+use crate::gapfill::{decode_gap_fill, encode_gap_fill};
+use crate::{bitcodec, core, DIRTY_FLAG};
+use crate::{Feature, NodeData, Tree, TreeFileError, TreeOpenMode};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The first bytes of every bundle.
+const BUNDLE_IDENTIFIER: [u8; 8] = *b"DOTBUNDL";
+
+/// The version of the bundle format written.
+const BUNDLE_VERSION: u16 = 1;
+
+/// The tags of the sections of a bundle, in order.
+const SECTIONS: [&[u8; 4]; 5] = [b"META", b"SCHM", b"STAT", b"NODE", b"HASH"];
+
+#[derive(Debug)]
+pub enum BundleError {
+    /// The bundle couldn't be written.
+    Write,
+
+    /// The bundle couldn't be read.
+    Read,
+
+    /// The bundle is truncated, or isn't a bundle of a tree.
+    Invalid,
+
+    /// The bundle was written in a newer format. Try upgrading the crate's
+    /// version.
+    UnsupportedVersion,
+
+    /// A section doesn't match its hash, or the imported tree doesn't match
+    /// the statistics or the hash of the nodes of the bundle.
+    Corrupted,
+
+    /// The tree couldn't be read or written.
+    Tree(TreeFileError),
+}
+
+impl From<TreeFileError> for BundleError {
+    fn from(error: TreeFileError) -> Self {
+        BundleError::Tree(error)
+    }
+}
+
+/// The statistics of a bundled tree.
+#[derive(Debug, PartialEq)]
+struct Stats {
+    stored: u64,
+    enabled: u64,
+    levels: u32,
+    size: u64,
+}
+
+impl Tree {
+    /// Write a bundle of the tree to `path`: its schema, gap fill and every
+    /// stored node, along with metadata, statistics and hashes to check
+    /// them with. Persistent trees bundle the version being read, without
+    /// its history. Read it back with [`import_bundle`](Tree::import_bundle).
+    pub fn export_bundle(&self, path: &str) -> Result<(), BundleError> {
+        let nodes = match self.stored_nodes() {
+            Ok(nodes) => nodes,
+            Err(_) => return Err(BundleError::Tree(TreeFileError::Corrupted)),
+        };
+
+        let mut meta = vec![];
+        let crate_version = env!("CARGO_PKG_VERSION");
+        meta.push(crate_version.len() as u8);
+        meta.extend(crate_version.as_bytes());
+        let created = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(created) => created.as_secs(),
+            Err(_) => 0,
+        };
+        meta.extend(created.to_be_bytes());
+        meta.extend(bitcodec::u32_to_u8_array(self.writer_id));
+        meta.extend(
+            self.version()
+                .map_or(0, |version| version + 1)
+                .to_be_bytes(),
+        );
+
+        // The headers are bundled as if the tree file was closed.
+        let mut schema = vec![0_u8; self.header_size];
+        if self.storage.read_at(0, &mut schema).is_err() {
+            return Err(BundleError::Tree(TreeFileError::MissingHeaders));
+        };
+        bitcodec::pack_bits_at(&mut schema[10..12], DIRTY_FLAG, &[false]);
+        schema.extend(encode_gap_fill(&self.gap_fill));
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(BundleError::Tree(TreeFileError::FileNotOpened)),
+        };
+        let stats = encode_stats(&Stats {
+            stored: nodes.len() as u64,
+            enabled: nodes.iter().filter(|node| node.enabled).count() as u64,
+            levels: self.levels(),
+            size,
+        });
+
+        let mut records = vec![];
+        for node in &nodes {
+            records.extend(node.position.to_be_bytes());
+            records.push(node.enabled as u8);
+            records.extend(bitcodec::bits_to_bytes(&node.subitems.concat()));
+        }
+
+        let mut bundle = BUNDLE_IDENTIFIER.to_vec();
+        bundle.extend(BUNDLE_VERSION.to_be_bytes());
+        for (tag, contents) in SECTIONS.iter().zip([
+            meta,
+            schema,
+            stats,
+            records,
+            nodes_hash(&nodes).to_be_bytes().to_vec(),
+        ]) {
+            bundle.extend(*tag);
+            bundle.extend((contents.len() as u64).to_be_bytes());
+            bundle.extend(&contents);
+            bundle.extend(hash(&contents).to_be_bytes());
+        }
+
+        match fs::write(path, bundle) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(BundleError::Write),
+        }
+    }
+
+    /// Rebuild the tree of the bundle at `path` in a new tree file at
+    /// `dest`, opened for writing. Every section is checked against its
+    /// hash before the tree is written, and the rebuilt tree is checked
+    /// against the statistics and the hash of the nodes of the bundle
+    /// before it's returned.
+    ///
+    /// Persistent trees are rebuilt writing one node at a time, so each
+    /// node is a version of the rebuilt tree.
+    pub fn import_bundle(path: &str, dest: &str) -> Result<Tree, BundleError> {
+        let bundle = match fs::read(path) {
+            Ok(bundle) => bundle,
+            Err(_) => return Err(BundleError::Read),
+        };
+
+        if bundle.len() < 10 || bundle[..8] != BUNDLE_IDENTIFIER {
+            return Err(BundleError::Invalid);
+        };
+        if u16::from_be_bytes([bundle[8], bundle[9]]) > BUNDLE_VERSION {
+            return Err(BundleError::UnsupportedVersion);
+        };
+
+        let mut sections = vec![];
+        let mut offset = 10;
+        for tag in SECTIONS {
+            let header = bundle
+                .get(offset..offset + 12)
+                .ok_or(BundleError::Invalid)?;
+            if &header[..4] != tag {
+                return Err(BundleError::Invalid);
+            };
+            let len = u64::from_be_bytes(header[4..].try_into().unwrap());
+
+            let start = offset + 12;
+            let end = usize::try_from(len)
+                .ok()
+                .and_then(|len| start.checked_add(len))
+                .filter(|end| end + 8 <= bundle.len())
+                .ok_or(BundleError::Invalid)?;
+            let contents = &bundle[start..end];
+            if hash(contents).to_be_bytes() != bundle[end..end + 8] {
+                return Err(BundleError::Corrupted);
+            };
+
+            sections.push(contents);
+            offset = end + 8;
+        }
+        let [_, schema, stats, records, nodes_hash_bytes] = sections[..] else {
+            return Err(BundleError::Invalid);
+        };
+
+        let mut options = core::parse_headers(|at, buf| {
+            let bytes = usize::try_from(at)
+                .ok()
+                .and_then(|at| schema.get(at..at + buf.len()));
+            match bytes {
+                Some(bytes) => {
+                    buf.copy_from_slice(bytes);
+                    true
+                }
+                None => false,
+            }
+        })?;
+        let header_size = 16 + options.subitems.len() * 4;
+        options.gap_fill = schema
+            .get(header_size..)
+            .and_then(|bytes| decode_gap_fill(bytes, &options.subitems))
+            .ok_or(BundleError::Invalid)?;
+
+        let stats = decode_stats(stats).ok_or(BundleError::Invalid)?;
+        let nodes_hash_bytes: [u8; 8] = nodes_hash_bytes
+            .try_into()
+            .map_err(|_| BundleError::Invalid)?;
+
+        let subitems = options.subitems.clone();
+        let node_bits: usize = subitems.iter().map(|size| *size as usize).sum();
+        let record_size = 17 + node_bits.div_ceil(8);
+        if records.len() % record_size != 0 {
+            return Err(BundleError::Invalid);
+        };
+
+        let disabling = options.features.contains(&Feature::Disabling);
+        let mut tree = Tree::create_at(dest, TreeOpenMode::ReadWrite, options)?;
+        let mut nodes = vec![];
+        for record in records.chunks(record_size) {
+            let position = u128::from_be_bytes(record[..16].try_into().unwrap());
+            let enabled = match record[16] {
+                0 => false,
+                1 => true,
+                _ => return Err(BundleError::Invalid),
+            };
+            let bits = bitcodec::bytes_to_bits(&record[17..]);
+
+            let mut offset = 0;
+            let mut node = NodeData {
+                position,
+                enabled,
+                subitems: vec![],
+            };
+            for size in &subitems {
+                node.subitems
+                    .push(bits[offset..offset + *size as usize].to_vec());
+                offset += *size as usize;
+            }
+
+            if tree
+                .write_node(&node.subitems, position, disabling && !enabled)
+                .is_err()
+            {
+                return Err(BundleError::Tree(TreeFileError::MissingPermissions));
+            };
+            nodes.push(node);
+        }
+
+        let rebuilt = match tree.stored_nodes() {
+            Ok(rebuilt) => rebuilt,
+            Err(_) => return Err(BundleError::Tree(TreeFileError::Corrupted)),
+        };
+        let enabled = rebuilt.iter().filter(|node| node.enabled).count() as u64;
+        if rebuilt.len() as u64 != stats.stored
+            || enabled != stats.enabled
+            || tree.levels() != stats.levels
+            || nodes_hash(&rebuilt).to_be_bytes() != nodes_hash_bytes
+            || nodes_hash(&nodes).to_be_bytes() != nodes_hash_bytes
+        {
+            return Err(BundleError::Corrupted);
+        };
+
+        tree.sync()?;
+
+        Ok(tree)
+    }
+}
+
+fn encode_stats(stats: &Stats) -> Vec<u8> {
+    let mut bytes = stats.stored.to_be_bytes().to_vec();
+    bytes.extend(stats.enabled.to_be_bytes());
+    bytes.extend(bitcodec::u32_to_u8_array(stats.levels));
+    bytes.extend(stats.size.to_be_bytes());
+
+    bytes
+}
+
+fn decode_stats(bytes: &[u8]) -> Option<Stats> {
+    if bytes.len() != 28 {
+        return None;
+    };
+
+    Some(Stats {
+        stored: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+        enabled: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        levels: bitcodec::u8_array_to_u32(bytes[16..20].try_into().unwrap()),
+        size: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+    })
+}
+
+/// The hash of a set of nodes: the 64-bit FNV-1a hash of the position and
+/// digest (see [`NodeData::digest`]) of each of them, in order.
+fn nodes_hash(nodes: &[NodeData]) -> u64 {
+    let mut bytes = vec![];
+    for node in nodes {
+        bytes.extend(node.position.to_be_bytes());
+        bytes.extend(node.digest().to_be_bytes());
+    }
+
+    hash(&bytes)
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
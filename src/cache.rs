@@ -0,0 +1,458 @@
+use crate::{Feature, Layout, MemoryMode, NodeError, Tree};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+
+/// The size in bytes of each page of the tree file kept in the cache.
+pub(crate) const PAGE_SIZE: u64 = 4096;
+
+/// A page read in the background: its number, the amount of writes made
+/// before it was requested, and its bytes.
+type PrefetchedPage = (u64, u64, Vec<u8>);
+
+/// Pages of the tree file kept in memory. Reads covered by the cached pages
+/// don't reach the storage, and writes go through to both, so a read always
+/// sees the writes made before it through the same tree, flushed or not.
+#[derive(Debug, Default)]
+pub(crate) struct PageCache {
+    pages: HashMap<u64, Vec<u8>>,
+
+    /// The pages being read in the background.
+    incoming: Vec<Mutex<Receiver<PrefetchedPage>>>,
+
+    /// The amount of writes made while pages were being read in the
+    /// background.
+    writes: u64,
+
+    /// The last write to each page made while pages were being read in the
+    /// background. Pages read before their last write are stale.
+    written: HashMap<u64, u64>,
+}
+
+impl PageCache {
+    /// The amount of pages cached.
+    pub(crate) fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Cache a page read from the storage, unless it's already cached.
+    pub(crate) fn insert(&mut self, page: u64, bytes: Vec<u8>) {
+        self.pages.entry(page).or_insert(bytes);
+    }
+
+    /// Whether pages are being read in the background.
+    pub(crate) fn prefetching(&self) -> bool {
+        !self.incoming.is_empty()
+    }
+
+    /// Cache the pages read in the background so far. Returns the amount of
+    /// bytes cached.
+    pub(crate) fn receive(&mut self) -> u64 {
+        let mut received = 0;
+
+        let mut index = 0;
+        while index < self.incoming.len() {
+            let incoming = self.incoming[index]
+                .get_mut()
+                .unwrap_or_else(|error| error.into_inner());
+
+            loop {
+                match incoming.try_recv() {
+                    Ok((page, writes, bytes)) => {
+                        let stale = self.written.get(&page).is_some_and(|last| *last > writes);
+                        if !stale && !self.pages.contains_key(&page) {
+                            received += bytes.len() as u64;
+                            self.pages.insert(page, bytes);
+                        };
+                    }
+                    Err(TryRecvError::Empty) => {
+                        index += 1;
+                        break;
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        self.incoming.remove(index);
+                        break;
+                    }
+                };
+            }
+        }
+
+        if self.incoming.is_empty() {
+            self.written.clear();
+        };
+
+        received
+    }
+
+    /// Fill `buf` from the cached pages. Returns false (leaving `buf` in an
+    /// unspecified state) if any of its bytes isn't cached.
+    pub(crate) fn read(&self, offset: u64, buf: &mut [u8]) -> bool {
+        if self.pages.is_empty() {
+            return false;
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let start = (at % PAGE_SIZE) as usize;
+            let len = (PAGE_SIZE as usize - start).min(buf.len() - done);
+
+            let page = match self.pages.get(&(at / PAGE_SIZE)) {
+                Some(page) if page.len() >= start + len => page,
+                _ => return false,
+            };
+
+            buf[done..done + len].copy_from_slice(&page[start..start + len]);
+            done += len;
+        }
+
+        true
+    }
+
+    /// Drop the cached bytes past the end of a truncated storage, so they
+    /// don't read back if the storage grows again. Pages being read in the
+    /// background might have been read before the truncation, so they're
+    /// dropped too.
+    pub(crate) fn truncate(&mut self, size: u64) {
+        self.incoming.clear();
+        self.written.clear();
+        self.pages.retain(|page, _| page * PAGE_SIZE < size);
+        if let Some(page) = self.pages.get_mut(&(size / PAGE_SIZE)) {
+            page.truncate((size % PAGE_SIZE) as usize);
+        };
+    }
+
+    /// Update the cached pages with bytes written to the storage.
+    pub(crate) fn write(&mut self, offset: u64, buf: &[u8]) {
+        if !self.incoming.is_empty() {
+            self.writes += 1;
+            for page in offset / PAGE_SIZE..(offset + buf.len() as u64).div_ceil(PAGE_SIZE) {
+                self.written.insert(page, self.writes);
+            }
+        };
+
+        if self.pages.is_empty() {
+            return;
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let start = (at % PAGE_SIZE) as usize;
+            let len = (PAGE_SIZE as usize - start).min(buf.len() - done);
+
+            if let Some(page) = self.pages.get_mut(&(at / PAGE_SIZE)) {
+                if page.len() < start + len {
+                    page.resize(start + len, 0);
+                };
+                page[start..start + len].copy_from_slice(&buf[done..done + len]);
+            };
+            done += len;
+        }
+    }
+}
+
+/// The last positions accessed through a tree.
+#[derive(Debug, Default)]
+pub(crate) struct AccessTrace {
+    capacity: usize,
+    positions: VecDeque<u128>,
+}
+
+impl Tree {
+    /// Record the last `capacity` positions read or written through the
+    /// tree. A capacity of 0 stops recording.
+    pub fn access_trace(&self, capacity: usize) {
+        let mut accesses = self.accesses();
+        accesses.capacity = capacity;
+        while accesses.positions.len() > capacity {
+            accesses.positions.pop_front();
+        }
+    }
+
+    /// The recorded positions, from the oldest to the newest access.
+    pub fn recent_accesses(&self) -> impl Iterator<Item = u128> + '_ {
+        let positions: Vec<u128> = self.accesses().positions.iter().copied().collect();
+        positions.into_iter()
+    }
+
+    /// Keep the pages holding the nodes in `positions` (and the nodes needed
+    /// to find them in persistent trees) in memory, so that reading them
+    /// never reaches the storage. Missing nodes are skipped. Trees in
+    /// [`Minimal`](MemoryMode::Minimal) memory mode, and handles opened with
+    /// [`reader`](Tree::reader), pin nothing.
+    ///
+    /// Writes through other handles of the same storage don't update the
+    /// pinned pages.
+    pub fn pin(&self, positions: &[u128]) -> Result<(), NodeError> {
+        if self.memory_mode == MemoryMode::Minimal || self.is_reader() {
+            return Ok(());
+        };
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        for page in self.node_pages(positions)? {
+            let offset = page * PAGE_SIZE;
+            let mut bytes = vec![0_u8; PAGE_SIZE.min(size.saturating_sub(offset)) as usize];
+            if self.read_bytes(offset, &mut bytes).is_err() {
+                return Err(NodeError::Unexistent);
+            };
+            self.cache_mut().pages.insert(page, bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Pin the pages holding a single node, like [`pin`](Tree::pin).
+    pub fn pin_node(&self, position: u128) -> Result<(), NodeError> {
+        self.pin(&[position])
+    }
+
+    /// Start reading the pages holding the nodes in `positions` in the
+    /// background, and pin them (like [`pin`](Tree::pin)) as they arrive.
+    /// Returns right away, except for persistent trees, whose paths from
+    /// the root are read first to find the nodes.
+    ///
+    /// Pages written while they're being read are read again when needed.
+    pub fn prefetch(&self, positions: &[u128]) -> Result<(), NodeError> {
+        if self.memory_mode == MemoryMode::Minimal || self.is_reader() {
+            return Ok(());
+        };
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        let pages = self.node_pages(positions)?;
+        if pages.is_empty() {
+            return Ok(());
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let storage = Arc::clone(&self.storage);
+        let mut cache = self.cache_mut();
+        let writes = cache.writes;
+        thread::spawn(move || {
+            for page in pages {
+                let offset = page * PAGE_SIZE;
+                let mut bytes = vec![0_u8; PAGE_SIZE.min(size.saturating_sub(offset)) as usize];
+                if storage.read_at(offset, &mut bytes).is_err() {
+                    continue;
+                };
+
+                // The tree was dropped.
+                if sender.send((page, writes, bytes)).is_err() {
+                    return;
+                };
+            }
+        });
+        cache.incoming.push(Mutex::new(receiver));
+
+        Ok(())
+    }
+
+    /// Pin the pages holding the first `levels` levels of the tree (like
+    /// [`pin`](Tree::pin)), reading each run of consecutive pages at once.
+    /// The nodes near the root are read by nearly every operation.
+    pub fn warm(&self, levels: u32) -> Result<(), NodeError> {
+        if self.memory_mode == MemoryMode::Minimal || self.is_reader() {
+            return Ok(());
+        };
+
+        let positions = match 1_u128.checked_shl(levels) {
+            Some(width) => width - 1,
+            None => u128::MAX,
+        };
+
+        let mut pages = vec![];
+        if self.features.contains(&Feature::Persistent) {
+            let mut pending: Vec<(u32, u128)> = self
+                .root_slot()?
+                .map(|slot| (0, slot))
+                .into_iter()
+                .collect();
+            while let Some((level, slot)) = pending.pop() {
+                if level >= levels {
+                    continue;
+                };
+
+                pages.extend(self.slot_pages(slot));
+                for child in self.read_slot_header(slot)?.children.iter().flatten() {
+                    pending.push((level + 1, *child));
+                }
+            }
+        } else {
+            let slots = positions.min(self.nodes() as u128);
+            match self.layout {
+                // The slots of the first levels are contiguous, in every
+                // column.
+                Layout::LevelOrder | Layout::Columnar { .. } if slots > 0 => {
+                    let first = self.slot_spans(0);
+                    let last = self.slot_spans(slots - 1);
+                    for ((start, _), (end, width)) in first.into_iter().zip(last) {
+                        pages.extend(self.byte_pages(start, end + width as u128));
+                    }
+                }
+                Layout::LevelOrder | Layout::Columnar { .. } => (),
+                Layout::VanEmdeBoas { .. } => {
+                    for position in 0..positions.min(self.layout.position_limit().unwrap_or(0)) {
+                        match self.layout.slot(position) {
+                            Some(slot) if slot < self.nodes() as u128 => {
+                                pages.extend(self.slot_pages(slot))
+                            }
+                            _ => (),
+                        };
+                    }
+                }
+            };
+        }
+        pages.sort_unstable();
+        pages.dedup();
+        let cached = self.cache();
+        pages.retain(|page| !cached.pages.contains_key(page));
+        drop(cached);
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        let mut run_start = 0;
+        while run_start < pages.len() {
+            let mut run_end = run_start + 1;
+            while run_end < pages.len() && pages[run_end] == pages[run_end - 1] + 1 {
+                run_end += 1;
+            }
+
+            let offset = pages[run_start] * PAGE_SIZE;
+            let end = ((pages[run_end - 1] + 1) * PAGE_SIZE).min(size);
+            let mut bytes = match usize::try_from(end.saturating_sub(offset)) {
+                Ok(len) => vec![0_u8; len],
+                Err(_) => return Err(NodeError::OffsetOverflow),
+            };
+            if self.read_bytes(offset, &mut bytes).is_err() {
+                return Err(NodeError::Unexistent);
+            };
+
+            for (page, chunk) in pages[run_start..run_end]
+                .iter()
+                .zip(bytes.chunks(PAGE_SIZE as usize))
+            {
+                self.cache_mut().pages.insert(*page, chunk.to_vec());
+            }
+            run_start = run_end;
+        }
+
+        Ok(())
+    }
+
+    /// The pages holding a storage slot.
+    fn slot_pages(&self, slot: u128) -> Vec<u64> {
+        self.slot_spans(slot)
+            .into_iter()
+            .flat_map(|(offset, width)| self.byte_pages(offset, offset + width as u128))
+            .collect()
+    }
+
+    /// The pages holding the bits from `start` to `end`, counted after the
+    /// file headers.
+    fn byte_pages(&self, start: u128, end: u128) -> std::ops::Range<u64> {
+        let start = self.header_size as u64 + (start / 8) as u64;
+        let end = self.header_size as u64 + end.div_ceil(8) as u64;
+
+        start / PAGE_SIZE..end.div_ceil(PAGE_SIZE)
+    }
+
+    /// The pages holding the nodes in `positions` that aren't cached yet.
+    fn node_pages(&self, positions: &[u128]) -> Result<Vec<u64>, NodeError> {
+        let mut pages = vec![];
+
+        for position in positions {
+            for slot in self.path_slots(*position)? {
+                for page in self.slot_pages(slot) {
+                    if !self.cache().pages.contains_key(&page) && !pages.contains(&page) {
+                        pages.push(page);
+                    };
+                }
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Drop every pinned page, and the pages being prefetched.
+    pub fn unpin_all(&self) {
+        let mut cache = self.cache_mut();
+        cache.pages.clear();
+        cache.incoming.clear();
+        cache.written.clear();
+    }
+
+    /// The amount of pages pinned in memory, or kept while reading (see
+    /// [`MemoryMode::Aggressive`]).
+    pub fn pinned_pages(&self) -> usize {
+        self.cache().pages.len()
+    }
+
+    /// Record an access to a position, if accesses are being recorded.
+    pub(crate) fn record_access(&self, position: u128) {
+        let mut accesses = self.accesses();
+        if accesses.capacity == 0 {
+            return;
+        };
+
+        if accesses.positions.len() == accesses.capacity {
+            accesses.positions.pop_front();
+        };
+        accesses.positions.push_back(position);
+    }
+
+    /// The slots read to find a position: only its own slot in flat trees, or
+    /// every slot from the root in persistent trees.
+    fn path_slots(&self, position: u128) -> Result<Vec<u128>, NodeError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return match self.layout.slot(position) {
+                Some(slot) if slot < self.nodes() as u128 => Ok(vec![slot]),
+                _ => Ok(vec![]),
+            };
+        };
+
+        let mut slots = vec![];
+        let mut slot = self.root_slot()?;
+        for index in crate::positions::path(position) {
+            let current = match slot {
+                Some(current) => current,
+                None => return Ok(slots),
+            };
+            slots.push(current);
+            slot = self.read_slot(current)?.children[index as usize];
+        }
+        slots.extend(slot);
+
+        Ok(slots)
+    }
+
+    /// The pinned pages, shared with the other readers.
+    pub(crate) fn cache(&self) -> RwLockReadGuard<'_, PageCache> {
+        self.cache.read().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// The pinned pages, for updating them.
+    pub(crate) fn cache_mut(&self) -> RwLockWriteGuard<'_, PageCache> {
+        self.cache
+            .write()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// The recorded accesses.
+    fn accesses(&self) -> MutexGuard<'_, AccessTrace> {
+        self.accesses
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
@@ -0,0 +1,105 @@
+//! Change data capture: the changes committed to a persistent tree, in the
+//! order they were committed.
+
+use crate::{Feature, Tree, TreeFileError, WriteChange};
+use std::time::SystemTime;
+
+/// The changes committed in one version of a persistent tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommittedChange {
+    /// The version the changes were committed in. Sequence numbers only
+    /// grow, so the last one seen can be passed to
+    /// [`cdc_stream`](Tree::cdc_stream) to resume the stream.
+    pub sequence: u64,
+
+    /// When the version was written, if the tree file has a timestamp for
+    /// it.
+    pub created: Option<SystemTime>,
+
+    /// The nodes changed, sorted by position.
+    pub changes: Vec<WriteChange>,
+}
+
+/// An iterator over the changes committed to a tree, returned by
+/// [`cdc_stream`](Tree::cdc_stream).
+#[derive(Debug)]
+pub struct ChangeStream<'a> {
+    tree: &'a Tree,
+    root: Option<u128>,
+    next: u64,
+    end: u64,
+}
+
+impl Tree {
+    /// Stream the changes committed to a persistent tree after the version
+    /// `after` (or every change, if `None`), one version at a time and in
+    /// commit order, e.g. to keep an index or a cache up to date. The stream
+    /// ends at the latest committed version; calling again with the last
+    /// sequence number seen picks up the later changes.
+    ///
+    /// The versions of an active transaction aren't committed yet, so they
+    /// show up once it's committed. An aborted transaction shows up as its
+    /// writes followed by the rollback. Versions reclaimed by the garbage
+    /// collector are merged into the next retained version, and resuming
+    /// after one fails with
+    /// [`UnexistentVersion`](TreeFileError::UnexistentVersion).
+    pub fn cdc_stream(&self, after: Option<u64>) -> Result<ChangeStream<'_>, TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        let end = match self.transaction_start()? {
+            Some(start) => start + 1,
+            None => self.version_count(),
+        };
+
+        let (root, next) = match after {
+            Some(version) => match version < self.version_count() {
+                true => match self.version_root(version)? {
+                    Some(root) => (Some(root), version + 1),
+                    None => return Err(TreeFileError::UnexistentVersion),
+                },
+                false => return Err(TreeFileError::UnexistentVersion),
+            },
+            None => (None, 0),
+        };
+
+        Ok(ChangeStream {
+            tree: self,
+            root,
+            next,
+            end,
+        })
+    }
+}
+
+impl Iterator for ChangeStream<'_> {
+    type Item = Result<CommittedChange, TreeFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.end {
+            let version = self.next;
+            self.next += 1;
+
+            let root = match self.tree.version_root(version) {
+                Ok(Some(root)) => root,
+                Ok(None) => continue,
+                Err(error) => return Some(Err(error)),
+            };
+
+            let changes = match self.tree.diff_roots(self.root, Some(root)) {
+                Ok(changes) => changes,
+                Err(error) => return Some(Err(error)),
+            };
+            self.root = Some(root);
+
+            return Some(Ok(CommittedChange {
+                sequence: version,
+                created: self.tree.version_timestamp(version),
+                changes,
+            }));
+        }
+
+        None
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{
+    bitcodec, CreateOptions, Feature, GapFill, Layout, NodeError, SchemaError, Storage, Tree,
+    TreeFileError, TreeOpenMode,
+};
+
+/// The amount of bits read at once when collecting a subitem. Chunks hold at
+/// least one node.
+const COLUMN_CHUNK_BITS: u128 = 1 << 25;
+
+/// One subitem of every enabled node, packed one after the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubitemColumn {
+    /// The size of the subitem in bits.
+    pub width: u32,
+
+    /// The position of the node each value was read from, in ascending
+    /// order.
+    pub positions: Vec<u128>,
+
+    /// The values, `width` bits each, packed most significant bit first.
+    pub bits: Vec<u8>,
+}
+
+impl SubitemColumn {
+    /// The bits of the `i`th value.
+    pub fn get(&self, i: usize) -> Vec<bool> {
+        bitcodec::unpack_bits_at(&self.bits, i * self.width as usize, self.width as usize)
+    }
+
+    /// The values as numbers. Panics if the subitem is wider than 64 bits.
+    pub fn values(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.positions.len())
+            .map(|i| bitcodec::unpack_u64(&self.bits, i * self.width as usize, self.width))
+    }
+
+    fn push(&mut self, position: u128, subitem: &[bool]) {
+        let offset = self.positions.len() * self.width as usize;
+        self.bits
+            .resize((offset + self.width as usize).div_ceil(8), 0);
+        bitcodec::pack_bits_at(&mut self.bits, offset, subitem);
+        self.positions.push(position);
+    }
+}
+
+/// Reserve the room of every column of a new columnar tree.
+pub(crate) fn reserve(storage: &dyn Storage, options: &CreateOptions) -> Result<(), TreeFileError> {
+    let levels = match options.layout {
+        Layout::Columnar { levels } => levels,
+        _ => return Ok(()),
+    };
+
+    let header_size = 16 + options.subitems.len() as u128 * 4;
+    let size: u128 = crate::core::column_widths(&options.features, &options.subitems)
+        .iter()
+        .map(|width| crate::core::column_size(*width, levels))
+        .sum();
+    let size = match u64::try_from(header_size + size) {
+        Ok(size) => size,
+        Err(_) => return Err(TreeFileError::InvalidSchema(SchemaError::InvalidLayout)),
+    };
+
+    match storage.set_size(size) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TreeFileError::MissingPermissions),
+    }
+}
+
+impl Tree {
+    /// The parts of a storage slot, as the offset in bits after the file
+    /// headers and the width of each.
+    pub(crate) fn slot_spans(&self, slot: u128) -> Vec<(u128, u32)> {
+        crate::core::slot_spans(&self.features, &self.subitems, self.layout, slot)
+    }
+
+    /// Copy the tree into a new tree file at `dest` stored with `layout`,
+    /// e.g. from the interleaved [`Layout::LevelOrder`] to
+    /// [`Layout::Columnar`] and back. Nodes are copied one at a time, so the
+    /// tree doesn't need to fit in memory. The new tree is returned open for
+    /// writing.
+    ///
+    /// Fails with [`SchemaError::InvalidLayout`] if an enabled node is
+    /// deeper than the levels of `layout`. Persistent trees can't be
+    /// converted.
+    pub fn convert_layout(
+        &mut self,
+        dest: &'static str,
+        layout: Layout,
+    ) -> Result<Tree, TreeFileError> {
+        if self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let mut tree = Tree::create_with_options(
+            dest,
+            TreeOpenMode::ReadWrite,
+            CreateOptions {
+                features: self.features.clone(),
+                subitems: self.subitems.clone(),
+                bit_order: self.bit_order,
+                layout,
+                gap_fill: match layout {
+                    Layout::Columnar { .. } => GapFill::Zeros,
+                    _ => self.gap_fill.clone(),
+                },
+            },
+        )?;
+
+        let slots = self.nodes() as u128;
+        let positions = match self.layout.position_limit() {
+            Some(limit) => limit,
+            None => slots,
+        };
+        for position in 0..positions {
+            let contents = match self.layout.slot(position) {
+                Some(slot) if slot < slots => match self.read_slot(slot) {
+                    Ok(contents) => contents,
+                    Err(_) => return Err(TreeFileError::Corrupted),
+                },
+                _ => continue,
+            };
+
+            match tree.set_node_quiet(&contents.subitems, &position, true, !contents.enabled) {
+                Ok(_) => (),
+                Err(NodeError::OutsideLayout) if !contents.enabled => (),
+                Err(NodeError::OutsideLayout) => {
+                    return Err(TreeFileError::InvalidSchema(SchemaError::InvalidLayout))
+                }
+                Err(_) => return Err(TreeFileError::MissingPermissions),
+            };
+        }
+
+        Ok(tree)
+    }
+
+    /// Read one subitem of every enabled node, without decoding the others.
+    /// Flat trees are read sequentially, in chunks of nodes, and columnar
+    /// trees read only the column of the subitem (and of the headers, if
+    /// nodes can be disabled).
+    pub fn collect_subitem(&self, index: usize) -> Result<SubitemColumn, NodeError> {
+        let width = match self.subitems.get(index) {
+            Some(width) => *width,
+            None => return Err(NodeError::InvalidIndex),
+        };
+        let offset = self.node_header_size() + self.subitems[..index].iter().sum::<u32>();
+
+        let mut column = SubitemColumn {
+            width,
+            positions: vec![],
+            bits: vec![],
+        };
+
+        if let Layout::Columnar { .. } = self.layout {
+            self.collect_column(index, &mut column)?;
+
+            return Ok(column);
+        };
+
+        if self.features.contains(&Feature::Persistent) || self.layout != Layout::LevelOrder {
+            let positions: Vec<u128> = self.positions()?.collect::<Result<_, _>>()?;
+            for position in positions {
+                let slot = self.resolve(position)?;
+                let bits = self.read_slot_bits(slot, offset + width)?;
+                column.push(position, &bits[offset as usize..]);
+            }
+
+            return Ok(column);
+        };
+
+        let node_size = self.node_size() as u128;
+        let nodes = self.nodes() as u128;
+        let disabling = self.features.contains(&Feature::Disabling);
+
+        let chunk = (COLUMN_CHUNK_BITS / node_size).max(1);
+        let mut start = 0;
+        while start < nodes {
+            let end = nodes.min(start + chunk);
+
+            let start_byte = start * node_size / 8;
+            let end_byte = (end * node_size).div_ceil(8);
+            let mut bytes = vec![0_u8; (end_byte - start_byte) as usize];
+            match self.read_bytes(self.header_size as u64 + start_byte as u64, &mut bytes) {
+                Ok(_) => (),
+                Err(_) => return Err(NodeError::Unexistent),
+            };
+
+            for position in start..end {
+                let node = (position * node_size - start_byte * 8) as usize;
+                if disabling && !self.bit_order.unpack_bits_at(&bytes, node, 1)[0] {
+                    continue;
+                };
+
+                let subitem =
+                    self.bit_order
+                        .unpack_bits_at(&bytes, node + offset as usize, width as usize);
+                column.push(position, &subitem);
+            }
+
+            start = end;
+        }
+
+        Ok(column)
+    }
+
+    fn collect_column(&self, index: usize, column: &mut SubitemColumn) -> Result<(), NodeError> {
+        let nodes = self.nodes() as u128;
+        let width = column.width;
+        let disabling = self.features.contains(&Feature::Disabling);
+
+        let chunk = (COLUMN_CHUNK_BITS / width as u128).max(1);
+        let mut start = 0;
+        while start < nodes {
+            let end = nodes.min(start + chunk);
+
+            // Every span of the first node of the chunk marks where its
+            // column starts.
+            let spans = self.slot_spans(start);
+            let enabled = match disabling {
+                true => {
+                    let (offset, header_size) = spans[0];
+                    let headers = self.read_bits(offset, ((end - start) as u32) * header_size)?;
+                    headers.into_iter().step_by(header_size as usize).collect()
+                }
+                false => vec![true; (end - start) as usize],
+            };
+
+            let (offset, _) = spans[spans.len() - self.subitems.len() + index];
+            let values = self.read_bits(offset, ((end - start) as u32) * width)?;
+
+            for (i, position) in (start..end).enumerate() {
+                if enabled[i] {
+                    let value = i * width as usize;
+                    column.push(position, &values[value..value + width as usize]);
+                };
+            }
+
+            start = end;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Ordering nodes by a subitem read as an integer, e.g. to sort the children
+//! of a node or check the ordering invariants of a search tree.
+
+use crate::{Node, NodeData, NodeError, Tree};
+use std::cmp::Ordering;
+use std::iter;
+
+/// A subitem read as an integer of its width, ordered by its value. Returned
+/// by [`cmp_key`](NodeData::cmp_key), so that nodes can be sorted with
+/// [`sort_by_key`](slice::sort_by_key).
+///
+/// Keys of subitems of different widths are compared by value too: shorter
+/// unsigned subitems are padded with zeros, and shorter signed subitems with
+/// their sign bit.
+#[derive(Debug, Clone)]
+pub struct SubitemKey {
+    bits: Vec<bool>,
+
+    /// Whether the subitem is a two's complement signed integer.
+    signed: bool,
+}
+
+impl SubitemKey {
+    /// The key of a subitem read as an unsigned integer, or a two's
+    /// complement signed integer if `signed`.
+    pub fn new(bits: &[bool], signed: bool) -> Self {
+        SubitemKey {
+            bits: bits.to_vec(),
+            signed,
+        }
+    }
+
+    fn is_negative(&self) -> bool {
+        self.signed && self.bits.first() == Some(&true)
+    }
+}
+
+impl Ord for SubitemKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.is_negative(), other.is_negative()) {
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => (),
+        };
+
+        // Both have the same sign, so extending them to the same width with
+        // it leaves them ordered like their bits.
+        let fill = self.is_negative();
+        let width = self.bits.len().max(other.bits.len());
+        extend(&self.bits, fill, width).cmp(extend(&other.bits, fill, width))
+    }
+}
+
+impl PartialOrd for SubitemKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SubitemKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SubitemKey {}
+
+impl NodeData {
+    /// The key ordering the node by a subitem read as an unsigned integer,
+    /// or a two's complement signed integer if `signed`.
+    pub fn cmp_key(&self, subitem_index: usize, signed: bool) -> Result<SubitemKey, NodeError> {
+        match self.subitems.get(subitem_index) {
+            Some(subitem) => Ok(SubitemKey::new(subitem, signed)),
+            None => Err(NodeError::InvalidIndex),
+        }
+    }
+}
+
+impl Node<'_> {
+    /// The key ordering the node by a subitem read as an unsigned integer,
+    /// or a two's complement signed integer if `signed`.
+    pub fn cmp_key(&self, subitem_index: usize, signed: bool) -> Result<SubitemKey, NodeError> {
+        match self.subitems.get(subitem_index) {
+            Some(subitem) => Ok(SubitemKey::new(subitem, signed)),
+            None => Err(NodeError::InvalidIndex),
+        }
+    }
+}
+
+impl Tree {
+    /// Compare the enabled nodes at positions `a` and `b` by a subitem read
+    /// as an unsigned integer, or a two's complement signed integer if
+    /// `signed`.
+    pub fn compare_nodes(
+        &self,
+        a: u128,
+        b: u128,
+        subitem_index: usize,
+        signed: bool,
+    ) -> Result<Ordering, NodeError> {
+        let a = self.read_node(a)?.cmp_key(subitem_index, signed)?;
+        let b = self.read_node(b)?.cmp_key(subitem_index, signed)?;
+
+        Ok(a.cmp(&b))
+    }
+}
+
+/// The bits of a subitem, padded in front with `fill` up to `width` bits.
+fn extend(bits: &[bool], fill: bool, width: usize) -> impl Iterator<Item = bool> + '_ {
+    iter::repeat_n(fill, width - bits.len()).chain(bits.iter().copied())
+}
@@ -0,0 +1,546 @@
+//! The parts of the format that don't need file I/O: parsing the headers
+//! and decoding the nodes of a tree file held in memory. They build without
+//! the `std` feature, as long as an allocator is available.
+
+use crate::{
+    bitcodec, layout, schema, BitOrder, CreateOptions, Feature, GapFill, Layout, NodeData,
+    NodeError, SchemaError, TreeFileError, BIT_ORDER_FLAG, DIRTY_FLAG, FILE_IDENTIFIER,
+    FORMAT_VERSION, MAX_SUBITEMS, POINTER_SIZE,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use strum::IntoEnumIterator;
+
+/// The decoded contents of a storage slot.
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) struct Slot {
+    pub(crate) enabled: bool,
+    pub(crate) children: [Option<u128>; 2],
+    pub(crate) hints: [bool; 2],
+    pub(crate) subitems: Vec<Vec<bool>>,
+}
+
+/// The size in bits of the headers prepended to each node by `features`.
+pub(crate) fn node_header_size(features: &[Feature]) -> u32 {
+    let mut size = 0;
+
+    if features.contains(&Feature::Disabling) {
+        size += 1;
+    }
+
+    if features.contains(&Feature::Persistent) {
+        size += POINTER_SIZE * 2;
+    }
+
+    if features.contains(&Feature::ChildHints) {
+        size += 2;
+    }
+
+    size
+}
+
+/// The total size in bits of each node of a tree with `features` and
+/// `subitems`.
+pub(crate) fn node_size(features: &[Feature], subitems: &[u32]) -> u32 {
+    let mut size = node_header_size(features);
+
+    for subitem in subitems {
+        size += *subitem;
+    }
+
+    size
+}
+
+/// Read and check the headers of a tree file through `read_at`, which fills
+/// a buffer with the bytes at an offset, returning the options the tree was
+/// created with.
+pub(crate) fn parse_headers(
+    mut read_at: impl FnMut(u64, &mut [u8]) -> bool,
+) -> Result<CreateOptions, TreeFileError> {
+    let mut features: Vec<Feature> = vec![];
+    let mut subitems: Vec<u32> = vec![];
+
+    let mut file_headers = [0u8; 16];
+    if !read_at(0, &mut file_headers) {
+        return Err(TreeFileError::MissingHeaders);
+    };
+
+    if file_headers[0..8] != FILE_IDENTIFIER {
+        return Err(TreeFileError::InvalidIdentifier);
+    };
+
+    if file_headers[8..10] != FORMAT_VERSION {
+        return Err(TreeFileError::UnsupportedFormatVersion);
+    };
+
+    let feature_bits = bitcodec::bytes_to_bits(&file_headers[10..12]);
+
+    // The bits past the last feature and before the layout are unused, so a
+    // tree file setting them was written by a newer version of the format.
+    if feature_bits[Feature::iter().count()..layout::LAYOUT_COLUMNAR_BIT].contains(&true) {
+        return Err(TreeFileError::UnsupportedFormatVersion);
+    };
+
+    for (i, feature) in Feature::iter().enumerate() {
+        if feature_bits[i] {
+            features.push(feature);
+        }
+    }
+
+    let bit_order = match feature_bits[BIT_ORDER_FLAG] {
+        false => BitOrder::MsbFirst,
+        true => BitOrder::LsbFirst,
+    };
+
+    let layout = match bitcodec::bits_to_u64(
+        &feature_bits[layout::LAYOUT_LEVELS_BIT
+            ..layout::LAYOUT_LEVELS_BIT + layout::LAYOUT_LEVELS_BITS as usize],
+    ) as u32
+    {
+        levels if feature_bits[layout::LAYOUT_COLUMNAR_BIT] => Layout::Columnar { levels },
+        0 => Layout::LevelOrder,
+        levels => Layout::VanEmdeBoas { levels },
+    };
+
+    let subitem_count = bitcodec::u8_array_to_u32(&match &file_headers[12..16] {
+        [a, b, c, d] => [*a, *b, *c, *d],
+        _ => panic!("Slice does not have a length of 4"),
+    });
+    if subitem_count as usize > MAX_SUBITEMS {
+        return Err(TreeFileError::InvalidSchema(SchemaError::TooManySubitems {
+            count: subitem_count as usize,
+            max: MAX_SUBITEMS,
+        }));
+    };
+
+    for i in 0..subitem_count as u64 {
+        let mut subitem_bytes = [0_u8; 4];
+        if !read_at(16 + i * 4, &mut subitem_bytes) {
+            return Err(TreeFileError::MissingHeaders);
+        };
+        subitems.push(bitcodec::u8_array_to_u32(&subitem_bytes));
+    }
+
+    match schema::validate(&features, &subitems)
+        .and_then(|_| schema::validate_layout(&features, layout))
+    {
+        Ok(_) => (),
+        Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+    };
+
+    Ok(CreateOptions {
+        features,
+        subitems,
+        bit_order,
+        layout,
+        gap_fill: GapFill::Zeros,
+    })
+}
+
+/// Split a slot's bits into its feature headers and subitems. Subitems
+/// that aren't in `bits` are left out. Fails with what's wrong if `bits`
+/// don't hold the feature headers.
+pub(crate) fn decode_slot(
+    features: &[Feature],
+    subitems: &[u32],
+    bits: &[bool],
+) -> Result<Slot, &'static str> {
+    if bits.len() < node_header_size(features) as usize {
+        return Err("the slot is shorter than its headers");
+    };
+
+    let mut offset = 0;
+
+    let mut enabled = true;
+    if features.contains(&Feature::Disabling) {
+        enabled = bits[0];
+        offset += 1;
+    };
+
+    let mut children = [None, None];
+    if features.contains(&Feature::Persistent) {
+        for child in children.iter_mut() {
+            let pointer =
+                bitcodec::bits_to_u64(&bits[offset..offset + POINTER_SIZE as usize]) as u128;
+            if pointer != 0 {
+                *child = Some(pointer - 1);
+            }
+            offset += POINTER_SIZE as usize;
+        }
+    };
+
+    let mut hints = [false, false];
+    if features.contains(&Feature::ChildHints) {
+        hints = [bits[offset], bits[offset + 1]];
+        offset += 2;
+    };
+
+    let mut decoded: Vec<Vec<bool>> = vec![];
+    for subitem in subitems {
+        let subitem = match bits.get(offset..offset + *subitem as usize) {
+            Some(subitem) => subitem,
+            None => break,
+        };
+        decoded.push(subitem.to_vec());
+        offset += subitem.len();
+    }
+
+    Ok(Slot {
+        enabled,
+        children,
+        hints,
+        subitems: decoded,
+    })
+}
+
+/// The width in bits of each column of a columnar tree: the feature headers
+/// of the nodes first, followed by each subitem.
+pub(crate) fn column_widths(features: &[Feature], subitems: &[u32]) -> Vec<u32> {
+    let mut widths = vec![node_header_size(features)];
+    widths.extend(subitems);
+
+    widths
+}
+
+/// The size in bytes of a column of `width` bits for `levels` levels. Every
+/// column starts on a byte boundary.
+pub(crate) fn column_size(width: u32, levels: u32) -> u128 {
+    (((1_u128 << levels) - 1) * width as u128).div_ceil(8)
+}
+
+/// The parts of a storage slot, as the offset in bits after the file headers
+/// and the width of each. Nodes of columnar trees are split between the
+/// columns, other nodes are stored whole.
+pub(crate) fn slot_spans(
+    features: &[Feature],
+    subitems: &[u32],
+    layout: Layout,
+    slot: u128,
+) -> Vec<(u128, u32)> {
+    let node_size = node_size(features, subitems);
+    let levels = match layout {
+        Layout::Columnar { levels } => levels,
+        // Slots too far to be stored saturate, and are rejected as past the
+        // largest offset of the storage.
+        _ => return vec![(slot.saturating_mul(node_size as u128), node_size)],
+    };
+
+    let mut spans = vec![];
+    let mut start: u128 = 0;
+    for width in column_widths(features, subitems) {
+        if width != 0 {
+            spans.push((
+                (start * 8).saturating_add(slot.saturating_mul(width as u128)),
+                width,
+            ));
+        };
+        start += column_size(width, levels);
+    }
+
+    spans
+}
+
+/// The amount of slots stored in `size` bytes after the file headers.
+pub(crate) fn slot_count(features: &[Feature], subitems: &[u32], layout: Layout, size: u64) -> u64 {
+    match (node_size(features, subitems), layout) {
+        (0, _) => 0,
+        (_, Layout::Columnar { levels }) => (1 << levels) - 1,
+        (node_size, _) => size * 8 / node_size as u64,
+    }
+}
+
+/// A flat tree file held in memory (e.g. a byte slice or a `Vec<u8>`), read
+/// without any file I/O.
+#[derive(Debug)]
+pub struct TreeBytes<B> {
+    bytes: B,
+    options: CreateOptions,
+    header_size: usize,
+}
+
+impl<B: AsRef<[u8]>> TreeBytes<B> {
+    /// Parse the headers of a tree file. Fails with
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown) if the tree was
+    /// copied while open for writing. Persistent trees can't be read, as
+    /// their versions are kept in other files.
+    pub fn parse(bytes: B) -> Result<Self, TreeFileError> {
+        let data = bytes.as_ref();
+        let options = parse_headers(|offset, buf| {
+            match data.get(offset as usize..offset as usize + buf.len()) {
+                Some(read) => {
+                    buf.copy_from_slice(read);
+                    true
+                }
+                None => false,
+            }
+        })?;
+
+        if bitcodec::bytes_to_bits(&data[10..12])[DIRTY_FLAG] {
+            return Err(TreeFileError::UncleanShutdown);
+        };
+
+        if options.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        Ok(TreeBytes {
+            bytes,
+            header_size: 16 + options.subitems.len() * 4,
+            options,
+        })
+    }
+
+    /// The options the tree was created with.
+    pub fn options(&self) -> &CreateOptions {
+        &self.options
+    }
+
+    /// The amount of nodes in the tree, like [`Tree::nodes`](crate::Tree::nodes).
+    pub fn nodes(&self) -> u64 {
+        slot_count(
+            &self.options.features,
+            &self.options.subitems,
+            self.options.layout,
+            self.bytes.as_ref().len().saturating_sub(self.header_size) as u64,
+        )
+    }
+
+    /// Decode a node by its tranversal position.
+    pub fn node(&self, position: u128) -> Result<NodeData, NodeError> {
+        let slot = match self.options.layout.slot(position) {
+            Some(slot) if slot < self.nodes() as u128 => slot,
+            _ => return Err(NodeError::Unexistent),
+        };
+
+        let mut bits = vec![];
+        for (offset, width) in slot_spans(
+            &self.options.features,
+            &self.options.subitems,
+            self.options.layout,
+            slot,
+        ) {
+            let start = self.header_size + (offset / 8) as usize;
+            let end = self.header_size + (offset + width as u128).div_ceil(8) as usize;
+            let span = match self.bytes.as_ref().get(start..end) {
+                Some(span) => span,
+                None => return Err(NodeError::Unexistent),
+            };
+            bits.extend(self.options.bit_order.unpack_bits_at(
+                span,
+                (offset % 8) as usize,
+                width as usize,
+            ));
+        }
+
+        let contents = match decode_slot(&self.options.features, &self.options.subitems, &bits) {
+            Ok(contents) => contents,
+            Err(detail) => return Err(self.corruption(position, slot, detail)),
+        };
+        if contents.subitems.len() < self.options.subitems.len() {
+            return Err(self.corruption(position, slot, "the slot is shorter than its subitems"));
+        };
+        if !contents.enabled {
+            return Err(NodeError::Disabled);
+        };
+
+        Ok(NodeData {
+            position,
+            enabled: true,
+            subitems: contents.subitems,
+        })
+    }
+
+    /// A [`Corrupt`](NodeError::Corrupt) error for the slot of the node at
+    /// `position`, located at the start of the slot.
+    fn corruption(&self, position: u128, slot: u128, detail: &'static str) -> NodeError {
+        let spans = slot_spans(
+            &self.options.features,
+            &self.options.subitems,
+            self.options.layout,
+            slot,
+        );
+        let start = spans.first().map_or(0, |(offset, _)| offset / 8);
+
+        NodeError::Corrupt {
+            position: Some(position),
+            offset: (self.header_size as u128 + start) as u64,
+            detail,
+        }
+    }
+
+    /// Decode the subitems of a node into `out`, one number per subitem,
+    /// without allocating. Every subitem must be at most 64 bits, and `out`
+    /// must have room for all of them. Returns the amount of subitems
+    /// written.
+    pub fn decode_node_into(&self, position: u128, out: &mut [u64]) -> Result<usize, NodeError> {
+        let subitems = &self.options.subitems;
+        if out.len() < subitems.len() {
+            return Err(NodeError::SubitemCountMismatch {
+                expected: subitems.len(),
+                got: out.len(),
+            });
+        };
+
+        let slot = self.enabled_slot(position)?;
+        for (index, value) in out.iter_mut().take(subitems.len()).enumerate() {
+            let (offset, width) = self.column_span(slot, Some(index));
+            if width > 64 {
+                return Err(NodeError::InvalidSubitem);
+            };
+            *value = self.options.bit_order.unpack_u64(
+                self.bytes.as_ref(),
+                self.bit_index(offset, width)?,
+                width,
+            );
+        }
+
+        Ok(subitems.len())
+    }
+
+    /// Copy one subitem of a node into `out`, most significant bit first,
+    /// without allocating. `out` must hold at least as many bits as the
+    /// subitem. Returns the size of the subitem in bits.
+    pub fn decode_subitem_into(
+        &self,
+        position: u128,
+        index: usize,
+        out: &mut [u8],
+    ) -> Result<u32, NodeError> {
+        if index >= self.options.subitems.len() {
+            return Err(NodeError::InvalidIndex);
+        };
+
+        let slot = self.enabled_slot(position)?;
+        let (offset, width) = self.column_span(slot, Some(index));
+        if out.len() * 8 < width as usize {
+            return Err(NodeError::InvalidSubitem);
+        };
+
+        let start = self.bit_index(offset, width)?;
+        for bit in 0..width as usize {
+            let set = self.bytes.as_ref()[(start + bit) / 8]
+                & self.options.bit_order.mask(start + bit)
+                != 0;
+            bitcodec::pack_bits_at(out, bit, &[set]);
+        }
+
+        Ok(width)
+    }
+
+    /// The slot of an enabled node, checked without allocating.
+    fn enabled_slot(&self, position: u128) -> Result<u128, NodeError> {
+        let slot = match self.options.layout.slot(position) {
+            Some(slot) if slot < self.nodes() as u128 => slot,
+            _ => return Err(NodeError::Unexistent),
+        };
+
+        if self.options.features.contains(&Feature::Disabling) {
+            let (offset, width) = self.column_span(slot, None);
+            let at = self.bit_index(offset, width)?;
+            if self.bytes.as_ref()[at / 8] & self.options.bit_order.mask(at) == 0 {
+                return Err(NodeError::Disabled);
+            };
+        };
+
+        Ok(slot)
+    }
+
+    /// The offset in bits after the file headers and the width of a slot's
+    /// feature headers (`None`) or of one of its subitems.
+    fn column_span(&self, slot: u128, index: Option<usize>) -> (u128, u32) {
+        let (features, subitems) = (&self.options.features, &self.options.subitems);
+        let header = node_header_size(features);
+        let (leading, width) = match index {
+            Some(index) => (&subitems[..index], subitems[index]),
+            None => (&subitems[..0], header),
+        };
+        // The widths of the columns before the span's.
+        let before = index
+            .map(|_| header)
+            .into_iter()
+            .chain(leading.iter().copied());
+
+        match self.options.layout {
+            Layout::Columnar { levels } => (
+                before.map(|width| column_size(width, levels)).sum::<u128>() * 8
+                    + slot * width as u128,
+                width,
+            ),
+            _ => (
+                slot * node_size(features, subitems) as u128 + before.map(u128::from).sum::<u128>(),
+                width,
+            ),
+        }
+    }
+
+    /// The index in the bytes of the first bit of a span, checking that the
+    /// whole span is there.
+    fn bit_index(&self, offset: u128, width: u32) -> Result<usize, NodeError> {
+        let end = self.header_size as u128 * 8 + offset + width as u128;
+        match end <= self.bytes.as_ref().len() as u128 * 8 {
+            true => Ok(self.header_size * 8 + offset as usize),
+            false => Err(NodeError::Unexistent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The headers of a tree file of `version` with one 8-bit subitem and
+    /// the features header `features`.
+    fn headers(version: [u8; 2], features: [u8; 2]) -> Vec<u8> {
+        let mut headers = FILE_IDENTIFIER.to_vec();
+        headers.extend(version);
+        headers.extend(features);
+        headers.extend([0, 0, 0, 1, 0, 0, 0, 8]);
+        headers
+    }
+
+    fn parse(headers: &[u8]) -> Result<CreateOptions, TreeFileError> {
+        parse_headers(|offset, buf| match headers.get(offset as usize..) {
+            Some(bytes) if bytes.len() >= buf.len() => {
+                buf.copy_from_slice(&bytes[..buf.len()]);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn reads_the_current_version() {
+        let options = parse(&headers(FORMAT_VERSION, [0b1000_0000, 0])).unwrap();
+        assert_eq!(options.features, vec![Feature::Disabling]);
+        assert_eq!(options.subitems, vec![8]);
+    }
+
+    #[test]
+    fn rejects_the_first_version() {
+        assert!(matches!(
+            parse(&headers([0, 0], [0b1000_0000, 0])),
+            Err(TreeFileError::UnsupportedFormatVersion)
+        ));
+    }
+
+    #[test]
+    fn reads_first_version_headers_once_converted() {
+        // The conversion in the changelog: the version set to `00 01`, and
+        // the features header cleared but for the disabling bit.
+        for (features, converted) in [
+            ([0b1000_0000, 0], vec![Feature::Disabling]),
+            ([0b1010_0001, 0b0100_0011], vec![Feature::Disabling]),
+            ([0b0000_0000, 0], vec![]),
+        ] {
+            let mut file = headers([0, 0], features);
+            file[8..10].copy_from_slice(&[0, 1]);
+            file[10] &= 0b1000_0000;
+            file[11] = 0;
+
+            let options = parse(&file).unwrap();
+            assert_eq!(options.features, converted);
+            assert_eq!(options.bit_order, BitOrder::MsbFirst);
+            assert_eq!(options.layout, Layout::LevelOrder);
+        }
+    }
+}
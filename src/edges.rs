@@ -0,0 +1,202 @@
+//! Import of trees stored as adjacency lists, one edge per row of a CSV or
+//! TSV file.
+
+use crate::{bitcodec, NodeError, Tree};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// How the rows of an adjacency list are read.
+#[derive(Debug, Clone)]
+pub struct EdgeOptions {
+    /// The byte separating the fields of a row, e.g. `b','` or `b'\t'`.
+    pub delimiter: u8,
+
+    /// Skip the first row.
+    pub header: bool,
+
+    /// Read rows as `parent_id,child_id,subitems...` instead of
+    /// `parent_position,child_index,subitems...`. Ids can be any text, and
+    /// each child is placed in the first free child position of its parent.
+    pub ids: bool,
+}
+
+impl Default for EdgeOptions {
+    fn default() -> Self {
+        EdgeOptions {
+            delimiter: b',',
+            header: false,
+            ids: false,
+        }
+    }
+}
+
+/// Why an adjacency list couldn't be imported. `line` is the 1-based line
+/// of the row where the problem was found.
+#[derive(Debug)]
+pub enum EdgeError {
+    /// The input couldn't be read.
+    Read,
+
+    /// The row doesn't have a parent, a child and every subitem.
+    Syntax { line: usize },
+
+    /// A subitem isn't a number, or doesn't fit in its size.
+    InvalidValue { line: usize },
+
+    /// The parent isn't in the tree, or was imported after the child.
+    UnknownParent { line: usize },
+
+    /// The parent already has two children, or the child id was already
+    /// used.
+    TooManyChildren { line: usize },
+
+    /// A node couldn't be written.
+    Node { line: usize, error: NodeError },
+}
+
+impl Tree {
+    /// Import the edges of an adjacency list. Every row is written as soon as
+    /// it's read, so the input doesn't need to fit in memory. The root's row
+    /// leaves both the parent and the child empty (`,,subitems...`) when
+    /// reading positions, or only the parent when reading ids.
+    ///
+    /// Subitems are unsigned decimal numbers of at most 64 bits. Fields
+    /// can't be quoted. Returns the amount of nodes imported.
+    pub fn import_edges(
+        &mut self,
+        reader: impl BufRead,
+        options: &EdgeOptions,
+    ) -> Result<u64, EdgeError> {
+        let delimiter = options.delimiter as char;
+
+        // The position of each id, and the amount of children placed under
+        // it.
+        let mut ids: HashMap<String, (u128, u8)> = HashMap::new();
+
+        let mut imported = 0;
+        for (index, row) in reader.lines().enumerate() {
+            let line = index + 1;
+            let row = match row {
+                Ok(row) => row,
+                Err(_) => return Err(EdgeError::Read),
+            };
+            let row = row.trim_end_matches('\r');
+            if row.is_empty() || (options.header && index == 0) {
+                continue;
+            };
+
+            let fields: Vec<&str> = row.split(delimiter).collect();
+            if fields.len() != 2 + self.subitems.len() {
+                return Err(EdgeError::Syntax { line });
+            };
+
+            let position = match options.ids {
+                true => self.place_id(fields[0], fields[1], &mut ids, line)?,
+                false => self.place_position(fields[0], fields[1], line)?,
+            };
+
+            let mut subitems = vec![];
+            for (field, size) in fields[2..].iter().zip(&self.subitems) {
+                if *size > 64 {
+                    return Err(EdgeError::InvalidValue { line });
+                };
+                let value = match field.trim().parse::<u64>() {
+                    Ok(value) if *size == 64 || value >> size == 0 => value,
+                    _ => return Err(EdgeError::InvalidValue { line }),
+                };
+                subitems.push(bitcodec::u64_to_bits(value, *size));
+            }
+
+            match self.bulk(|tree| tree.set_node_quiet(&subitems, &position, false, false)) {
+                Ok(_) => imported += 1,
+                Err(error) => return Err(EdgeError::Node { line, error }),
+            };
+        }
+
+        Ok(imported)
+    }
+
+    /// The position of a `parent_position,child_index` row.
+    fn place_position(
+        &mut self,
+        parent: &str,
+        index: &str,
+        line: usize,
+    ) -> Result<u128, EdgeError> {
+        let (parent, index) = (parent.trim(), index.trim());
+        if parent.is_empty() && index.is_empty() {
+            return Ok(0);
+        };
+
+        let parent = match parent.parse::<u128>() {
+            Ok(parent) => parent,
+            Err(_) => return Err(EdgeError::Syntax { line }),
+        };
+        let index = match index.parse::<u8>() {
+            Ok(index) if index < 2 => index,
+            _ => return Err(EdgeError::Syntax { line }),
+        };
+
+        if !self.has_enabled_node(parent) {
+            return Err(EdgeError::UnknownParent { line });
+        };
+
+        match parent
+            .checked_mul(2)
+            .and_then(|double| double.checked_add(1 + index as u128))
+        {
+            Some(position) => Ok(position),
+            None => Err(EdgeError::Node {
+                line,
+                error: NodeError::Unexistent,
+            }),
+        }
+    }
+
+    /// The position of a `parent_id,child_id` row, recording the child's id.
+    fn place_id(
+        &mut self,
+        parent: &str,
+        child: &str,
+        ids: &mut HashMap<String, (u128, u8)>,
+        line: usize,
+    ) -> Result<u128, EdgeError> {
+        let (parent, child) = (parent.trim(), child.trim());
+        if child.is_empty() {
+            return Err(EdgeError::Syntax { line });
+        };
+        if ids.contains_key(child) {
+            return Err(EdgeError::TooManyChildren { line });
+        };
+
+        let position = match parent.is_empty() {
+            true => 0,
+            false => {
+                let (parent, children) = match ids.get_mut(parent) {
+                    Some(parent) => parent,
+                    None => return Err(EdgeError::UnknownParent { line }),
+                };
+                if *children == 2 {
+                    return Err(EdgeError::TooManyChildren { line });
+                };
+                *children += 1;
+
+                match parent
+                    .checked_mul(2)
+                    .and_then(|double| double.checked_add(*children as u128))
+                {
+                    Some(position) => position,
+                    None => {
+                        return Err(EdgeError::Node {
+                            line,
+                            error: NodeError::Unexistent,
+                        })
+                    }
+                }
+            }
+        };
+        ids.insert(child.to_string(), (position, 0));
+
+        Ok(position)
+    }
+}
@@ -0,0 +1,170 @@
+//! Schema evolution: copying a tree into a new tree file with other
+//! subitems, or with its subitems reset.
+
+use crate::{CreateOptions, Feature, GapFill, Tree, TreeFileError, TreeOpenMode};
+
+impl Tree {
+    /// Copy the tree into a new tree file at `dest` whose nodes have one
+    /// more subitem of `bits` bits, set to `default_value` in every node.
+    /// The subitem goes after the others, or before the writer id and
+    /// last-modified time of trees with the attribution feature. Nodes are
+    /// copied one at a time, as they're read, and the new tree is returned
+    /// open for writing.
+    ///
+    /// The nodes are packed without padding, so the tree file is always
+    /// rewritten. Nodes are copied as they are: the validator, the write
+    /// hooks and the attribution stamps are skipped. Persistent trees can't
+    /// be evolved.
+    pub fn add_subitem(
+        &mut self,
+        bits: u32,
+        default_value: &[bool],
+        dest: &'static str,
+    ) -> Result<Tree, TreeFileError> {
+        if default_value.len() != bits as usize {
+            return Err(TreeFileError::InvalidDefaultValue);
+        };
+
+        let index = match self.features.contains(&Feature::Attribution) {
+            true => self.subitems.len() - 2,
+            false => self.subitems.len(),
+        };
+
+        let mut subitems = self.subitems.clone();
+        subitems.insert(index, bits);
+
+        self.copy_subitems(dest, subitems, |node| {
+            node.insert(index, default_value.to_vec());
+        })
+    }
+
+    /// Copy the tree into a new tree file at `dest` whose nodes lack the
+    /// subitem at `index`, e.g. once it's obsolete, shrinking the nodes and
+    /// the tree file. Nodes are copied like with
+    /// [`add_subitem`](Tree::add_subitem).
+    ///
+    /// The writer id and last-modified time of trees with the attribution
+    /// feature can't be dropped.
+    pub fn drop_subitem(
+        &mut self,
+        index: usize,
+        dest: &'static str,
+    ) -> Result<Tree, TreeFileError> {
+        if index >= self.subitems.len() {
+            return Err(TreeFileError::UnexistentSubitem);
+        };
+
+        if self.features.contains(&Feature::Attribution) && index >= self.subitems.len() - 2 {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let mut subitems = self.subitems.clone();
+        subitems.remove(index);
+
+        self.copy_subitems(dest, subitems, |node| {
+            node.remove(index);
+        })
+    }
+
+    /// Copy the shape of the tree into a new tree file at `dest`: every
+    /// stored node is copied, enabled or disabled as it is, but with
+    /// `default_subitems` as its subitems, e.g. to start trees of many
+    /// tenants from one precomputed structure. Nodes are copied like with
+    /// [`add_subitem`](Tree::add_subitem), so the writer id and
+    /// last-modified time of trees with the attribution feature are reset
+    /// too.
+    pub fn clone_structure(
+        &self,
+        dest: &'static str,
+        default_subitems: &[Vec<bool>],
+    ) -> Result<Tree, TreeFileError> {
+        if default_subitems.len() != self.subitems.len()
+            || default_subitems
+                .iter()
+                .zip(&self.subitems)
+                .any(|(subitem, size)| subitem.len() != *size as usize)
+        {
+            return Err(TreeFileError::InvalidDefaultValue);
+        };
+
+        let options = CreateOptions {
+            features: self.features.clone(),
+            subitems: self.subitems.clone(),
+            bit_order: self.bit_order,
+            layout: self.layout,
+            gap_fill: self.gap_fill.clone(),
+        };
+
+        self.copy_into(dest, options, |node| {
+            node.clone_from_slice(default_subitems);
+        })
+    }
+
+    /// Copy every stored node into a new tree at `dest` with other
+    /// subitems, changing the subitems of each node with `change`.
+    fn copy_subitems(
+        &mut self,
+        dest: &'static str,
+        subitems: Vec<u32>,
+        change: impl Fn(&mut Vec<Vec<bool>>),
+    ) -> Result<Tree, TreeFileError> {
+        let gap_fill = match self.gap_fill.clone() {
+            GapFill::Template(mut template) => {
+                change(&mut template);
+                GapFill::Template(template)
+            }
+            gap_fill => gap_fill,
+        };
+        let options = CreateOptions {
+            features: self.features.clone(),
+            subitems,
+            bit_order: self.bit_order,
+            layout: self.layout,
+            gap_fill,
+        };
+
+        self.copy_into(dest, options, change)
+    }
+
+    /// Copy every stored node into a new tree at `dest` created with
+    /// `options`, changing the subitems of each node with `change`. Nodes
+    /// are enabled if the new tree lacks the disabling feature.
+    pub(crate) fn copy_into(
+        &self,
+        dest: &str,
+        options: CreateOptions,
+        change: impl Fn(&mut Vec<Vec<bool>>),
+    ) -> Result<Tree, TreeFileError> {
+        if self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let disabling = options.features.contains(&Feature::Disabling);
+        let mut tree = Tree::create_at(dest, TreeOpenMode::ReadWrite, options)?;
+
+        let slots = self.nodes() as u128;
+        let positions = match self.layout.position_limit() {
+            Some(limit) => limit,
+            None => slots,
+        };
+        for position in 0..positions {
+            let mut contents = match self.layout.slot(position) {
+                Some(slot) if slot < slots => match self.read_slot(slot) {
+                    Ok(contents) => contents,
+                    Err(_) => return Err(TreeFileError::Corrupted),
+                },
+                _ => continue,
+            };
+
+            change(&mut contents.subitems);
+            if tree
+                .write_node(&contents.subitems, position, disabling && !contents.enabled)
+                .is_err()
+            {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        }
+
+        Ok(tree)
+    }
+}
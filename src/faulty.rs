@@ -0,0 +1,175 @@
+//! A storage failing on purpose, to test how an application recovers from
+//! the failures of the storage of its trees. Open a tree over it with
+//! [`Tree::with_storage`](crate::Tree::with_storage).
+
+use crate::Storage;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+/// A failure of a [`FaultyStorage`]. Each fault lets `after` operations of
+/// its kind through (counted from when it's injected) and fails the next
+/// one, once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The read fails with an error.
+    ReadError { after: u64 },
+
+    /// The read succeeds, but only the first half of the bytes are read, and
+    /// the rest read as zeros.
+    ShortRead { after: u64 },
+
+    /// The write fails with an error, without writing anything.
+    WriteError { after: u64 },
+
+    /// Only the first `len` bytes of the write are written, then it fails
+    /// with an error, like a write torn by a crash.
+    PartialWrite { after: u64, len: usize },
+
+    /// The flush fails with an error.
+    SyncError { after: u64 },
+}
+
+/// The kinds of operations faults fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Read,
+    Write,
+    Sync,
+}
+
+impl Fault {
+    fn kind(&self) -> Kind {
+        match self {
+            Fault::ReadError { .. } | Fault::ShortRead { .. } => Kind::Read,
+            Fault::WriteError { .. } | Fault::PartialWrite { .. } => Kind::Write,
+            Fault::SyncError { .. } => Kind::Sync,
+        }
+    }
+
+    fn after(&mut self) -> &mut u64 {
+        match self {
+            Fault::ReadError { after }
+            | Fault::ShortRead { after }
+            | Fault::WriteError { after }
+            | Fault::PartialWrite { after, .. }
+            | Fault::SyncError { after } => after,
+        }
+    }
+}
+
+/// A storage passing every operation to another storage, except the ones
+/// failed by the injected faults.
+#[derive(Debug)]
+pub struct FaultyStorage<S: Storage> {
+    inner: S,
+
+    /// The faults that haven't failed an operation yet.
+    faults: Mutex<Vec<Fault>>,
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    /// A storage over `inner`, without faults.
+    pub fn new(inner: S) -> Self {
+        FaultyStorage {
+            inner,
+            faults: Mutex::new(vec![]),
+        }
+    }
+
+    /// Fail an operation with `fault`.
+    pub fn inject(&self, fault: Fault) {
+        self.faults().push(fault);
+    }
+
+    /// Remove the faults that haven't failed an operation yet.
+    pub fn clear(&self) {
+        self.faults().clear();
+    }
+
+    /// The faults that haven't failed an operation yet, with the amount of
+    /// operations each still lets through.
+    pub fn pending(&self) -> Vec<Fault> {
+        self.faults().clone()
+    }
+
+    /// The storage the operations are passed to.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Vec<Fault>> {
+        self.faults
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// The fault failing an operation of `kind`, if one is due. Every other
+    /// fault of the kind lets the operation through.
+    fn due(&self, kind: Kind) -> Option<Fault> {
+        let mut faults = self.faults();
+
+        let mut due = None;
+        faults.retain_mut(|fault| {
+            if fault.kind() != kind {
+                return true;
+            };
+            if *fault.after() > 0 {
+                *fault.after() -= 1;
+                return true;
+            };
+            if due.is_some() {
+                return true;
+            };
+
+            due = Some(*fault);
+            false
+        });
+
+        due
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.due(Kind::Read) {
+            Some(Fault::ReadError { .. }) => Err(injected()),
+            Some(_) => {
+                self.inner.read_at(offset, buf)?;
+                let read = buf.len() / 2;
+                buf[read..].fill(0);
+                Ok(())
+            }
+            None => self.inner.read_at(offset, buf),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        match self.due(Kind::Write) {
+            Some(Fault::PartialWrite { len, .. }) => {
+                self.inner.write_at(offset, &buf[..len.min(buf.len())])?;
+                Err(injected())
+            }
+            Some(_) => Err(injected()),
+            None => self.inner.write_at(offset, buf),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.inner.set_size(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self.due(Kind::Sync) {
+            Some(_) => Err(injected()),
+            None => self.inner.sync(),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Enabling and disabling features of an existing tree file.
+
+use crate::cache::PAGE_SIZE;
+use crate::{
+    bitcodec, schema, sidecar_path, write_dirty, CreateOptions, Feature, Tree, TreeFileError,
+    TreeOpenMode,
+};
+use std::fs;
+use std::sync::Arc;
+use strum::IntoEnumIterator;
+
+impl Tree {
+    /// Enable a feature of the tree, rewriting what it needs.
+    ///
+    /// The occupancy bitmap and the level table are built from the stored
+    /// nodes, and the audit log starts with the next write (or goes on, if
+    /// the feature was enabled before). The attribution feature only
+    /// changes the headers, so the last two subitems must already fit a
+    /// writer id and a last-modified time.
+    ///
+    /// The disabling and child hints features change the size of every
+    /// node, so the nodes are copied one at a time into a new tree file
+    /// next to the tree (with the `.rewrite` extension), which is then
+    /// copied over the tree file and removed. If the copy over the tree
+    /// file is interrupted, the tree reads as not closed properly and the
+    /// rewritten tree file is kept. Trees with reader handles can't change
+    /// their node size.
+    ///
+    /// Persistent trees can't have features toggled, and no tree can become
+    /// persistent.
+    pub fn enable_feature(&mut self, feature: Feature) -> Result<(), TreeFileError> {
+        if self.features.contains(&feature) {
+            return Ok(());
+        };
+
+        let mut features = self.features.clone();
+        features.push(feature);
+        features.sort_by_key(|feature| feature_bit(*feature));
+
+        self.toggle_feature(feature, features, true)
+    }
+
+    /// Disable a feature of the tree, rewriting what it needs like with
+    /// [`enable_feature`](Tree::enable_feature).
+    ///
+    /// Disabling the disabling feature enables every stored node. The
+    /// occupancy bitmap and the level table are removed, but the audit log
+    /// is kept.
+    pub fn disable_feature(&mut self, feature: Feature) -> Result<(), TreeFileError> {
+        if !self.features.contains(&feature) {
+            return Ok(());
+        };
+
+        let features = self
+            .features
+            .iter()
+            .copied()
+            .filter(|enabled| *enabled != feature)
+            .collect();
+
+        self.toggle_feature(feature, features, false)
+    }
+
+    fn toggle_feature(
+        &mut self,
+        feature: Feature,
+        features: Vec<Feature>,
+        enable: bool,
+    ) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty()
+            || feature == Feature::Persistent
+            || self.features.contains(&Feature::Persistent)
+        {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        match schema::validate(&features, &self.subitems)
+            .and_then(|_| schema::validate_layout(&features, self.layout))
+            .and_then(|_| schema::validate_reserved_slots(&features, self.layout))
+        {
+            Ok(_) => (),
+            Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+        };
+
+        match feature {
+            Feature::Disabling | Feature::ChildHints => {
+                self.bulk(|tree| tree.rewrite_nodes(features))
+            }
+            Feature::Occupancy | Feature::LevelStats if enable => {
+                if let Err(error) = self.build_sidecar(feature) {
+                    match feature {
+                        Feature::Occupancy => self.occupancy = None,
+                        _ => {
+                            self.levels = None;
+                            self.leaves = None;
+                        }
+                    };
+                    return Err(error);
+                };
+
+                self.write_features(features)
+            }
+            Feature::Occupancy | Feature::LevelStats => {
+                self.write_features(features)?;
+
+                let extension = match feature {
+                    Feature::Occupancy => {
+                        self.occupancy = None;
+                        "occupancy"
+                    }
+                    _ => {
+                        self.levels = None;
+                        self.leaves = None;
+                        let _ = fs::remove_file(sidecar_path(&self.path, "leaves"));
+                        "levels"
+                    }
+                };
+                let _ = fs::remove_file(sidecar_path(&self.path, extension));
+
+                Ok(())
+            }
+            Feature::Audit if enable => {
+                let exists = sidecar_path(&self.path, "audit").exists();
+                self.audit = Some(self.open_sidecar("audit", !exists)?);
+
+                self.write_features(features)
+            }
+            Feature::Audit => {
+                self.write_features(features)?;
+                self.audit = None;
+
+                Ok(())
+            }
+            Feature::Attribution | Feature::Persistent => self.write_features(features),
+        }
+    }
+
+    /// Build the occupancy bitmap or the level table (and the leaf table
+    /// kept with it) from the stored nodes.
+    /// The headers are written after, so an interrupted build leaves the
+    /// feature disabled.
+    fn build_sidecar(&mut self, feature: Feature) -> Result<(), TreeFileError> {
+        let positions: Vec<u128> = match self.positions() {
+            Ok(positions) => match positions.collect() {
+                Ok(positions) => positions,
+                Err(_) => return Err(TreeFileError::Corrupted),
+            },
+            Err(_) => return Err(TreeFileError::Corrupted),
+        };
+
+        match feature {
+            Feature::Occupancy => self.occupancy = Some(self.open_sidecar("occupancy", true)?),
+            _ => self.levels = Some(self.open_sidecar("levels", true)?),
+        };
+
+        for position in positions {
+            let written = match feature {
+                Feature::Occupancy => self.mark_occupancy(position, true),
+                _ => self.update_level_stats(position, false, true),
+            };
+            if written.is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        }
+
+        if feature == Feature::LevelStats && !self.features.contains(&Feature::Persistent) {
+            self.build_leaves()?;
+        };
+
+        Ok(())
+    }
+
+    /// Copy the nodes into a tree file with another node size, and copy it
+    /// over the tree file.
+    fn rewrite_nodes(&mut self, features: Vec<Feature>) -> Result<(), TreeFileError> {
+        if Arc::strong_count(&self.storage) > 1 {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let options = CreateOptions {
+            features: features.clone(),
+            subitems: self.subitems.clone(),
+            bit_order: self.bit_order,
+            layout: self.layout,
+            gap_fill: self.gap_fill.clone(),
+        };
+        let temp = sidecar_path(&self.path, "rewrite");
+        let mut rewritten = self.copy_into(&temp.to_string_lossy(), options, |_| ())?;
+        rewritten.sync()?;
+
+        // The rewritten tree is still open, so its headers mark the tree
+        // file as open for writing while it's copied.
+        let size = match rewritten.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        let mut block = vec![0_u8; PAGE_SIZE as usize];
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            let len = PAGE_SIZE.min(size - offset) as usize;
+            if rewritten
+                .storage
+                .read_at(offset, &mut block[..len])
+                .is_err()
+            {
+                return Err(TreeFileError::Corrupted);
+            };
+            if self.write_bytes(offset, &block[..len]).is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        }
+        if self.set_storage_size(size).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.unpin_all();
+        self.features = features;
+        rewritten.close()?;
+
+        for extension in ["occupancy", "levels", "leaves"] {
+            let source = sidecar_path(&temp, extension);
+            if source.exists() && fs::rename(source, sidecar_path(&self.path, extension)).is_err() {
+                return Err(TreeFileError::FileNotOpened);
+            };
+        }
+        let _ = fs::remove_file(sidecar_path(&temp, "audit"));
+        let _ = fs::remove_file(&temp);
+
+        self.open_occupancy(false)?;
+        self.open_levels(false)?;
+        self.open_leaves(false)?;
+        if self.merkle.is_some() {
+            self.build_merkle()?;
+        };
+        self.rebuild_indexes()?;
+        write_dirty(&*self.storage, true)?;
+        self.sync()
+    }
+
+    /// Write the feature bits of the headers, keeping the others.
+    fn write_features(&mut self, features: Vec<Feature>) -> Result<(), TreeFileError> {
+        let mut feature_bytes = [0_u8; 2];
+        match self.storage.read_at(10, &mut feature_bytes) {
+            Ok(_) => (),
+            Err(_) => return Err(TreeFileError::MissingHeaders),
+        };
+
+        for feature in Feature::iter() {
+            bitcodec::pack_bits_at(
+                &mut feature_bytes,
+                feature_bit(feature),
+                &[features.contains(&feature)],
+            );
+        }
+
+        if self.write_bytes(10, &feature_bytes).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.features = features;
+
+        self.sync()
+    }
+}
+
+/// The bit of a feature in the headers.
+fn feature_bit(feature: Feature) -> usize {
+    Feature::iter()
+        .position(|candidate| candidate == feature)
+        .unwrap_or_default()
+}
@@ -0,0 +1,82 @@
+//! Aggregating a subtree with a closure, top-down with
+//! [`fold`](Tree::fold) or bottom-up with [`reduce`](Tree::reduce).
+
+use crate::{positions, NodeData, NodeError, TraversalOptions, Tree};
+
+impl Tree {
+    /// Fold the enabled nodes of the subtree rooted at `position` into a
+    /// value, starting from `init`, visiting them in the pre-order of
+    /// [`traverse`](Tree::traverse): each node before its left subtree, and
+    /// its left subtree before its right one.
+    pub fn fold<T>(
+        &self,
+        position: u128,
+        init: T,
+        mut f: impl FnMut(T, &NodeData) -> T,
+    ) -> Result<T, NodeError> {
+        let mut acc = init;
+        for node in self.traverse(position, TraversalOptions::default()) {
+            acc = f(acc, &node?);
+        }
+
+        Ok(acc)
+    }
+
+    /// Reduce the subtree rooted at `position` bottom-up: `f` is called with
+    /// each enabled node and the results of its children, so every node is
+    /// combined after its subtrees are. A child that's missing or disabled
+    /// passes `None`, and its subtree isn't visited.
+    ///
+    /// Returns `None` if the node at `position` is missing or disabled.
+    pub fn reduce<T>(
+        &self,
+        position: u128,
+        mut f: impl FnMut(&NodeData, Option<T>, Option<T>) -> T,
+    ) -> Result<Option<T>, NodeError> {
+        self.check_node_bytes()?;
+
+        let slot = match self.resolve(position) {
+            Ok(slot) => slot,
+            Err(NodeError::Unexistent) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        self.reduce_slot(position, slot, &mut f)
+    }
+
+    /// Reduce the subtree of the node at `position`, stored in `slot`.
+    fn reduce_slot<T>(
+        &self,
+        position: u128,
+        slot: u128,
+        f: &mut impl FnMut(&NodeData, Option<T>, Option<T>) -> T,
+    ) -> Result<Option<T>, NodeError> {
+        let contents = match self.read_slot(slot) {
+            Ok(contents) if contents.enabled => contents,
+            Ok(_) | Err(NodeError::Unexistent) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let mut results = [None, None];
+
+        for index in [0, 1] {
+            // The deepest positions have no children that can be addressed.
+            let child = match positions::child(position, index) {
+                Some(child) => child,
+                None => continue,
+            };
+            if let Some(child_slot) = self.child_slot(&contents.children, child, index) {
+                results[index as usize] = self.reduce_slot(child, child_slot, f)?;
+            };
+        }
+
+        let node = NodeData {
+            position,
+            enabled: true,
+            subitems: contents.subitems,
+        };
+        let [left, right] = results;
+
+        Ok(Some(f(&node, left, right)))
+    }
+}
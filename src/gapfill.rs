@@ -0,0 +1,157 @@
+//! Filling the slots skipped by writes past the end of a tree file.
+//!
+//! A gap fill other than zeros is kept next to the tree file (with the
+//! `.gapfill` extension) as its kind (1 byte: 1 for ones, 2 for a template)
+//! followed by the subitems of the template, packed one after the other.
+
+use crate::{
+    bitcodec, positions, sidecar_path, Feature, GapFill, NodeError, Slot, Tree, TreeFileError,
+};
+use std::fs;
+use std::io::ErrorKind;
+
+/// The amount of slots filled with each write.
+const GAP_FILL_CHUNK_SLOTS: u128 = 1 << 12;
+
+impl Tree {
+    /// What the slots skipped by writes past the end of the tree file are
+    /// filled with.
+    pub fn gap_fill(&self) -> &GapFill {
+        &self.gap_fill
+    }
+
+    /// Record the gap fill of a new tree, or read the one of an existing
+    /// tree. A new tree drops the gap fill of the tree file it replaced.
+    pub(crate) fn open_gap_fill(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "gapfill");
+
+        if create {
+            if self.gap_fill == GapFill::Zeros {
+                let _ = fs::remove_file(path);
+                return Ok(());
+            };
+
+            return match fs::write(path, encode_gap_fill(&self.gap_fill)) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(TreeFileError::MissingPermissions),
+            };
+        };
+
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        self.gap_fill = match decode_gap_fill(&bytes, &self.subitems) {
+            Some(gap_fill) => gap_fill,
+            None => return Err(TreeFileError::Corrupted),
+        };
+
+        Ok(())
+    }
+
+    /// Fail if writing `position` would leave slots that can't be told apart
+    /// from nodes. Trees with the disabling feature read them as disabled,
+    /// and trees with another gap fill chose what they hold. Columnar trees
+    /// hold every slot from the start, so they need the disabling feature.
+    ///
+    /// Persistent trees store the ancestors missing along the path to a
+    /// node, so a node can only be written below a stored parent.
+    pub(crate) fn check_gap(&self, position: u128) -> Result<(), NodeError> {
+        if self.features.contains(&Feature::Disabling) {
+            return Ok(());
+        };
+
+        if self.features.contains(&Feature::Persistent) {
+            if position == 0 {
+                return Ok(());
+            };
+            return match self.resolve(positions::parent(position)) {
+                Ok(_) => Ok(()),
+                Err(NodeError::Unexistent) => Err(NodeError::WouldLeaveGap),
+                Err(error) => Err(error),
+            };
+        };
+
+        if self.gap_fill != GapFill::Zeros {
+            return Ok(());
+        };
+
+        match self.layout.slot(position) {
+            Some(slot) if slot > self.nodes() as u128 => Err(NodeError::WouldLeaveGap),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fill the slots between the end of the tree file and `slot`, which is
+    /// about to be written. Gaps filled with zeros are left to the storage.
+    pub(crate) fn fill_gap(&mut self, slot: u128) -> Result<(), NodeError> {
+        let bits = match &self.gap_fill {
+            GapFill::Zeros => return Ok(()),
+            GapFill::Ones => vec![true; self.node_size() as usize],
+            GapFill::Template(template) => self.encode_slot(&Slot {
+                enabled: false,
+                children: [None, None],
+                hints: [false, false],
+                subitems: template.clone(),
+            })?,
+        };
+
+        // Only interleaved trees fill their gaps, so each slot is a single
+        // span of bits.
+        let node_size = bits.len() as u128;
+        let mut first = self.nodes() as u128;
+        while first < slot {
+            let count = (slot - first).min(GAP_FILL_CHUNK_SLOTS);
+            let chunk: Vec<bool> = bits
+                .iter()
+                .copied()
+                .cycle()
+                .take((count * node_size) as usize)
+                .collect();
+            self.write_bits(first * node_size, &chunk)?;
+            first += count;
+        }
+
+        Ok(())
+    }
+}
+
+/// Encode a gap fill as its kind (0 for zeros, 1 for ones, 2 for a template)
+/// followed by the subitems of the template.
+pub(crate) fn encode_gap_fill(gap_fill: &GapFill) -> Vec<u8> {
+    match gap_fill {
+        GapFill::Zeros => vec![0],
+        GapFill::Ones => vec![1],
+        GapFill::Template(template) => {
+            let mut bytes = vec![2];
+            bytes.extend(bitcodec::bits_to_bytes(&template.concat()));
+            bytes
+        }
+    }
+}
+
+/// Decode a gap fill of a tree with `subitems`. `None` if it's invalid.
+pub(crate) fn decode_gap_fill(bytes: &[u8], subitems: &[u32]) -> Option<GapFill> {
+    match bytes.first() {
+        Some(0) => Some(GapFill::Zeros),
+        Some(1) => Some(GapFill::Ones),
+        Some(2) => {
+            let bits = bitcodec::bytes_to_bits(&bytes[1..]);
+            let size: usize = subitems.iter().map(|size| *size as usize).sum();
+            if bits.len() < size {
+                return None;
+            };
+
+            let mut offset = 0;
+            let mut template = vec![];
+            for size in subitems {
+                template.push(bits[offset..offset + *size as usize].to_vec());
+                offset += *size as usize;
+            }
+            Some(GapFill::Template(template))
+        }
+        _ => None,
+    }
+}
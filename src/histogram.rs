@@ -0,0 +1,120 @@
+//! The distribution of the values of a subitem across the enabled nodes of a
+//! tree, e.g. to choose which subitems to index or to spot skewed data.
+
+use crate::{bitcodec, Feature, NodeError, TraversalOptions, Tree};
+
+/// The bytes of the tree file read at once by the scans of the nodes.
+const HISTOGRAM_CHUNK_BYTES: usize = 1 << 20;
+
+/// The distribution of the values of a subitem, returned by
+/// [`Tree::histogram`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// The amount of enabled nodes.
+    pub count: u64,
+
+    /// The smallest value, if there are enabled nodes.
+    pub min: Option<u64>,
+
+    /// The largest value, if there are enabled nodes.
+    pub max: Option<u64>,
+
+    /// The buckets splitting the values from `min` to `max`, from the
+    /// smallest values to the largest.
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// A range of values of a [`Histogram`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramBucket {
+    /// The smallest value in the bucket.
+    pub start: u64,
+
+    /// The largest value in the bucket, included in it.
+    pub end: u64,
+
+    /// The amount of enabled nodes whose value is in the bucket.
+    pub count: u64,
+}
+
+impl Tree {
+    /// The distribution of the values of the subitem `subitem_index` across
+    /// the enabled nodes, in `bucket_count` buckets of the same width between
+    /// the smallest and the largest value (or fewer, if the values span
+    /// fewer than `bucket_count` values). The subitem must be at most 64
+    /// bits.
+    ///
+    /// The tree file is scanned twice in file order, first for the smallest
+    /// and largest values, then for the buckets. Persistent trees keep the
+    /// nodes of older versions in the same file, so the nodes of the
+    /// version being read are traversed instead.
+    pub fn histogram(
+        &self,
+        subitem_index: usize,
+        bucket_count: usize,
+    ) -> Result<Histogram, NodeError> {
+        match self.subitems.get(subitem_index) {
+            Some(size) if *size <= 64 => (),
+            Some(_) => return Err(NodeError::InvalidSubitem),
+            None => return Err(NodeError::InvalidIndex),
+        };
+
+        let mut histogram = Histogram {
+            count: 0,
+            min: None,
+            max: None,
+            buckets: vec![],
+        };
+        self.each_value(subitem_index, |value| {
+            histogram.count += 1;
+            histogram.min = Some(histogram.min.map_or(value, |min| min.min(value)));
+            histogram.max = Some(histogram.max.map_or(value, |max| max.max(value)));
+        })?;
+
+        let (min, max) = match (histogram.min, histogram.max) {
+            (Some(min), Some(max)) if bucket_count > 0 => (min, max),
+            _ => return Ok(histogram),
+        };
+
+        let values = (max - min) as u128 + 1;
+        let width = values.div_ceil(bucket_count as u128);
+        histogram.buckets = (0..values.div_ceil(width))
+            .map(|bucket| HistogramBucket {
+                start: (min as u128 + bucket * width) as u64,
+                end: (min as u128 + (bucket + 1) * width - 1).min(max as u128) as u64,
+                count: 0,
+            })
+            .collect();
+
+        self.each_value(subitem_index, |value| {
+            let bucket = ((value - min) as u128 / width) as usize;
+            histogram.buckets[bucket].count += 1;
+        })?;
+
+        Ok(histogram)
+    }
+
+    /// Call `visit` with the value of the subitem `subitem_index` of every
+    /// enabled node.
+    fn each_value(
+        &self,
+        subitem_index: usize,
+        mut visit: impl FnMut(u64),
+    ) -> Result<(), NodeError> {
+        if self.features.contains(&Feature::Persistent) {
+            for node in self.traverse(0, TraversalOptions::default()) {
+                visit(bitcodec::bits_to_u64(&node?.subitems[subitem_index]));
+            }
+
+            return Ok(());
+        };
+
+        self.scan(HISTOGRAM_CHUNK_BYTES, |slots| {
+            for slot in slots.iter().filter(|slot| slot.enabled) {
+                visit(bitcodec::bits_to_u64(&slot.subitems[subitem_index]));
+            }
+
+            true
+        })
+    }
+}
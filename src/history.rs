@@ -0,0 +1,188 @@
+use crate::{bitcodec, positions, Feature, NodeData, Storage, Tree, TreeFileError, WriteChange};
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The size in bytes of each entry of the timestamp table.
+pub(crate) const TIMESTAMP_ENTRY_SIZE: u64 = 8;
+
+/// A version recorded in a persistent tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    /// The version number, which can be passed to
+    /// [`open_version`](Tree::open_version).
+    pub version: u64,
+
+    /// When the version was written. `None` if the tree file has no
+    /// timestamp for it.
+    pub created: Option<SystemTime>,
+}
+
+impl Tree {
+    /// Open the timestamp table of a persistent tree. Tree files created
+    /// before timestamps were recorded keep working without one.
+    pub(crate) fn open_timestamps(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Ok(());
+        };
+
+        self.timestamps = match self.open_sidecar("timestamps", create) {
+            Ok(timestamps) => Some(timestamps),
+            Err(_) if !create => None,
+            Err(error) => return Err(error),
+        };
+
+        Ok(())
+    }
+
+    /// Every version that can still be opened, from the oldest to the
+    /// newest.
+    pub fn versions(&self) -> Result<Vec<VersionInfo>, TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        let mut versions = vec![];
+        for version in 0..self.version_count() {
+            if self.version_root(version)?.is_none() {
+                continue;
+            };
+
+            versions.push(VersionInfo {
+                version,
+                created: self.version_timestamp(version),
+            });
+        }
+
+        Ok(versions)
+    }
+
+    /// When a version was written, if the timestamp table has it.
+    pub(crate) fn version_timestamp(&self, version: u64) -> Option<SystemTime> {
+        let timestamps = self.timestamps.as_ref()?;
+
+        let mut entry = [0_u8; TIMESTAMP_ENTRY_SIZE as usize];
+        timestamps
+            .read_at(version * TIMESTAMP_ENTRY_SIZE, &mut entry)
+            .ok()?;
+
+        Some(UNIX_EPOCH + Duration::from_millis(bitcodec::u8_array_to_u64(&entry)))
+    }
+
+    /// Record the time the last version was written. Versions written
+    /// without a timestamp table stay without a timestamp.
+    pub(crate) fn push_timestamp(&mut self) -> Result<(), TreeFileError> {
+        let version = self.version_count().saturating_sub(1);
+        let timestamps = match &mut self.timestamps {
+            Some(timestamps) => timestamps,
+            None => return Ok(()),
+        };
+
+        let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as u64,
+            Err(_) => 0,
+        };
+
+        match timestamps.seek(SeekFrom::Start(version * TIMESTAMP_ENTRY_SIZE)) {
+            Ok(_) => (),
+            Err(_) => return Err(TreeFileError::MissingPermissions),
+        };
+        match timestamps.write_all(&bitcodec::u64_to_u8_array(millis)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::MissingPermissions),
+        }
+    }
+
+    /// The nodes that differ between two versions, sorted by position.
+    /// Subtrees shared by both versions are skipped without reading them.
+    ///
+    /// Each change goes from `from` to `to`, and nodes stored in only one of
+    /// them have the other side set to `None`.
+    pub fn diff_versions(&self, from: u64, to: u64) -> Result<Vec<WriteChange>, TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        let mut roots = [None, None];
+        for (root, version) in roots.iter_mut().zip([from, to]) {
+            *root = match version < self.version_count() {
+                true => self.version_root(version)?,
+                false => None,
+            };
+            if root.is_none() {
+                return Err(TreeFileError::UnexistentVersion);
+            };
+        }
+
+        self.diff_roots(roots[0], roots[1])
+    }
+
+    /// The nodes that differ between the versions rooted at two slots. A
+    /// missing root is an empty version.
+    pub(crate) fn diff_roots(
+        &self,
+        from: Option<u128>,
+        to: Option<u128>,
+    ) -> Result<Vec<WriteChange>, TreeFileError> {
+        let mut changes = vec![];
+        let mut pending = vec![(0, from, to)];
+        while let Some((position, from, to)) = pending.pop() {
+            if from == to {
+                continue;
+            };
+
+            let mut nodes = [None, None];
+            let mut children = [[None, None], [None, None]];
+            for (side, slot) in [from, to].into_iter().enumerate() {
+                let slot = match slot {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+
+                let contents = match self.read_slot(slot) {
+                    Ok(contents) => contents,
+                    Err(_) => return Err(TreeFileError::Corrupted),
+                };
+
+                // Children are always written before their parents, so a
+                // child pointing forward could form a cycle.
+                if contents
+                    .children
+                    .iter()
+                    .flatten()
+                    .any(|child| *child >= slot)
+                {
+                    return Err(TreeFileError::Corrupted);
+                };
+
+                children[side] = contents.children;
+                nodes[side] = Some(NodeData {
+                    position,
+                    enabled: contents.enabled,
+                    subitems: contents.subitems,
+                });
+            }
+
+            for (index, (from, to)) in children[0].into_iter().zip(children[1]).enumerate() {
+                match positions::child(position, index as u8) {
+                    Some(child) => pending.push((child, from, to)),
+                    // Only the slots of positions that can be addressed are
+                    // pointed to.
+                    None if from.is_some() || to.is_some() => return Err(TreeFileError::Corrupted),
+                    None => (),
+                };
+            }
+
+            let [before, after] = nodes;
+            if before != after {
+                changes.push(WriteChange {
+                    position,
+                    before,
+                    after,
+                });
+            };
+        }
+        changes.sort_unstable_by_key(|change| change.position);
+
+        Ok(changes)
+    }
+}
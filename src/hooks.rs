@@ -0,0 +1,250 @@
+use crate::{Feature, NodeData, NodeError, Operation, Tree};
+use std::fmt;
+use std::sync::Arc;
+
+/// The function checking the nodes written to a tree.
+type ValidatorFn = dyn Fn(u128, &[Vec<bool>]) -> Result<(), String> + Send + Sync;
+
+/// The validator of a tree, shared with its subtree writers.
+#[derive(Clone)]
+pub(crate) struct Validator(Arc<ValidatorFn>);
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Validator").finish_non_exhaustive()
+    }
+}
+
+/// A change to one node of a tree, as seen by the write hooks or between
+/// two versions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteChange {
+    /// The tranversal position of the node.
+    pub position: u128,
+
+    /// The node before the write. `None` if it wasn't stored.
+    pub before: Option<NodeData>,
+
+    /// The node after the write. `None` if it's no longer stored.
+    pub after: Option<NodeData>,
+}
+
+impl WriteChange {
+    /// The change that undoes this one.
+    fn reverse(&self) -> WriteChange {
+        WriteChange {
+            position: self.position,
+            before: self.after.clone(),
+            after: self.before.clone(),
+        }
+    }
+}
+
+/// A function called after every write to a tree.
+type WriteHookFn = dyn Fn(&WriteChange) -> Result<(), String> + Send + Sync;
+
+/// A write hook of a tree.
+#[derive(Clone)]
+pub(crate) struct WriteHook(Arc<WriteHookFn>);
+
+impl fmt::Debug for WriteHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WriteHook").finish_non_exhaustive()
+    }
+}
+
+impl Tree {
+    /// Set a function that is called with the position and subitems of every
+    /// node before it's written. Returning an error cancels the write, which
+    /// fails with [`NodeError::ValidationFailed`].
+    pub fn set_validator(
+        &mut self,
+        validator: impl Fn(u128, &[Vec<bool>]) -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.validator = Some(Validator(Arc::new(validator)));
+    }
+
+    /// Remove the validator.
+    pub fn clear_validator(&mut self) {
+        self.validator = None;
+    }
+
+    /// Check a node about to be written with the validator, if there's one.
+    pub(crate) fn validate_node(
+        &self,
+        position: u128,
+        subitems: &[Vec<bool>],
+    ) -> Result<(), NodeError> {
+        match &self.validator {
+            Some(Validator(validator)) => match validator(position, subitems) {
+                Ok(_) => Ok(()),
+                Err(message) => Err(NodeError::ValidationFailed(message)),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Add a hook that is called with every node written to the tree, after
+    /// it's written, to keep data derived from the nodes up to date. Hooks
+    /// are called in the order they were added.
+    ///
+    /// If a hook returns an error, the write is rolled back and fails with
+    /// [`NodeError::HookFailed`]. The hooks called before it are then called
+    /// again with the reversed change, so they can undo their work.
+    pub fn after_write(
+        &mut self,
+        hook: impl Fn(&WriteChange) -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.write_hooks.push(WriteHook(Arc::new(hook)));
+    }
+
+    /// Remove every write hook.
+    pub fn clear_write_hooks(&mut self) {
+        self.write_hooks.clear();
+    }
+
+    /// Write a node, calling the write hooks and rolling the write back if
+    /// one of them fails.
+    pub(crate) fn write_with_hooks(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<(), NodeError> {
+        if self.write_hooks.is_empty() && self.audit.is_none() {
+            return self.write_node(subitems, position, disabled);
+        };
+
+        // The value is only retained once the hooks accept the write, so a
+        // write rolled back leaves no entry in the log, and neither does the
+        // write of the old bytes that rolls it back.
+        let value_log = self.value_log.take();
+        let result = self.write_hooked(subitems, position, disabled);
+        self.value_log = value_log;
+        let change = result?;
+        self.retain_value(subitems, position, disabled)?;
+
+        self.record_audit(
+            Operation::WriteNode,
+            Some(position),
+            change.before.as_ref(),
+            change.after.as_ref(),
+        )
+    }
+
+    /// Write a node and call the write hooks with the change, rolling the
+    /// write back if one of them fails.
+    fn write_hooked(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<WriteChange, NodeError> {
+        let before = self.stored_node(position)?;
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        let versions = self.version_count();
+
+        self.write_node(subitems, position, disabled)?;
+
+        let change = WriteChange {
+            position,
+            before,
+            after: Some(NodeData {
+                position,
+                enabled: !disabled,
+                subitems: subitems.to_vec(),
+            }),
+        };
+
+        let hooks = self.write_hooks.clone();
+        for (i, WriteHook(hook)) in hooks.iter().enumerate() {
+            let message = match hook(&change) {
+                Ok(_) => continue,
+                Err(message) => message,
+            };
+
+            self.roll_back(&change, size, versions)?;
+            for WriteHook(hook) in &hooks[..i] {
+                let _ = hook(&change.reverse());
+            }
+
+            return Err(NodeError::HookFailed(message));
+        }
+
+        Ok(change)
+    }
+
+    /// The node stored at a position, disabled or not.
+    fn stored_node(&self, position: u128) -> Result<Option<NodeData>, NodeError> {
+        let slot = match self.features.contains(&Feature::Persistent) {
+            true => self.resolve(position).ok(),
+            false => self
+                .layout
+                .slot(position)
+                .filter(|slot| *slot < self.nodes() as u128),
+        };
+
+        match slot {
+            Some(slot) => {
+                let contents = self.read_slot(slot)?;
+                Ok(Some(NodeData {
+                    position,
+                    enabled: contents.enabled,
+                    subitems: contents.subitems,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Undo a write, given the size of the tree file and the amount of
+    /// versions before it.
+    fn roll_back(
+        &mut self,
+        change: &WriteChange,
+        size: u64,
+        versions: u64,
+    ) -> Result<(), NodeError> {
+        let enabled = change.before.as_ref().is_some_and(|node| node.enabled);
+        let written = change.after.as_ref().is_some_and(|node| node.enabled);
+
+        // Persistent writes only append slots and a version.
+        if self.features.contains(&Feature::Persistent) {
+            if self.set_storage_size(size).is_err() {
+                return Err(NodeError::Unexistent);
+            };
+            self.truncate_versions(versions)?;
+
+            self.mark_occupancy(change.position, enabled)?;
+            self.update_level_stats(change.position, written, enabled)?;
+            self.update_merkle(change.position)?;
+            return self.update_indexes(change.position);
+        };
+
+        match &change.before {
+            Some(node) => self.write_node(&node.subitems, change.position, !node.enabled),
+            None => {
+                // Clear the node first, so that the data kept about it is
+                // updated, and then drop the bytes it added.
+                let empty: Vec<Vec<bool>> = self
+                    .subitems
+                    .iter()
+                    .map(|size| vec![false; *size as usize])
+                    .collect();
+                self.write_node(&empty, change.position, true)?;
+
+                // Without the disabling feature, the cleared node is only
+                // gone once its bytes are.
+                match self.set_storage_size(size) {
+                    Ok(_) => self
+                        .update_merkle(change.position)
+                        .and_then(|_| self.update_indexes(change.position)),
+                    Err(_) => Err(NodeError::Unexistent),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! A minimal HTTP/1.1 client reading a published tree file with range
+//! requests.
+
+use crate::{ObjectStorage, ObjectStore, Tree, TreeFileError, TreeOpenMode};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The size in bytes of the blocks requested from the server.
+const HTTP_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The amount of blocks kept in memory, besides the top levels.
+const HTTP_CACHED_BLOCKS: usize = 256;
+
+/// The amount of levels read when the tree is opened and kept in memory.
+const HTTP_WARM_LEVELS: u32 = 12;
+
+/// How long connecting, sending a request or reading a response may stall
+/// before the read fails.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A tree file served over plain HTTP, read only.
+#[derive(Debug)]
+struct HttpObject {
+    /// The `host:port` to connect to.
+    address: String,
+
+    /// The host, as sent in the `Host` header.
+    host: String,
+
+    /// The path of the tree file on the server.
+    path: String,
+
+    /// The size of the tree file, once the server sent it.
+    size: OnceLock<u64>,
+}
+
+/// A response to a request, without its body.
+struct Response<R> {
+    status: u16,
+    length: Option<u64>,
+    chunked: bool,
+    reader: R,
+}
+
+impl HttpObject {
+    /// Parse an `http://host[:port]/path` URL.
+    fn new(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        if authority.is_empty() {
+            return None;
+        };
+        let address = match authority.contains(':') {
+            true => authority.to_string(),
+            false => format!("{}:80", authority),
+        };
+
+        Some(Self {
+            address,
+            host: authority.to_string(),
+            path: path.to_string(),
+            size: OnceLock::new(),
+        })
+    }
+
+    /// Connect to the first address the host resolves to that accepts the
+    /// connection in time, with reads and writes timing out too.
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = io::Error::from(io::ErrorKind::NotFound);
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, HTTP_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+                    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(connect_error) => error = connect_error,
+            };
+        }
+
+        Err(error)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        range: Option<(u64, u64)>,
+    ) -> io::Result<Response<BufReader<TcpStream>>> {
+        let mut stream = self.connect()?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            method, self.path, self.host
+        );
+        if let Some((offset, len)) = range {
+            request += &format!("Range: bytes={}-{}\r\n", offset, offset + len - 1);
+        };
+        request += "\r\n";
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = match line.split(' ').nth(1).map(str::parse) {
+            Some(Ok(status)) => status,
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+
+        let mut length = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            };
+            if let Some((name, value)) = header.split_once(':') {
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.parse().ok();
+                } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value.eq_ignore_ascii_case("chunked");
+                };
+            };
+        }
+
+        Ok(Response {
+            status,
+            length,
+            chunked,
+            reader,
+        })
+    }
+}
+
+impl<R: BufRead> Response<R> {
+    /// Read the body, failing with [`InvalidData`](io::ErrorKind::InvalidData)
+    /// before reading more than `limit` bytes, so a server sending a huge
+    /// length can't make the client allocate it.
+    fn body(mut self, limit: u64) -> io::Result<Vec<u8>> {
+        let mut body = vec![];
+
+        if self.chunked {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                self.reader.read_line(&mut line)?;
+                let size = line.trim_end().split(';').next().unwrap_or("");
+                let size = match u64::from_str_radix(size, 16) {
+                    Ok(size) => size,
+                    Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+                };
+                if size == 0 {
+                    break;
+                };
+
+                let end = match (body.len() as u64).checked_add(size) {
+                    Some(end) if end <= limit => end as usize,
+                    _ => return Err(io::ErrorKind::InvalidData.into()),
+                };
+                let start = body.len();
+                body.resize(end, 0);
+                self.reader.read_exact(&mut body[start..])?;
+                // The line break after the chunk.
+                self.reader.read_line(&mut line)?;
+            }
+        } else {
+            match self.length {
+                Some(length) if length > limit => return Err(io::ErrorKind::InvalidData.into()),
+                Some(length) => {
+                    body.resize(length as usize, 0);
+                    self.reader.read_exact(&mut body)?;
+                }
+                None => {
+                    self.reader
+                        .by_ref()
+                        .take(limit.saturating_add(1))
+                        .read_to_end(&mut body)?;
+                    if body.len() as u64 > limit {
+                        return Err(io::ErrorKind::InvalidData.into());
+                    };
+                }
+            };
+        };
+
+        Ok(body)
+    }
+}
+
+impl ObjectStore for HttpObject {
+    fn size(&self) -> io::Result<u64> {
+        let response = self.request("HEAD", None)?;
+
+        match (response.status, response.length) {
+            (200, Some(length)) => Ok(*self.size.get_or_init(|| length)),
+            (404, _) => Err(io::ErrorKind::NotFound.into()),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    fn get_range(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let response = self.request("GET", Some((offset, len)))?;
+
+        match response.status {
+            206 => {
+                if response.length.is_some_and(|length| length != len) {
+                    return Err(io::ErrorKind::InvalidData.into());
+                };
+                let body = response.body(len)?;
+                match body.len() as u64 == len {
+                    true => Ok(body),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+            // The server ignored the range and sent the whole file.
+            200 => {
+                let size = match self.size.get() {
+                    Some(size) => *size,
+                    None => self.size()?,
+                };
+                let body = response.body(size)?;
+                let range = match (usize::try_from(offset), usize::try_from(offset + len)) {
+                    (Ok(start), Ok(end)) => start..end,
+                    _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+                match body.get(range) {
+                    Some(range) => Ok(range.to_vec()),
+                    None => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    fn create_multipart(&self) -> io::Result<String> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn upload_part(&self, _upload: &str, _part: u32, _bytes: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn complete_multipart(&self, _upload: &str) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn abort_multipart(&self, _upload: &str) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+}
+
+impl Tree {
+    /// Open a tree file published on an HTTP server (e.g. a CDN), read only.
+    /// Nodes are read with range requests, and the top levels of the tree
+    /// are read when it's opened and kept in memory, as nearly every read
+    /// goes through them.
+    ///
+    /// Only `http://` URLs are supported. The tree is opened like with
+    /// [`open_object`](Tree::open_object), as it's read from a single file.
+    pub fn open_http(url: &str) -> Result<Self, TreeFileError> {
+        let object = match HttpObject::new(url) {
+            Some(object) => object,
+            None => return Err(TreeFileError::FileNotOpened),
+        };
+
+        let storage = match ObjectStorage::new(object, HTTP_BLOCK_SIZE, HTTP_CACHED_BLOCKS) {
+            Ok(storage) => storage,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        let tree = Self::open_object(storage, TreeOpenMode::Read)?;
+        if tree.warm(HTTP_WARM_LEVELS).is_err() {
+            return Err(TreeFileError::Corrupted);
+        };
+
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, length: Option<u64>, chunked: bool, body: &str) -> Response<&[u8]> {
+        Response {
+            status,
+            length,
+            chunked,
+            reader: body.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn bounds_response_bodies() {
+        let body =
+            |length, chunked, bytes, limit| response(206, length, chunked, bytes).body(limit);
+
+        assert_eq!(body(Some(4), false, "abcd", 4).unwrap(), b"abcd");
+        assert_eq!(
+            body(None, true, "2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n", 4).unwrap(),
+            b"abcd"
+        );
+        assert_eq!(body(None, false, "abcd", 4).unwrap(), b"abcd");
+
+        // Lengths larger than the limit fail before anything is allocated.
+        assert!(body(Some(u64::MAX), false, "abcd", 4).is_err());
+        assert!(body(None, true, "ffffffffffffffff\r\nab\r\n0\r\n\r\n", 4).is_err());
+        assert!(body(None, true, "3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n", 4).is_err());
+        assert!(body(None, false, "abcde", 4).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{Feature, Operation, Storage, Tree, TreeFileError};
+use std::fmt;
+
+/// How serious a finding of an integrity check is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Worth knowing, but the tree is fine.
+    Info,
+
+    /// The tree can be read, but wastes space or is inconsistent with itself.
+    Warning,
+
+    /// Some of the tree's data is wrong or can't be read.
+    Error,
+}
+
+/// A problem found while checking a tree file.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// How serious the problem is.
+    pub severity: Severity,
+
+    /// The tranversal position of the node involved, if any.
+    pub position: Option<u128>,
+
+    /// The offset in bytes of the problem in the file involved, if any.
+    pub offset: Option<u64>,
+
+    /// What is wrong.
+    pub description: String,
+
+    /// How the problem can be fixed, if it can.
+    pub fix: Option<String>,
+}
+
+/// The findings of an integrity check.
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// The problems found, in the order they were found.
+    pub findings: Vec<Finding>,
+}
+
+impl IntegrityReport {
+    /// Whether no errors were found. Warnings and info findings are allowed.
+    pub fn is_ok(&self) -> bool {
+        self.findings
+            .iter()
+            .all(|finding| finding.severity < Severity::Error)
+    }
+
+    /// The findings with at least the given severity, in the order they
+    /// were found.
+    pub fn with_severity(&self, severity: Severity) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(move |finding| finding.severity >= severity)
+    }
+
+    fn push(
+        &mut self,
+        severity: Severity,
+        position: Option<u128>,
+        offset: Option<u64>,
+        description: String,
+        fix: Option<&str>,
+    ) {
+        self.findings.push(Finding {
+            severity,
+            position,
+            offset,
+            description,
+            fix: fix.map(String::from),
+        });
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.severity)?;
+
+        if let Some(position) = self.position {
+            write!(f, " at position {}", position)?;
+        };
+
+        if let Some(offset) = self.offset {
+            write!(f, " (offset {})", offset)?;
+        };
+
+        write!(f, ": {}", self.description)?;
+
+        if let Some(fix) = &self.fix {
+            write!(f, " Fix: {}", fix)?;
+        };
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.findings.is_empty() {
+            return writeln!(f, "no problems found");
+        };
+
+        for finding in &self.findings {
+            writeln!(f, "{}", finding)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Tree {
+    /// Check the tree file and its sidecar files for inconsistencies.
+    /// Returns an error only if the files can't be read.
+    pub fn verify(&self) -> Result<IntegrityReport, TreeFileError> {
+        self.traced_read(Operation::Verify, None, |tree| {
+            let mut report = IntegrityReport::default();
+
+            tree.verify_size(&mut report)?;
+            if tree.features.contains(&Feature::Persistent) {
+                tree.verify_versions(&mut report)?;
+            };
+            tree.verify_occupancy(&mut report)?;
+
+            Ok(report)
+        })
+    }
+
+    /// Check that the tree file ends where its last node does.
+    fn verify_size(&self, report: &mut IntegrityReport) -> Result<(), TreeFileError> {
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        let used = match self
+            .slot_spans(self.nodes().saturating_sub(1) as u128)
+            .last()
+        {
+            Some((offset, width)) if self.nodes() > 0 => {
+                self.header_size as u64 + (offset + *width as u128).div_ceil(8) as u64
+            }
+            _ => self.header_size as u64,
+        };
+        if size > used {
+            report.push(
+                Severity::Warning,
+                None,
+                Some(used),
+                format!(
+                    "The tree file has {} bytes after its last node.",
+                    size - used
+                ),
+                Some("Truncate the tree file to its last node."),
+            );
+        };
+
+        Ok(())
+    }
+
+    /// Check that the version table and the child pointers only point to
+    /// stored slots, and report the slots no version uses.
+    fn verify_versions(&self, report: &mut IntegrityReport) -> Result<(), TreeFileError> {
+        let slots = self.nodes() as u128;
+        let node_size = self.node_size() as u64;
+
+        let table_size = match &self.versions {
+            Some(versions) => match versions.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(_) => return Err(TreeFileError::FileNotOpened),
+            },
+            None => return Ok(()),
+        };
+        if table_size % 8 != 0 {
+            report.push(
+                Severity::Error,
+                None,
+                Some(table_size - table_size % 8),
+                "The version table ends with a partial entry.".to_string(),
+                Some("Truncate the version table to its last whole entry."),
+            );
+        };
+
+        let mut reachable = match usize::try_from(slots) {
+            Ok(slots) => vec![false; slots],
+            Err(_) => return Err(TreeFileError::OffsetOverflow),
+        };
+        let mut pending: Vec<u128> = vec![];
+        for version in 0..self.version_count() {
+            match self.version_root(version)? {
+                Some(root) if root >= slots => report.push(
+                    Severity::Error,
+                    None,
+                    Some(version * 8),
+                    format!(
+                        "Version {} has its root in slot {}, but the tree only has {} slots.",
+                        version, root, slots
+                    ),
+                    None,
+                ),
+                Some(root) => pending.push(root),
+                None => (),
+            };
+        }
+
+        while let Some(slot) = pending.pop() {
+            if reachable[slot as usize] {
+                continue;
+            };
+            reachable[slot as usize] = true;
+
+            let contents = match self.read_slot(slot) {
+                Ok(contents) => contents,
+                Err(_) => return Err(TreeFileError::FileNotOpened),
+            };
+
+            // Children are always written before their parents, so a child
+            // pointing forward could form a cycle.
+            for child in contents.children.iter().flatten() {
+                if *child >= slot || *child >= slots {
+                    report.push(
+                        Severity::Error,
+                        None,
+                        Some(self.header_size as u64 + slot as u64 * node_size / 8),
+                        format!("Slot {} points to an invalid child slot {}.", slot, child),
+                        None,
+                    );
+                } else {
+                    pending.push(*child);
+                };
+            }
+        }
+
+        let unreachable = reachable.iter().filter(|reachable| !**reachable).count();
+        if unreachable > 0 {
+            report.push(
+                Severity::Info,
+                None,
+                None,
+                format!("{} slots aren't used by any version.", unreachable),
+                Some("Run the garbage collector to reclaim them."),
+            );
+        };
+
+        Ok(())
+    }
+
+    /// Check that the occupancy bitmap matches the nodes of the latest
+    /// version.
+    fn verify_occupancy(&self, report: &mut IntegrityReport) -> Result<(), TreeFileError> {
+        let bitmap_size = match &self.occupancy {
+            Some(bitmap) => match bitmap.size() {
+                Ok(size) => size as u128,
+                Err(_) => return Err(TreeFileError::FileNotOpened),
+            },
+            None => return Ok(()),
+        };
+
+        let enabled: Vec<u128> = match self.stored_nodes() {
+            Ok(nodes) => nodes
+                .iter()
+                .filter(|node| node.enabled)
+                .map(|node| node.position)
+                .collect(),
+            Err(_) => return Err(TreeFileError::Corrupted),
+        };
+
+        let limit = (bitmap_size * 8).max(enabled.last().map_or(0, |last| last + 1));
+        let mut occupied = vec![];
+        if bitmap_size > 0 {
+            occupied = match self.occupancy(0..bitmap_size * 8) {
+                Ok(occupied) => occupied,
+                Err(_) => return Err(TreeFileError::FileNotOpened),
+            };
+        };
+
+        for position in 0..limit {
+            let marked = usize::try_from(position)
+                .ok()
+                .and_then(|position| occupied.get(position).copied())
+                .unwrap_or(false);
+            if marked == enabled.binary_search(&position).is_ok() {
+                continue;
+            };
+
+            report.push(
+                Severity::Error,
+                Some(position),
+                u64::try_from(position / 8).ok(),
+                match marked {
+                    true => "The occupancy bitmap marks a node that isn't enabled.".to_string(),
+                    false => "The occupancy bitmap doesn't mark an enabled node.".to_string(),
+                },
+                Some("Rebuild the occupancy bitmap from the nodes."),
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! The placement of the nodes of flat trees in the tree file.
+
+/// The bit of the features header selecting a columnar layout.
+pub(crate) const LAYOUT_COLUMNAR_BIT: usize = 7;
+
+/// The first bit of the features header storing the levels of a van Emde
+/// Boas or columnar layout.
+pub(crate) const LAYOUT_LEVELS_BIT: usize = 8;
+
+/// The amount of bits of the features header storing the levels of a van
+/// Emde Boas or columnar layout.
+pub(crate) const LAYOUT_LEVELS_BITS: u32 = 6;
+
+/// The order in which the nodes of a flat tree are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Layout {
+    /// Level by level, from left to right. Slot `n` holds position `n`.
+    #[default]
+    LevelOrder,
+
+    /// Cache-oblivious van Emde Boas order for a tree of at most `levels`
+    /// levels: the top half of the levels is stored first, followed by each
+    /// of the subtrees below it, all laid out recursively. Nodes on the same
+    /// root-to-leaf path end up close to each other.
+    VanEmdeBoas { levels: u32 },
+
+    /// Level order, but with the feature headers of every node stored in a
+    /// column of their own, followed by a column for each subitem. Room for
+    /// all the nodes of `levels` levels is reserved when the tree is
+    /// created. Reading one subitem of many nodes reads only its column.
+    Columnar { levels: u32 },
+}
+
+impl Layout {
+    /// The maximum amount of levels of a van Emde Boas or columnar layout.
+    pub const MAX_LEVELS: u32 = (1 << LAYOUT_LEVELS_BITS) - 1;
+
+    /// The slot holding a position. `None` if the position is deeper than
+    /// the levels of the layout.
+    pub fn slot(&self, position: u128) -> Option<u128> {
+        match self {
+            Layout::LevelOrder => Some(position),
+            Layout::VanEmdeBoas { levels } => {
+                let depth = match position.checked_add(1) {
+                    Some(index) => index.ilog2(),
+                    None => return None,
+                };
+                if depth >= *levels {
+                    return None;
+                };
+
+                Some(veb_slot(position + 1, depth, *levels))
+            }
+            Layout::Columnar { levels } => match position < (1 << levels) - 1 {
+                true => Some(position),
+                false => None,
+            },
+        }
+    }
+
+    /// The first position that can't be stored, if any.
+    pub fn position_limit(&self) -> Option<u128> {
+        match self {
+            Layout::LevelOrder => None,
+            Layout::VanEmdeBoas { levels } | Layout::Columnar { levels } => Some((1 << levels) - 1),
+        }
+    }
+}
+
+/// The slot of the node with the 1-based level order index `index`, at
+/// `depth` in a van Emde Boas layout of `levels` levels.
+fn veb_slot(index: u128, depth: u32, levels: u32) -> u128 {
+    if levels == 1 {
+        return 0;
+    };
+
+    let top_levels = levels / 2;
+    let bottom_levels = levels - top_levels;
+
+    if depth < top_levels {
+        return veb_slot(index, depth, top_levels);
+    };
+
+    // The bottom subtree holding the node, and the node's index inside it.
+    let below = depth - top_levels;
+    let subtree = (index >> below) - (1 << top_levels);
+    let local = (1 << below) | (index & ((1 << below) - 1));
+
+    let top_size = (1 << top_levels) - 1;
+    let bottom_size = (1 << bottom_levels) - 1;
+
+    top_size + subtree * bottom_size + veb_slot(local, below, bottom_levels)
+}
@@ -0,0 +1,110 @@
+//! Reading nodes with large subitems without decoding all of them at once.
+//! Decoded subitems take a byte per bit, so a node of a few megabits decoded
+//! whole takes megabytes.
+
+use crate::{NodeError, Operation, Tree};
+
+/// A node whose subitems are only read and decoded when they're first
+/// accessed. It only borrows the tree, like [`Tree::read_node`].
+#[derive(Debug)]
+pub struct LazyNode<'a> {
+    pub(crate) tree: &'a Tree,
+
+    /// The tranversal position.
+    pub position: u128,
+
+    /// The storage slot holding the node.
+    slot: u128,
+
+    /// The subitems decoded so far.
+    subitems: Vec<Option<Vec<bool>>>,
+}
+
+impl Tree {
+    /// Get a node by its tranversal position without decoding its subitems,
+    /// which are read one at a time through [`LazyNode::subitem`].
+    pub fn lazy_node(&self, position: u128) -> Result<LazyNode<'_>, NodeError> {
+        self.record_access(position);
+
+        let slot = self.traced_read(Operation::ReadNode, Some(position), |tree| {
+            let slot = tree.resolve(position)?;
+            match tree.read_slot_header(slot)?.enabled {
+                true => Ok(slot),
+                false => Err(NodeError::Disabled),
+            }
+        })?;
+
+        Ok(LazyNode {
+            tree: self,
+            position,
+            slot,
+            subitems: vec![None; self.subitems.len()],
+        })
+    }
+
+    /// Limit the size in bytes of the nodes decoded whole. Trees whose nodes
+    /// are larger can't be read through [`node`](Tree::node),
+    /// [`read_node`](Tree::read_node), [`traverse`](Tree::traverse) or
+    /// [`traverse_page`](Tree::traverse_page), which fail with
+    /// [`NodeTooLarge`](NodeError::NodeTooLarge), and have to be read
+    /// through [`lazy_node`](Tree::lazy_node) instead. `None` (the default)
+    /// doesn't limit them.
+    pub fn set_max_node_bytes(&mut self, max: Option<usize>) {
+        self.max_node_bytes = max;
+    }
+
+    /// The limit of the size in bytes of the nodes decoded whole, if there's
+    /// one.
+    pub fn max_node_bytes(&self) -> Option<usize> {
+        self.max_node_bytes
+    }
+
+    /// Fail if the nodes of the tree are larger than the nodes decoded whole
+    /// may be.
+    pub(crate) fn check_node_bytes(&self) -> Result<(), NodeError> {
+        let size = self.node_size().div_ceil(8) as usize;
+        match self.max_node_bytes {
+            Some(max) if size > max => Err(NodeError::NodeTooLarge { size, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl LazyNode<'_> {
+    /// The amount of subitems of the node.
+    pub fn len(&self) -> usize {
+        self.subitems.len()
+    }
+
+    /// Whether the node has no subitems.
+    pub fn is_empty(&self) -> bool {
+        self.subitems.is_empty()
+    }
+
+    /// The size in bits of a subitem, without reading it.
+    pub fn subitem_size(&self, index: usize) -> Option<u32> {
+        self.tree.subitems.get(index).copied()
+    }
+
+    /// A subitem in bits, read and decoded the first time it's accessed.
+    pub fn subitem(&mut self, index: usize) -> Result<&[bool], NodeError> {
+        let width = match self.tree.subitems.get(index) {
+            Some(width) => *width,
+            None => return Err(NodeError::InvalidIndex),
+        };
+
+        if self.subitems[index].is_none() {
+            let within =
+                self.tree.node_header_size() + self.tree.subitems[..index].iter().sum::<u32>();
+            let bits = match width {
+                0 => vec![],
+                _ => self
+                    .tree
+                    .read_bits(self.tree.slot_bit_offset(self.slot, within), width)?,
+            };
+            self.subitems[index] = Some(bits);
+        };
+
+        Ok(self.subitems[index].as_deref().unwrap_or_default())
+    }
+}
@@ -0,0 +1,180 @@
+//! Writer leases: a time limit on how long a tree file may be left open for
+//! writing without its writer showing it's alive, so that readers and other
+//! writers can tell a tree still being written from one left open by a
+//! crash.
+//!
+//! The lease is kept in a file next to the tree file (with the `.lease`
+//! extension), holding when it expires, in milliseconds since the Unix epoch
+//! (8 bytes), and the writer id of the tree holding it (4 bytes).
+
+use crate::{bitcodec, sidecar_path, NodeError, Tree, TreeFileError, TreeOpenMode};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The lease on a tree file, as read from its lease file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lease {
+    /// When the lease expires, unless it's renewed.
+    pub expires: SystemTime,
+
+    /// The writer id of the tree holding it.
+    pub writer_id: u32,
+}
+
+impl Lease {
+    /// Whether the lease expired, so its writer is presumed dead.
+    pub fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+}
+
+/// The lease held by a tree.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeldLease {
+    duration: Duration,
+    expires: SystemTime,
+}
+
+impl Tree {
+    /// Hold a lease on the tree file for `duration`, replacing the one the
+    /// tree held. Until it expires, opening the tree file fails with
+    /// [`LeaseHeld`](TreeFileError::LeaseHeld) rather than
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown).
+    ///
+    /// The lease must be renewed with [`renew_lease`](Tree::renew_lease)
+    /// before it expires: once it does, another process may break it with
+    /// [`break_lease`](Tree::break_lease), so the writes of nodes fail with
+    /// [`LeaseExpired`](NodeError::LeaseExpired). Closing the tree releases
+    /// it.
+    pub fn acquire_lease(&mut self, duration: Duration) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty() {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        self.write_lease(duration)
+    }
+
+    /// Extend the lease of the tree by the duration it was acquired for,
+    /// counted from now. Fails with
+    /// [`LeaseExpired`](TreeFileError::LeaseExpired) if the tree holds no
+    /// lease, or it already expired, as it might have been broken.
+    pub fn renew_lease(&mut self) -> Result<(), TreeFileError> {
+        match self.lease {
+            Some(lease) if lease.expires > SystemTime::now() => self.write_lease(lease.duration),
+            _ => Err(TreeFileError::LeaseExpired),
+        }
+    }
+
+    /// Stop holding the lease of the tree, if it holds one.
+    pub fn release_lease(&mut self) {
+        if self.lease.take().is_some() {
+            let _ = fs::remove_file(sidecar_path(&self.path, "lease"));
+        };
+    }
+
+    /// The lease on the tree file at `file_path`, if it has one.
+    pub fn lease(file_path: &str) -> Result<Option<Lease>, TreeFileError> {
+        read_lease(Path::new(file_path))
+    }
+
+    /// Open a tree file that wasn't closed cleanly, once the lease of the
+    /// tree that left it open expired. Fails with
+    /// [`LeaseHeld`](TreeFileError::LeaseHeld) if the lease is still held.
+    ///
+    /// The lease file is removed, and the tree file is opened like
+    /// [`open_unclean`](Tree::open_unclean), so it might have partial
+    /// changes: verify it before trusting it.
+    pub fn break_lease(file_path: &'static str, mode: TreeOpenMode) -> Result<Self, TreeFileError> {
+        if let Some(lease) = read_lease(Path::new(file_path))? {
+            if !lease.is_expired() {
+                return Err(TreeFileError::LeaseHeld);
+            };
+
+            if fs::remove_file(sidecar_path(Path::new(file_path), "lease")).is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        };
+
+        Self::open_unclean(file_path, mode)
+    }
+
+    /// Check that the lease of the tree, if it holds one, didn't expire.
+    pub(crate) fn check_lease(&self) -> Result<(), NodeError> {
+        match self.lease {
+            Some(lease) if lease.expires <= SystemTime::now() => Err(NodeError::LeaseExpired),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a lease expiring `duration` from now. The lease file is written
+    /// next to it and renamed over it, so readers never find it half
+    /// written.
+    fn write_lease(&mut self, duration: Duration) -> Result<(), TreeFileError> {
+        let expires = match SystemTime::now().checked_add(duration) {
+            Some(expires) => expires,
+            None => return Err(TreeFileError::OffsetOverflow),
+        };
+        let millis = expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let mut bytes = u64::try_from(millis)
+            .unwrap_or(u64::MAX)
+            .to_be_bytes()
+            .to_vec();
+        bytes.extend(bitcodec::u32_to_u8_array(self.writer_id));
+
+        let temp = sidecar_path(&self.path, "lease.new");
+        if fs::write(&temp, bytes).is_err()
+            || fs::rename(&temp, sidecar_path(&self.path, "lease")).is_err()
+        {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.lease = Some(HeldLease { duration, expires });
+
+        Ok(())
+    }
+}
+
+/// Read the lease on the tree file at `path`, if it has one.
+pub(crate) fn read_lease(path: &Path) -> Result<Option<Lease>, TreeFileError> {
+    let path = sidecar_path(path, "lease");
+    if !path.exists() {
+        return Ok(None);
+    };
+
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(TreeFileError::FileNotOpened),
+    };
+    if bytes.len() != 12 {
+        return Err(TreeFileError::Corrupted);
+    };
+
+    let millis = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+    let expires = match UNIX_EPOCH.checked_add(Duration::from_millis(millis)) {
+        Some(expires) => expires,
+        None => return Err(TreeFileError::Corrupted),
+    };
+    Ok(Some(Lease {
+        expires,
+        writer_id: bitcodec::u8_array_to_u32(bytes[8..].try_into().unwrap()),
+    }))
+}
+
+/// The error opening a tree file left open for writing: whether its writer
+/// still holds a lease on it.
+pub(crate) fn unclean_error(path: &Path) -> TreeFileError {
+    match read_lease(path) {
+        Ok(Some(lease)) if !lease.is_expired() => TreeFileError::LeaseHeld,
+        _ => TreeFileError::UncleanShutdown,
+    }
+}
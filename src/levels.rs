@@ -0,0 +1,208 @@
+use crate::{positions, Feature, NodeError, Storage, Tree, TreeFileError};
+use std::ops::Range;
+
+/// The size in bytes of each entry of the level table.
+const LEVEL_ENTRY_SIZE: u64 = 40;
+
+/// The amount of positions whose occupancy is read at once when scanning a
+/// level.
+const SCAN_CHUNK_SIZE: u128 = 64 * 1024;
+
+/// The enabled nodes of a level of the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LevelStats {
+    /// The amount of enabled nodes in the level.
+    pub live: u64,
+
+    /// The position of the first enabled node of the level.
+    pub first: Option<u128>,
+
+    /// The position of the last enabled node of the level.
+    pub last: Option<u128>,
+}
+
+impl LevelStats {
+    /// The positions between the first and the last enabled nodes of the
+    /// level. Empty if the level has no enabled nodes.
+    pub fn range(&self) -> Range<u128> {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => first..last + 1,
+            _ => 0..0,
+        }
+    }
+
+    fn decode(entry: &[u8; LEVEL_ENTRY_SIZE as usize]) -> Self {
+        let position = |bytes: &[u8]| {
+            let mut array = [0_u8; 16];
+            array.copy_from_slice(bytes);
+            u128::from_be_bytes(array).checked_sub(1)
+        };
+
+        let mut live = [0_u8; 8];
+        live.copy_from_slice(&entry[0..8]);
+
+        Self {
+            live: u64::from_be_bytes(live),
+            first: position(&entry[8..24]),
+            last: position(&entry[24..40]),
+        }
+    }
+
+    fn encode(&self) -> [u8; LEVEL_ENTRY_SIZE as usize] {
+        let position = |position: Option<u128>| position.map_or(0, |position| position + 1);
+
+        let mut entry = [0_u8; LEVEL_ENTRY_SIZE as usize];
+        entry[0..8].copy_from_slice(&self.live.to_be_bytes());
+        entry[8..24].copy_from_slice(&position(self.first).to_be_bytes());
+        entry[24..40].copy_from_slice(&position(self.last).to_be_bytes());
+        entry
+    }
+}
+
+impl Tree {
+    /// The enabled nodes of a level. Read from the level table in constant
+    /// time when the tree has the level stats feature, or counted by reading
+    /// the whole level otherwise.
+    pub fn level_stats(&self, level: u32) -> Result<LevelStats, NodeError> {
+        match &self.levels {
+            Some(_) if self.version.is_none() => self.read_level_stats(level),
+            _ => self.scan_level(level),
+        }
+    }
+
+    /// Open (or create) the level table if the tree has the level stats
+    /// feature.
+    pub(crate) fn open_levels(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::LevelStats) {
+            return Ok(());
+        };
+
+        self.levels = Some(self.open_sidecar("levels", create)?);
+
+        Ok(())
+    }
+
+    /// Whether a position currently holds an enabled node, if the tree keeps
+    /// level stats. Read before writing a node, to update the stats after.
+    pub(crate) fn enabled_before_write(&self, position: u128) -> Result<bool, NodeError> {
+        if self.levels.is_none() {
+            return Ok(false);
+        };
+
+        let slot = match self.resolve(position) {
+            Ok(slot) => slot,
+            Err(NodeError::Unexistent) => return Ok(false),
+            Err(error) => return Err(error),
+        };
+
+        match self.read_slot_header(slot) {
+            Ok(contents) => Ok(contents.enabled),
+            Err(NodeError::Unexistent) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Update the stats of the level of a node that was written.
+    pub(crate) fn update_level_stats(
+        &mut self,
+        position: u128,
+        was_enabled: bool,
+        enabled: bool,
+    ) -> Result<(), NodeError> {
+        if self.levels.is_none() || was_enabled == enabled {
+            return Ok(());
+        };
+
+        let level = positions::level(position);
+        let mut stats = self.read_level_stats(level)?;
+
+        if enabled {
+            stats.live += 1;
+            stats.first = Some(stats.first.map_or(position, |first| first.min(position)));
+            stats.last = Some(stats.last.map_or(position, |last| last.max(position)));
+        } else if stats.first == Some(position) || stats.last == Some(position) {
+            // The node was written already, so the level can be scanned for
+            // the new first and last nodes.
+            stats = self.scan_level(level)?;
+        } else {
+            stats.live = stats.live.saturating_sub(1);
+        };
+
+        self.write_level_stats(level, &stats)
+    }
+
+    fn read_level_stats(&self, level: u32) -> Result<LevelStats, NodeError> {
+        let levels = match &self.levels {
+            Some(levels) => levels,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        let offset = level as u64 * LEVEL_ENTRY_SIZE;
+        let size = match levels.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        if offset + LEVEL_ENTRY_SIZE > size {
+            return Ok(LevelStats::default());
+        };
+
+        let mut entry = [0_u8; LEVEL_ENTRY_SIZE as usize];
+        match levels.read_at(offset, &mut entry) {
+            Ok(_) => Ok(LevelStats::decode(&entry)),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+
+    fn write_level_stats(&mut self, level: u32, stats: &LevelStats) -> Result<(), NodeError> {
+        let levels = match &self.levels {
+            Some(levels) => levels,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        match levels.write_at(level as u64 * LEVEL_ENTRY_SIZE, &stats.encode()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+
+    /// Count the enabled nodes of a level by reading it.
+    fn scan_level(&self, level: u32) -> Result<LevelStats, NodeError> {
+        let mut stats = LevelStats::default();
+
+        let limit = match self.position_limit()? {
+            Some(limit) => limit,
+            None => {
+                for node in self.stored_nodes()? {
+                    if node.enabled && positions::level(node.position) == level {
+                        stats.live += 1;
+                        stats.first = stats.first.or(Some(node.position));
+                        stats.last = Some(node.position);
+                    };
+                }
+                return Ok(stats);
+            }
+        };
+
+        let first = match 1_u128.checked_shl(level) {
+            Some(width) => width - 1,
+            None => return Ok(stats),
+        };
+        let end = first.saturating_mul(2).saturating_add(1).min(limit);
+
+        let mut start = first;
+        while start < end {
+            let chunk_end = end.min(start + SCAN_CHUNK_SIZE);
+            for (i, occupied) in self.occupancy(start..chunk_end)?.iter().enumerate() {
+                if *occupied {
+                    let position = start + i as u128;
+                    stats.live += 1;
+                    stats.first = stats.first.or(Some(position));
+                    stats.last = Some(position);
+                };
+            }
+            start = chunk_end;
+        }
+
+        Ok(stats)
+    }
+}
@@ -1,14 +1,258 @@
 #![crate_name = "dot_tree"]
-
-mod utils;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod annotations;
+#[cfg(feature = "std")]
+mod attribution;
+#[cfg(feature = "std")]
+mod audit;
+#[cfg(feature = "std")]
+mod backup;
+pub mod bitcodec;
+#[cfg(feature = "std")]
+pub mod bst;
+#[cfg(feature = "std")]
+mod bundle;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+mod cdc;
+#[cfg(feature = "std")]
+mod columns;
+#[cfg(feature = "std")]
+mod compare;
+pub mod core;
+#[cfg(feature = "std")]
+mod edges;
+#[cfg(feature = "std")]
+mod evolve;
+#[cfg(feature = "testing")]
+mod faulty;
+#[cfg(feature = "std")]
+mod features;
+#[cfg(feature = "std")]
+mod fold;
+#[cfg(feature = "std")]
+mod gapfill;
+#[cfg(feature = "std")]
+mod histogram;
+#[cfg(feature = "std")]
+mod history;
+#[cfg(feature = "std")]
+mod hooks;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "std")]
+mod integrity;
+mod layout;
+#[cfg(feature = "std")]
+mod lazy;
+#[cfg(feature = "std")]
+mod lease;
+#[cfg(feature = "std")]
+mod levels;
+#[cfg(feature = "std")]
+mod lint;
+#[cfg(feature = "std")]
+mod lookup;
+#[cfg(feature = "std")]
+mod memory;
+#[cfg(feature = "std")]
+mod merkle;
+#[cfg(feature = "std")]
+mod mirror;
+#[cfg(feature = "std")]
+mod newick;
+#[cfg(feature = "object_store")]
+mod object;
+#[cfg(feature = "std")]
+mod occupancy;
+#[cfg(feature = "std")]
+mod paging;
+#[cfg(feature = "std")]
+mod patch;
+#[cfg(feature = "std")]
+mod persistent;
+#[cfg(feature = "std")]
+mod placement;
+#[cfg(feature = "std")]
+mod positions;
+pub mod protocol;
+#[cfg(feature = "std")]
+mod query;
+#[cfg(feature = "std")]
+mod rank;
+#[cfg(feature = "std")]
+mod readonly;
+#[cfg(feature = "std")]
+mod recording;
+#[cfg(feature = "std")]
+mod repair;
+#[cfg(feature = "std")]
+mod retention;
+#[cfg(feature = "std")]
+mod sample;
+#[cfg(feature = "std")]
+mod savepoints;
+#[cfg(feature = "std")]
+mod scan;
+mod schema;
+#[cfg(feature = "std")]
+mod search;
+#[cfg(feature = "std")]
+mod seqlock;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "std")]
+mod shrink;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(feature = "std")]
+mod storage;
+#[cfg(feature = "std")]
+mod streaming;
+#[cfg(feature = "std")]
+mod summary;
+#[cfg(feature = "std")]
+mod table;
+#[cfg(feature = "std")]
+mod throttle;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+mod transaction;
+#[cfg(feature = "std")]
+mod traversal;
+#[cfg(feature = "std")]
+mod verification;
+#[cfg(feature = "std")]
+mod writers;
+pub(crate) use crate::core::node_header_size;
+pub use crate::core::TreeBytes;
+#[cfg(feature = "std")]
+pub(crate) use crate::core::{node_size, Slot};
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+pub use annotations::MAX_ANNOTATION_SIZE;
+#[cfg(feature = "std")]
+pub use attribution::Attribution;
+#[cfg(feature = "std")]
+pub use audit::AuditEntry;
+#[cfg(feature = "std")]
+pub use backup::BackupError;
+pub use bitcodec::BitOrder;
+#[cfg(feature = "std")]
+pub use bundle::BundleError;
+#[cfg(feature = "std")]
+pub use cdc::{ChangeStream, CommittedChange};
+#[cfg(feature = "std")]
+pub use columns::SubitemColumn;
+#[cfg(feature = "std")]
+pub use compare::SubitemKey;
+#[cfg(feature = "std")]
+pub use edges::{EdgeError, EdgeOptions};
+#[cfg(feature = "testing")]
+pub use faulty::{Fault, FaultyStorage};
+#[cfg(feature = "std")]
+pub use histogram::{Histogram, HistogramBucket};
+#[cfg(feature = "std")]
+pub use history::VersionInfo;
+#[cfg(feature = "std")]
+pub use hooks::WriteChange;
+#[cfg(feature = "std")]
+pub use integrity::{Finding, IntegrityReport, Severity};
+pub use layout::Layout;
+#[cfg(feature = "std")]
+pub use lazy::LazyNode;
+#[cfg(feature = "std")]
+pub use lease::Lease;
+#[cfg(feature = "std")]
+pub use levels::LevelStats;
+#[cfg(feature = "std")]
+pub use memory::MemoryMode;
+#[cfg(feature = "std")]
+pub use newick::{NewickError, NewickFormatter};
+#[cfg(feature = "object_store")]
+pub use object::{ObjectStorage, ObjectStore, MIN_PART_SIZE};
+#[cfg(feature = "std")]
+pub use occupancy::Positions;
+#[cfg(feature = "std")]
+pub use paging::{Page, TraversalOrder};
+#[cfg(feature = "std")]
+pub use patch::{apply_patch, create_patch, PatchError};
+#[cfg(feature = "std")]
+pub use persistent::GcReport;
+#[cfg(feature = "std")]
+pub use placement::{
+    AppendOnlyPlacement, FreeListPlacement, LevelOrderPlacement, PlacementStrategy,
+};
+#[cfg(feature = "std")]
+pub use query::{IndexEstimate, Query, QueryError, QueryPlan};
+#[cfg(feature = "std")]
+pub use readonly::ReadOnlyTree;
+#[cfg(feature = "std")]
+pub use recording::ReplayError;
+#[cfg(feature = "std")]
+pub use repair::RepairReport;
+#[cfg(feature = "std")]
+pub use scan::ScannedSlot;
+pub use schema::{analyze_schema, SchemaError, SchemaReport, MAX_NODE_SIZE, MAX_SUBITEMS};
+#[cfg(feature = "server")]
+pub use server::TreeServer;
+#[cfg(feature = "std")]
+pub use shrink::ShrinkReport;
+#[cfg(feature = "std")]
+pub use snapshot::{MatchOptions, Mismatch, Snapshot};
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "std")]
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(feature = "std")]
+pub use storage::{Storage, TieredStorage};
+#[cfg(feature = "std")]
+pub use streaming::{SubitemReader, SubitemWriter};
+#[cfg(feature = "std")]
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
+#[cfg(feature = "std")]
+pub use summary::TreeSummary;
+#[cfg(feature = "std")]
+pub use table::{TableError, TableFormat};
+#[cfg(feature = "std")]
+pub use trace::{IoStats, Operation, SlowOperation, TraceEvent};
+#[cfg(feature = "std")]
+pub use traversal::{Traversal, TraversalOptions};
+#[cfg(feature = "std")]
+pub use verification::WriteVerification;
+#[cfg(feature = "std")]
+pub use writers::SubtreeWriter;
 
 // NEKOTREE
 const FILE_IDENTIFIER: [u8; 8] = [0x4e, 0x45, 0x4b, 0x4f, 0x54, 0x52, 0x45, 0x45];
-const FORMAT_VERSION: [u8; 2] = [0_u8, 0_u8];
+
+/// The version of the format written, and the only one read. Version 1
+/// (`00 00`) only had the disabling feature, and numbered the children of
+/// nodes other than the root differently.
+const FORMAT_VERSION: [u8; 2] = [0_u8, 1_u8];
+
+/// The bit of the features header that selects LSB-first bit order.
+const BIT_ORDER_FLAG: usize = 15;
+
+/// The bit of the features header set while the tree is open for writing.
+const DIRTY_FLAG: usize = 14;
+
+/// The size in bits of each child pointer stored by persistent trees.
+const POINTER_SIZE: u32 = 32;
 
 #[derive(Debug)]
 pub enum TreeFileError {
@@ -29,6 +273,59 @@ pub enum TreeFileError {
 
     /// The tree file requires file permissions to write.
     MissingPermissions,
+
+    /// The file is missing a feature to perform the operation.
+    MissingFeature,
+
+    /// The requested version isn't in the tree's version table.
+    UnexistentVersion,
+
+    /// The tree file's contents don't match its headers.
+    Corrupted,
+
+    /// The layout of the tree's nodes can't be stored.
+    InvalidSchema(SchemaError),
+
+    /// The changes couldn't be flushed to disk.
+    SyncFailed,
+
+    /// The tree file wasn't closed after being opened for writing, so it
+    /// might have partial changes. Either it's still open, or the process
+    /// writing it crashed.
+    UncleanShutdown,
+
+    /// The operation can't be performed with the tree's features.
+    UnsupportedFeature,
+
+    /// The tree is too large to be walked in memory on this target, e.g.
+    /// more slots than a 32-bit target can index.
+    OffsetOverflow,
+
+    /// The other tree has different features, subitems, bit order or layout.
+    SchemaMismatch,
+
+    /// The default value of a new subitem, or the default subitems of a
+    /// cloned structure, don't have their sizes.
+    InvalidDefaultValue,
+
+    /// The nodes don't have a subitem at the index.
+    UnexistentSubitem,
+
+    /// The tree has no transaction in the state needed (e.g. committing a
+    /// transaction that wasn't prepared), or already has one.
+    InvalidTransactionState,
+
+    /// The annotation is longer than [`MAX_ANNOTATION_SIZE`].
+    AnnotationTooLarge,
+
+    /// The tree file wasn't closed after being opened for writing, and the
+    /// tree writing it still holds a lease on it (see
+    /// [`Tree::acquire_lease`]), so it's presumed alive.
+    LeaseHeld,
+
+    /// The tree holds no lease, or its lease expired (see
+    /// [`Tree::renew_lease`]).
+    LeaseExpired,
 }
 
 #[derive(Debug)]
@@ -45,17 +342,156 @@ pub enum NodeError {
     /// The subitem has an incorrect size
     InvalidSubitem,
 
+    /// The amount of subitems passed doesn't match the tree's subitems.
+    SubitemCountMismatch { expected: usize, got: usize },
+
     /// Attempted to create a node that already exists (with override = false).
     NodeAlreadyExists,
 
     /// The file is missing a feature to perform the operation.
     MissingFeature,
+
+    /// The tree has no free slots left that a child pointer can address.
+    SlotLimitReached,
+
+    /// The position is deeper than the levels of the tree's layout.
+    OutsideLayout,
+
+    /// The position isn't in the subtree the handle writes.
+    OutsideSubtree,
+
+    /// The validator rejected the node.
+    ValidationFailed(String),
+
+    /// A write hook failed, so the write was rolled back.
+    HookFailed(String),
+
+    /// The node is past the end of a tree without the disabling feature, so
+    /// the slots before it would read as nodes whose subitems are all zeros.
+    /// Write the nodes before it first, or create the tree with the
+    /// disabling feature or another [`GapFill`]. Persistent trees without
+    /// the disabling feature fail the same way when the node's parent isn't
+    /// stored, as its missing ancestors would be stored as nodes.
+    WouldLeaveGap,
+
+    /// The page token wasn't returned by a page of the same traversal, or
+    /// the version it was read from is gone.
+    InvalidToken,
+
+    /// The bytes read back after a write don't match the bytes written (see
+    /// [`WriteVerification`]).
+    VerifyFailed,
+
+    /// The tree has a feature that the operation doesn't support.
+    UnsupportedFeature,
+
+    /// An offset or a size doesn't fit in the integers it's stored in, e.g.
+    /// a node past the largest offset of the storage, or a bitmap larger
+    /// than 4 GiB read on a 32-bit target.
+    OffsetOverflow,
+
+    /// The tree's nodes are larger than the nodes decoded whole may be (see
+    /// [`Tree::set_max_node_bytes`]). Both sizes are in bytes.
+    NodeTooLarge { size: usize, max: usize },
+
+    /// The node's slot doesn't decode, e.g. because the tree file doesn't
+    /// match its headers. `offset` is the offset in bytes in the tree file of
+    /// the bytes found wrong (or of the slot holding them), and `detail` what
+    /// is wrong with them. `position` is `None` when the slot can't be mapped
+    /// back to a position, e.g. in persistent trees.
+    Corrupt {
+        position: Option<u128>,
+        offset: u64,
+        detail: &'static str,
+    },
+
+    /// The lease of the tree expired, so another process might have broken
+    /// it and be writing the tree file (see [`Tree::acquire_lease`]).
+    LeaseExpired,
+
+    /// The tree's transaction is prepared, so its nodes can't be written
+    /// until it's committed or aborted (see [`Tree::prepare`]).
+    InvalidTransactionState,
 }
 
 /// Format features.
-#[derive(PartialEq, Debug, EnumIter)]
+#[derive(PartialEq, Debug, Clone, Copy, EnumIter)]
 pub enum Feature {
+    /// Store whether each node is enabled in the node itself, so that nodes
+    /// can be disabled and missing nodes read as disabled. Without it, nodes
+    /// can't be written past the end of the tree file, or below a missing
+    /// node in persistent trees (see [`NodeError::WouldLeaveGap`]), and
+    /// columnar trees can't be created.
     Disabling,
+
+    /// Never overwrite nodes. Every write path-copies the node's ancestors
+    /// into new slots and records the new root as a new version.
+    Persistent,
+
+    /// Keep a bitmap of the positions holding an enabled node next to the
+    /// tree file, so that structure queries don't have to decode nodes.
+    Occupancy,
+
+    /// Store whether each child of a node is enabled in the node itself, so
+    /// that structure queries on a read node don't have to read its children.
+    ChildHints,
+
+    /// Keep the amount of enabled nodes of each level, and the first and
+    /// last of them, in a table next to the tree file.
+    LevelStats,
+
+    /// Append a record of every change made to the tree to an audit log
+    /// next to the tree file.
+    Audit,
+
+    /// Keep the writer id and the time of the last write of each node in its
+    /// last two subitems, which are filled in on every write.
+    Attribution,
+}
+
+/// The layout of a new tree file.
+#[derive(Debug, Default)]
+pub struct CreateOptions {
+    /// The features enabled in the tree file.
+    pub features: Vec<Feature>,
+
+    /// The size of each node subitem in bits.
+    pub subitems: Vec<u32>,
+
+    /// The order in which the nodes' bits are packed.
+    pub bit_order: BitOrder,
+
+    /// The order in which the nodes are stored.
+    pub layout: Layout,
+
+    /// What the slots skipped by a write past the end of the tree file are
+    /// filled with.
+    pub gap_fill: GapFill,
+}
+
+/// What the slots between the end of a tree file and a node written past it
+/// are filled with. Without the disabling feature, zeroed slots can't be told
+/// apart from nodes whose subitems are all zeros.
+///
+/// Filling with anything but zeros writes every skipped slot, so writing a
+/// node far past the end of the tree file takes as long as writing the nodes
+/// before it. Persistent trees store the ancestors missing along the path to
+/// a written node, and columnar trees are reserved whole when they're
+/// created, so both are always filled with zeros, and need the disabling
+/// feature for such slots to read as disabled. Persistent trees without it
+/// can only be written below a stored node.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum GapFill {
+    /// Every bit is zero, which reads as a disabled node if the tree has the
+    /// disabling feature.
+    #[default]
+    Zeros,
+
+    /// Every bit is one, feature headers included.
+    Ones,
+
+    /// A disabled node with these subitems, e.g. a sentinel value.
+    Template(Vec<Vec<bool>>),
 }
 
 /// Permissions to request when opening the tree file. Opening in write mode
@@ -66,11 +502,13 @@ pub enum TreeOpenMode {
     ReadWrite,
 }
 
-/// A tree file.
+#[cfg(feature = "std")]
+/// A tree file. Trees are `Send + Sync`: the methods that only read take
+/// `&self`, so a shared tree can be read from many threads at once.
 #[derive(Debug)]
 pub struct Tree {
-    /// The tree file.
-    pub file: File,
+    /// The storage holding the tree file.
+    pub storage: Arc<dyn Storage>,
 
     /// The mode in which the tree file was opened.
     pub mode: TreeOpenMode,
@@ -83,8 +521,136 @@ pub struct Tree {
 
     /// The size of each node subitem in bits.
     pub subitems: Vec<u32>,
+
+    /// The order in which the nodes' bits are packed.
+    pub bit_order: BitOrder,
+
+    /// The order in which the nodes are stored.
+    pub layout: Layout,
+
+    /// What the slots skipped by writes past the end are filled with.
+    gap_fill: GapFill,
+
+    /// The path of the tree file.
+    path: PathBuf,
+
+    /// The version table of persistent trees.
+    versions: Option<File>,
+
+    /// The time each version of a persistent tree was written.
+    timestamps: Option<File>,
+
+    /// The version the tree is pinned to. `None` follows the latest version.
+    version: Option<u64>,
+
+    /// The occupancy bitmap of trees with the occupancy feature.
+    occupancy: Option<File>,
+
+    /// The level table of trees with the level stats feature.
+    levels: Option<File>,
+
+    /// The leaf count of every subtree, kept with the level table by trees
+    /// that aren't persistent.
+    leaves: Option<File>,
+
+    /// The audit log of trees with the audit feature.
+    audit: Option<File>,
+
+    /// The subtree hashes of trees keeping them.
+    merkle: Option<File>,
+
+    /// The log of the subitem indexes, if the tree has any.
+    index_log: Option<File>,
+
+    /// The indexes, by the subitems of their keys.
+    indexes: BTreeMap<Vec<usize>, lookup::SubitemIndex>,
+
+    /// The revision table of trees that were backed up.
+    revisions: Option<File>,
+
+    /// The log of the annotations, if the tree has any.
+    annotation_log: Option<File>,
+
+    /// The annotations, by position.
+    annotations: BTreeMap<u128, Vec<u8>>,
+
+    /// The log of the values retained, if the tree retains any.
+    value_log: Option<File>,
+
+    /// The entries of the log retained for each position, from the oldest
+    /// to the newest.
+    retained: BTreeMap<u128, VecDeque<u64>>,
+
+    /// The amount of values retained per position.
+    retained_depth: u32,
+
+    /// The id recorded as the writer of the changes made through the tree.
+    writer_id: u32,
+
+    /// The hook receiving trace events.
+    trace: Option<trace::TraceHook>,
+
+    /// The function checking the nodes before they're written.
+    validator: Option<hooks::Validator>,
+
+    /// The hooks called after every write.
+    write_hooks: Vec<hooks::WriteHook>,
+
+    /// The amount of bytes moved to and from the storage.
+    io: Mutex<trace::IoCounters>,
+
+    /// The limit of the writes of bulk operations, if there's one.
+    budget: Option<throttle::IoBudget>,
+
+    /// Whether a bulk operation is running.
+    bulk: bool,
+
+    /// Whether the tree's transaction is prepared, so nodes can't be written
+    /// until it's committed or aborted.
+    prepared: bool,
+
+    /// The trace the calls changing the tree are recorded in, if any.
+    recording: Option<File>,
+
+    /// How the writes of nodes are checked.
+    verification: WriteVerification,
+
+    /// The limit of the size in bytes of the nodes decoded whole, if there's
+    /// one.
+    max_node_bytes: Option<usize>,
+
+    /// The lease the tree holds on the tree file, if it holds one.
+    lease: Option<lease::HeldLease>,
+
+    /// How much memory the tree keeps to avoid reading the storage again.
+    memory_mode: MemoryMode,
+
+    /// Whether the tree was already flushed by [`close`](Tree::close).
+    closed: bool,
+
+    /// Held while writing bytes shared with nodes that another handle might
+    /// be writing at the same time.
+    boundary: Arc<Mutex<()>>,
+
+    /// The pages of the tree file pinned in memory.
+    cache: RwLock<cache::PageCache>,
+
+    /// The last positions accessed, if they're being recorded.
+    accesses: Mutex<cache::AccessTrace>,
+
+    /// The sequence counters shared with the reader handles, once there's
+    /// one.
+    sequences: Option<Arc<seqlock::BlockSequences>>,
 }
 
+// Sharing a tree between threads is what `&self` reads are for.
+#[cfg(feature = "std")]
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Tree>();
+};
+
+#[cfg(feature = "std")]
 /// A node in the tree.
 #[derive(Debug)]
 pub struct Node<'a> {
@@ -95,75 +661,171 @@ pub struct Node<'a> {
 
     /// The node's subitems in bits.
     pub subitems: Vec<Vec<bool>>,
+
+    /// Whether each child is enabled, if the tree has child hints.
+    hints: Option<[bool; 2]>,
 }
 
-impl Tree {
-    /// Open an existent tree file.
-    pub fn open(file_path: &'static str, mode: TreeOpenMode) -> Result<Self, TreeFileError> {
-        let mut features: Vec<Feature> = vec![];
-        let mut subitems: Vec<u32> = vec![];
+/// The contents of a node, detached from the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeData {
+    /// The tranversal position.
+    pub position: u128,
 
-        {
-            let mut file = match OpenOptions::new().read(true).create(false).open(&file_path) {
-                Ok(file) => file,
-                Err(_) => return Err(TreeFileError::FileNotOpened),
-            };
+    /// Whether the node is enabled.
+    pub enabled: bool,
 
-            let mut file_headers = [0u8; 16];
-            match file.read_exact(&mut file_headers) {
-                Ok(_) => (),
-                Err(_) => return Err(TreeFileError::MissingHeaders),
-            };
+    /// The node's subitems in bits.
+    pub subitems: Vec<Vec<bool>>,
+}
 
-            if file_headers[0..8] != FILE_IDENTIFIER {
-                return Err(TreeFileError::InvalidIdentifier);
-            };
+#[cfg(feature = "std")]
+/// The path of a file kept next to a tree file, named after it with an extra
+/// `extension`.
+pub(crate) fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
 
-            if file_headers[8..10] != FORMAT_VERSION {
-                return Err(TreeFileError::UnsupportedFormatVersion);
-            };
+#[cfg(feature = "std")]
+/// Create an empty file for a new tree. Fails if the file already has data.
+pub(crate) fn create_file(file_path: &str) -> Result<File, TreeFileError> {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file_path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(TreeFileError::FileNotOpened),
+    };
+
+    let mut file_buffer = [0_u8; 1];
+    if file.read_exact(&mut file_buffer).is_ok() {
+        return Err(TreeFileError::FileHasContents);
+    };
+
+    Ok(file)
+}
 
-            let feature_bits = utils::bytes_to_bits(&file_headers[10..12]);
-            let mut i = 0;
-            for feature in Feature::iter() {
-                if feature_bits[i] {
-                    features.push(feature);
-                }
-                i += 1;
-            }
+/// Read and check the headers of a tree file, returning the options it was
+/// created with.
+#[cfg(feature = "std")]
+pub(crate) fn read_headers(storage: &dyn Storage) -> Result<CreateOptions, TreeFileError> {
+    crate::core::parse_headers(|offset, buf| storage.read_at(offset, buf).is_ok())
+}
 
-            let subitem_count = utils::u8_array_to_u32(&match &file_headers[12..16] {
-                [a, b, c, d] => [*a, *b, *c, *d],
-                _ => panic!("Slice does not have a length of 4"),
-            });
-            for _ in 0..subitem_count {
-                let mut subitem_bytes = [0_u8; 4];
-                match file.read_exact(&mut subitem_bytes) {
-                    Ok(_) => (),
-                    Err(_) => return Err(TreeFileError::MissingHeaders),
-                };
-                subitems.push(utils::u8_array_to_u32(&subitem_bytes));
-            }
-        }
+#[cfg(feature = "std")]
+/// Whether a tree file is marked as open for writing.
+pub(crate) fn read_dirty(storage: &dyn Storage) -> Result<bool, TreeFileError> {
+    let mut feature_bytes = [0_u8; 2];
+    match storage.read_at(10, &mut feature_bytes) {
+        Ok(_) => (),
+        Err(_) => return Err(TreeFileError::MissingHeaders),
+    };
+
+    Ok(bitcodec::bytes_to_bits(&feature_bytes)[DIRTY_FLAG])
+}
+
+#[cfg(feature = "std")]
+/// Mark (or unmark) a tree file as open for writing.
+pub(crate) fn write_dirty(storage: &dyn Storage, dirty: bool) -> Result<(), TreeFileError> {
+    let mut feature_bytes = [0_u8; 2];
+    match storage.read_at(10, &mut feature_bytes) {
+        Ok(_) => (),
+        Err(_) => return Err(TreeFileError::MissingHeaders),
+    };
+
+    bitcodec::pack_bits_at(&mut feature_bytes, DIRTY_FLAG, &[dirty]);
 
+    match storage.write_at(10, &feature_bytes) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TreeFileError::MissingPermissions),
+    }
+}
+
+#[cfg(feature = "std")]
+/// Write the headers of a new tree file.
+pub(crate) fn write_headers(
+    storage: &dyn Storage,
+    options: &CreateOptions,
+) -> Result<(), TreeFileError> {
+    let mut headers = vec![];
+    headers.extend(FILE_IDENTIFIER);
+    headers.extend(FORMAT_VERSION);
+
+    let mut feature_bits: Vec<bool> = Feature::iter()
+        .map(|feature| options.features.contains(&feature))
+        .collect();
+    feature_bits.extend(vec![false; 16 - feature_bits.len()]); // Align to 2 bytes
+    feature_bits[BIT_ORDER_FLAG] = options.bit_order == BitOrder::LsbFirst;
+    if let Layout::VanEmdeBoas { levels } | Layout::Columnar { levels } = options.layout {
+        feature_bits[layout::LAYOUT_COLUMNAR_BIT] =
+            matches!(options.layout, Layout::Columnar { .. });
+        feature_bits.splice(
+            layout::LAYOUT_LEVELS_BIT
+                ..layout::LAYOUT_LEVELS_BIT + layout::LAYOUT_LEVELS_BITS as usize,
+            bitcodec::u64_to_bits(levels as u64, layout::LAYOUT_LEVELS_BITS),
+        );
+    };
+    headers.extend(bitcodec::bits_to_bytes(&feature_bits));
+
+    headers.extend(bitcodec::u32_to_u8_array(options.subitems.len() as u32));
+
+    for subitem in &options.subitems {
+        headers.extend(bitcodec::u32_to_u8_array(*subitem));
+    }
+
+    match storage.write_at(0, &headers) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TreeFileError::MissingPermissions),
+    }
+}
+
+#[cfg(feature = "std")]
+impl Tree {
+    /// Open an existent tree file. Fails with
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown) if the tree file
+    /// wasn't closed the last time it was opened for writing, or
+    /// [`LeaseHeld`](TreeFileError::LeaseHeld) if its writer still holds a
+    /// lease on it.
+    pub fn open(file_path: &'static str, mode: TreeOpenMode) -> Result<Self, TreeFileError> {
+        Self::open_file(file_path, mode, true)
+    }
+
+    /// Open an existent tree file even if it wasn't closed cleanly, e.g. to
+    /// verify it after a crash.
+    pub fn open_unclean(
+        file_path: &'static str,
+        mode: TreeOpenMode,
+    ) -> Result<Self, TreeFileError> {
+        Self::open_file(file_path, mode, false)
+    }
+
+    fn open_file(
+        file_path: &str,
+        mode: TreeOpenMode,
+        check_clean: bool,
+    ) -> Result<Self, TreeFileError> {
         let file = match OpenOptions::new()
             .read(true)
             .write(mode == TreeOpenMode::ReadWrite)
-            .open(&file_path)
+            .open(file_path)
         {
             Ok(file) => file,
             Err(_) => return Err(TreeFileError::FileNotOpened),
         };
 
-        let header_size = 16 + (subitems.len() * 4) as usize;
+        let options = read_headers(&file)?;
 
-        Ok(Self {
-            file,
-            mode,
-            header_size,
-            features,
-            subitems,
-        })
+        if check_clean && read_dirty(&file)? {
+            return Err(lease::unclean_error(Path::new(file_path)));
+        };
+
+        Self::from_parts(Arc::new(file), mode, file_path, options, false)
     }
 
     /// Create a new tree file.
@@ -173,91 +835,296 @@ impl Tree {
         features: Vec<Feature>,
         subitems: Vec<u32>,
     ) -> Result<Self, TreeFileError> {
-        {
-            let mut file = match OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&file_path)
-            {
-                Ok(file) => file,
-                Err(_) => return Err(TreeFileError::FileNotOpened),
-            };
-
-            let mut file_buffer = [0_u8; 1];
-            match file.read_exact(&mut file_buffer) {
-                Ok(_) => {
-                    return Err(TreeFileError::FileHasContents);
-                }
-                Err(_) => (),
-            };
-
-            file.write(&FILE_IDENTIFIER).unwrap();
-            file.write(&FORMAT_VERSION).unwrap();
+        Self::create_with_options(
+            file_path,
+            mode,
+            CreateOptions {
+                features,
+                subitems,
+                ..Default::default()
+            },
+        )
+    }
 
-            let mut feature_bits = vec![features.contains(&Feature::Disabling)];
-            feature_bits.extend(vec![false; 16 - feature_bits.len()]); // Align to 2 bytes
-            file.write(&utils::bits_to_bytes(&feature_bits)).unwrap();
+    /// Create a new tree file with the given layout.
+    pub fn create_with_options(
+        file_path: &'static str,
+        mode: TreeOpenMode,
+        options: CreateOptions,
+    ) -> Result<Self, TreeFileError> {
+        Self::create_at(file_path, mode, options)
+    }
 
-            file.write(&utils::u32_to_u8_array(subitems.len() as u32))
-                .unwrap();
+    /// Create a tree file like [`create_with_options`](Tree::create_with_options),
+    /// at a path built at runtime.
+    pub(crate) fn create_at(
+        file_path: &str,
+        mode: TreeOpenMode,
+        options: CreateOptions,
+    ) -> Result<Self, TreeFileError> {
+        match schema::validate(&options.features, &options.subitems)
+            .and_then(|_| schema::validate_layout(&options.features, options.layout))
+            .and_then(|_| schema::validate_reserved_slots(&options.features, options.layout))
+            .and_then(|_| {
+                schema::validate_gap_fill(
+                    &options.features,
+                    &options.subitems,
+                    options.layout,
+                    &options.gap_fill,
+                )
+            }) {
+            Ok(_) => (),
+            Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+        };
 
-            for subitem in &subitems {
-                file.write(&utils::u32_to_u8_array(*subitem)).unwrap();
-            }
-        }
+        let file = create_file(file_path)?;
+        write_headers(&file, &options)?;
+        columns::reserve(&file, &options)?;
 
-        let file = OpenOptions::new()
+        let file = match OpenOptions::new()
             .read(true)
             .write(mode == TreeOpenMode::ReadWrite)
-            .open(&file_path)
-            .unwrap();
+            .open(file_path)
+        {
+            Ok(file) => file,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        Self::from_parts(Arc::new(file), mode, file_path, options, true)
+    }
+
+    /// Build a tree over a storage whose headers were already read (or
+    /// written).
+    pub(crate) fn from_parts(
+        storage: Arc<dyn Storage>,
+        mode: TreeOpenMode,
+        file_path: &str,
+        options: CreateOptions,
+        created: bool,
+    ) -> Result<Self, TreeFileError> {
+        let mut tree = Self::detached(storage, mode, file_path, options);
+        tree.open_versions(created)?;
+        tree.open_timestamps(created)?;
+        tree.open_occupancy(created)?;
+        tree.open_levels(created)?;
+        tree.open_leaves(created)?;
+        tree.open_audit(created)?;
+        tree.open_merkle(created)?;
+        tree.open_indexes(created)?;
+        tree.open_revisions(created)?;
+        tree.open_gap_fill(created)?;
+        tree.open_annotations(created)?;
+        tree.open_retention(created)?;
+
+        if tree.mode == TreeOpenMode::ReadWrite {
+            write_dirty(&*tree.storage, true)?;
+            tree.sync()?;
+        };
+        tree.open_transaction(created)?;
 
-        let header_size = 16 + (subitems.len() * 4) as usize;
+        Ok(tree)
+    }
+
+    /// Build a tree over a storage without opening the files kept next to
+    /// it.
+    fn detached(
+        storage: Arc<dyn Storage>,
+        mode: TreeOpenMode,
+        file_path: &str,
+        options: CreateOptions,
+    ) -> Self {
+        let header_size = 16 + options.subitems.len() * 4;
 
-        Ok(Self {
-            file,
+        Self {
+            storage,
             mode,
             header_size,
-            features,
-            subitems,
-        })
+            features: options.features,
+            subitems: options.subitems,
+            bit_order: options.bit_order,
+            layout: options.layout,
+            gap_fill: options.gap_fill,
+            path: PathBuf::from(file_path),
+            versions: None,
+            timestamps: None,
+            version: None,
+            occupancy: None,
+            levels: None,
+            leaves: None,
+            audit: None,
+            merkle: None,
+            index_log: None,
+            indexes: BTreeMap::new(),
+            revisions: None,
+            annotation_log: None,
+            annotations: BTreeMap::new(),
+            value_log: None,
+            retained: BTreeMap::new(),
+            retained_depth: 0,
+            writer_id: 0,
+            trace: None,
+            validator: None,
+            write_hooks: vec![],
+            io: Default::default(),
+            budget: None,
+            bulk: false,
+            prepared: false,
+            recording: None,
+            verification: WriteVerification::Off,
+            max_node_bytes: None,
+            lease: None,
+            memory_mode: MemoryMode::Balanced,
+            closed: false,
+            boundary: Arc::default(),
+            cache: Default::default(),
+            accesses: Default::default(),
+            sequences: None,
+        }
+    }
+
+    /// Open a tree file stored in memory, e.g. one embedded with
+    /// `include_bytes!`. The bytes are copied, and the tree is read only.
+    ///
+    /// Only the tree file is available, so trees with the occupancy or level
+    /// stats features are read without their auxiliary files, and
+    /// persistent trees (which need their version table) can't be opened.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TreeFileError> {
+        let storage = storage::ByteStorage(bytes.to_vec());
+        let options = read_headers(&storage)?;
+
+        if read_dirty(&storage)? {
+            return Err(TreeFileError::UncleanShutdown);
+        };
+
+        if options.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        Ok(Self::detached(
+            Arc::new(storage),
+            TreeOpenMode::Read,
+            "",
+            options,
+        ))
+    }
+
+    /// Open a file kept next to the tree file, truncating it first if
+    /// `create` is true.
+    pub(crate) fn open_sidecar(
+        &self,
+        extension: &str,
+        create: bool,
+    ) -> Result<File, TreeFileError> {
+        let path = sidecar_path(&self.path, extension);
+
+        if create && File::create(&path).is_err() {
+            return Err(TreeFileError::FileNotOpened);
+        };
+
+        match OpenOptions::new()
+            .read(true)
+            .write(self.mode == TreeOpenMode::ReadWrite)
+            .open(path)
+        {
+            Ok(file) => Ok(file),
+            Err(_) => Err(TreeFileError::FileNotOpened),
+        }
     }
 
     /// Flush the changes to disk.
     pub fn flush(&mut self) {
-        self.file.sync_all().unwrap();
+        self.sync().unwrap();
     }
 
-    /// The total node size in bits (including headers).
-    pub fn node_size(&self) -> u32 {
-        let mut size = 0;
+    /// Flush the changes to disk and close the tree file, reporting whether
+    /// the changes were made durable.
+    pub fn close(mut self) -> Result<(), TreeFileError> {
+        self.closed = true;
+        self.finish()
+    }
 
-        for subitem in &self.subitems {
-            size += *subitem;
-        }
+    /// Flush the changes to disk, and then mark the tree file as closed.
+    fn finish(&mut self) -> Result<(), TreeFileError> {
+        self.sync()?;
 
-        if self.features.contains(&Feature::Disabling) {
-            size += 1;
-        }
+        if self.mode == TreeOpenMode::ReadWrite {
+            write_dirty(&*self.storage, false)?;
+            self.sync()?;
+        };
+        self.release_lease();
 
-        size
+        Ok(())
+    }
+
+    /// Flush the changes in the tree file and its sidecar files to disk.
+    fn sync(&mut self) -> Result<(), TreeFileError> {
+        self.traced(Operation::Flush, None, |tree| {
+            if tree.storage.sync().is_err() {
+                return Err(TreeFileError::SyncFailed);
+            };
+
+            for sidecar in [
+                &tree.versions,
+                &tree.timestamps,
+                &tree.occupancy,
+                &tree.levels,
+                &tree.leaves,
+                &tree.audit,
+                &tree.merkle,
+                &tree.index_log,
+                &tree.revisions,
+                &tree.annotation_log,
+                &tree.value_log,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if sidecar.sync_all().is_err() {
+                    return Err(TreeFileError::SyncFailed);
+                };
+            }
+
+            Ok(())
+        })
+    }
+
+    /// The size in bits of the headers prepended to each node by the enabled
+    /// features.
+    pub fn node_header_size(&self) -> u32 {
+        node_header_size(&self.features)
+    }
+
+    /// The total node size in bits (including headers).
+    pub fn node_size(&self) -> u32 {
+        node_size(&self.features, &self.subitems)
     }
 
     /// The amount of nodes in the tree. Might return a slightly incorrect
     /// value if the total size of a tree in bits is less than 4 bits.
+    ///
+    /// Persistent trees count every stored slot, including the copies kept
+    /// for older versions. Trees whose nodes have no bits can't store any
+    /// node. Columnar trees always store every position of their layout.
     pub fn nodes(&self) -> u64 {
-        let tree_storage_size = match self.file.metadata() {
-            Ok(metadata) => metadata.len() - self.header_size as u64,
+        let tree_storage_size = match self.storage.size() {
+            Ok(size) => size.saturating_sub(self.header_size as u64),
             Err(_) => 0,
         };
 
-        tree_storage_size * 8 / self.node_size() as u64
+        crate::core::slot_count(
+            &self.features,
+            &self.subitems,
+            self.layout,
+            tree_storage_size,
+        )
     }
 
     /// The tree's root node.
-    pub fn root(&mut self) -> Result<Node, NodeError> {
+    ///
+    /// Like [`node`](Tree::node), it borrows the tree mutably, as the node
+    /// can write through it. Use [`read_node`](Tree::read_node) or
+    /// [`lazy_node`](Tree::lazy_node) to read it through a shared tree.
+    pub fn root(&mut self) -> Result<Node<'_>, NodeError> {
         self.node(0)
     }
 
@@ -273,175 +1140,692 @@ impl Tree {
     }
 
     /// Get a node by its tranversal position.
-    pub fn node(&mut self, position: u128) -> Result<Node, NodeError> {
-        let node_size = self.node_size() as f64;
-        let nodes = self.nodes() as u128;
+    ///
+    /// The [`Node`] returned keeps the tree borrowed mutably, so it can
+    /// write its subitems and children. Reading through a shared tree (from
+    /// many threads, say) goes through [`read_node`](Tree::read_node) or
+    /// [`lazy_node`](Tree::lazy_node) instead, which only borrow it.
+    pub fn node(&mut self, position: u128) -> Result<Node<'_>, NodeError> {
+        let contents = self.read_enabled_slot(position)?;
+
+        let hints = match self.features.contains(&Feature::ChildHints) {
+            true => Some(contents.hints),
+            false => None,
+        };
 
-        let start_byte = ((self.header_size as f64) + (position as f64) * node_size / 8.0) as u64;
-        let pad_l = (node_size * position as f64) % 8.0;
-        let buf_size = ((pad_l + node_size) as u64).div_ceil(8);
+        Ok(Node {
+            tree: self,
+            position,
+            subitems: contents.subitems,
+            hints,
+        })
+    }
 
-        if position >= nodes as u128 {
-            return Err(NodeError::Unexistent);
-        };
+    /// Read the contents of a node by its tranversal position. Unlike
+    /// [`node`](Tree::node), it only borrows the tree, so many threads can
+    /// read through the same tree at once.
+    pub fn read_node(&self, position: u128) -> Result<NodeData, NodeError> {
+        let contents = self.read_enabled_slot(position)?;
 
-        self.file.seek(SeekFrom::Start(start_byte as u64)).unwrap();
+        Ok(NodeData {
+            position,
+            enabled: true,
+            subitems: contents.subitems,
+        })
+    }
 
-        let mut byte_buffer = vec![0_u8; buf_size as usize];
+    /// Whether an enabled node is stored at `position`, reading only the
+    /// feature headers of its slot.
+    pub(crate) fn has_enabled_node(&self, position: u128) -> bool {
+        self.resolve(position)
+            .and_then(|slot| self.read_slot_header(slot))
+            .is_ok_and(|contents| contents.enabled)
+    }
 
-        match self.file.read_exact(&mut byte_buffer) {
-            Ok(_) => (),
-            Err(_) => return Err(NodeError::Unexistent),
-        };
+    /// Read the slot holding an enabled node.
+    fn read_enabled_slot(&self, position: u128) -> Result<Slot, NodeError> {
+        self.check_node_bytes()?;
+        self.record_access(position);
+
+        let contents = self.traced_read(Operation::ReadNode, Some(position), |tree| {
+            let slot = tree.resolve(position)?;
+            match tree.read_slot(slot) {
+                Err(NodeError::Corrupt {
+                    position: None,
+                    offset,
+                    detail,
+                }) => Err(NodeError::Corrupt {
+                    position: Some(position),
+                    offset,
+                    detail,
+                }),
+                result => result,
+            }
+        })?;
 
-        let bit_buffer: Vec<bool> = utils::bytes_to_bits(&byte_buffer);
+        match contents.enabled {
+            true => Ok(contents),
+            false => Err(NodeError::Disabled),
+        }
+    }
 
-        let mut bits: Vec<bool> =
-            bit_buffer[(pad_l as usize)..((pad_l + node_size) as usize)].to_vec();
+    /// Set a node by its tranversal position. If `overwrite` is false, the
+    /// function will return an error if the node already exists. If the node
+    /// is unexistent, it will be created. This will also add all the
+    /// (disabled) nodes needed to set a node in this position.
+    ///
+    /// Persistent trees keep the node's previous contents and record the
+    /// write as a new version instead.
+    ///
+    /// The returned node is built from the written subitems, without reading
+    /// it back from the tree file, except in trees with the attribution
+    /// feature, whose last two subitems are filled in by the write. Trees
+    /// with child hints read only the feature headers of the node back, to
+    /// find its hints. To check the written bytes against the storage, set
+    /// [`WriteVerification::ReadBack`], which fails the write if they don't
+    /// match.
+    ///
+    /// Setting a disabled node fails with
+    /// [`Disabled`](NodeError::Disabled) after writing it, as there's no
+    /// enabled node to return, in trees with the disabling feature. Trees
+    /// without it don't store whether nodes are enabled, so the node is
+    /// written enabled and returned.
+    pub fn set_node(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: &u128,
+        overwrite: bool,
+        disabled: bool,
+    ) -> Result<Node<'_>, NodeError> {
+        self.set_node_quiet(subitems, position, overwrite, disabled)?;
 
-        if self.features.contains(&Feature::Disabling) {
-            if bits[0] == false {
-                return Err(NodeError::Disabled);
-            };
+        if disabled && self.features.contains(&Feature::Disabling) {
+            return Err(NodeError::Disabled);
+        };
 
-            bits.remove(0);
+        if self.features.contains(&Feature::Attribution) {
+            return self.node(*position);
         };
 
-        let mut subitems: Vec<Vec<bool>> = vec![];
-        for subitem in &self.subitems {
-            subitems.push(bits[0..*subitem as usize].to_vec());
-            bits.drain(0..*subitem as usize);
-        }
+        let hints = match self.features.contains(&Feature::ChildHints) {
+            true => Some(self.read_slot_header(self.resolve(*position)?)?.hints),
+            false => None,
+        };
 
         Ok(Node {
             tree: self,
-            position,
-            subitems,
+            position: *position,
+            subitems: subitems.to_vec(),
+            hints,
         })
     }
 
-    /// Set a node by its tranversal position. If `overwrite` is false, the
-    /// function will return an error if the node already exists. If the node
-    /// is unexistent, it will be created. This will also add all the
-    /// (disabled) nodes needed to set a node in this position.
-    pub fn set_node(
+    /// Set a node like [`set_node`](Tree::set_node), without returning it.
+    pub fn set_node_quiet(
         &mut self,
-        subitems: &Vec<Vec<bool>>,
+        subitems: &[Vec<bool>],
         position: &u128,
         overwrite: bool,
         disabled: bool,
-    ) -> Result<Node, NodeError> {
-        let mut bits: Vec<bool> = vec![];
+    ) -> Result<(), NodeError> {
+        self.record_access(*position);
+
+        // Trees without the disabling feature don't store whether nodes are
+        // enabled, so every node written is enabled.
+        let disabled = disabled && self.features.contains(&Feature::Disabling);
+
+        let result = self.traced(Operation::WriteNode, Some(*position), |tree| {
+            if subitems.len() != tree.subitems.len() {
+                return Err(NodeError::SubitemCountMismatch {
+                    expected: tree.subitems.len(),
+                    got: subitems.len(),
+                });
+            };
 
-        if self.features.contains(&Feature::Disabling) {
-            bits.push(!disabled);
+            for (i, subitem) in tree.subitems.iter().enumerate() {
+                if subitems[i].len() != *subitem as usize {
+                    return Err(NodeError::InvalidSubitem);
+                };
+            }
+
+            if !overwrite && tree.has_enabled_node(*position) {
+                return Err(NodeError::NodeAlreadyExists);
+            };
+
+            tree.check_gap(*position)?;
+
+            let subitems = tree.stamped(subitems);
+            tree.validate_node(*position, &subitems)?;
+
+            tree.write_with_hooks(&subitems, *position, disabled)
+        });
+        self.record_set_node(subitems, *position, overwrite, disabled, result.is_ok());
+
+        result
+    }
+
+    /// Write a node and update the data kept about it, without checking it.
+    pub(crate) fn write_node(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<(), NodeError> {
+        // Checked before anything is written, as persistent trees store deep
+        // positions the occupancy bitmap can't mark.
+        if self.occupancy.is_some() && u64::try_from(position / 8).is_err() {
+            return Err(NodeError::OffsetOverflow);
+        };
+        self.check_leaf_entry(position)?;
+        self.check_lease()?;
+        if self.prepared {
+            return Err(NodeError::InvalidTransactionState);
+        };
+
+        let was_enabled = self.enabled_before_write(position)?;
+
+        if self.features.contains(&Feature::Persistent) {
+            self.set_node_persistent(subitems, position, disabled)?;
+        } else {
+            let slot = match self.layout.slot(position) {
+                Some(slot) => slot,
+                None => return Err(NodeError::OutsideLayout),
+            };
+            self.fill_gap(slot)?;
+
+            let mut contents = Slot {
+                enabled: !disabled,
+                children: [None, None],
+                hints: [false, false],
+                subitems: subitems.to_vec(),
+            };
+            if self.features.contains(&Feature::ChildHints) && slot < self.nodes() as u128 {
+                contents.hints = self.read_slot(slot)?.hints;
+            };
+            self.write_slot(slot, &contents)?;
+
+            if self.features.contains(&Feature::ChildHints) && position > 0 {
+                self.set_child_hint(position, !disabled)?;
+            };
+        }
+
+        self.mark_occupancy(position, !disabled)?;
+        self.update_level_stats(position, was_enabled, !disabled)?;
+        self.update_leaf_counts(position, was_enabled, !disabled)?;
+        self.update_merkle(position)?;
+        self.update_indexes(position)?;
+        self.retain_value(subitems, position, disabled)
+    }
+
+    /// Record in the parent of a flat tree's node whether the node is
+    /// enabled.
+    fn set_child_hint(&mut self, position: u128, enabled: bool) -> Result<(), NodeError> {
+        let parent = positions::parent(position);
+        let index = (position - 1) % 2;
+
+        // Parents are always less deep than their children, so they fit in
+        // the layout.
+        let parent = self.layout.slot(parent).unwrap_or(parent);
+
+        if parent >= self.nodes() as u128 {
+            // Missing nodes are disabled and have no enabled children.
+            if !enabled {
+                return Ok(());
+            };
+
+            let contents = Slot {
+                enabled: false,
+                children: [None, None],
+                hints: [index == 0, index == 1],
+                subitems: self
+                    .subitems
+                    .iter()
+                    .map(|size| vec![false; *size as usize])
+                    .collect(),
+            };
+            return self.write_slot(parent, &contents);
+        };
+
+        let mut contents = self.read_slot(parent)?;
+        if contents.hints[index as usize] != enabled {
+            contents.hints[index as usize] = enabled;
+            self.write_slot(parent, &contents)?;
         };
 
-        let mut i: usize = 0;
-        for subitem in &self.subitems {
-            if subitems[i].len() != *subitem as usize {
-                return Err(NodeError::InvalidSubitem);
+        Ok(())
+    }
+
+    /// Map a tranversal position to the storage slot holding it.
+    fn resolve(&self, position: u128) -> Result<u128, NodeError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return match self.layout.slot(position) {
+                Some(slot) => Ok(slot),
+                None => Err(NodeError::Unexistent),
+            };
+        };
+
+        let mut slot = match self.root_slot()? {
+            Some(slot) => slot,
+            None => return Err(NodeError::Unexistent),
+        };
+
+        for index in positions::path(position) {
+            slot = match self.read_slot(slot)?.children[index as usize] {
+                Some(child) => child,
+                None => return Err(NodeError::Unexistent),
             };
-            i += 1;
         }
 
-        bits.extend(subitems.concat());
+        Ok(slot)
+    }
 
-        if !overwrite {
-            match self.node(*position) {
-                Ok(_) => return Err(NodeError::NodeAlreadyExists),
-                Err(_) => (),
+    /// Every node stored for the version being read, disabled ones
+    /// included, sorted by position. Flat trees store every position before
+    /// their last node.
+    pub(crate) fn stored_nodes(&self) -> Result<Vec<NodeData>, NodeError> {
+        let mut nodes = vec![];
+
+        if !self.features.contains(&Feature::Persistent) {
+            let slots = self.nodes() as u128;
+            let positions = match self.layout.position_limit() {
+                Some(limit) => 0..limit,
+                None => 0..slots,
             };
+
+            for position in positions {
+                let contents = match self.resolve(position) {
+                    Ok(slot) if slot < slots => self.read_slot(slot)?,
+                    _ => continue,
+                };
+                nodes.push(NodeData {
+                    position,
+                    enabled: contents.enabled,
+                    subitems: contents.subitems,
+                });
+            }
+
+            return Ok(nodes);
         };
 
-        let node_size = self.node_size() as u128;
-        let nodes = self.nodes() as u128;
-        if nodes < *position {
-            // Must add empty (0s?) nodes before the position
-            let _ = self.file.seek(SeekFrom::End(0_i64));
-            let _ = self.file.write(&vec![
-                0_u8;
-                ((nodes - position) * node_size).div_ceil(8) as usize
-            ]);
+        let mut pending = match self.root_slot()? {
+            Some(slot) => vec![(0, slot)],
+            None => vec![],
         };
+        while let Some((position, slot)) = pending.pop() {
+            let contents = self.read_slot(slot)?;
+
+            // Children are always written before their parents, so following
+            // a forward pointer could loop forever.
+            for (index, child) in contents.children.iter().enumerate() {
+                match child {
+                    Some(child) if *child < slot => match positions::child(position, index as u8) {
+                        Some(child_position) => pending.push((child_position, *child)),
+                        None => return Err(NodeError::Unexistent),
+                    },
+                    Some(_) => return Err(NodeError::Unexistent),
+                    None => (),
+                };
+            }
 
-        let start_byte = self.header_size as u128 + (position * node_size) / 8;
-        let pad_l = (position * node_size) % 8;
-        let buf_size = (pad_l + node_size).div_ceil(8);
+            nodes.push(NodeData {
+                position,
+                enabled: contents.enabled,
+                subitems: contents.subitems,
+            });
+        }
+        nodes.sort_unstable_by_key(|node| node.position);
 
-        let mut byte_buffer = vec![0_u8; buf_size as usize];
+        Ok(nodes)
+    }
 
-        self.file.seek(SeekFrom::Start(start_byte as u64)).unwrap();
-        match self.file.read_exact(&mut byte_buffer) {
-            Ok(_) => (),
-            Err(_) => {
-                self.file
-                    .seek(SeekFrom::Start(
-                        (self.header_size as u128 + ((position * node_size as u128) / 8)) as u64,
-                    ))
-                    .unwrap();
-
-                // Read only first byte to get the padding (and to avoid corrupting the previous node).
-                byte_buffer = vec![0_u8];
-                let _ = self.file.read_exact(&mut byte_buffer);
+    /// Read and decode the contents of a storage slot.
+    fn read_slot(&self, slot: u128) -> Result<Slot, NodeError> {
+        let contents = self.read_decoded_slot(slot, self.node_size())?;
+        if contents.subitems.len() < self.subitems.len() {
+            return Err(self.corruption(slot, 0, "the slot is shorter than its subitems"));
+        };
+
+        Ok(contents)
+    }
+
+    /// Read and decode only the feature headers of a storage slot, leaving
+    /// its subitems empty.
+    pub(crate) fn read_slot_header(&self, slot: u128) -> Result<Slot, NodeError> {
+        self.read_decoded_slot(slot, self.node_header_size())
+    }
+
+    /// Read and decode the first `size` bits of a storage slot, checking
+    /// that its child pointers point to stored slots.
+    fn read_decoded_slot(&self, slot: u128, size: u32) -> Result<Slot, NodeError> {
+        let bits = self.read_slot_bits(slot, size)?;
+        let contents = match self.decode_slot(&bits) {
+            Ok(contents) => contents,
+            Err(detail) => return Err(self.corruption(slot, 0, detail)),
+        };
+
+        if self.features.contains(&Feature::Persistent) {
+            let slots = self.nodes() as u128;
+            let first = self.features.contains(&Feature::Disabling) as u32;
+            for (index, child) in contents.children.iter().enumerate() {
+                if child.is_some_and(|child| child >= slots) {
+                    return Err(self.corruption(
+                        slot,
+                        first + index as u32 * POINTER_SIZE,
+                        "a child pointer is past the last slot",
+                    ));
+                };
             }
         };
 
-        let pad_l_bits = utils::bytes_to_bits(&byte_buffer)[..(pad_l as usize)].to_vec();
-        let pad_r_bits =
-            utils::bytes_to_bits(&byte_buffer)[((pad_l + node_size) % 8) as usize..].to_vec();
+        Ok(contents)
+    }
+
+    /// A [`Corrupt`](NodeError::Corrupt) error for the bits `bit` bits into
+    /// a storage slot.
+    fn corruption(&self, slot: u128, bit: u32, detail: &'static str) -> NodeError {
+        let offset = self.slot_bit_offset(slot, bit);
+
+        // Only slots of trees laid out in level order are at their position.
+        let position = match (self.features.contains(&Feature::Persistent), self.layout) {
+            (false, Layout::LevelOrder | Layout::Columnar { .. }) => Some(slot),
+            _ => None,
+        };
+
+        NodeError::Corrupt {
+            position,
+            offset: (self.header_size as u128 + offset / 8) as u64,
+            detail,
+        }
+    }
+
+    /// Read the first `size` bits of a storage slot.
+    fn read_slot_bits(&self, slot: u128, size: u32) -> Result<Vec<bool>, NodeError> {
+        if slot >= self.nodes() as u128 {
+            return Err(NodeError::Unexistent);
+        };
+
+        let mut bits = vec![];
+        for (offset, width) in self.slot_spans(slot) {
+            let len = width.min(size - bits.len() as u32);
+            if len == 0 {
+                break;
+            };
+            bits.extend(self.read_bits(offset, len)?);
+        }
+
+        Ok(bits)
+    }
 
-        let fragment_bits: Vec<bool> = vec![pad_l_bits, bits, pad_r_bits].concat();
+    /// Read `len` bits starting `offset` bits after the file headers.
+    pub(crate) fn read_bits(&self, offset: u128, len: u32) -> Result<Vec<bool>, NodeError> {
+        self.check_offset(offset.saturating_add(len as u128))?;
+        let start_byte = self.header_size as u128 + offset / 8;
+        let pad_l = offset % 8;
+        let buf_size = (pad_l + len as u128).div_ceil(8);
 
-        match self.file.seek(SeekFrom::Start(start_byte as u64)) {
+        let mut byte_buffer = vec![0_u8; buf_size as usize];
+
+        match self.read_bytes(start_byte as u64, &mut byte_buffer) {
             Ok(_) => (),
             Err(_) => return Err(NodeError::Unexistent),
         };
-        match self.file.write(&utils::bits_to_bytes(&fragment_bits)) {
-            Ok(_) => (),
+
+        Ok(self
+            .bit_order
+            .unpack_bits_at(&byte_buffer, pad_l as usize, len as usize))
+    }
+
+    /// Encode and write the contents of a storage slot. Writing past the end
+    /// of the file fills the gap with zeros (disabled nodes).
+    fn write_slot(&mut self, slot: u128, contents: &Slot) -> Result<(), NodeError> {
+        let bits = self.encode_slot(contents)?;
+
+        let mut written = 0;
+        for (offset, width) in self.slot_spans(slot) {
+            self.write_bits(offset, &bits[written..written + width as usize])?;
+            written += width as usize;
+        }
+        self.io().logical_bits_written += bits.len() as u64;
+
+        Ok(())
+    }
+
+    /// Write bits starting `offset` bits after the file headers.
+    pub(crate) fn write_bits(&mut self, offset: u128, bits: &[bool]) -> Result<(), NodeError> {
+        let len = bits.len() as u128;
+        if len == 0 {
+            return Ok(());
+        };
+
+        self.check_offset(offset.saturating_add(len))?;
+        let start_byte = self.header_size as u128 + offset / 8;
+        let pad_l = offset % 8;
+        let buf_size = (pad_l + len).div_ceil(8);
+
+        // Keep the bits of the neighbouring nodes that share the first and
+        // last bytes. Only those two bytes are read, as every other byte is
+        // overwritten. Whatever lies past the end of the file reads as zeros.
+        let last = buf_size as usize - 1;
+        let partial_last = !(pad_l + len).is_multiple_of(8);
+        let partial_first = pad_l != 0 || (last == 0 && partial_last);
+
+        let boundary = Arc::clone(&self.boundary);
+        let _guard = match partial_first || partial_last {
+            true => Some(boundary.lock().unwrap_or_else(|error| error.into_inner())),
+            false => None,
+        };
+        let stored_size = match self.storage.size() {
+            Ok(size) => size,
             Err(_) => return Err(NodeError::Unexistent),
         };
 
-        self.node(*position)
+        let mut byte_buffer = vec![0_u8; buf_size as usize];
+        for (index, partial) in [(0, partial_first), (last, partial_last && last != 0)] {
+            let offset = start_byte as u64 + index as u64;
+            if !partial || offset >= stored_size {
+                continue;
+            };
+
+            match self.read_bytes(offset, &mut byte_buffer[index..index + 1]) {
+                Ok(_) => (),
+                Err(_) => return Err(NodeError::Unexistent),
+            };
+        }
+
+        self.bit_order
+            .pack_bits_at(&mut byte_buffer, pad_l as usize, bits);
+
+        if self.write_bytes(start_byte as u64, &byte_buffer).is_err() {
+            return Err(NodeError::Unexistent);
+        };
+
+        self.verify_written(start_byte as u64, &byte_buffer)
+    }
+
+    /// Fail if the bits up to `end` bits after the file headers are past the
+    /// largest offset of the storage, which the casts of the offsets to `u64`
+    /// would otherwise wrap around.
+    fn check_offset(&self, end: u128) -> Result<(), NodeError> {
+        match (self.header_size as u128).saturating_add(end.div_ceil(8)) <= u64::MAX as u128 {
+            true => Ok(()),
+            false => Err(NodeError::OffsetOverflow),
+        }
+    }
+
+    /// Read bytes from the storage.
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.cache().prefetching() {
+            let received = self.cache_mut().receive();
+            self.io().bytes_read += received;
+        };
+        if self.cache().read(offset, buf) {
+            return Ok(());
+        };
+
+        // Trees sharing their storage with other handles through sequence
+        // locks never cache what they read.
+        if self.memory_mode == MemoryMode::Aggressive
+            && self.sequences.is_none()
+            && self.read_through_cache(offset, buf)?
+        {
+            return Ok(());
+        };
+
+        match &self.sequences {
+            Some(sequences) => {
+                let len = buf.len();
+                sequences.read(offset, len, || self.storage.read_at(offset, buf))?
+            }
+            None => self.storage.read_at(offset, buf)?,
+        };
+        self.io().bytes_read += buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Write bytes to the storage.
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.throttle(buf.len() as u64);
+        match &self.sequences {
+            Some(sequences) => {
+                sequences.write(offset, buf.len(), || self.storage.write_at(offset, buf))?
+            }
+            None => self.storage.write_at(offset, buf)?,
+        };
+        self.mark_revisions(offset, buf.len())?;
+        self.cache_mut().write(offset, buf);
+        self.io().bytes_written += buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Truncate or extend (with zeros) the storage.
+    pub(crate) fn set_storage_size(&self, size: u64) -> io::Result<()> {
+        self.storage.set_size(size)?;
+        self.cache_mut().truncate(size);
+        self.truncate_revisions(size)
+    }
+
+    /// The offset in bits after the file headers of the bit `bit` bits into
+    /// a storage slot.
+    pub(crate) fn slot_bit_offset(&self, slot: u128, bit: u32) -> u128 {
+        let mut bit = bit as u128;
+        for (start, width) in self.slot_spans(slot) {
+            if bit < width as u128 {
+                return start + bit;
+            };
+            bit -= width as u128;
+        }
+
+        0
+    }
+
+    /// Split a slot's bits into its feature headers and subitems. Subitems
+    /// that aren't in `bits` are left out.
+    fn decode_slot(&self, bits: &[bool]) -> Result<Slot, &'static str> {
+        crate::core::decode_slot(&self.features, &self.subitems, bits)
+    }
+
+    /// Join a slot's feature headers and subitems into its stored bits.
+    fn encode_slot(&self, contents: &Slot) -> Result<Vec<bool>, NodeError> {
+        let mut bits: Vec<bool> = vec![];
+
+        if self.features.contains(&Feature::Disabling) {
+            bits.push(contents.enabled);
+        };
+
+        if self.features.contains(&Feature::Persistent) {
+            for child in contents.children {
+                let pointer = match child {
+                    Some(slot) => slot + 1,
+                    None => 0,
+                };
+                if pointer >= 1 << POINTER_SIZE {
+                    return Err(NodeError::SlotLimitReached);
+                };
+                bits.extend(bitcodec::u64_to_bits(pointer as u64, POINTER_SIZE));
+            }
+        };
+
+        if self.features.contains(&Feature::ChildHints) {
+            bits.extend(contents.hints);
+        };
+
+        bits.extend(contents.subitems.concat());
+
+        Ok(bits)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Tree {
+    /// Flush the changes of trees that weren't closed, reporting failures to
+    /// the trace hook.
+    fn drop(&mut self) {
+        if self.closed || self.mode != TreeOpenMode::ReadWrite {
+            return;
+        };
+
+        if let Err(error) = self.finish() {
+            self.report(TraceEvent::CloseFailed(error));
+        };
     }
 }
 
+#[cfg(feature = "std")]
 impl Node<'_> {
     /// Get the level (depth) of the node.
     pub fn level(&self) -> u32 {
-        if self.position != 0 {
-            self.position.ilog2()
-        } else {
-            0
-        }
+        positions::level(self.position)
     }
 
     /// Get the parent of the node.
-    pub fn parent(&mut self) -> Result<Node, NodeError> {
+    pub fn parent(&mut self) -> Result<Node<'_>, NodeError> {
         if self.position == 0 {
             return Err(NodeError::Unexistent);
         };
 
-        self.tree.node((self.position - 1) / 2)
+        self.tree.node(positions::parent(self.position))
     }
 
     /// Get a child of the node. Index 0 is the left child, index 1 is the
-    /// right child.
-    pub fn child(&mut self, index: u8) -> Result<Node, NodeError> {
+    /// right child. Trees with child hints don't read a child that isn't
+    /// enabled, and return [`NodeError::Unexistent`] instead.
+    pub fn child(&mut self, index: u8) -> Result<Node<'_>, NodeError> {
         if index > 1 {
             return Err(NodeError::InvalidIndex);
         }
 
-        if self.position == 0 {
-            self.tree.node(1 + index as u128)
-        } else {
-            self.tree.node(self.position * 2 + index as u128)
+        if let Some(hints) = self.hints {
+            if !hints[index as usize] {
+                return Err(NodeError::Unexistent);
+            };
+        };
+
+        match positions::child(self.position, index) {
+            Some(child) => self.tree.node(child),
+            None => Err(NodeError::Unexistent),
         }
     }
 
     /// Check if the node is a leaf (hasn't got any children).
     pub fn is_leaf(&mut self) -> bool {
-        self.child(0).is_err() && self.child(1).is_err()
+        if let Some(hints) = self.hints {
+            return !hints.contains(&true);
+        };
+
+        let first_child = match positions::child(self.position, 0) {
+            Some(first_child) => first_child,
+            None => return true,
+        };
+
+        match self
+            .tree
+            .occupancy(first_child..first_child.saturating_add(2))
+        {
+            Ok(children) => !children.contains(&true),
+            Err(_) => true,
+        }
     }
 
     /// Add a child to the node.
@@ -450,22 +1834,17 @@ impl Node<'_> {
         index: u8,
         subitems: Vec<Vec<bool>>,
         overwrite: bool,
-    ) -> Result<Node, NodeError> {
+    ) -> Result<Node<'_>, NodeError> {
         if index > 1 {
             return Err(NodeError::InvalidIndex);
         }
 
-        if self.position == 0 {
-            self.tree
-                .set_node(&subitems, &(1 + index as u128), overwrite, true)
-        } else {
-            self.tree.set_node(
-                &subitems,
-                &(self.position * 2 + index as u128),
-                overwrite,
-                true,
-            )
-        }
+        let child = match positions::child(self.position, index) {
+            Some(child) => child,
+            None => return Err(NodeError::Unexistent),
+        };
+
+        self.tree.set_node(&subitems, &child, overwrite, true)
     }
 
     /// Disables the node.
@@ -474,11 +1853,8 @@ impl Node<'_> {
             return Err(NodeError::MissingFeature);
         };
 
-        let _ = self
-            .tree
-            .set_node(&self.subitems, &self.position, true, true);
-
-        Ok(())
+        self.tree
+            .set_node_quiet(&self.subitems, &self.position, true, true)
     }
 
     /// Enables the node.
@@ -487,30 +1863,30 @@ impl Node<'_> {
             return Err(NodeError::MissingFeature);
         };
 
-        let _ = self
-            .tree
-            .set_node(&self.subitems, &self.position, true, false);
-
-        Ok(())
+        self.tree
+            .set_node_quiet(&self.subitems, &self.position, true, false)
     }
 
-    /// Update the node's subitems.
+    /// Update the node's subitems. They're kept unchanged if the write fails,
+    /// e.g. because the validator rejected them.
     pub fn update(&mut self, subitems: Vec<Vec<bool>>) -> Result<(), NodeError> {
-        let _ = self.tree.set_node(&subitems, &self.position, false, false);
+        self.tree
+            .set_node_quiet(&subitems, &self.position, true, false)?;
         self.subitems = subitems;
 
         Ok(())
     }
 
     /// Refresh the node's data from the tree file.
-    pub fn refresh(&mut self) -> Result<Node, NodeError> {
+    pub fn refresh(&mut self) -> Result<Node<'_>, NodeError> {
         let node = match self.tree.node(self.position) {
             Ok(node) => node,
             Err(_) => return Err(NodeError::Unexistent),
         };
 
-        self.position = node.position.clone();
+        self.position = node.position;
         self.subitems = node.subitems.clone();
+        self.hints = node.hints;
 
         Ok(node)
     }
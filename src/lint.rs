@@ -0,0 +1,124 @@
+//! Warnings about schemas and tree files that work, but store or read much
+//! more than they need to.
+
+use crate::{analyze_schema, Feature, Finding, IntegrityReport, Severity, Tree, TreeFileError};
+
+/// The amount of subitems above which the nodes are flagged.
+const LINT_MAX_SUBITEMS: usize = 32;
+
+/// The amount of bits of neighbouring nodes read along with each node above
+/// which unaligned nodes are a warning, instead of info.
+const LINT_MAX_WASTED_BITS: f64 = 8.0;
+
+/// The amount of levels from which a sparse tree is flagged.
+const LINT_DEEP_LEVELS: u32 = 24;
+
+/// The share of slots holding enabled nodes below which a deep tree is
+/// sparse.
+const LINT_SPARSE_RATIO: f64 = 0.01;
+
+/// The amount of bytes of the tree file read at once to count the enabled
+/// nodes.
+const LINT_SCAN_CHUNK: usize = 1 << 16;
+
+/// The share of disabled slots above which the tree is flagged.
+const LINT_MAX_DISABLED_RATIO: f64 = 0.5;
+
+impl Tree {
+    /// Look for conditions that make the tree slower or bigger than it has
+    /// to be: nodes that straddle bytes badly, too many subitems, deep and
+    /// sparse trees, and a high share of disabled nodes. Each finding
+    /// explains the problem and how to fix it. Every stored node is read.
+    pub fn lint(&self) -> Result<IntegrityReport, TreeFileError> {
+        let mut report = IntegrityReport::default();
+        let mut flag = |severity, description: String, fix: &str| {
+            report.findings.push(Finding {
+                severity,
+                position: None,
+                offset: None,
+                description,
+                fix: Some(fix.to_string()),
+            });
+        };
+
+        let schema = match analyze_schema(&self.subitems, &self.features) {
+            Ok(schema) => schema,
+            Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+        };
+        if !schema.aligned {
+            let severity = match schema.wasted_bits_per_slot > LINT_MAX_WASTED_BITS {
+                true => Severity::Warning,
+                false => Severity::Info,
+            };
+            flag(
+                severity,
+                format!(
+                    "The nodes are {} bits, so every node read or written also touches {:.1} bits of its neighbours on average.",
+                    schema.node_size, schema.wasted_bits_per_slot
+                ),
+                &format!(
+                    "Realign the nodes to byte boundaries with a padding subitem of {} bits (see analyze_schema and add_subitem).",
+                    schema.suggested_subitems[schema.suggested_subitems.len() - 1]
+                ),
+            );
+        };
+
+        if self.subitems.len() > LINT_MAX_SUBITEMS {
+            flag(
+                Severity::Warning,
+                format!(
+                    "The nodes have {} subitems, and every one of them is decoded on each read.",
+                    self.subitems.len()
+                ),
+                "Merge related subitems, or drop the obsolete ones with drop_subitem.",
+            );
+        };
+
+        // The slots of persistent trees hold every version, so their share
+        // of disabled slots says nothing about the tree.
+        if self.features.contains(&Feature::Persistent) {
+            return Ok(report);
+        };
+
+        let slots = self.nodes();
+        if slots == 0 {
+            return Ok(report);
+        };
+
+        let mut enabled: u64 = 0;
+        let scanned = self.scan(LINT_SCAN_CHUNK, |slots| {
+            enabled += slots.iter().filter(|slot| slot.enabled).count() as u64;
+            true
+        });
+        if scanned.is_err() {
+            return Err(TreeFileError::Corrupted);
+        };
+
+        let levels = self.levels() + 1;
+        let live = enabled as f64 / slots as f64;
+        if levels >= LINT_DEEP_LEVELS && live < LINT_SPARSE_RATIO {
+            flag(
+                Severity::Warning,
+                format!(
+                    "The tree is {} levels deep, but only {:.3}% of its {} slots hold a node.",
+                    levels,
+                    live * 100.0,
+                    slots
+                ),
+                "Recreate the tree with the persistent feature, which only stores the nodes written, or keep the deep subtrees in trees of their own.",
+            );
+        } else if 1.0 - live > LINT_MAX_DISABLED_RATIO {
+            flag(
+                Severity::Warning,
+                format!(
+                    "{:.1}% of the {} slots hold disabled nodes.",
+                    (1.0 - live) * 100.0,
+                    slots
+                ),
+                "Compact the tree by rebuilding it with its subtrees closer to the root, or convert it to the persistent feature, which only stores the nodes written.",
+            );
+        };
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,546 @@
+//! Indexes of the values of subitems, to find the nodes holding a value (or
+//! a range of values) without reading every node.
+//!
+//! The indexes are kept in a log next to the tree file (with the `.index`
+//! extension), replayed when the tree is opened. Each entry is the id of
+//! the index (4 bytes), the kind of entry (1 byte), the position (16 bytes)
+//! and the key of the node, its subitems packed into bytes one after the
+//! other. The entry creating an index holds the amount of subitems of its
+//! key instead of a position, followed by each of them (4 bytes each).
+
+use crate::{
+    bitcodec, sidecar_path, GapFill, NodeError, Storage, Tree, TreeFileError, TreeOpenMode,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::ops::RangeBounds;
+
+/// The size in bytes of each entry of the log, before its key.
+const INDEX_ENTRY_HEADER_SIZE: usize = 21;
+
+/// The size in bytes below which the log is never compacted.
+const INDEX_COMPACT_MIN_SIZE: u64 = 1 << 20;
+
+/// The kinds of entries of the log.
+const ENTRY_REMOVED: u8 = 0;
+const ENTRY_SET: u8 = 1;
+const ENTRY_CREATED: u8 = 2;
+const ENTRY_DROPPED: u8 = 3;
+
+/// The values of some subitems of a node, in the order of the index.
+type IndexKey = Vec<Vec<bool>>;
+
+/// The positions of the enabled nodes holding each key of an index, sorted
+/// by key.
+#[derive(Debug, Default)]
+pub(crate) struct SubitemIndex {
+    /// The id of the index in the log.
+    id: u32,
+    keys: BTreeMap<IndexKey, BTreeSet<u128>>,
+    positions: HashMap<u128, IndexKey>,
+}
+
+impl SubitemIndex {
+    /// Record the key of the node at `position`, or that the position no
+    /// longer holds an enabled node. Returns whether it changed.
+    fn set(&mut self, position: u128, key: Option<IndexKey>) -> bool {
+        if self.positions.get(&position) == key.as_ref() {
+            return false;
+        };
+
+        if let Some(old) = self.positions.remove(&position) {
+            if let Some(positions) = self.keys.get_mut(&old) {
+                positions.remove(&position);
+                if positions.is_empty() {
+                    self.keys.remove(&old);
+                };
+            };
+        };
+
+        if let Some(key) = key {
+            self.keys.entry(key.clone()).or_default().insert(position);
+            self.positions.insert(position, key);
+        };
+
+        true
+    }
+}
+
+impl Tree {
+    /// The positions of the enabled nodes whose subitem at `index` is
+    /// `value`, in ascending order.
+    ///
+    /// Looked up directly when the subitem is indexed (see
+    /// [`create_subitem_index`](Tree::create_subitem_index)), or is the
+    /// first subitem of an index, or found by reading the subitem of every
+    /// node otherwise.
+    pub fn lookup_by_subitem(&self, index: usize, value: &[bool]) -> Result<Vec<u128>, NodeError> {
+        match self.subitems.get(index) {
+            Some(size) if *size as usize == value.len() => (),
+            Some(_) => return Err(NodeError::InvalidSubitem),
+            None => return Err(NodeError::InvalidIndex),
+        };
+
+        let key = vec![value.to_vec()];
+        let mut found = match self.indexed_between(index, value, value) {
+            Some((found, _)) => found,
+            None => self.scan_range(&[index], key.clone()..=key)?,
+        };
+        found.sort_unstable();
+
+        Ok(found)
+    }
+
+    /// The positions of the enabled nodes whose subitems at `subitems`, one
+    /// after the other, fall in `range`, sorted by those subitems and then
+    /// by position. Keys shorter than `subitems` compare before every key
+    /// they're a prefix of, so `vec![a]..vec![b]` selects the nodes whose
+    /// first subitem is from `a` up to `b`.
+    ///
+    /// Looked up directly when `subitems` are indexed (see
+    /// [`create_index`](Tree::create_index)), or found by reading those
+    /// subitems of every node otherwise.
+    pub fn lookup_range(
+        &self,
+        subitems: &[usize],
+        range: impl RangeBounds<IndexKey>,
+    ) -> Result<Vec<u128>, NodeError> {
+        if subitems.is_empty() || subitems.iter().any(|index| *index >= self.subitems.len()) {
+            return Err(NodeError::InvalidIndex);
+        };
+
+        match (self.indexes.get(subitems), self.version) {
+            (Some(subitem_index), None) => Ok(subitem_index
+                .keys
+                .range(range)
+                .flat_map(|(_, positions)| positions.iter().copied())
+                .collect()),
+            _ => self.scan_range(subitems, range),
+        }
+    }
+
+    /// Index the values of the subitem at `index`, like
+    /// [`create_index`](Tree::create_index) with only that subitem.
+    pub fn create_subitem_index(&mut self, index: usize) -> Result<(), TreeFileError> {
+        self.create_index(&[index])
+    }
+
+    /// Drop the index of the subitem at `index`.
+    pub fn drop_subitem_index(&mut self, index: usize) -> Result<(), TreeFileError> {
+        self.drop_index(&[index])
+    }
+
+    /// Index the nodes by the values of the subitems at `subitems`, compared
+    /// in that order, from the stored nodes of the latest version. The index
+    /// is kept next to the tree file, updated on every write, until it's
+    /// dropped. Indexes are kept in memory while the tree is open; the
+    /// entries of the log are sorted by key when it's compacted.
+    ///
+    /// Trees with a gap fill other than zeros can't index their subitems, as
+    /// the filled slots aren't indexed.
+    pub fn create_index(&mut self, subitems: &[usize]) -> Result<(), TreeFileError> {
+        self.check_indexes()?;
+
+        if subitems.is_empty() || subitems.iter().any(|index| *index >= self.subitems.len()) {
+            return Err(TreeFileError::UnexistentSubitem);
+        };
+        if self.indexes.contains_key(subitems) {
+            return Ok(());
+        };
+
+        let keys = match self.read_keys(subitems) {
+            Ok(keys) => keys,
+            Err(_) => return Err(TreeFileError::Corrupted),
+        };
+
+        let mut subitem_index = SubitemIndex {
+            id: self
+                .indexes
+                .values()
+                .map(|subitem_index| subitem_index.id + 1)
+                .max()
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut entries = encode_created(subitem_index.id, subitems);
+        for (position, key) in keys {
+            entries.extend(encode_entry(
+                subitem_index.id,
+                ENTRY_SET,
+                position,
+                Some(&key),
+            ));
+            subitem_index.set(position, Some(key));
+        }
+
+        if self.index_log.is_none() {
+            self.index_log = Some(self.open_sidecar("index", true)?);
+        };
+        if self.append_index_entries(&entries).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.indexes.insert(subitems.to_vec(), subitem_index);
+
+        self.sync()
+    }
+
+    /// Drop the index of the subitems at `subitems`. The log is removed with
+    /// the last index.
+    pub fn drop_index(&mut self, subitems: &[usize]) -> Result<(), TreeFileError> {
+        self.check_indexes()?;
+
+        let id = match self.indexes.remove(subitems) {
+            Some(subitem_index) => subitem_index.id,
+            None => return Ok(()),
+        };
+
+        if self.indexes.is_empty() {
+            self.index_log = None;
+            let _ = fs::remove_file(sidecar_path(&self.path, "index"));
+            return Ok(());
+        };
+
+        if self
+            .append_index_entries(&encode_entry(id, ENTRY_DROPPED, 0, None))
+            .is_err()
+        {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        self.sync()
+    }
+
+    /// The subitems of each index, in the order they're compared. Indexes
+    /// are sorted by their subitems.
+    pub fn indexes(&self) -> Vec<Vec<usize>> {
+        self.indexes.keys().cloned().collect()
+    }
+
+    /// The positions of the enabled nodes whose subitem at `index` is from
+    /// `low` up to `high`, and the amount of nodes indexed, if an index
+    /// starting with the subitem holds the latest version. Any such index
+    /// keeps the nodes with each value of the subitem together, from the
+    /// shortest key starting with it.
+    pub(crate) fn indexed_between(
+        &self,
+        index: usize,
+        low: &[bool],
+        high: &[bool],
+    ) -> Option<(Vec<u128>, u64)> {
+        let (subitems, subitem_index) = self.indexes.range(vec![index]..).next()?;
+        if subitems[0] != index || self.version.is_some() {
+            return None;
+        };
+
+        let found = subitem_index
+            .keys
+            .range(vec![low.to_vec()]..)
+            .take_while(|(key, _)| key[0].as_slice() <= high)
+            .flat_map(|(_, positions)| positions.iter().copied())
+            .collect();
+
+        Some((found, subitem_index.positions.len() as u64))
+    }
+
+    /// Open the log and replay it, if the tree has indexes. A new tree drops
+    /// the indexes of the tree file it replaced.
+    pub(crate) fn open_indexes(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "index");
+
+        if create {
+            let _ = fs::remove_file(path);
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        };
+
+        let log = self.open_sidecar("index", false)?;
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        // The subitems of the index with each id.
+        let mut ids: HashMap<u32, Vec<usize>> = HashMap::new();
+
+        // An entry cut short by a crash is dropped, as its write never
+        // finished.
+        let mut offset = 0;
+        while offset + INDEX_ENTRY_HEADER_SIZE <= bytes.len() {
+            let entry = &bytes[offset..];
+            let id = bitcodec::u8_array_to_u32(entry[0..4].try_into().unwrap());
+            let position = u128::from_be_bytes(entry[5..21].try_into().unwrap());
+
+            let end = match entry[4] {
+                ENTRY_CREATED => INDEX_ENTRY_HEADER_SIZE.saturating_add(position as usize * 4),
+                ENTRY_SET => match ids.get(&id) {
+                    Some(subitems) => INDEX_ENTRY_HEADER_SIZE + self.key_size(subitems),
+                    None => return Err(TreeFileError::Corrupted),
+                },
+                ENTRY_REMOVED | ENTRY_DROPPED => INDEX_ENTRY_HEADER_SIZE,
+                _ => return Err(TreeFileError::Corrupted),
+            };
+            if end > entry.len() {
+                break;
+            };
+
+            match entry[4] {
+                ENTRY_CREATED => {
+                    let subitems: Vec<usize> = entry[INDEX_ENTRY_HEADER_SIZE..end]
+                        .chunks(4)
+                        .map(|index| bitcodec::u8_array_to_u32(index.try_into().unwrap()) as usize)
+                        .collect();
+                    if subitems.is_empty()
+                        || subitems.iter().any(|index| *index >= self.subitems.len())
+                    {
+                        return Err(TreeFileError::Corrupted);
+                    };
+
+                    self.indexes.insert(
+                        subitems.clone(),
+                        SubitemIndex {
+                            id,
+                            ..Default::default()
+                        },
+                    );
+                    ids.insert(id, subitems);
+                }
+                ENTRY_DROPPED => {
+                    if let Some(subitems) = ids.remove(&id) {
+                        self.indexes.remove(&subitems);
+                    };
+                }
+                kind => {
+                    let subitems = match ids.get(&id) {
+                        Some(subitems) => subitems,
+                        None => return Err(TreeFileError::Corrupted),
+                    };
+                    let key = (kind == ENTRY_SET)
+                        .then(|| self.decode_key(subitems, &entry[INDEX_ENTRY_HEADER_SIZE..end]));
+                    if let Some(subitem_index) = self.indexes.get_mut(subitems) {
+                        subitem_index.set(position, key);
+                    };
+                }
+            };
+
+            offset += end;
+        }
+
+        self.index_log = Some(log);
+
+        Ok(())
+    }
+
+    /// Update the indexes with the node that was written at `position`, if
+    /// the tree has indexes.
+    pub(crate) fn update_indexes(&mut self, position: u128) -> Result<(), NodeError> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        };
+
+        let node = match self.resolve(position) {
+            Ok(slot) => match self.read_slot(slot) {
+                Ok(contents) if contents.enabled => Some(contents.subitems),
+                Ok(_) | Err(NodeError::Unexistent) => None,
+                Err(error) => return Err(error),
+            },
+            Err(NodeError::Unexistent) => None,
+            Err(error) => return Err(error),
+        };
+
+        let mut entries = vec![];
+        for (subitems, subitem_index) in self.indexes.iter_mut() {
+            let key = node
+                .as_ref()
+                .map(|node| subitems.iter().map(|index| node[*index].clone()).collect());
+            if subitem_index.set(position, key.clone()) {
+                let kind = match key {
+                    Some(_) => ENTRY_SET,
+                    None => ENTRY_REMOVED,
+                };
+                entries.extend(encode_entry(subitem_index.id, kind, position, key.as_ref()));
+            };
+        }
+
+        match self.append_index_entries(&entries) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+
+    /// Rebuild every index from the stored nodes, e.g. after the nodes were
+    /// rewritten without going through the writes.
+    pub(crate) fn rebuild_indexes(&mut self) -> Result<(), TreeFileError> {
+        let indexes = self.indexes();
+        if indexes.is_empty() {
+            return Ok(());
+        };
+
+        self.indexes.clear();
+        self.index_log = Some(self.open_sidecar("index", true)?);
+        for subitems in indexes {
+            self.create_index(&subitems)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_indexes(&self) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty() || self.gap_fill != GapFill::Zeros {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        Ok(())
+    }
+
+    /// The key of every enabled node, read one subitem at a time, in
+    /// ascending order of position.
+    fn read_keys(&self, subitems: &[usize]) -> Result<Vec<(u128, IndexKey)>, NodeError> {
+        let mut keys: Vec<(u128, IndexKey)> = vec![];
+        for (i, index) in subitems.iter().enumerate() {
+            let column = self.collect_subitem(*index)?;
+            if i == 0 {
+                keys = column
+                    .positions
+                    .iter()
+                    .map(|position| (*position, vec![]))
+                    .collect();
+            };
+
+            for (value, (_, key)) in keys.iter_mut().enumerate() {
+                key.push(column.get(value));
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Find the nodes whose key falls in `range` by reading every node.
+    fn scan_range(
+        &self,
+        subitems: &[usize],
+        range: impl RangeBounds<IndexKey>,
+    ) -> Result<Vec<u128>, NodeError> {
+        let mut found: Vec<(IndexKey, u128)> = self
+            .read_keys(subitems)?
+            .into_iter()
+            .filter(|(_, key)| range.contains(key))
+            .map(|(position, key)| (key, position))
+            .collect();
+        found.sort_unstable();
+
+        Ok(found.into_iter().map(|(_, position)| position).collect())
+    }
+
+    /// The size in bytes of the keys of an index of `subitems`.
+    fn key_size(&self, subitems: &[usize]) -> usize {
+        subitems
+            .iter()
+            .map(|index| self.subitems[*index] as usize)
+            .sum::<usize>()
+            .div_ceil(8)
+    }
+
+    fn decode_key(&self, subitems: &[usize], bytes: &[u8]) -> IndexKey {
+        let mut offset = 0;
+        subitems
+            .iter()
+            .map(|index| {
+                let size = self.subitems[*index] as usize;
+                offset += size;
+                bitcodec::unpack_bits_at(bytes, offset - size, size)
+            })
+            .collect()
+    }
+
+    /// Append entries to the log, compacting it first if most of its
+    /// entries were overwritten.
+    fn append_index_entries(&mut self, entries: &[u8]) -> std::io::Result<()> {
+        let log = match &self.index_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        if entries.is_empty() {
+            return Ok(());
+        };
+
+        let size = log.size()?;
+        if size >= INDEX_COMPACT_MIN_SIZE && size > 4 * self.live_index_size() {
+            return self.compact_indexes(entries);
+        };
+
+        log.write_at(size, entries)
+    }
+
+    /// The size in bytes of the entries needed to rebuild the indexes.
+    fn live_index_size(&self) -> u64 {
+        self.indexes
+            .iter()
+            .map(|(subitems, subitem_index)| {
+                let entry = (INDEX_ENTRY_HEADER_SIZE + self.key_size(subitems)) as u64;
+                entry * (subitem_index.positions.len() as u64 + 1)
+            })
+            .sum()
+    }
+
+    /// Rewrite the log with only the entries needed to rebuild the indexes,
+    /// sorted by key, followed by `entries`. The new log is written next to
+    /// it and renamed over it, so a crash leaves one of them whole.
+    fn compact_indexes(&mut self, entries: &[u8]) -> std::io::Result<()> {
+        let mut bytes = vec![];
+        for (subitems, subitem_index) in &self.indexes {
+            bytes.extend(encode_created(subitem_index.id, subitems));
+            for (key, positions) in &subitem_index.keys {
+                for position in positions {
+                    bytes.extend(encode_entry(
+                        subitem_index.id,
+                        ENTRY_SET,
+                        *position,
+                        Some(key),
+                    ));
+                }
+            }
+        }
+        bytes.extend(entries);
+
+        let temp = sidecar_path(&self.path, "index.compact");
+        fs::write(&temp, bytes)?;
+        fs::rename(&temp, sidecar_path(&self.path, "index"))?;
+        self.index_log = Some(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(sidecar_path(&self.path, "index"))?,
+        );
+
+        Ok(())
+    }
+}
+
+/// Encode the entry creating the index `id` of `subitems`.
+fn encode_created(id: u32, subitems: &[usize]) -> Vec<u8> {
+    let mut entry = encode_entry(id, ENTRY_CREATED, subitems.len() as u128, None);
+    for index in subitems {
+        entry.extend(bitcodec::u32_to_u8_array(*index as u32));
+    }
+
+    entry
+}
+
+fn encode_entry(id: u32, kind: u8, position: u128, key: Option<&IndexKey>) -> Vec<u8> {
+    let mut entry = bitcodec::u32_to_u8_array(id).to_vec();
+    entry.push(kind);
+    entry.extend(position.to_be_bytes());
+    if let Some(key) = key {
+        entry.extend(bitcodec::bits_to_bytes(&key.concat()));
+    };
+
+    entry
+}
@@ -0,0 +1,89 @@
+//! How much memory a tree spends to read less from the storage.
+
+use crate::cache::PAGE_SIZE;
+use crate::Tree;
+use std::io;
+
+/// The amount of pages read through the cache of trees in
+/// [`Aggressive`](MemoryMode::Aggressive) mode (1 GiB).
+const AGGRESSIVE_CACHE_PAGES: usize = 1 << 18;
+
+/// The smallest chunk read by scans of trees in
+/// [`Minimal`](MemoryMode::Minimal) mode.
+const MINIMAL_CHUNK_BYTES: usize = 4096;
+
+/// How much memory a tree keeps to avoid reading the storage again.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MemoryMode {
+    /// Nothing is kept in memory between reads: the pinned pages are
+    /// dropped, pinning, prefetching and warming do nothing, and scans read
+    /// a sixteenth of the chunk they're asked for (at least 4 KiB).
+    Minimal,
+
+    /// Only the pages pinned, prefetched or warmed are kept in memory, and
+    /// scans read the chunks they're asked for.
+    #[default]
+    Balanced,
+
+    /// Every page read is kept in memory too, up to 1 GiB of pages, and
+    /// scans read four times the chunk they're asked for. Like pinned pages,
+    /// the pages kept aren't updated by writes through other handles of the
+    /// same storage.
+    Aggressive,
+}
+
+impl MemoryMode {
+    /// The size of the chunks read by a scan asked for chunks of `bytes`.
+    pub(crate) fn chunk_bytes(&self, bytes: usize) -> usize {
+        match self {
+            MemoryMode::Minimal => (bytes / 16).max(MINIMAL_CHUNK_BYTES).min(bytes),
+            MemoryMode::Balanced => bytes,
+            MemoryMode::Aggressive => bytes.saturating_mul(4),
+        }
+    }
+}
+
+impl Tree {
+    /// Set how much memory the tree keeps to avoid reading the storage
+    /// again. Switching to [`Minimal`](MemoryMode::Minimal) drops the pinned
+    /// pages.
+    pub fn set_memory_mode(&mut self, mode: MemoryMode) {
+        self.memory_mode = mode;
+        if mode == MemoryMode::Minimal {
+            self.unpin_all();
+        };
+    }
+
+    /// How much memory the tree keeps to avoid reading the storage again.
+    pub fn memory_mode(&self) -> MemoryMode {
+        self.memory_mode
+    }
+
+    /// Read bytes missing from the cache from the storage in whole pages,
+    /// and cache them. Returns false, without reading anything, if they'd
+    /// fill the cache past its limit.
+    pub(crate) fn read_through_cache(&self, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+        let first = offset / PAGE_SIZE;
+        let end = offset + buf.len() as u64;
+        let pages = end.div_ceil(PAGE_SIZE) - first;
+        if self.cache().len() as u64 + pages > AGGRESSIVE_CACHE_PAGES as u64 {
+            return Ok(false);
+        };
+
+        let start = first * PAGE_SIZE;
+        let read_end = (end.div_ceil(PAGE_SIZE) * PAGE_SIZE).min(self.storage.size()?.max(end));
+        let mut bytes = vec![0_u8; (read_end - start) as usize];
+        self.storage.read_at(start, &mut bytes)?;
+        self.io().bytes_read += bytes.len() as u64;
+
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&bytes[skip..skip + buf.len()]);
+
+        let mut cache = self.cache_mut();
+        for (index, page) in bytes.chunks(PAGE_SIZE as usize).enumerate() {
+            cache.insert(first + index as u64, page.to_vec());
+        }
+
+        Ok(true)
+    }
+}
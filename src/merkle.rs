@@ -0,0 +1,362 @@
+//! Structural hashes of subtrees, to compare subtrees without reading them.
+//!
+//! Trees keeping their subtree hashes store them next to the tree file
+//! (with the `.merkle` extension), 8 bytes per position, for the latest
+//! version.
+
+use crate::{
+    positions, sidecar_path, GapFill, NodeData, NodeError, Slot, Storage, Tree, TreeFileError,
+    TreeOpenMode,
+};
+use std::fs;
+
+/// The size in bytes of each entry of the hash table.
+const MERKLE_ENTRY_SIZE: u64 = 8;
+
+impl Tree {
+    /// The hash of the subtree rooted at `position`, combining the digest of
+    /// each enabled node (see [`NodeData::digest`]) with the hashes of its
+    /// children's subtrees, bottom up. Subtrees with the same enabled nodes in
+    /// the same shape hash the same wherever they are, and subtrees without
+    /// enabled nodes hash to `0`.
+    ///
+    /// Read in constant time when the tree keeps its subtree hashes (see
+    /// [`keep_subtree_hashes`](Tree::keep_subtree_hashes)), or computed by
+    /// reading the whole subtree otherwise.
+    pub fn subtree_hash(&self, position: u128) -> Result<u64, NodeError> {
+        if self.merkle.is_some() && self.version.is_none() {
+            return self.read_merkle(position);
+        };
+
+        self.hash_subtree(position, false)
+    }
+
+    /// Whether the subtrees rooted at `a` and `b` have the same enabled nodes
+    /// with the same subitems in the same shape, like subtrees with the same
+    /// [`subtree_hash`](Tree::subtree_hash) but without hash collisions.
+    ///
+    /// Both subtrees are read node by node side by side, stopping at the
+    /// first difference, so neither is held in memory. Trees keeping their
+    /// subtree hashes tell subtrees with different hashes apart without
+    /// reading them.
+    pub fn subtrees_equal(&self, a: u128, b: u128) -> Result<bool, NodeError> {
+        if a == b {
+            return Ok(true);
+        };
+
+        if self.merkle.is_some()
+            && self.version.is_none()
+            && self.read_merkle(a)? != self.read_merkle(b)?
+        {
+            return Ok(false);
+        };
+
+        let mut pending = vec![((a, self.subtree_slot(a)?), (b, self.subtree_slot(b)?))];
+        while let Some((a, b)) = pending.pop() {
+            let (a_contents, b_contents) = (self.subtree_node(a.1)?, self.subtree_node(b.1)?);
+
+            let enabled = |contents: &Option<Slot>| contents.as_ref().is_some_and(|c| c.enabled);
+            if enabled(&a_contents) != enabled(&b_contents) {
+                return Ok(false);
+            };
+            if let (Some(a_contents), Some(b_contents)) = (&a_contents, &b_contents) {
+                if a_contents.enabled && a_contents.subitems != b_contents.subitems {
+                    return Ok(false);
+                };
+            };
+
+            for index in [1, 0] {
+                let a_child = self.subtree_child(a.0, &a_contents, index);
+                let b_child = self.subtree_child(b.0, &b_contents, index);
+                if a_child.1.is_some() || b_child.1.is_some() {
+                    pending.push((a_child, b_child));
+                };
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The slot holding `position`, if it's stored.
+    fn subtree_slot(&self, position: u128) -> Result<Option<u128>, NodeError> {
+        match self.resolve(position) {
+            Ok(slot) => Ok(Some(slot)),
+            Err(NodeError::Unexistent) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The contents of a slot of a subtree, if it's stored.
+    fn subtree_node(&self, slot: Option<u128>) -> Result<Option<Slot>, NodeError> {
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+
+        match self.read_slot(slot) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(NodeError::Unexistent) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// A child of the node at `position` and the slot holding it, if the
+    /// node is stored and the child can be addressed.
+    fn subtree_child(
+        &self,
+        position: u128,
+        contents: &Option<Slot>,
+        index: u8,
+    ) -> (u128, Option<u128>) {
+        let child = match positions::child(position, index) {
+            Some(child) => child,
+            None => return (position, None),
+        };
+        let slot = contents
+            .as_ref()
+            .and_then(|contents| self.child_slot(&contents.children, child, index));
+
+        (child, slot)
+    }
+
+    /// Keep the hash of every subtree in a table next to the tree file,
+    /// built from the stored nodes and updated on every write, or remove the
+    /// table. The table is kept until it's removed, and the tree file is
+    /// opened with it.
+    ///
+    /// Trees with a gap fill other than zeros can't keep their subtree
+    /// hashes, as the filled slots aren't hashed.
+    pub fn keep_subtree_hashes(&mut self, keep: bool) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty() || self.gap_fill != GapFill::Zeros {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        if !keep {
+            self.merkle = None;
+            let _ = fs::remove_file(sidecar_path(&self.path, "merkle"));
+            return Ok(());
+        };
+
+        if let Err(error) = self.build_merkle() {
+            self.merkle = None;
+            let _ = fs::remove_file(sidecar_path(&self.path, "merkle"));
+            return Err(error);
+        };
+
+        self.sync()
+    }
+
+    /// Open the hash table if the tree keeps its subtree hashes. A new tree
+    /// drops the table of the tree file it replaced.
+    pub(crate) fn open_merkle(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "merkle");
+
+        if create {
+            let _ = fs::remove_file(path);
+        } else if path.exists() {
+            self.merkle = Some(self.open_sidecar("merkle", false)?);
+        };
+
+        Ok(())
+    }
+
+    /// Fill a new hash table from the stored nodes of the latest version.
+    pub(crate) fn build_merkle(&mut self) -> Result<(), TreeFileError> {
+        self.merkle = Some(self.open_sidecar("merkle", true)?);
+
+        match self.hash_subtree(0, true) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::Corrupted),
+        }
+    }
+
+    /// Update the hashes of a node that was written and of its ancestors, if
+    /// the tree keeps a hash table. Stops at the first unchanged hash, as the
+    /// ones above it don't change either.
+    pub(crate) fn update_merkle(&mut self, position: u128) -> Result<(), NodeError> {
+        if self.merkle.is_none() {
+            return Ok(());
+        };
+
+        let mut position = position;
+        loop {
+            let mut hashes = [0; 2];
+            for index in [0, 1] {
+                if let Some(child) = positions::child(position, index) {
+                    hashes[index as usize] = self.read_merkle(child)?;
+                };
+            }
+            let hash = combine(self.node_digest(position)?, hashes[0], hashes[1]);
+            if hash == self.read_merkle(position)? {
+                return Ok(());
+            };
+            self.write_merkle(position, hash)?;
+
+            if position == 0 {
+                return Ok(());
+            };
+            position = positions::parent(position);
+        }
+    }
+
+    /// Hash the subtree rooted at `position`, recording the hash of every
+    /// non-empty subtree in the hash table if `record` is true.
+    fn hash_subtree(&self, position: u128, record: bool) -> Result<u64, NodeError> {
+        match self.position_limit()? {
+            Some(limit) => self.hash_flat(position, limit, record),
+            None => match self.resolve(position) {
+                Ok(slot) => self.hash_persistent(slot, position, record),
+                Err(NodeError::Unexistent) => Ok(0),
+                Err(error) => Err(error),
+            },
+        }
+    }
+
+    /// Hash the subtree of a flat tree rooted at `position`.
+    fn hash_flat(&self, position: u128, limit: u128, record: bool) -> Result<u64, NodeError> {
+        if position >= limit {
+            return Ok(0);
+        };
+
+        let mut hashes = [0; 2];
+        for index in [0, 1] {
+            if let Some(child) = positions::child(position, index) {
+                hashes[index as usize] = self.hash_flat(child, limit, record)?;
+            };
+        }
+        let hash = combine(self.node_digest(position)?, hashes[0], hashes[1]);
+
+        if record && hash != 0 {
+            self.write_merkle(position, hash)?;
+        };
+
+        Ok(hash)
+    }
+
+    /// Hash the subtree of a persistent tree rooted at `position`, stored
+    /// from `slot`.
+    fn hash_persistent(&self, slot: u128, position: u128, record: bool) -> Result<u64, NodeError> {
+        let contents = self.read_slot(slot)?;
+
+        let mut hashes = [0; 2];
+        for (index, child) in contents.children.iter().enumerate() {
+            if let Some(child) = child {
+                let child_position = match positions::child(position, index as u8) {
+                    Some(child_position) => child_position,
+                    None => return Err(NodeError::Unexistent),
+                };
+                hashes[index] = self.hash_persistent(*child, child_position, record)?;
+            };
+        }
+
+        let digest = match contents.enabled {
+            true => NodeData {
+                position,
+                enabled: true,
+                subitems: contents.subitems,
+            }
+            .digest(),
+            false => 0,
+        };
+        let hash = combine(digest, hashes[0], hashes[1]);
+
+        if record && hash != 0 {
+            self.write_merkle(position, hash)?;
+        };
+
+        Ok(hash)
+    }
+
+    /// The digest of the node at `position`, or `0` if it's disabled or
+    /// missing.
+    fn node_digest(&self, position: u128) -> Result<u64, NodeError> {
+        let slot = match self.resolve(position) {
+            Ok(slot) => slot,
+            Err(NodeError::Unexistent) => return Ok(0),
+            Err(error) => return Err(error),
+        };
+
+        match self.read_slot(slot) {
+            Ok(contents) if contents.enabled => Ok(NodeData {
+                position,
+                enabled: true,
+                subitems: contents.subitems,
+            }
+            .digest()),
+            Ok(_) | Err(NodeError::Unexistent) => Ok(0),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn read_merkle(&self, position: u128) -> Result<u64, NodeError> {
+        let merkle = match &self.merkle {
+            Some(merkle) => merkle,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        let offset = match merkle_offset(position) {
+            Some(offset) => offset,
+            None => return Ok(0),
+        };
+        let size = match merkle.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        if offset + MERKLE_ENTRY_SIZE > size {
+            return Ok(0);
+        };
+
+        let mut entry = [0_u8; MERKLE_ENTRY_SIZE as usize];
+        match merkle.read_at(offset, &mut entry) {
+            Ok(_) => Ok(u64::from_be_bytes(entry)),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+
+    fn write_merkle(&self, position: u128, hash: u64) -> Result<(), NodeError> {
+        let (merkle, offset) = match (&self.merkle, merkle_offset(position)) {
+            (Some(merkle), Some(offset)) => (merkle, offset),
+            (None, _) => return Err(NodeError::MissingFeature),
+            (_, None) => return Err(NodeError::OutsideLayout),
+        };
+
+        match merkle.write_at(offset, &hash.to_be_bytes()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+}
+
+/// The offset of the entry of `position` in the hash table.
+fn merkle_offset(position: u128) -> Option<u64> {
+    u64::try_from(position)
+        .ok()
+        .and_then(|position| position.checked_mul(MERKLE_ENTRY_SIZE))
+        .filter(|offset| offset.checked_add(MERKLE_ENTRY_SIZE).is_some())
+}
+
+/// The hash of a subtree: the 64-bit FNV-1a hash of the digest of its root
+/// followed by the hashes of its children's subtrees. Never `0`, which marks
+/// an empty subtree.
+fn combine(digest: u64, left: u64, right: u64) -> u64 {
+    if digest == 0 && left == 0 && right == 0 {
+        return 0;
+    };
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in [digest, left, right]
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+    {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash.max(1)
+}
@@ -0,0 +1,132 @@
+//! Exchanging the subtrees of a node, e.g. to canonicalize a tree or rotate
+//! it while balancing it. Every stored node of the subtree is relocated to
+//! its new position, so the data kept about positions follows the nodes.
+
+use crate::{positions, Feature, Node, NodeData, NodeError, Tree};
+use std::collections::HashSet;
+
+impl Tree {
+    /// Exchange the left and right subtrees of the node at `position`, each
+    /// keeping its shape.
+    ///
+    /// See [`mirror`](Tree::mirror) for how the nodes are relocated.
+    pub fn swap_children(&mut self, position: u128) -> Result<(), NodeError> {
+        self.relocate_subtree(position, |depth, offset| offset ^ (1 << (depth - 1)))
+    }
+
+    /// Mirror the subtree of the node at `position`, exchanging the left and
+    /// right children of every node in it.
+    ///
+    /// Every stored node of the subtree below `position`, disabled ones
+    /// included, is written to its new position, and the positions left
+    /// without a node are disabled. Trees without the disabling feature
+    /// can't disable them, so if any position would be left without a node
+    /// they fail with [`MissingFeature`](NodeError::MissingFeature), without
+    /// writing anything. The nodes are written without being validated, but
+    /// the write hooks are called for each of them, and persistent trees
+    /// record each of them as a version.
+    pub fn mirror(&mut self, position: u128) -> Result<(), NodeError> {
+        self.relocate_subtree(position, |depth, offset| (1 << depth) - 1 - offset)
+    }
+
+    /// Move every stored node below `position` to the position at the same
+    /// depth below it whose offset from the left of the level is
+    /// `relocate(depth, offset)`.
+    fn relocate_subtree(
+        &mut self,
+        position: u128,
+        relocate: impl Fn(u32, u128) -> u128,
+    ) -> Result<(), NodeError> {
+        let nodes = self.stored_below(position)?;
+
+        let targets: Vec<u128> = nodes
+            .iter()
+            .map(|node| {
+                let depth = positions::level(node.position) - positions::level(position);
+                let first = ((position + 1) << depth) - 1;
+                first + relocate(depth, node.position - first)
+            })
+            .collect();
+
+        let moved: HashSet<u128> = targets.iter().copied().collect();
+        let emptied: Vec<&NodeData> = nodes
+            .iter()
+            .filter(|node| !moved.contains(&node.position))
+            .collect();
+        if !emptied.is_empty() && !self.features.contains(&Feature::Disabling) {
+            return Err(NodeError::MissingFeature);
+        };
+
+        // Ancestors are written before their descendants, so that the
+        // ancestors of every node written are stored.
+        for (node, target) in nodes.iter().zip(&targets) {
+            self.write_with_hooks(&node.subitems, *target, !node.enabled)?;
+        }
+        for node in emptied {
+            self.write_with_hooks(&node.subitems, node.position, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Every node stored below `position`, disabled ones included, in level
+    /// order.
+    fn stored_below(&self, position: u128) -> Result<Vec<NodeData>, NodeError> {
+        let mut nodes = vec![];
+
+        let slot = self.resolve(position)?;
+        if self.read_slot_header(slot).is_err() {
+            return Err(NodeError::Unexistent);
+        };
+
+        let mut level = vec![(position, slot)];
+        while !level.is_empty() {
+            let mut next = vec![];
+            for (position, slot) in level {
+                let contents = match self.read_slot(slot) {
+                    Ok(contents) => contents,
+                    Err(NodeError::Unexistent) => continue,
+                    Err(error) => return Err(error),
+                };
+
+                for index in [0, 1] {
+                    // The deepest positions have no children that can be
+                    // addressed.
+                    let child = match positions::child(position, index) {
+                        Some(child) => child,
+                        None => continue,
+                    };
+                    if let Some(child_slot) = self.child_slot(&contents.children, child, index) {
+                        next.push((child, child_slot));
+                    };
+                }
+
+                nodes.push(NodeData {
+                    position,
+                    enabled: contents.enabled,
+                    subitems: contents.subitems,
+                });
+            }
+            level = next;
+        }
+
+        // The node at `position` itself stays where it is.
+        nodes.remove(0);
+
+        Ok(nodes)
+    }
+}
+
+impl Node<'_> {
+    /// Exchange the left and right subtrees of the node, like
+    /// [`Tree::swap_children`].
+    pub fn swap_children(&mut self) -> Result<(), NodeError> {
+        self.tree.swap_children(self.position)?;
+
+        if let Some(hints) = &mut self.hints {
+            hints.swap(0, 1);
+        };
+
+        Ok(())
+    }
+}
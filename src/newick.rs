@@ -0,0 +1,254 @@
+//! Conversion from and to the Newick tree format, e.g. `((A,B)C,D)E;`.
+
+use crate::{positions, NodeData, NodeError, Tree};
+
+/// How the nodes of a tree are written in the Newick format.
+pub trait NewickFormatter {
+    /// The label of a node. Labels with spaces or Newick punctuation are
+    /// quoted.
+    fn label(&self, node: &NodeData) -> String;
+
+    /// The length of the branch from the node to its parent, if any.
+    fn branch_length(&self, _node: &NodeData) -> Option<f64> {
+        None
+    }
+}
+
+/// Why a Newick tree couldn't be imported.
+#[derive(Debug)]
+pub enum NewickError {
+    /// The input isn't a valid Newick tree. `offset` is the byte where the
+    /// problem was found.
+    Syntax { offset: usize },
+
+    /// A node at `offset` has more than two children.
+    TooManyChildren { offset: usize },
+
+    /// A node couldn't be written.
+    Node(NodeError),
+}
+
+impl Tree {
+    /// Export the tree in the Newick format, with the children of each node
+    /// from left to right. Only enabled nodes are exported, so the branches
+    /// under a disabled node are left out. Nodes with a
+    /// single child lose whether it was the left or the right one.
+    pub fn export_newick(&self, formatter: &impl NewickFormatter) -> Result<String, NodeError> {
+        let mut newick = String::new();
+
+        match self.write_newick(0, formatter, &mut newick) {
+            Ok(_) => (),
+            Err(NodeError::Unexistent) | Err(NodeError::Disabled) => (),
+            Err(error) => return Err(error),
+        };
+        newick.push(';');
+
+        Ok(newick)
+    }
+
+    fn write_newick(
+        &self,
+        position: u128,
+        formatter: &impl NewickFormatter,
+        newick: &mut String,
+    ) -> Result<(), NodeError> {
+        let node = self.read_node(position)?;
+
+        let mut children = vec![];
+        for index in 0..2 {
+            // The deepest positions have no children that can be addressed.
+            let child_position = match positions::child(position, index) {
+                Some(child_position) => child_position,
+                None => continue,
+            };
+            let mut child = String::new();
+            match self.write_newick(child_position, formatter, &mut child) {
+                Ok(_) => children.push(child),
+                Err(NodeError::Unexistent) | Err(NodeError::Disabled) => (),
+                Err(error) => return Err(error),
+            };
+        }
+
+        if !children.is_empty() {
+            newick.push('(');
+            newick.push_str(&children.join(","));
+            newick.push(')');
+        };
+
+        newick.push_str(&quote_label(&formatter.label(&node)));
+
+        if let Some(length) = formatter.branch_length(&node) {
+            newick.push(':');
+            newick.push_str(&length.to_string());
+        };
+
+        Ok(())
+    }
+
+    /// Import a Newick tree, rooted at position 0. `parse` builds the
+    /// subitems of each node from its label and branch length. The children
+    /// of a node are placed from left to right.
+    pub fn import_newick(
+        &mut self,
+        newick: &str,
+        parse: impl Fn(&str, Option<f64>) -> Vec<Vec<bool>>,
+    ) -> Result<(), NewickError> {
+        let mut parser = Parser {
+            input: newick.as_bytes(),
+            offset: 0,
+        };
+
+        let root = parser.subtree()?;
+        parser.skip_whitespace();
+        if parser.next() != Some(b';') {
+            return Err(NewickError::Syntax {
+                offset: parser.offset,
+            });
+        };
+
+        let mut pending = vec![(0, root)];
+        while let Some((position, node)) = pending.pop() {
+            let subitems = parse(&node.label, node.branch_length);
+            match self.bulk(|tree| tree.set_node_quiet(&subitems, &position, true, false)) {
+                Ok(_) => (),
+                Err(error) => return Err(NewickError::Node(error)),
+            };
+
+            for (index, child) in node.children.into_iter().enumerate() {
+                match positions::child(position, index as u8) {
+                    Some(child_position) => pending.push((child_position, child)),
+                    None => return Err(NewickError::Node(NodeError::Unexistent)),
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Quote a label if it has characters with a meaning in Newick.
+fn quote_label(label: &str) -> String {
+    if label
+        .chars()
+        .any(|c| c.is_whitespace() || "()[]':;,".contains(c))
+    {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label.to_string()
+    }
+}
+
+/// A node read from a Newick tree.
+struct ParsedNode {
+    label: String,
+    branch_length: Option<f64>,
+    children: Vec<ParsedNode>,
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.offset).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek();
+        self.offset += 1;
+        byte
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.offset += 1;
+        }
+    }
+
+    fn subtree(&mut self) -> Result<ParsedNode, NewickError> {
+        self.skip_whitespace();
+
+        let mut children = vec![];
+        if self.peek() == Some(b'(') {
+            let start = self.offset;
+            loop {
+                self.offset += 1;
+                children.push(self.subtree()?);
+                self.skip_whitespace();
+
+                match self.peek() {
+                    Some(b',') => (),
+                    Some(b')') => {
+                        self.offset += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(NewickError::Syntax {
+                            offset: self.offset,
+                        })
+                    }
+                };
+            }
+
+            if children.len() > 2 {
+                return Err(NewickError::TooManyChildren { offset: start });
+            };
+        };
+
+        let label = self.label()?;
+
+        self.skip_whitespace();
+        let mut branch_length = None;
+        if self.peek() == Some(b':') {
+            self.offset += 1;
+            let start = self.offset;
+            let length = self.label()?;
+            branch_length = match length.trim().parse() {
+                Ok(length) => Some(length),
+                Err(_) => return Err(NewickError::Syntax { offset: start }),
+            };
+        };
+
+        Ok(ParsedNode {
+            label,
+            branch_length,
+            children,
+        })
+    }
+
+    fn label(&mut self) -> Result<String, NewickError> {
+        self.skip_whitespace();
+
+        if self.peek() == Some(b'\'') {
+            let start = self.offset;
+            self.offset += 1;
+
+            let mut label = vec![];
+            loop {
+                match self.next() {
+                    Some(b'\'') if self.peek() == Some(b'\'') => {
+                        self.offset += 1;
+                        label.push(b'\'');
+                    }
+                    Some(b'\'') => break,
+                    Some(byte) => label.push(byte),
+                    None => return Err(NewickError::Syntax { offset: start }),
+                };
+            }
+
+            return Ok(String::from_utf8_lossy(&label).into_owned());
+        };
+
+        let start = self.offset;
+        while self
+            .peek()
+            .is_some_and(|byte| !b"()[]':;,".contains(&byte) && !byte.is_ascii_whitespace())
+        {
+            self.offset += 1;
+        }
+
+        Ok(String::from_utf8_lossy(&self.input[start..self.offset]).into_owned())
+    }
+}
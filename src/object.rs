@@ -0,0 +1,381 @@
+//! A storage keeping the tree file as one object of an object store (e.g.
+//! S3), read with ranged GETs and written back with multipart uploads.
+
+use crate::{
+    columns, read_dirty, read_headers, schema, write_dirty, write_headers, CreateOptions, Feature,
+    GapFill, Storage, Tree, TreeFileError, TreeOpenMode,
+};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The smallest size in bytes of the parts of a multipart upload, but the
+/// last one.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// A client of the object store holding the tree file. Implement it over the
+/// store's SDK to keep trees in it.
+pub trait ObjectStore: Debug + Send + Sync {
+    /// The size in bytes of the object.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Read `len` bytes of the object starting at `offset`, with a ranged
+    /// GET.
+    fn get_range(&self, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+
+    /// Start a multipart upload replacing the object, and return its id.
+    fn create_multipart(&self) -> io::Result<String>;
+
+    /// Upload a part of a multipart upload. Parts are numbered from 1, and
+    /// all of them but the last one hold at least [`MIN_PART_SIZE`] bytes.
+    fn upload_part(&self, upload: &str, part: u32, bytes: &[u8]) -> io::Result<()>;
+
+    /// Upload a part of a multipart upload by copying `len` bytes of the
+    /// object being replaced, starting at `offset`. Stores that can copy
+    /// parts server side (e.g. S3's `UploadPartCopy`) should override it,
+    /// as the bytes are read and uploaded again otherwise.
+    fn upload_part_copy(&self, upload: &str, part: u32, offset: u64, len: u64) -> io::Result<()> {
+        let bytes = self.get_range(offset, len)?;
+        self.upload_part(upload, part, &bytes)
+    }
+
+    /// Finish a multipart upload, replacing the object with its parts.
+    fn complete_multipart(&self, upload: &str) -> io::Result<()>;
+
+    /// Cancel a multipart upload, leaving the object as it was.
+    fn abort_multipart(&self, upload: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+struct ObjectState {
+    /// The size of the tree file, with the changes not uploaded yet.
+    size: u64,
+
+    /// The size of the object. Bytes between it and `size` read as zeros.
+    stored: u64,
+
+    /// The cached blocks, changed or not.
+    blocks: HashMap<u64, Vec<u8>>,
+
+    /// The order in which the blocks were cached, oldest first.
+    order: VecDeque<u64>,
+
+    /// The blocks changed since the last upload. They're always cached.
+    dirty: BTreeSet<u64>,
+
+    /// Whether the size changed since the last upload.
+    resized: bool,
+}
+
+/// A storage over an object, caching the blocks read from it. Writes are
+/// kept in memory until the storage is synced, which uploads the whole
+/// object again: objects can't be changed in place, but the parts without
+/// changes are copied from the old object.
+#[derive(Debug)]
+pub struct ObjectStorage<S: ObjectStore> {
+    store: S,
+
+    /// The size in bytes of the blocks read and cached.
+    block_size: u64,
+
+    /// The amount of unchanged blocks kept in memory.
+    cached_blocks: usize,
+
+    state: Mutex<ObjectState>,
+}
+
+impl<S: ObjectStore> ObjectStorage<S> {
+    /// Read the object through blocks of `block_size` bytes, caching up to
+    /// `cached_blocks` of them. Changed blocks are kept until they're
+    /// uploaded, beyond the limit.
+    pub fn new(store: S, block_size: u64, cached_blocks: usize) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+
+        let size = store.size()?;
+
+        Ok(Self {
+            store,
+            block_size,
+            cached_blocks,
+            state: Mutex::new(ObjectState {
+                size,
+                stored: size,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// The client of the object store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    fn state(&self) -> MutexGuard<'_, ObjectState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// The bytes of a block, fetched if they aren't cached.
+    fn block<'a>(&self, state: &'a mut ObjectState, block: u64) -> io::Result<&'a mut Vec<u8>> {
+        if !state.blocks.contains_key(&block) {
+            let offset = block * self.block_size;
+            let len = self.block_size.min(state.size.saturating_sub(offset));
+
+            let mut bytes = match state.stored.saturating_sub(offset).min(len) {
+                0 => vec![],
+                stored => self.store.get_range(offset, stored)?,
+            };
+            bytes.resize(len as usize, 0);
+
+            self.evict(state);
+            state.blocks.insert(block, bytes);
+            state.order.push_back(block);
+        };
+
+        Ok(state.blocks.get_mut(&block).unwrap())
+    }
+
+    /// Truncate or extend (with zeros) the file.
+    fn resize(&self, state: &mut ObjectState, size: u64) {
+        let block_size = self.block_size;
+
+        // Bytes past a truncation read as zeros if the file grows again.
+        state.stored = state.stored.min(size);
+        state.size = size;
+        state.resized = true;
+
+        let blocks = size.div_ceil(block_size);
+        state.blocks.retain(|block, _| *block < blocks);
+        state.dirty.retain(|block| *block < blocks);
+        state.order.retain(|block| *block < blocks);
+
+        // Only the last cached block can have another size.
+        for (block, bytes) in state.blocks.iter_mut() {
+            bytes.resize(block_size.min(size - block * block_size) as usize, 0);
+        }
+    }
+
+    /// Drop the oldest unchanged blocks until there's room for another one.
+    fn evict(&self, state: &mut ObjectState) {
+        let mut kept = VecDeque::new();
+
+        while state.blocks.len() - state.dirty.len() >= self.cached_blocks {
+            let block = match state.order.pop_front() {
+                Some(block) => block,
+                None => break,
+            };
+
+            if state.dirty.contains(&block) {
+                kept.push_back(block);
+            } else {
+                state.blocks.remove(&block);
+            };
+        }
+
+        kept.extend(state.order.drain(..));
+        state.order = kept;
+    }
+
+    /// The blocks holding `len` bytes at `offset`, with the range of each
+    /// one within the bytes.
+    fn spans(&self, offset: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize)> + '_ {
+        let end = offset + len as u64;
+
+        (offset / self.block_size..end.div_ceil(self.block_size)).map(move |block| {
+            let start = (block * self.block_size).max(offset);
+            let stop = ((block + 1) * self.block_size).min(end);
+            (block, (start - offset) as usize, (stop - offset) as usize)
+        })
+    }
+}
+
+impl<S: ObjectStore> Storage for ObjectStorage<S> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut state = self.state();
+        if offset + buf.len() as u64 > state.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+
+        for (block, start, stop) in self.spans(offset, buf.len()) {
+            let at = (offset + start as u64 - block * self.block_size) as usize;
+            let bytes = self.block(&mut state, block)?;
+            buf[start..stop].copy_from_slice(&bytes[at..at + stop - start]);
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+
+        let end = offset + buf.len() as u64;
+        if end > state.size {
+            self.resize(&mut state, end);
+        };
+
+        for (block, start, stop) in self.spans(offset, buf.len()) {
+            let at = (offset + start as u64 - block * self.block_size) as usize;
+            let bytes = self.block(&mut state, block)?;
+            bytes[at..at + stop - start].copy_from_slice(&buf[start..stop]);
+            state.dirty.insert(block);
+        }
+
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.state().size)
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let mut state = self.state();
+        self.resize(&mut state, size);
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.state();
+        if state.dirty.is_empty() && !state.resized {
+            return Ok(());
+        };
+
+        let upload = self.store.create_multipart()?;
+        match self.upload(&mut state, &upload) {
+            Ok(_) => self.store.complete_multipart(&upload)?,
+            Err(error) => {
+                let _ = self.store.abort_multipart(&upload);
+                return Err(error);
+            }
+        };
+
+        state.stored = state.size;
+        state.resized = false;
+        state.dirty.clear();
+        self.evict(&mut state);
+
+        Ok(())
+    }
+}
+
+impl<S: ObjectStore> ObjectStorage<S> {
+    /// Upload every part of the object, copying the ones without changes.
+    fn upload(&self, state: &mut ObjectState, upload: &str) -> io::Result<()> {
+        let part_blocks = MIN_PART_SIZE.div_ceil(self.block_size);
+        let blocks = state.size.div_ceil(self.block_size);
+
+        for (index, first) in (0..blocks.max(1)).step_by(part_blocks as usize).enumerate() {
+            let part = index as u32 + 1;
+            let last = (first + part_blocks).min(blocks);
+            let offset = first * self.block_size;
+            let end = (last * self.block_size).min(state.size);
+
+            if end <= state.stored && state.dirty.range(first..last).next().is_none() {
+                self.store
+                    .upload_part_copy(upload, part, offset, end - offset)?;
+                continue;
+            };
+
+            let mut bytes = Vec::with_capacity((end - offset) as usize);
+            for block in first..last {
+                match state.blocks.get(&block) {
+                    Some(cached) => bytes.extend_from_slice(cached),
+                    None => {
+                        let offset = block * self.block_size;
+                        let len = self.block_size.min(state.size - offset);
+                        let stored = state.stored.saturating_sub(offset).min(len);
+                        if stored > 0 {
+                            bytes.extend(self.store.get_range(offset, stored)?);
+                        };
+                        bytes.resize(bytes.len() + (len - stored) as usize, 0);
+                    }
+                };
+            }
+            self.store.upload_part(upload, part, &bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Tree {
+    /// Open a tree file kept in an object store. Fails with
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown) if the tree file
+    /// wasn't closed the last time it was opened for writing.
+    ///
+    /// Only the tree file is kept in the object, so trees with the
+    /// occupancy or level stats features are read without their auxiliary
+    /// files, and trees with features needing them to be written (or, for
+    /// persistent trees, read) can't be opened this way.
+    pub fn open_object<S: ObjectStore + 'static>(
+        storage: ObjectStorage<S>,
+        mode: TreeOpenMode,
+    ) -> Result<Self, TreeFileError> {
+        let options = read_headers(&storage)?;
+
+        if read_dirty(&storage)? {
+            return Err(TreeFileError::UncleanShutdown);
+        };
+
+        Self::from_object(storage, mode, options)
+    }
+
+    /// Create a new tree file in an object store, replacing the object.
+    pub fn create_object<S: ObjectStore + 'static>(
+        storage: ObjectStorage<S>,
+        options: CreateOptions,
+    ) -> Result<Self, TreeFileError> {
+        match schema::validate(&options.features, &options.subitems)
+            .and_then(|_| schema::validate_layout(&options.features, options.layout))
+            .and_then(|_| schema::validate_reserved_slots(&options.features, options.layout))
+        {
+            Ok(_) => (),
+            Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+        };
+
+        // The gap fill is kept next to the tree file.
+        if options.gap_fill != GapFill::Zeros {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        if storage.set_size(0).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        write_headers(&storage, &options)?;
+        columns::reserve(&storage, &options)?;
+
+        Self::from_object(storage, TreeOpenMode::ReadWrite, options)
+    }
+
+    fn from_object<S: ObjectStore + 'static>(
+        storage: ObjectStorage<S>,
+        mode: TreeOpenMode,
+        options: CreateOptions,
+    ) -> Result<Self, TreeFileError> {
+        let unsupported: &[Feature] = match mode {
+            TreeOpenMode::Read => &[Feature::Persistent],
+            TreeOpenMode::ReadWrite => &[
+                Feature::Persistent,
+                Feature::Occupancy,
+                Feature::LevelStats,
+                Feature::Audit,
+            ],
+        };
+        if unsupported
+            .iter()
+            .any(|feature| options.features.contains(feature))
+        {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let mut tree = Self::detached(Arc::new(storage), mode, "", options);
+        if tree.mode == TreeOpenMode::ReadWrite {
+            write_dirty(&*tree.storage, true)?;
+            tree.sync()?;
+        };
+
+        Ok(tree)
+    }
+}
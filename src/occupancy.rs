@@ -0,0 +1,389 @@
+use crate::{bitcodec, positions, Feature, Layout, NodeError, Storage, Tree, TreeFileError};
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// The maximum amount of bitmap bytes read at once when counting.
+const COUNT_CHUNK_SIZE: u128 = 64 * 1024;
+
+/// The amount of nodes whose headers are read at once when listing
+/// positions.
+const HEADER_CHUNK_SIZE: u128 = 4096;
+
+/// Where the positions of the enabled nodes are read from.
+#[derive(Debug)]
+enum PositionSource {
+    /// The occupancy bitmap.
+    Bitmap,
+
+    /// The enabled bit of each node.
+    Headers,
+
+    /// Every stored node is enabled.
+    All,
+}
+
+/// An iterator over the positions holding an enabled node, in ascending
+/// order.
+#[derive(Debug)]
+pub struct Positions<'a> {
+    tree: &'a Tree,
+    source: PositionSource,
+
+    /// The first position that hasn't been read yet.
+    next: u128,
+
+    /// The first position after which no node can be enabled.
+    end: u128,
+
+    /// The enabled positions read but not yet yielded.
+    buffer: VecDeque<u128>,
+}
+
+impl Tree {
+    /// Which of the positions in `range` hold an enabled node. Answered from
+    /// the occupancy bitmap when the tree has the occupancy feature, without
+    /// decoding any node.
+    pub fn occupancy(&self, range: Range<u128>) -> Result<Vec<bool>, NodeError> {
+        if range.is_empty() {
+            return Ok(vec![]);
+        };
+
+        let bitmap = match &self.occupancy {
+            Some(bitmap) if self.version.is_none() => bitmap,
+            _ => {
+                return Ok(range
+                    .map(|position| self.has_enabled_node(position))
+                    .collect())
+            }
+        };
+
+        let size = match bitmap.size() {
+            Ok(size) => size as u128,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        let start_byte = (range.start / 8).min(size);
+        let end_byte = range.end.div_ceil(8).min(size);
+
+        let (len, count) = match (
+            usize::try_from(end_byte - start_byte),
+            usize::try_from(range.end - range.start),
+        ) {
+            (Ok(len), Ok(count)) => (len, count),
+            _ => return Err(NodeError::OffsetOverflow),
+        };
+
+        let mut bytes = vec![0_u8; len];
+        match bitmap.read_at(start_byte as u64, &mut bytes) {
+            Ok(_) => (),
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        let bits = bitcodec::bytes_to_bits(&bytes);
+        let first = (range.start - start_byte * 8) as usize;
+
+        Ok((0..count)
+            .map(|i| bits.get(first + i).copied().unwrap_or(false))
+            .collect())
+    }
+
+    /// The amount of enabled nodes in the subtree rooted at `position`,
+    /// including the node itself. Enabled nodes below a disabled one are
+    /// counted too.
+    ///
+    /// Counted from the occupancy bitmap when the tree has the occupancy
+    /// feature, one contiguous range per level, without decoding any node.
+    pub fn subtree_size(&self, position: u128) -> Result<u128, NodeError> {
+        let limit = match self.position_limit()? {
+            Some(limit) => limit,
+            None => return self.subtree_size_persistent(position),
+        };
+
+        let mut size = 0;
+        let mut first = position;
+        let mut width: u128 = 1;
+        while first < limit {
+            size += self.count_occupied(first..first.saturating_add(width).min(limit))?;
+
+            first = match first.checked_mul(2).and_then(|first| first.checked_add(1)) {
+                Some(first) => first,
+                None => break,
+            };
+            width = width.saturating_mul(2);
+        }
+
+        Ok(size)
+    }
+
+    /// The positions holding an enabled node, without decoding any subitem.
+    /// Read from the occupancy bitmap when the tree has the occupancy
+    /// feature, or from the nodes' headers otherwise.
+    pub fn positions(&self) -> Result<Positions<'_>, NodeError> {
+        let mut buffer = VecDeque::new();
+
+        let (source, end) = match self.position_limit()? {
+            Some(limit) if self.occupancy.is_some() && self.version.is_none() => {
+                (PositionSource::Bitmap, limit)
+            }
+            Some(limit) if self.features.contains(&Feature::Disabling) => {
+                (PositionSource::Headers, limit)
+            }
+            Some(limit) => (PositionSource::All, limit),
+            None => {
+                let mut enabled = self.positions_persistent()?;
+                enabled.sort_unstable();
+                buffer.extend(enabled);
+                (PositionSource::All, 0)
+            }
+        };
+
+        Ok(Positions {
+            tree: self,
+            source,
+            next: 0,
+            end,
+            buffer,
+        })
+    }
+
+    /// Find the enabled positions of a persistent tree by following its
+    /// pointers.
+    fn positions_persistent(&self) -> Result<Vec<u128>, NodeError> {
+        let mut enabled = vec![];
+        let mut pending = match self.root_slot()? {
+            Some(slot) => vec![(0, slot)],
+            None => vec![],
+        };
+
+        while let Some((position, slot)) = pending.pop() {
+            let contents = self.read_slot_header(slot)?;
+            if contents.enabled {
+                enabled.push(position);
+            };
+
+            for (index, child) in contents.children.iter().enumerate() {
+                match child {
+                    Some(child) if *child < slot => match positions::child(position, index as u8) {
+                        Some(child_position) => pending.push((child_position, *child)),
+                        None => return Err(NodeError::Unexistent),
+                    },
+                    Some(_) => return Err(NodeError::Unexistent),
+                    None => (),
+                };
+            }
+        }
+
+        Ok(enabled)
+    }
+
+    /// Read which of the positions in `range` of a flat tree are enabled,
+    /// reading only the first bit of each node.
+    fn enabled_bits(&self, range: Range<u128>) -> Result<Vec<bool>, NodeError> {
+        // The nodes of other layouts aren't contiguous, so they're read one by
+        // one.
+        if self.layout != Layout::LevelOrder {
+            return range
+                .map(|position| match self.resolve(position) {
+                    Ok(slot) => match self.read_slot_header(slot) {
+                        Ok(contents) => Ok(contents.enabled),
+                        Err(NodeError::Unexistent) => Ok(false),
+                        Err(error) => Err(error),
+                    },
+                    Err(NodeError::Unexistent) => Ok(false),
+                    Err(error) => Err(error),
+                })
+                .collect();
+        };
+
+        let node_size = self.node_size() as u128;
+
+        let start_bit = range.start * node_size;
+        let start_byte = start_bit / 8;
+        let end_byte = ((range.end - 1) * node_size + 1).div_ceil(8);
+
+        let mut bytes = vec![0_u8; (end_byte - start_byte) as usize];
+        match self.read_bytes(self.header_size as u64 + start_byte as u64, &mut bytes) {
+            Ok(_) => (),
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        Ok(range
+            .map(|position| {
+                let offset = (position * node_size - start_byte * 8) as usize;
+                self.bit_order.unpack_bits_at(&bytes, offset, 1)[0]
+            })
+            .collect())
+    }
+
+    /// The first position after which no node can be enabled, or `None` for
+    /// persistent trees without an up-to-date occupancy bitmap.
+    pub(crate) fn position_limit(&self) -> Result<Option<u128>, NodeError> {
+        match (&self.occupancy, self.version) {
+            (Some(bitmap), None) => match bitmap.size() {
+                Ok(size) => Ok(Some(size as u128 * 8)),
+                Err(_) => Err(NodeError::Unexistent),
+            },
+            _ if self.features.contains(&Feature::Persistent) => Ok(None),
+            _ => match self.layout.position_limit() {
+                Some(limit) => Ok(Some(limit)),
+                None => Ok(Some(self.nodes() as u128)),
+            },
+        }
+    }
+
+    /// Count the subtree of a persistent tree by following its pointers.
+    fn subtree_size_persistent(&self, position: u128) -> Result<u128, NodeError> {
+        let mut size = 0;
+        let mut pending = match self.resolve(position) {
+            Ok(slot) => vec![slot],
+            Err(NodeError::Unexistent) => vec![],
+            Err(error) => return Err(error),
+        };
+
+        while let Some(slot) = pending.pop() {
+            let contents = self.read_slot(slot)?;
+            if contents.enabled {
+                size += 1;
+            };
+            pending.extend(contents.children.iter().flatten());
+        }
+
+        Ok(size)
+    }
+
+    /// The amount of enabled nodes in a range of positions.
+    pub(crate) fn count_occupied(&self, range: Range<u128>) -> Result<u128, NodeError> {
+        let bitmap = match &self.occupancy {
+            Some(bitmap) if self.version.is_none() => bitmap,
+            _ => {
+                let occupancy = self.occupancy(range)?;
+                return Ok(occupancy.iter().filter(|occupied| **occupied).count() as u128);
+            }
+        };
+
+        let size = match bitmap.size() {
+            Ok(size) => size as u128,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        let end = range.end.min(size * 8);
+
+        let mut count = 0;
+        let mut start = range.start;
+        while start < end {
+            let chunk_end = end.min((start / 8 + COUNT_CHUNK_SIZE) * 8);
+
+            let start_byte = start / 8;
+            let mut bytes = vec![0_u8; (chunk_end.div_ceil(8) - start_byte) as usize];
+            match bitmap.read_at(start_byte as u64, &mut bytes) {
+                Ok(_) => (),
+                Err(_) => return Err(NodeError::Unexistent),
+            };
+
+            // Drop the bits outside of the range in the first and last bytes.
+            let last = bytes.len() - 1;
+            bytes[0] &= 0xFF >> (start % 8);
+            if !chunk_end.is_multiple_of(8) {
+                bytes[last] &= 0xFF << (8 - chunk_end % 8);
+            };
+
+            count += bytes
+                .iter()
+                .map(|byte| byte.count_ones() as u128)
+                .sum::<u128>();
+            start = chunk_end;
+        }
+
+        Ok(count)
+    }
+
+    /// Open (or create) the occupancy bitmap if the tree has the occupancy
+    /// feature.
+    pub(crate) fn open_occupancy(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Occupancy) {
+            return Ok(());
+        };
+
+        self.occupancy = Some(self.open_sidecar("occupancy", create)?);
+
+        Ok(())
+    }
+
+    /// Record whether a position holds an enabled node in the occupancy
+    /// bitmap, if the tree has one.
+    pub(crate) fn mark_occupancy(
+        &mut self,
+        position: u128,
+        occupied: bool,
+    ) -> Result<(), NodeError> {
+        let bitmap = match &self.occupancy {
+            Some(bitmap) => bitmap,
+            None => return Ok(()),
+        };
+
+        let offset = match u64::try_from(position / 8) {
+            Ok(offset) => offset,
+            Err(_) => return Err(NodeError::OffsetOverflow),
+        };
+        let _guard = self
+            .boundary
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        let mut byte = [0_u8; 1];
+        if bitmap.size().map(|size| size > offset).unwrap_or(false)
+            && bitmap.read_at(offset, &mut byte).is_err()
+        {
+            return Err(NodeError::Unexistent);
+        };
+
+        bitcodec::pack_bits_at(&mut byte, (position % 8) as usize, &[occupied]);
+
+        match bitmap.write_at(offset, &byte) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+}
+
+impl Iterator for Positions<'_> {
+    type Item = Result<u128, NodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() && self.next < self.end {
+            let start = self.next;
+            let occupied = match self.source {
+                PositionSource::Bitmap => {
+                    let end = self.end.min(start + COUNT_CHUNK_SIZE * 8);
+                    self.tree.occupancy(start..end)
+                }
+                PositionSource::Headers => {
+                    let end = self.end.min(start + HEADER_CHUNK_SIZE);
+                    self.tree.enabled_bits(start..end)
+                }
+                PositionSource::All => {
+                    let end = self.end.min(start + HEADER_CHUNK_SIZE);
+                    Ok(vec![true; (end - start) as usize])
+                }
+            };
+
+            let occupied = match occupied {
+                Ok(occupied) => occupied,
+                Err(error) => {
+                    self.next = self.end;
+                    return Some(Err(error));
+                }
+            };
+
+            self.next += occupied.len() as u128;
+            self.buffer.extend(
+                occupied
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, occupied)| **occupied)
+                    .map(|(i, _)| start + i as u128),
+            );
+        }
+
+        self.buffer.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,271 @@
+//! Traversals read a page at a time, resumed from an opaque token, e.g. to
+//! paginate the nodes of a tree over a REST API.
+
+use crate::{positions, Feature, NodeData, NodeError, Tree};
+
+/// The order in which [`traverse_page`](Tree::traverse_page) visits the
+/// nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraversalOrder {
+    /// Depth first, each node before its children, from left to right, like
+    /// [`traverse`](Tree::traverse).
+    PreOrder,
+
+    /// Level by level from the root, from left to right, which is ascending
+    /// order of position.
+    LevelOrder,
+}
+
+/// A page of the enabled nodes of a traversal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    /// The nodes of the page, in traversal order.
+    pub nodes: Vec<NodeData>,
+
+    /// The token resuming the traversal after the page. `None` if it was the
+    /// last page.
+    pub next: Option<String>,
+}
+
+impl Tree {
+    /// Read up to `limit` (at least 1) enabled nodes of a traversal of the
+    /// whole tree, starting after the page the `token` was returned with, or
+    /// from the root if there's no token.
+    ///
+    /// Pages of persistent trees are read from the version the first page
+    /// was read from, so writes made between pages don't show up in the
+    /// following pages. Other trees are read as they are when each page is
+    /// read: pages always resume right after the last node of the previous
+    /// page, but nodes written between pages show up if they come after it.
+    ///
+    /// Fails with [`InvalidToken`](NodeError::InvalidToken) if the token
+    /// wasn't returned by a page of the same order and tree, or its version
+    /// was collected by [`gc`](Tree::gc).
+    pub fn traverse_page(
+        &self,
+        order: TraversalOrder,
+        token: Option<&str>,
+        limit: usize,
+    ) -> Result<Page, NodeError> {
+        self.check_node_bytes()?;
+
+        let persistent = self.features.contains(&Feature::Persistent);
+        let (version, after) = match token {
+            Some(token) => match decode_token(order, token) {
+                Some((version, after)) if version.is_some() == persistent => (version, Some(after)),
+                _ => return Err(NodeError::InvalidToken),
+            },
+            None => (self.version(), None),
+        };
+
+        let root = match version {
+            Some(version) => match self.version_root(version) {
+                Ok(root) => root,
+                Err(_) => return Err(NodeError::InvalidToken),
+            },
+            None => self.layout.slot(0),
+        };
+        let root = match (root, after) {
+            (Some(root), _) => root,
+            (None, Some(_)) => return Err(NodeError::InvalidToken),
+            (None, None) => {
+                return Ok(Page {
+                    nodes: vec![],
+                    next: None,
+                })
+            }
+        };
+
+        let limit = limit.max(1);
+        let mut nodes = vec![];
+        match order {
+            TraversalOrder::PreOrder => {
+                let pending = match after {
+                    Some(after) => self.pending_after(root, after)?,
+                    None => vec![(0, 0, root)],
+                };
+                for node in self.resume_traversal(pending).take(limit) {
+                    nodes.push(node?);
+                }
+            }
+            TraversalOrder::LevelOrder => {
+                let mut level = after.map_or(0, positions::level);
+
+                // Every level past the one of `after` is read whole, so the
+                // first level without stored nodes is the last one.
+                while nodes.len() < limit && level < u128::BITS {
+                    let reached = self.page_level(root, level, after, limit, &mut nodes)?;
+                    if !reached && after.is_none_or(|after| level > positions::level(after)) {
+                        break;
+                    };
+                    level += 1;
+                }
+            }
+        };
+
+        let next = match nodes.last() {
+            Some(last) if nodes.len() == limit => Some(encode_token(order, version, last.position)),
+            _ => None,
+        };
+
+        Ok(Page { nodes, next })
+    }
+
+    /// The nodes left to visit by a pre-order traversal from the root slot
+    /// `root` after it visited `after`: the right siblings along the path to
+    /// it and its children.
+    fn pending_after(&self, root: u128, after: u128) -> Result<Vec<(u128, u32, u128)>, NodeError> {
+        let mut pending = vec![];
+
+        let mut position = 0;
+        let mut slot = root;
+        for index in positions::path(after) {
+            let children = self.page_children(slot)?;
+            if let (0, Some(right)) = (index, positions::child(position, 1)) {
+                if let Some(right_slot) = self.child_slot(&children, right, 1) {
+                    pending.push((right, positions::level(right), right_slot));
+                };
+            };
+
+            // The path only goes through positions that can be addressed.
+            position = match positions::child(position, index) {
+                Some(child) => child,
+                None => return Err(NodeError::InvalidToken),
+            };
+            slot = match self.child_slot(&children, position, index) {
+                Some(slot) => slot,
+                None => return Err(NodeError::InvalidToken),
+            };
+        }
+
+        let children = self.page_children(slot)?;
+        for index in [1, 0] {
+            // The deepest positions have no children that can be addressed.
+            let child = match positions::child(after, index) {
+                Some(child) => child,
+                None => continue,
+            };
+            if let Some(child_slot) = self.child_slot(&children, child, index) {
+                pending.push((child, positions::level(child), child_slot));
+            };
+        }
+
+        Ok(pending)
+    }
+
+    /// The child pointers of the slot, which only persistent trees store.
+    fn page_children(&self, slot: u128) -> Result<[Option<u128>; 2], NodeError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Ok([None; 2]);
+        };
+
+        match self.read_slot_header(slot) {
+            Ok(contents) => Ok(contents.children),
+            Err(NodeError::Unexistent) => Err(NodeError::InvalidToken),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Add the enabled nodes at `level` after `after` to the page, from left
+    /// to right, until it has `limit` nodes. Returns whether any node was
+    /// stored at the level after `after`.
+    fn page_level(
+        &self,
+        root: u128,
+        level: u32,
+        after: Option<u128>,
+        limit: usize,
+        nodes: &mut Vec<NodeData>,
+    ) -> Result<bool, NodeError> {
+        let mut reached = false;
+
+        let mut pending = vec![(0, root)];
+        while let Some((position, slot)) = pending.pop() {
+            // Subtrees whose nodes at the level all come before `after` were
+            // read by earlier pages.
+            let depth = level - positions::level(position);
+            if after.is_some_and(|after| last_at_depth(position, depth) <= after) {
+                continue;
+            };
+
+            let contents = match depth {
+                0 => self.read_slot(slot),
+                _ => self.read_slot_header(slot),
+            };
+            let contents = match contents {
+                Ok(contents) => contents,
+                Err(NodeError::Unexistent) => continue,
+                Err(error) => return Err(error),
+            };
+
+            if depth == 0 {
+                reached = true;
+                if contents.enabled {
+                    nodes.push(NodeData {
+                        position,
+                        enabled: true,
+                        subitems: contents.subitems,
+                    });
+                    if nodes.len() == limit {
+                        break;
+                    };
+                };
+                continue;
+            };
+
+            // Pushed right first, so that the left child is visited first.
+            for index in [1, 0] {
+                let child = match positions::child(position, index) {
+                    Some(child) => child,
+                    None => continue,
+                };
+                if let Some(child_slot) = self.child_slot(&contents.children, child, index) {
+                    pending.push((child, child_slot));
+                };
+            }
+        }
+
+        Ok(reached)
+    }
+}
+
+/// The last position `depth` levels below `position` in its subtree.
+fn last_at_depth(position: u128, depth: u32) -> u128 {
+    match 1_u128
+        .checked_shl(depth)
+        .and_then(|width| position.checked_add(2)?.checked_mul(width))
+    {
+        Some(end) => end - 2,
+        None => u128::MAX,
+    }
+}
+
+/// The token resuming a traversal after `position`, read from `version`.
+fn encode_token(order: TraversalOrder, version: Option<u64>, position: u128) -> String {
+    let tag = match order {
+        TraversalOrder::PreOrder => 'p',
+        TraversalOrder::LevelOrder => 'l',
+    };
+
+    format!(
+        "{}{:x}.{:x}",
+        tag,
+        version.map_or(0, |version| version + 1),
+        position
+    )
+}
+
+fn decode_token(order: TraversalOrder, token: &str) -> Option<(Option<u64>, u128)> {
+    let tag = match order {
+        TraversalOrder::PreOrder => 'p',
+        TraversalOrder::LevelOrder => 'l',
+    };
+
+    let (version, position) = token.strip_prefix(tag)?.split_once('.')?;
+    let version = u64::from_str_radix(version, 16).ok()?;
+    let position = u128::from_str_radix(position, 16)
+        .ok()
+        .filter(|position| *position < u128::MAX)?;
+
+    Some((version.checked_sub(1), position))
+}
@@ -0,0 +1,290 @@
+//! Binary patches turning a tree file into a newer version of it, e.g. to
+//! update deployed read-only copies without shipping the whole file.
+//!
+//! A patch holds the size and digest of the tree file it applies to and the
+//! size of the patched file, followed by the bytes that differ. Each record
+//! is an offset (8 bytes), a length (4 bytes) and the bytes.
+
+use crate::cache::PAGE_SIZE;
+use crate::{
+    bitcodec, write_dirty, Layout, Storage, Tree, TreeFileError, TreeOpenMode, DIRTY_FLAG,
+};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+/// The size in bytes of the header of a patch.
+const PATCH_HEADER_SIZE: usize = 32;
+
+/// The size in bytes of the header of each record of a patch. Runs of
+/// changed bytes closer than this are sent as one record.
+const RECORD_HEADER_SIZE: u64 = 12;
+
+/// The maximum amount of bytes of a record.
+const MAX_RECORD_SIZE: u64 = u32::MAX as u64;
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The patch couldn't be written.
+    Write,
+
+    /// The patch couldn't be read.
+    Read,
+
+    /// The patch is truncated, or isn't a patch of a tree file.
+    Invalid,
+
+    /// The tree file isn't the one the patch was made from.
+    BaseMismatch,
+
+    /// The tree couldn't be read or written.
+    Tree(TreeFileError),
+}
+
+impl From<TreeFileError> for PatchError {
+    fn from(error: TreeFileError) -> Self {
+        PatchError::Tree(error)
+    }
+}
+
+/// Write a patch turning the tree file of `old` into the one of `new` to
+/// `writer`, and return the amount of bytes of the tree file it holds.
+///
+/// Only the bytes that differ are written, widened to whole nodes in trees
+/// whose nodes are stored one after the other, so a patch changing one node
+/// is a few bytes long. Both trees must have the same features, subitems,
+/// bit order and layout, or the patch fails with
+/// [`SchemaMismatch`](TreeFileError::SchemaMismatch), and trees with files
+/// kept next to the tree file can't be patched, like with
+/// [`backup_diff`](Tree::backup_diff).
+pub fn create_patch(old: &Tree, new: &Tree, mut writer: impl Write) -> Result<u64, PatchError> {
+    new.check_backup_features()?;
+
+    if old.features != new.features
+        || old.subitems != new.subitems
+        || old.bit_order != new.bit_order
+        || old.layout != new.layout
+    {
+        return Err(PatchError::Tree(TreeFileError::SchemaMismatch));
+    };
+
+    let (old_size, new_size) = match (old.storage.size(), new.storage.size()) {
+        (Ok(old_size), Ok(new_size)) => (old_size, new_size),
+        _ => return Err(PatchError::Tree(TreeFileError::FileNotOpened)),
+    };
+
+    // The bytes past the end of the old tree file always differ.
+    let mut runs: Vec<Range<u64>> = vec![];
+    let mut digest = Digest::default();
+    for offset in (0..old_size.max(new_size)).step_by(PAGE_SIZE as usize) {
+        let old_block = read_clean(&*old.storage, offset, old_size)?;
+        let new_block = read_clean(&*new.storage, offset, new_size)?;
+        digest.feed(&old_block);
+
+        for (i, byte) in new_block.iter().enumerate() {
+            if old_block.get(i) != Some(byte) {
+                let at = offset + i as u64;
+                push_run(&mut runs, new.widen(at..at + 1, new_size));
+            };
+        }
+    }
+
+    let mut header = [0_u8; PATCH_HEADER_SIZE];
+    header[0..8].copy_from_slice(&old_size.to_be_bytes());
+    header[8..16].copy_from_slice(&digest.0.to_be_bytes());
+    header[16..24].copy_from_slice(&new_size.to_be_bytes());
+    header[24..32].copy_from_slice(&(runs.len() as u64).to_be_bytes());
+    if writer.write_all(&header).is_err() {
+        return Err(PatchError::Write);
+    };
+
+    let mut patched = 0;
+    for run in runs {
+        let mut bytes = vec![0_u8; (run.end - run.start) as usize];
+        if new.storage.read_at(run.start, &mut bytes).is_err() {
+            return Err(PatchError::Tree(TreeFileError::Corrupted));
+        };
+        clean_dirty(run.start, &mut bytes);
+
+        if writer.write_all(&run.start.to_be_bytes()).is_err()
+            || writer
+                .write_all(&(bytes.len() as u32).to_be_bytes())
+                .is_err()
+            || writer.write_all(&bytes).is_err()
+        {
+            return Err(PatchError::Write);
+        };
+        patched += bytes.len() as u64;
+    }
+
+    if writer.flush().is_err() {
+        return Err(PatchError::Write);
+    };
+
+    Ok(patched)
+}
+
+/// Apply a patch made with [`create_patch`] to `tree`, turning its tree
+/// file into the new one. Fails with [`BaseMismatch`](PatchError::BaseMismatch)
+/// if the tree file isn't the old tree file of the patch, e.g. because it
+/// was patched already.
+pub fn apply_patch(tree: &mut Tree, mut reader: impl Read) -> Result<(), PatchError> {
+    tree.check_backup_features()?;
+
+    if tree.mode != TreeOpenMode::ReadWrite {
+        return Err(PatchError::Tree(TreeFileError::MissingPermissions));
+    };
+
+    let old_size = read_u64(&mut reader)?;
+    let old_digest = read_u64(&mut reader)?;
+    let new_size = read_u64(&mut reader)?;
+    let count = read_u64(&mut reader)?;
+
+    let size = match tree.storage.size() {
+        Ok(size) => size,
+        Err(_) => return Err(PatchError::Tree(TreeFileError::FileNotOpened)),
+    };
+    if size != old_size {
+        return Err(PatchError::BaseMismatch);
+    };
+
+    let mut digest = Digest::default();
+    for offset in (0..size).step_by(PAGE_SIZE as usize) {
+        digest.feed(&read_clean(&*tree.storage, offset, size)?);
+    }
+    if digest.0 != old_digest {
+        return Err(PatchError::BaseMismatch);
+    };
+
+    // The patch is read whole before the tree is changed.
+    let mut records = vec![];
+    for _ in 0..count {
+        let offset = read_u64(&mut reader)?;
+        let mut len = [0_u8; 4];
+        read_exact(&mut reader, &mut len)?;
+        let len = u32::from_be_bytes(len) as u64;
+
+        match offset.checked_add(len) {
+            Some(end) if end <= new_size => (),
+            _ => return Err(PatchError::Invalid),
+        };
+
+        let mut bytes = vec![0_u8; len as usize];
+        read_exact(&mut reader, &mut bytes)?;
+        records.push((offset, bytes));
+    }
+
+    if tree.set_storage_size(new_size).is_err() {
+        return Err(PatchError::Tree(TreeFileError::MissingPermissions));
+    };
+    tree.unpin_all();
+
+    for (offset, bytes) in records {
+        if tree.bulk(|tree| tree.write_bytes(offset, &bytes)).is_err() {
+            return Err(PatchError::Tree(TreeFileError::MissingPermissions));
+        };
+    }
+
+    // The patch never marks the tree file as open for writing, but the tree
+    // still is.
+    write_dirty(&*tree.storage, true)?;
+
+    Ok(())
+}
+
+impl Tree {
+    /// Widen a range of bytes of the tree file to the bytes of the nodes it
+    /// touches, if the nodes are stored one after the other.
+    fn widen(&self, range: Range<u64>, size: u64) -> Range<u64> {
+        let header_size = self.header_size as u64;
+        if matches!(self.layout, Layout::Columnar { .. }) || range.end <= header_size {
+            return range;
+        };
+
+        let node_size = self.node_size() as u64;
+        let first = (range.start.max(header_size) - header_size) * 8 / node_size;
+        let last = ((range.end - header_size) * 8 - 1) / node_size;
+
+        let start = match range.start < header_size {
+            true => range.start,
+            false => header_size + first * node_size / 8,
+        };
+        let end = header_size + ((last + 1) * node_size).div_ceil(8);
+
+        start..end.min(size)
+    }
+}
+
+/// Add a range of changed bytes to the runs, merging it with the last run
+/// if they're closer than a record header.
+fn push_run(runs: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if let Some(last) = runs.last_mut() {
+        if range.start <= last.end + RECORD_HEADER_SIZE && range.end - last.start <= MAX_RECORD_SIZE
+        {
+            last.end = last.end.max(range.end);
+            return;
+        };
+    };
+
+    runs.push(range);
+}
+
+/// Read the block of the tree file at `offset`, with the tree file marked as
+/// closed, so that trees open for writing can be compared.
+fn read_clean(storage: &dyn Storage, offset: u64, size: u64) -> Result<Vec<u8>, PatchError> {
+    if offset >= size {
+        return Ok(vec![]);
+    };
+
+    let mut block = vec![0_u8; PAGE_SIZE.min(size - offset) as usize];
+    if storage.read_at(offset, &mut block).is_err() {
+        return Err(PatchError::Tree(TreeFileError::Corrupted));
+    };
+    clean_dirty(offset, &mut block);
+
+    Ok(block)
+}
+
+/// Clear the flag marking the tree file as open for writing in `bytes`,
+/// read from `offset`.
+fn clean_dirty(offset: u64, bytes: &mut [u8]) {
+    let mut mask = [u8::MAX; 2];
+    bitcodec::pack_bits_at(&mut mask, DIRTY_FLAG, &[false]);
+
+    for (at, mask) in (10..12).zip(mask) {
+        if (offset..offset + bytes.len() as u64).contains(&at) {
+            bytes[(at - offset) as usize] &= mask;
+        };
+    }
+}
+
+/// The 64-bit FNV-1a hash of a tree file, fed one block at a time.
+struct Digest(u64);
+
+impl Default for Digest {
+    fn default() -> Self {
+        Digest(0xcbf29ce484222325)
+    }
+}
+
+impl Digest {
+    fn feed(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, PatchError> {
+    let mut bytes = [0_u8; 8];
+    read_exact(reader, &mut bytes)?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_exact(reader: &mut impl Read, bytes: &mut [u8]) -> Result<(), PatchError> {
+    match reader.read_exact(bytes) {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Err(PatchError::Invalid),
+        Err(_) => Err(PatchError::Read),
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{
+    bitcodec, history, positions, sidecar_path, Feature, NodeError, Operation, Slot, Storage, Tree,
+    TreeFileError, TreeOpenMode,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+/// The size in bytes of each entry of the version table.
+const VERSION_ENTRY_SIZE: u64 = 8;
+
+/// The amount of bytes of compacted slots buffered before they're written.
+const GC_WRITE_BYTES: usize = 64 * 1024;
+
+/// The result of collecting the unreachable slots of a persistent tree.
+#[derive(Debug)]
+pub struct GcReport {
+    /// The amount of versions that can still be opened.
+    pub retained_versions: u64,
+
+    /// The amount of slots that were removed from the tree file.
+    pub reclaimed_slots: u64,
+
+    /// The amount of bytes the tree file shrunk.
+    pub reclaimed_bytes: u64,
+}
+
+impl Tree {
+    /// Open an existent persistent tree file, pinned to one of its versions.
+    /// The tree is opened in read mode, as older versions can't be written.
+    ///
+    /// Recorded versions are never modified, so they can be opened while the
+    /// tree is open for writing.
+    pub fn open_version(file_path: &'static str, version: u64) -> Result<Self, TreeFileError> {
+        let mut tree = Tree::open_unclean(file_path, TreeOpenMode::Read)?;
+
+        if !tree.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        if version >= tree.version_count() || tree.version_root(version)?.is_none() {
+            return Err(TreeFileError::UnexistentVersion);
+        };
+
+        tree.version = Some(version);
+
+        Ok(tree)
+    }
+
+    /// The amount of versions recorded in the tree's version table. Trees
+    /// without the persistent feature have no versions.
+    pub fn version_count(&self) -> u64 {
+        match &self.versions {
+            Some(versions) => match versions.metadata() {
+                Ok(metadata) => metadata.len() / VERSION_ENTRY_SIZE,
+                Err(_) => 0,
+            },
+            None => 0,
+        }
+    }
+
+    /// The version being read. `None` if the tree isn't persistent or has no
+    /// versions yet.
+    pub fn version(&self) -> Option<u64> {
+        match self.version {
+            Some(version) => Some(version),
+            None => self.version_count().checked_sub(1),
+        }
+    }
+
+    /// Open (or create) the version table if the tree is persistent.
+    pub(crate) fn open_versions(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Ok(());
+        };
+
+        // A collection interrupted before the compacted tree file replaced
+        // the tree file is dropped, and one interrupted after it is
+        // finished, as the old version table points to the old slots.
+        let new_versions = sidecar_path(&self.path, "versions.gc");
+        if new_versions.exists() {
+            let new_tree = sidecar_path(&self.path, "gc");
+            let finished = match new_tree.exists() {
+                true => fs::remove_file(new_tree).and_then(|_| fs::remove_file(new_versions)),
+                false => fs::rename(new_versions, sidecar_path(&self.path, "versions")),
+            };
+            if finished.is_err() {
+                return Err(TreeFileError::FileNotOpened);
+            };
+        };
+
+        self.versions = Some(self.open_sidecar("versions", create)?);
+
+        Ok(())
+    }
+
+    /// Reclaim the slots that aren't reachable from any of the retained
+    /// versions. The latest version, the versions of the savepoints and the
+    /// version the active transaction started from are always retained. Collected versions
+    /// keep their numbers, but can't be opened anymore.
+    ///
+    /// The compacted tree file and version table are written next to them
+    /// and renamed over them, so a crash leaves either the old or the new
+    /// ones, and trees opened with [`open_version`](Tree::open_version)
+    /// keep reading the old ones. The tree must be opened from its file, and
+    /// fails with [`UnsupportedFeature`](TreeFileError::UnsupportedFeature)
+    /// while its storage is shared, e.g. with
+    /// [`SubtreeWriter`](crate::SubtreeWriter)s.
+    pub fn gc(&mut self, retain_versions: &[u64]) -> Result<GcReport, TreeFileError> {
+        let report = self
+            .bulk(|tree| tree.traced(Operation::Gc, None, |tree| tree.collect(retain_versions)));
+        self.record_gc(retain_versions, report.is_ok());
+        let report = report?;
+
+        match self.record_audit(Operation::Gc, None, None, None) {
+            Ok(_) => Ok(report),
+            Err(_) => Err(TreeFileError::MissingPermissions),
+        }
+    }
+
+    fn collect(&mut self, retain_versions: &[u64]) -> Result<GcReport, TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // The tree file is replaced, so the handles sharing its storage
+        // would keep writing to the old one.
+        if self.path.as_os_str().is_empty() || Arc::strong_count(&self.storage) > 1 {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        // Slots are about to move, so the pinned pages would hold other
+        // nodes.
+        self.unpin_all();
+
+        let version_count = self.version_count();
+        let latest = match version_count.checked_sub(1) {
+            Some(latest) => latest,
+            None => {
+                return Ok(GcReport {
+                    retained_versions: 0,
+                    reclaimed_slots: 0,
+                    reclaimed_bytes: 0,
+                })
+            }
+        };
+
+        let saved: Vec<u64> = self
+            .savepoints()?
+            .into_iter()
+            .map(|(_, version)| version)
+            .chain(self.transaction_start()?)
+            .collect();
+
+        let mut roots: Vec<Option<u128>> = vec![];
+        for version in 0..version_count {
+            if version == latest || retain_versions.contains(&version) || saved.contains(&version) {
+                roots.push(self.version_root(version)?);
+            } else {
+                roots.push(None);
+            }
+        }
+
+        // Mark every slot reachable from a retained root.
+        let slots = self.nodes();
+        let mut reachable = match usize::try_from(slots) {
+            Ok(slots) => vec![false; slots],
+            Err(_) => return Err(TreeFileError::OffsetOverflow),
+        };
+        let mut pending: Vec<u128> = roots.iter().flatten().copied().collect();
+        while let Some(slot) = pending.pop() {
+            if slot >= slots as u128 {
+                return Err(TreeFileError::Corrupted);
+            };
+
+            if reachable[slot as usize] {
+                continue;
+            };
+            reachable[slot as usize] = true;
+
+            match self.read_slot(slot) {
+                Ok(contents) => pending.extend(contents.children.iter().flatten()),
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+        }
+
+        let live: Vec<u128> = (0..slots as u128)
+            .filter(|slot| reachable[*slot as usize])
+            .collect();
+        let relocate = |slot: u128| live.binary_search(&slot).unwrap() as u128;
+
+        let old_size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        let new_tree = sidecar_path(&self.path, "gc");
+        let new_size = match self.write_compacted(&new_tree, &live, relocate) {
+            Ok(new_size) => new_size,
+            Err(error) => {
+                let _ = fs::remove_file(&new_tree);
+                return Err(error);
+            }
+        };
+
+        let mut table = vec![];
+        for root in &roots {
+            let entry = match root {
+                Some(slot) => relocate(*slot) as u64 + 1,
+                None => 0,
+            };
+            table.extend(bitcodec::u64_to_u8_array(entry));
+        }
+
+        // The tree file is replaced first: once it is, the version table
+        // written next to it is the only one matching it.
+        let new_versions = sidecar_path(&self.path, "versions.gc");
+        let written = File::create(&new_versions)
+            .and_then(|mut file| file.write_all(&table).and_then(|_| file.sync_all()));
+        if written.is_err() {
+            let _ = fs::remove_file(&new_tree);
+            let _ = fs::remove_file(&new_versions);
+            return Err(TreeFileError::MissingPermissions);
+        };
+        if fs::rename(&new_tree, &self.path).is_err() {
+            let _ = fs::remove_file(&new_tree);
+            let _ = fs::remove_file(&new_versions);
+            return Err(TreeFileError::MissingPermissions);
+        };
+        if fs::rename(&new_versions, sidecar_path(&self.path, "versions")).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        let storage = match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        self.storage = Arc::new(storage);
+        self.versions = Some(self.open_sidecar("versions", false)?);
+
+        // The pages read while compacting hold the old slots, and every slot
+        // after the headers may have moved.
+        self.unpin_all();
+        let marked = self
+            .mark_revisions(
+                self.header_size as u64,
+                (new_size - self.header_size as u64) as usize,
+            )
+            .and_then(|_| self.truncate_revisions(new_size));
+        if marked.is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        Ok(GcReport {
+            retained_versions: roots.iter().filter(|root| root.is_some()).count() as u64,
+            reclaimed_slots: slots - live.len() as u64,
+            reclaimed_bytes: old_size - new_size,
+        })
+    }
+
+    /// Write a copy of the tree file at `path` holding only the `live` slots,
+    /// in order, with their child pointers moved by `relocate`. Returns the
+    /// size of the copy.
+    fn write_compacted(
+        &mut self,
+        path: &std::path::Path,
+        live: &[u128],
+        relocate: impl Fn(u128) -> u128,
+    ) -> Result<u64, TreeFileError> {
+        let mut headers = vec![0_u8; self.header_size];
+        if self.storage.read_at(0, &mut headers).is_err() {
+            return Err(TreeFileError::MissingHeaders);
+        };
+
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(_) => return Err(TreeFileError::MissingPermissions),
+        };
+        let mut writer = BufWriter::new(file);
+        if writer.write_all(&headers).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Slots are packed one after the other, and written a whole byte at
+        // a time.
+        let mut bits: Vec<bool> = vec![];
+        for (index, slot) in live.iter().enumerate() {
+            let mut contents = match self.read_slot(*slot) {
+                Ok(contents) => contents,
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+            for child in contents.children.iter_mut() {
+                *child = child.map(&relocate);
+            }
+
+            match self.encode_slot(&contents) {
+                Ok(encoded) => bits.extend(encoded),
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+
+            let last = index + 1 == live.len();
+            if bits.len() >= GC_WRITE_BYTES * 8 || last {
+                let whole = match last {
+                    true => bits.len(),
+                    false => bits.len() / 8 * 8,
+                };
+                let bytes = self.bit_order.bits_to_bytes(&bits[..whole]);
+                self.throttle(bytes.len() as u64);
+                self.io().bytes_written += bytes.len() as u64;
+                if writer.write_all(&bytes).is_err() {
+                    return Err(TreeFileError::MissingPermissions);
+                };
+                bits.drain(..whole);
+            };
+        }
+
+        let file = match writer.into_inner() {
+            Ok(file) => file,
+            Err(_) => return Err(TreeFileError::MissingPermissions),
+        };
+        if file.sync_all().is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        Ok(self.header_size as u64 + (live.len() as u64 * self.node_size() as u64).div_ceil(8))
+    }
+
+    /// The slot holding the root of the version being read.
+    pub(crate) fn root_slot(&self) -> Result<Option<u128>, NodeError> {
+        let version = match self.version() {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+
+        match self.version_root(version) {
+            Ok(root) => Ok(root),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+
+    /// The slot holding the root of a version. `None` if the version was
+    /// collected.
+    pub(crate) fn version_root(&self, version: u64) -> Result<Option<u128>, TreeFileError> {
+        let versions = match &self.versions {
+            Some(versions) => versions,
+            None => return Err(TreeFileError::MissingFeature),
+        };
+
+        let mut entry = [0_u8; VERSION_ENTRY_SIZE as usize];
+        match versions.read_at(version * VERSION_ENTRY_SIZE, &mut entry) {
+            Ok(_) => (),
+            Err(_) => return Err(TreeFileError::UnexistentVersion),
+        };
+
+        Ok(bitcodec::u8_array_to_u64(&entry)
+            .checked_sub(1)
+            .map(u128::from))
+    }
+
+    /// Drop the versions after the first `count`.
+    pub(crate) fn truncate_versions(&mut self, count: u64) -> Result<(), NodeError> {
+        let versions = match &mut self.versions {
+            Some(versions) => versions,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        if versions.set_len(count * VERSION_ENTRY_SIZE).is_err() {
+            return Err(NodeError::Unexistent);
+        };
+
+        // The timestamps of the dropped versions would be taken by the next
+        // versions otherwise.
+        match &self.timestamps {
+            Some(timestamps) => match timestamps.set_len(count * history::TIMESTAMP_ENTRY_SIZE) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(NodeError::Unexistent),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Append a new version whose root is stored in `root`.
+    pub(crate) fn push_version(&mut self, root: u128) -> Result<(), NodeError> {
+        let versions = match &mut self.versions {
+            Some(versions) => versions,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        match versions.seek(SeekFrom::End(0)) {
+            Ok(_) => (),
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        match versions.write_all(&bitcodec::u64_to_u8_array(root as u64 + 1)) {
+            Ok(_) => (),
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        Ok(())
+    }
+
+    /// Write a node without touching any existing slot. The node and each of
+    /// its ancestors are copied into new slots at the end of the file, and
+    /// the copied root is recorded as a new version. Missing ancestors are
+    /// created disabled.
+    pub(crate) fn set_node_persistent(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<(), NodeError> {
+        let path = positions::path(position);
+
+        // The slots currently holding the root and each node down to
+        // `position`, if they exist.
+        let mut existing: Vec<Option<Slot>> = vec![];
+        let mut slot = self.root_slot()?;
+        for depth in 0..=path.len() {
+            let contents = match slot {
+                Some(slot) => Some(self.read_slot(slot)?),
+                None => None,
+            };
+
+            slot = match (&contents, path.get(depth)) {
+                (Some(contents), Some(index)) => contents.children[*index as usize],
+                _ => None,
+            };
+            existing.push(contents);
+        }
+
+        let empty_subitems: Vec<Vec<bool>> = self
+            .subitems
+            .iter()
+            .map(|size| vec![false; *size as usize])
+            .collect();
+
+        // Copy the path bottom-up, so that every copy can point to the copy
+        // of its child.
+        let first_slot = self.nodes() as u128;
+        let mut child: Option<(u8, u128, bool)> = None;
+        for (depth, contents) in existing.into_iter().enumerate().rev() {
+            let mut contents = match contents {
+                Some(contents) => contents,
+                None => Slot {
+                    enabled: false,
+                    children: [None, None],
+                    hints: [false, false],
+                    subitems: empty_subitems.clone(),
+                },
+            };
+
+            if depth == path.len() {
+                contents.enabled = !disabled;
+                contents.subitems = subitems.to_vec();
+            };
+
+            if let Some((index, slot, enabled)) = child {
+                contents.children[index as usize] = Some(slot);
+                contents.hints[index as usize] = enabled;
+            };
+
+            let slot = first_slot + (path.len() - depth) as u128;
+            self.write_slot(slot, &contents)?;
+
+            if depth > 0 {
+                child = Some((path[depth - 1], slot, contents.enabled));
+            };
+        }
+
+        self.push_version(first_slot + path.len() as u128)?;
+
+        match self.push_timestamp() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(NodeError::Unexistent),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Placement strategies: which position a new node is written at, for the
+//! layers storing nodes wherever there's room (e.g. a free list of records)
+//! rather than at a position of their own. The slot the position is stored
+//! in is then found with the tree's [`Layout`](crate::Layout), or appended
+//! in persistent trees.
+
+use crate::{Feature, NodeError, Tree};
+use std::collections::BTreeSet;
+
+/// Decides the position of each node inserted with
+/// [`Tree::insert_node`], and is told about the nodes written and removed
+/// through it, so it can keep track of the room left without reading the
+/// tree again.
+pub trait PlacementStrategy {
+    /// The position the next node is written at.
+    fn place(&mut self, tree: &Tree) -> Result<u128, NodeError>;
+
+    /// Called once a node was written at `position`.
+    fn placed(&mut self, _position: u128) {}
+
+    /// Called once the node at `position` was removed with
+    /// [`Tree::remove_node`], so it can be reused.
+    fn released(&mut self, _position: u128) {}
+}
+
+/// Places nodes at the first position without an enabled node, in level
+/// order, so the tree is filled level by level from the root and removed
+/// nodes are reused shallowest first. The default strategy.
+#[derive(Debug, Default)]
+pub struct LevelOrderPlacement {
+    /// No position before it is free.
+    next: u128,
+}
+
+impl PlacementStrategy for LevelOrderPlacement {
+    fn place(&mut self, tree: &Tree) -> Result<u128, NodeError> {
+        let limit = tree.layout.position_limit();
+
+        while tree.has_enabled_node(self.next) {
+            self.next += 1;
+        }
+        match limit {
+            Some(limit) if self.next >= limit => Err(NodeError::OutsideLayout),
+            _ => Ok(self.next),
+        }
+    }
+
+    fn released(&mut self, position: u128) {
+        self.next = self.next.min(position);
+    }
+}
+
+/// Places nodes past the last node stored, never reusing positions, so
+/// nodes are stored in the order they're inserted.
+#[derive(Debug, Default)]
+pub struct AppendOnlyPlacement {
+    /// The position after the last node stored, once it was found.
+    next: Option<u128>,
+}
+
+impl PlacementStrategy for AppendOnlyPlacement {
+    fn place(&mut self, tree: &Tree) -> Result<u128, NodeError> {
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                let mut next = 0;
+                for position in tree.positions()? {
+                    next = next.max(position? + 1);
+                }
+                *self.next.insert(next)
+            }
+        };
+
+        match tree.layout.position_limit() {
+            Some(limit) if next >= limit => Err(NodeError::OutsideLayout),
+            _ => Ok(next),
+        }
+    }
+
+    fn placed(&mut self, position: u128) {
+        self.next = Some(self.next.unwrap_or(0).max(position + 1));
+    }
+}
+
+/// Places nodes at the lowest position removed through it, if there's one,
+/// and past the last node stored otherwise. Positions removed before the
+/// strategy was made aren't known to it.
+#[derive(Debug, Default)]
+pub struct FreeListPlacement {
+    /// The positions removed and not written since.
+    free: BTreeSet<u128>,
+
+    /// Where nodes go once no removed position is left.
+    append: AppendOnlyPlacement,
+}
+
+impl PlacementStrategy for FreeListPlacement {
+    fn place(&mut self, tree: &Tree) -> Result<u128, NodeError> {
+        match self.free.first() {
+            Some(position) => Ok(*position),
+            None => self.append.place(tree),
+        }
+    }
+
+    fn placed(&mut self, position: u128) {
+        self.free.remove(&position);
+        self.append.placed(position);
+    }
+
+    fn released(&mut self, position: u128) {
+        self.free.insert(position);
+    }
+}
+
+impl Tree {
+    /// Write a new node at the position `strategy` places it at, and return
+    /// the position. Fails with
+    /// [`NodeAlreadyExists`](NodeError::NodeAlreadyExists) if the strategy
+    /// places it over an enabled node.
+    pub fn insert_node(
+        &mut self,
+        strategy: &mut impl PlacementStrategy,
+        subitems: &[Vec<bool>],
+    ) -> Result<u128, NodeError> {
+        let position = strategy.place(self)?;
+
+        self.set_node_quiet(subitems, &position, false, false)?;
+        strategy.placed(position);
+
+        Ok(position)
+    }
+
+    /// Disable the node at `position` and hand the position back to
+    /// `strategy` to be reused. The node's children are disabled with it,
+    /// and read again if a node is inserted at the position, so only leaves
+    /// should be removed.
+    pub fn remove_node(
+        &mut self,
+        strategy: &mut impl PlacementStrategy,
+        position: u128,
+    ) -> Result<(), NodeError> {
+        if !self.features.contains(&Feature::Disabling) {
+            return Err(NodeError::MissingFeature);
+        };
+
+        let node = self.read_node(position)?;
+        self.set_node_quiet(&node.subitems, &position, true, true)?;
+        strategy.released(position);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Arithmetic over tranversal positions. Position 0 is the root, and the
+//! children of position `n` are at `2n + 1` and `2n + 2`.
+//!
+//! Every position up to `u128::MAX` has a level and a path, but the
+//! children of the deepest ones can't be addressed, so [`child`] returns
+//! `None` for them rather than overflowing.
+
+pub fn level(position: u128) -> u32 {
+    match position.checked_add(1) {
+        Some(n) => n.ilog2(),
+        None => u128::BITS,
+    }
+}
+
+pub fn parent(position: u128) -> u128 {
+    (position - 1) / 2
+}
+
+/// The position of a child of `position`, if it can be addressed.
+pub fn child(position: u128, index: u8) -> Option<u128> {
+    position.checked_mul(2)?.checked_add(1 + index as u128)
+}
+
+/// The child indexes followed from the root to reach `position`.
+pub fn path(position: u128) -> Vec<u8> {
+    // `u128::MAX` is reached by always taking the left child, as it's the
+    // first position of the level past the largest `u128`.
+    let n = match position.checked_add(1) {
+        Some(n) => n,
+        None => return vec![0; u128::BITS as usize],
+    };
+
+    (0..n.ilog2()).rev().map(|i| ((n >> i) & 1) as u8).collect()
+}
+
+/// Whether `position` is `ancestor` or one of its descendants.
+pub fn is_descendant(mut position: u128, ancestor: u128) -> bool {
+    while position > ancestor {
+        position = parent(position);
+    }
+
+    position == ancestor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_positions_in_level_order() {
+        assert_eq!(level(0), 0);
+        assert_eq!((level(1), level(2), level(3)), (1, 1, 2));
+        assert_eq!(
+            (child(0, 0), child(0, 1), child(2, 0)),
+            (Some(1), Some(2), Some(5))
+        );
+        assert_eq!((parent(1), parent(2), parent(5)), (0, 0, 2));
+        assert_eq!(path(0), Vec::<u8>::new());
+        assert_eq!(path(5), vec![1, 0]);
+    }
+
+    #[test]
+    fn handles_the_deepest_positions() {
+        assert_eq!(level(u128::MAX), 128);
+        assert_eq!(level(u128::MAX - 1), 127);
+        assert_eq!(path(u128::MAX), vec![0; 128]);
+        assert_eq!(path(u128::MAX - 1).len(), 127);
+
+        let last_parent = u128::MAX >> 1;
+        assert_eq!(child(last_parent - 1, 1), Some(u128::MAX - 1));
+        assert_eq!(child(last_parent, 0), Some(u128::MAX));
+        assert_eq!(child(last_parent, 1), None);
+        assert_eq!(child(u128::MAX, 0), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! Versioned request and response types for reading and writing trees over
+//! a network, with their wire encoding, for network layers built outside
+//! the crate. They build without the `std` feature.
+//!
+//! Every message is encoded as the protocol version (2 bytes), a tag (1
+//! byte) and its fields, with numbers in big endian. Subitems are sent as
+//! their amount of bits (4 bytes) followed by the bits packed into bytes,
+//! and lists and strings as their length (4 bytes) followed by their items.
+
+use crate::{bitcodec, NodeData};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The version of the protocol, sent at the start of every message.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Read a node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetNode {
+    pub position: u128,
+}
+
+/// Write a node, like [`set_node`](crate::Tree::set_node).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetNode {
+    pub position: u128,
+    pub subitems: Vec<Vec<bool>>,
+    pub overwrite: bool,
+    pub disabled: bool,
+}
+
+/// Read the nodes of a subtree, like [`traverse`](crate::Tree::traverse).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Traverse {
+    pub position: u128,
+    pub max_depth: Option<u32>,
+    pub include_disabled: bool,
+}
+
+/// Receive a [`Changed`](Response::Changed) response for every later write
+/// to the subtree rooted at `position`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscribe {
+    pub position: u128,
+}
+
+/// A request to a server holding a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    GetNode(GetNode),
+    SetNode(SetNode),
+    Traverse(Traverse),
+    Subscribe(Subscribe),
+}
+
+/// A response of a server holding a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The node read by a [`GetNode`] request.
+    Node(NodeData),
+
+    /// The nodes read by a [`Traverse`] request, in pre-order.
+    Nodes(Vec<NodeData>),
+
+    /// A [`SetNode`] request was written.
+    Written,
+
+    /// A [`Subscribe`] request was accepted.
+    Subscribed,
+
+    /// A node of a subscribed subtree was written. `node` is `None` if it's
+    /// no longer stored.
+    Changed {
+        position: u128,
+        node: Option<NodeData>,
+    },
+
+    /// The request failed, with the name of the error (e.g. `Unexistent`).
+    Error(String),
+}
+
+/// Why a message couldn't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// The message is of another version of the protocol.
+    UnsupportedVersion(u16),
+
+    /// The message has an unknown tag.
+    UnknownMessage(u8),
+
+    /// The message ends before its last field.
+    Truncated,
+
+    /// The message has bytes after its last field, or a field is invalid.
+    Invalid,
+}
+
+impl Request {
+    /// Encode the request as a message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        match self {
+            Request::GetNode(request) => {
+                encoder.u8(0);
+                encoder.u128(request.position);
+            }
+            Request::SetNode(request) => {
+                encoder.u8(1);
+                encoder.u128(request.position);
+                encoder.subitems(&request.subitems);
+                encoder.bool(request.overwrite);
+                encoder.bool(request.disabled);
+            }
+            Request::Traverse(request) => {
+                encoder.u8(2);
+                encoder.u128(request.position);
+                match request.max_depth {
+                    Some(depth) => {
+                        encoder.bool(true);
+                        encoder.u32(depth);
+                    }
+                    None => encoder.bool(false),
+                };
+                encoder.bool(request.include_disabled);
+            }
+            Request::Subscribe(request) => {
+                encoder.u8(3);
+                encoder.u128(request.position);
+            }
+        };
+
+        encoder.bytes
+    }
+
+    /// Decode a message holding a request.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes)?;
+
+        let request = match decoder.u8()? {
+            0 => Request::GetNode(GetNode {
+                position: decoder.u128()?,
+            }),
+            1 => Request::SetNode(SetNode {
+                position: decoder.u128()?,
+                subitems: decoder.subitems()?,
+                overwrite: decoder.bool()?,
+                disabled: decoder.bool()?,
+            }),
+            2 => Request::Traverse(Traverse {
+                position: decoder.u128()?,
+                max_depth: match decoder.bool()? {
+                    true => Some(decoder.u32()?),
+                    false => None,
+                },
+                include_disabled: decoder.bool()?,
+            }),
+            3 => Request::Subscribe(Subscribe {
+                position: decoder.u128()?,
+            }),
+            tag => return Err(ProtocolError::UnknownMessage(tag)),
+        };
+
+        decoder.finish(request)
+    }
+}
+
+impl Response {
+    /// Encode the response as a message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        match self {
+            Response::Node(node) => {
+                encoder.u8(0);
+                encoder.node(node);
+            }
+            Response::Nodes(nodes) => {
+                encoder.u8(1);
+                encoder.u32(nodes.len() as u32);
+                for node in nodes {
+                    encoder.node(node);
+                }
+            }
+            Response::Written => encoder.u8(2),
+            Response::Subscribed => encoder.u8(3),
+            Response::Changed { position, node } => {
+                encoder.u8(4);
+                encoder.u128(*position);
+                match node {
+                    Some(node) => {
+                        encoder.bool(true);
+                        encoder.node(node);
+                    }
+                    None => encoder.bool(false),
+                };
+            }
+            Response::Error(name) => {
+                encoder.u8(5);
+                encoder.u32(name.len() as u32);
+                encoder.bytes.extend_from_slice(name.as_bytes());
+            }
+        };
+
+        encoder.bytes
+    }
+
+    /// Decode a message holding a response.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Decoder::new(bytes)?;
+
+        let response = match decoder.u8()? {
+            0 => Response::Node(decoder.node()?),
+            1 => {
+                let count = decoder.u32()?;
+                let mut nodes = Vec::new();
+                for _ in 0..count {
+                    nodes.push(decoder.node()?);
+                }
+                Response::Nodes(nodes)
+            }
+            2 => Response::Written,
+            3 => Response::Subscribed,
+            4 => Response::Changed {
+                position: decoder.u128()?,
+                node: match decoder.bool()? {
+                    true => Some(decoder.node()?),
+                    false => None,
+                },
+            },
+            5 => {
+                let len = decoder.u32()? as usize;
+                match String::from_utf8(decoder.take(len)?.to_vec()) {
+                    Ok(name) => Response::Error(name),
+                    Err(_) => return Err(ProtocolError::Invalid),
+                }
+            }
+            tag => return Err(ProtocolError::UnknownMessage(tag)),
+        };
+
+        decoder.finish(response)
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self {
+            bytes: PROTOCOL_VERSION.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u128(&mut self, value: u128) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn subitems(&mut self, subitems: &[Vec<bool>]) {
+        self.u32(subitems.len() as u32);
+        for subitem in subitems {
+            self.u32(subitem.len() as u32);
+            self.bytes.extend(bitcodec::bits_to_bytes(subitem));
+        }
+    }
+
+    fn node(&mut self, node: &NodeData) {
+        self.u128(node.position);
+        self.bool(node.enabled);
+        self.subitems(&node.subitems);
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Result<Self, ProtocolError> {
+        let mut decoder = Self { bytes };
+
+        let version = u16::from_be_bytes([decoder.u8()?, decoder.u8()?]);
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        };
+
+        Ok(decoder)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.bytes.len() < len {
+            return Err(ProtocolError::Truncated);
+        };
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ProtocolError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProtocolError::Invalid),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let mut bytes = [0_u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u128(&mut self) -> Result<u128, ProtocolError> {
+        let mut bytes = [0_u8; 16];
+        bytes.copy_from_slice(self.take(16)?);
+        Ok(u128::from_be_bytes(bytes))
+    }
+
+    fn subitems(&mut self) -> Result<Vec<Vec<bool>>, ProtocolError> {
+        let count = self.u32()?;
+
+        let mut subitems = Vec::new();
+        for _ in 0..count {
+            let len = self.u32()? as usize;
+            let mut bits = bitcodec::bytes_to_bits(self.take(len.div_ceil(8))?);
+            bits.truncate(len);
+            subitems.push(bits);
+        }
+
+        Ok(subitems)
+    }
+
+    fn node(&mut self) -> Result<NodeData, ProtocolError> {
+        Ok(NodeData {
+            position: self.u128()?,
+            enabled: self.bool()?,
+            subitems: self.subitems()?,
+        })
+    }
+
+    /// Check that the message has no bytes left.
+    fn finish<T>(self, message: T) -> Result<T, ProtocolError> {
+        match self.bytes.is_empty() {
+            true => Ok(message),
+            false => Err(ProtocolError::Invalid),
+        }
+    }
+}
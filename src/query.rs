@@ -0,0 +1,472 @@
+//! Filter expressions over the nodes of a tree, e.g.
+//! `subitem0 >= 10 and (level < 3 or not is_leaf)`.
+
+use crate::{bitcodec, positions, NodeData, NodeError, Traversal, TraversalOptions, Tree};
+use std::cmp::Ordering;
+use std::fmt;
+use std::vec;
+
+/// The share of the indexed nodes up to which a condition is looked up in
+/// its index, instead of scanning every node. Scans read the tree file in
+/// order, while the nodes found by an index are read one at a time.
+const QUERY_MAX_INDEX_SELECTIVITY: f64 = 0.2;
+
+/// Why a filter expression couldn't be compiled. `offset` is the byte where
+/// the problem was found.
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    /// The expression isn't valid.
+    Syntax { offset: usize },
+
+    /// The field doesn't exist, or is a subitem longer than 64 bits.
+    UnknownField { offset: usize },
+}
+
+/// A value of a node that can be compared.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Position,
+    Level,
+    Subitem(usize),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Compare(Field, Vec<Ordering>, u128),
+    IsLeaf,
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// How many nodes an index finds for a condition of a filter expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEstimate {
+    /// The subitem the condition compares, the first subitem of the index.
+    pub subitem: usize,
+
+    /// The amount of nodes the index finds for the condition.
+    pub matches: u64,
+
+    /// The amount of nodes in the index.
+    pub indexed: u64,
+}
+
+impl IndexEstimate {
+    /// The share of the indexed nodes the index finds.
+    pub fn selectivity(&self) -> f64 {
+        match self.indexed {
+            0 => 0.0,
+            indexed => self.matches as f64 / indexed as f64,
+        }
+    }
+}
+
+/// How a [`Query`] finds its nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryPlan {
+    /// Every enabled node is read and checked against the filter. Holds the
+    /// most selective index there was for a condition, if any, which found
+    /// too many nodes to be worth it.
+    Scan { best: Option<IndexEstimate> },
+
+    /// Only the nodes found by the index of a condition are read, and
+    /// checked against the whole filter.
+    Index(IndexEstimate),
+}
+
+#[derive(Debug)]
+enum Source<'a> {
+    Scan(Traversal<'a>),
+    Index(&'a Tree, vec::IntoIter<u128>),
+}
+
+/// The enabled nodes of a tree matching a filter expression, in pre-order
+/// like [`traverse`](Tree::traverse), whichever [`QueryPlan`] is used.
+#[derive(Debug)]
+pub struct Query<'a> {
+    source: Source<'a>,
+    filter: Expr,
+    plan: QueryPlan,
+}
+
+impl Tree {
+    /// Scan the enabled nodes matching a filter expression. Expressions
+    /// compare `position`, `level` or a subitem (`subitem0`, `subitem1`,
+    /// ...) against a number with `=`, `!=`, `<`, `<=`, `>` or `>=`, check
+    /// whether nodes are leaves with `is_leaf`, and combine those with
+    /// `not`, `and`, `or` and parentheses.
+    ///
+    /// Subitems are read as unsigned numbers, so subitems longer than 64
+    /// bits can't be compared.
+    ///
+    /// When a condition that every matching node meets compares a subitem
+    /// indexed by [`create_index`](Tree::create_index), and the index finds
+    /// few enough nodes for it, only those nodes are read. See
+    /// [`Query::explain`].
+    pub fn query(&self, expr: &str) -> Result<Query<'_>, QueryError> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            next: 0,
+            subitems: &self.subitems,
+            end: expr.len(),
+        };
+
+        let filter = parser.or()?;
+        if parser.next < tokens.len() {
+            return Err(QueryError::Syntax {
+                offset: tokens[parser.next].0,
+            });
+        };
+
+        // The condition with the fewest nodes in its index.
+        let mut conditions = vec![];
+        conjuncts(&filter, &mut conditions);
+        let mut best: Option<(IndexEstimate, Vec<u128>)> = None;
+        for condition in conditions {
+            if let Some((estimate, found)) = self.estimate(condition) {
+                if best
+                    .as_ref()
+                    .is_none_or(|(best, _)| estimate.matches < best.matches)
+                {
+                    best = Some((estimate, found));
+                };
+            };
+        }
+
+        let (source, plan) = match best {
+            Some((estimate, mut found))
+                if estimate.selectivity() <= QUERY_MAX_INDEX_SELECTIVITY =>
+            {
+                found.sort_unstable_by_key(|position| positions::path(*position));
+                (
+                    Source::Index(self, found.into_iter()),
+                    QueryPlan::Index(estimate),
+                )
+            }
+            best => (
+                Source::Scan(self.traverse(0, TraversalOptions::default())),
+                QueryPlan::Scan {
+                    best: best.map(|(estimate, _)| estimate),
+                },
+            ),
+        };
+
+        Ok(Query {
+            source,
+            filter,
+            plan,
+        })
+    }
+
+    /// Look up a condition comparing a subitem in an index starting with it.
+    fn estimate(&self, condition: &Expr) -> Option<(IndexEstimate, Vec<u128>)> {
+        let (index, orderings, value) = match condition {
+            Expr::Compare(Field::Subitem(index), orderings, value) => (*index, orderings, *value),
+            _ => return None,
+        };
+
+        let size = self.subitems[index];
+        let max = (1_u128 << size) - 1;
+        let (low, high) = match orderings.as_slice() {
+            [Ordering::Equal] => (value, value),
+            // Nothing is below zero.
+            [Ordering::Less] if value == 0 => (1, 0),
+            [Ordering::Less] => (0, value - 1),
+            [Ordering::Less, Ordering::Equal] => (0, value),
+            [Ordering::Greater] => (value.saturating_add(1), max),
+            [Ordering::Greater, Ordering::Equal] => (value, max),
+            _ => return None,
+        };
+
+        let (found, indexed) = match low <= high.min(max) {
+            true => self.indexed_between(
+                index,
+                &bitcodec::u64_to_bits(low as u64, size),
+                &bitcodec::u64_to_bits(high.min(max) as u64, size),
+            )?,
+            false => (vec![], self.indexed_between(index, &[], &[])?.1),
+        };
+
+        Some((
+            IndexEstimate {
+                subitem: index,
+                matches: found.len() as u64,
+                indexed,
+            },
+            found,
+        ))
+    }
+}
+
+impl Query<'_> {
+    /// How the nodes are found.
+    pub fn plan(&self) -> &QueryPlan {
+        &self.plan
+    }
+
+    /// Describe how the nodes are found, and why.
+    pub fn explain(&self) -> String {
+        self.plan.to_string()
+    }
+}
+
+impl Iterator for Query<'_> {
+    type Item = Result<NodeData, NodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, tree) = match &mut self.source {
+                Source::Scan(traversal) => (traversal.next()?.map(Some), traversal.tree),
+                Source::Index(tree, found) => (read_node(tree, found.next()?), *tree),
+            };
+            let node = match node {
+                Ok(Some(node)) => node,
+                Ok(None) => continue,
+                Err(error) => return Some(Err(error)),
+            };
+
+            match evaluate(&self.filter, &node, tree) {
+                Ok(true) => return Some(Ok(node)),
+                Ok(false) => (),
+                Err(error) => return Some(Err(error)),
+            };
+        }
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryPlan::Scan { best: None } => write!(
+                f,
+                "scan every node: no condition that every match meets compares an indexed subitem"
+            ),
+            QueryPlan::Scan {
+                best: Some(estimate),
+            } => write!(
+                f,
+                "scan every node: the index of subitem{} finds {} of {} nodes ({:.1}%), over the {:.0}% worth looking up",
+                estimate.subitem,
+                estimate.matches,
+                estimate.indexed,
+                estimate.selectivity() * 100.0,
+                QUERY_MAX_INDEX_SELECTIVITY * 100.0
+            ),
+            QueryPlan::Index(estimate) => write!(
+                f,
+                "look up subitem{} in its index: it finds {} of {} nodes ({:.1}%), each read and checked against the filter",
+                estimate.subitem,
+                estimate.matches,
+                estimate.indexed,
+                estimate.selectivity() * 100.0
+            ),
+        }
+    }
+}
+
+/// The conditions that every node matching `expr` meets.
+fn conjuncts<'a>(expr: &'a Expr, conditions: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::And(left, right) => {
+            conjuncts(left, conditions);
+            conjuncts(right, conditions);
+        }
+        expr => conditions.push(expr),
+    }
+}
+
+/// Read the node found by an index at `position`, if it's still stored.
+fn read_node(tree: &Tree, position: u128) -> Result<Option<NodeData>, NodeError> {
+    let slot = match tree.resolve(position) {
+        Ok(slot) => slot,
+        Err(NodeError::Unexistent) => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    match tree.read_slot(slot) {
+        Ok(contents) => Ok(Some(NodeData {
+            position,
+            enabled: contents.enabled,
+            subitems: contents.subitems,
+        })),
+        Err(NodeError::Unexistent) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn evaluate(expr: &Expr, node: &NodeData, tree: &Tree) -> Result<bool, NodeError> {
+    Ok(match expr {
+        Expr::Compare(field, orderings, value) => {
+            let field = match field {
+                Field::Position => node.position,
+                Field::Level => positions::level(node.position) as u128,
+                Field::Subitem(index) => bitcodec::bits_to_u64(&node.subitems[*index]) as u128,
+            };
+            orderings.contains(&field.cmp(value))
+        }
+        Expr::IsLeaf => match positions::child(node.position, 0) {
+            Some(first_child) => !tree
+                .occupancy(first_child..first_child.saturating_add(2))?
+                .contains(&true),
+            // The deepest positions have no children that can be addressed.
+            None => true,
+        },
+        Expr::Not(expr) => !evaluate(expr, node, tree)?,
+        Expr::And(left, right) => evaluate(left, node, tree)? && evaluate(right, node, tree)?,
+        Expr::Or(left, right) => evaluate(left, node, tree)? || evaluate(right, node, tree)?,
+    })
+}
+
+/// Split an expression into words, numbers, operators and parentheses, each
+/// with the byte it starts at.
+fn tokenize(expr: &str) -> Result<Vec<(usize, &str)>, QueryError> {
+    let mut tokens = vec![];
+    let bytes = expr.as_bytes();
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let start = offset;
+        match bytes[offset] {
+            b' ' | b'\t' | b'\n' | b'\r' => {
+                offset += 1;
+                continue;
+            }
+            b'(' | b')' => offset += 1,
+            b'<' | b'>' | b'=' | b'!' => {
+                offset += 1;
+                if bytes.get(offset) == Some(&b'=') {
+                    offset += 1;
+                };
+            }
+            byte if byte.is_ascii_alphanumeric() || byte == b'_' => {
+                while offset < bytes.len()
+                    && (bytes[offset].is_ascii_alphanumeric() || bytes[offset] == b'_')
+                {
+                    offset += 1;
+                }
+            }
+            _ => return Err(QueryError::Syntax { offset }),
+        };
+
+        tokens.push((start, &expr[start..offset]));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, &'a str)],
+    next: usize,
+    subitems: &'a [u32],
+
+    /// The length of the expression, where a missing token is reported.
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(|(_, token)| *token)
+    }
+
+    fn offset(&self) -> usize {
+        match self.tokens.get(self.next) {
+            Some((offset, _)) => *offset,
+            None => self.end,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some("or") {
+            self.next += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.not()?;
+        while self.peek() == Some("and") {
+            self.next += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, QueryError> {
+        if self.peek() == Some("not") {
+            self.next += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        };
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, QueryError> {
+        let offset = self.offset();
+        let field = match self.peek() {
+            Some("(") => {
+                self.next += 1;
+                let expr = self.or()?;
+                if self.peek() != Some(")") {
+                    return Err(QueryError::Syntax {
+                        offset: self.offset(),
+                    });
+                };
+                self.next += 1;
+
+                return Ok(expr);
+            }
+            Some("is_leaf") => {
+                self.next += 1;
+                return Ok(Expr::IsLeaf);
+            }
+            Some("position") => Field::Position,
+            Some("level") => Field::Level,
+            Some(token) if token.starts_with("subitem") => {
+                match token["subitem".len()..].parse::<usize>() {
+                    Ok(index) if self.subitems.get(index).is_some_and(|size| *size <= 64) => {
+                        Field::Subitem(index)
+                    }
+                    _ => return Err(QueryError::UnknownField { offset }),
+                }
+            }
+            Some(token) if token.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                return Err(QueryError::UnknownField { offset })
+            }
+            _ => return Err(QueryError::Syntax { offset }),
+        };
+        self.next += 1;
+
+        let orderings = match self.peek() {
+            Some("=") => vec![Ordering::Equal],
+            Some("!=") => vec![Ordering::Less, Ordering::Greater],
+            Some("<") => vec![Ordering::Less],
+            Some("<=") => vec![Ordering::Less, Ordering::Equal],
+            Some(">") => vec![Ordering::Greater],
+            Some(">=") => vec![Ordering::Greater, Ordering::Equal],
+            _ => {
+                return Err(QueryError::Syntax {
+                    offset: self.offset(),
+                })
+            }
+        };
+        self.next += 1;
+
+        let value = match self.peek().map(str::parse::<u128>) {
+            Some(Ok(value)) => value,
+            _ => {
+                return Err(QueryError::Syntax {
+                    offset: self.offset(),
+                })
+            }
+        };
+        self.next += 1;
+
+        Ok(Expr::Compare(field, orderings, value))
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{positions, Feature, NodeError, Storage, Tree, TreeFileError, TreeOpenMode};
+use std::fs::File;
+
+/// The maximum amount of positions checked at once when counting leaves.
+const LEAF_CHUNK_SIZE: u128 = 64 * 1024;
+
+/// The size in bytes of each entry of the leaf table.
+const LEAF_ENTRY_SIZE: u64 = 16;
+
+impl Tree {
+    /// The position of the `k`th leaf (starting from 0), counting leaves from
+    /// left to right. A leaf is an enabled node without enabled children.
+    ///
+    /// Only the nodes along the path to the leaf are visited when the tree
+    /// keeps a leaf table (see [`subtree_leaves`](Tree::subtree_leaves)).
+    pub fn kth_leaf(&self, k: u128) -> Result<u128, NodeError> {
+        let mut k = k;
+        let mut position = 0;
+
+        loop {
+            if self.subtree_leaves(position)? <= k {
+                return Err(NodeError::Unexistent);
+            };
+
+            if self.is_leaf_position(position)? {
+                return Ok(position);
+            };
+
+            // A node that isn't a leaf has children that can be addressed.
+            let (left, right) = match (positions::child(position, 0), positions::child(position, 1))
+            {
+                (Some(left), Some(right)) => (left, right),
+                _ => return Err(NodeError::Unexistent),
+            };
+            let left_leaves = self.subtree_leaves(left)?;
+            if k < left_leaves {
+                position = left;
+            } else {
+                k -= left_leaves;
+                position = right;
+            }
+        }
+    }
+
+    /// The amount of leaves to the left of `position`. For a leaf, that's
+    /// the `k` for which [`kth_leaf`](Tree::kth_leaf) returns its position.
+    pub fn leaf_rank(&self, position: u128) -> Result<u128, NodeError> {
+        let mut rank = 0;
+        let mut ancestor = 0;
+
+        for index in positions::path(position) {
+            // The path only goes through positions that can be addressed.
+            let (left, child) = match (
+                positions::child(ancestor, 0),
+                positions::child(ancestor, index),
+            ) {
+                (Some(left), Some(child)) => (left, child),
+                _ => return Err(NodeError::Unexistent),
+            };
+            if index == 1 {
+                rank += self.subtree_leaves(left)?;
+            };
+
+            ancestor = child;
+        }
+
+        Ok(rank)
+    }
+
+    /// The amount of leaves in the subtree rooted at `position`.
+    ///
+    /// Read from the leaf table in constant time when the tree isn't
+    /// persistent and has the level stats feature, or counted from the
+    /// occupancy of every level of the subtree otherwise.
+    pub fn subtree_leaves(&self, position: u128) -> Result<u128, NodeError> {
+        if let Some(leaves) = &self.leaves {
+            return read_leaf_count(leaves, position);
+        };
+
+        let limit = match self.position_limit()? {
+            Some(limit) => limit,
+            None => return self.subtree_leaves_persistent(position),
+        };
+
+        let mut leaves = 0;
+        let mut first = position;
+        let mut width: u128 = 1;
+        while first < limit {
+            let end = first.saturating_add(width).min(limit);
+
+            let mut start = first;
+            while start < end {
+                let chunk_end = end.min(start + LEAF_CHUNK_SIZE);
+                let nodes = self.occupancy(start..chunk_end)?;
+                // Flat trees store fewer positions than can be addressed.
+                let children = self.occupancy(
+                    positions::child(start, 0).unwrap_or(u128::MAX)
+                        ..positions::child(chunk_end, 0).unwrap_or(u128::MAX),
+                )?;
+
+                leaves += nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, node)| **node && !children[i * 2] && !children[i * 2 + 1])
+                    .count() as u128;
+                start = chunk_end;
+            }
+
+            first = match first.checked_mul(2).and_then(|first| first.checked_add(1)) {
+                Some(first) => first,
+                None => break,
+            };
+            width = width.saturating_mul(2);
+        }
+
+        Ok(leaves)
+    }
+
+    /// Count the leaves of a persistent tree by following its pointers.
+    fn subtree_leaves_persistent(&self, position: u128) -> Result<u128, NodeError> {
+        let mut leaves = 0;
+        let mut pending = match self.resolve(position) {
+            Ok(slot) => vec![slot],
+            Err(NodeError::Unexistent) => vec![],
+            Err(error) => return Err(error),
+        };
+
+        while let Some(slot) = pending.pop() {
+            let contents = self.read_slot(slot)?;
+
+            let mut has_children = false;
+            for child in contents.children.iter().flatten() {
+                has_children |= self.read_slot(*child)?.enabled;
+                pending.push(*child);
+            }
+
+            if contents.enabled && !has_children {
+                leaves += 1;
+            };
+        }
+
+        Ok(leaves)
+    }
+
+    /// Whether `position` holds an enabled node without enabled children.
+    fn is_leaf_position(&self, position: u128) -> Result<bool, NodeError> {
+        let first_child = positions::child(position, 0).unwrap_or(u128::MAX);
+
+        Ok(self
+            .occupancy(position..position.saturating_add(1))?
+            .first()
+            == Some(&true)
+            && !self
+                .occupancy(first_child..first_child.saturating_add(2))?
+                .contains(&true))
+    }
+
+    /// Open the leaf table if the tree has the level stats feature and isn't
+    /// persistent, whose deep positions the table can't hold. The table is
+    /// built when a tree written before it was kept is opened for writing.
+    pub(crate) fn open_leaves(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::LevelStats)
+            || self.features.contains(&Feature::Persistent)
+        {
+            return Ok(());
+        };
+
+        if create || crate::sidecar_path(&self.path, "leaves").exists() {
+            self.leaves = Some(self.open_sidecar("leaves", create)?);
+            return Ok(());
+        };
+
+        match self.mode {
+            TreeOpenMode::ReadWrite => self.build_leaves(),
+            // Counted from the occupancy of the nodes instead.
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the leaf table from the enabled nodes: every leaf is counted in
+    /// the subtree of each of its ancestors.
+    pub(crate) fn build_leaves(&mut self) -> Result<(), TreeFileError> {
+        self.leaves = None;
+
+        let mut leaves = vec![];
+        let positions = match self.positions() {
+            Ok(positions) => positions,
+            Err(_) => return Err(TreeFileError::Corrupted),
+        };
+        for position in positions {
+            let position = match position {
+                Ok(position) => position,
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+            match self.is_leaf_position(position) {
+                Ok(true) => leaves.push(position),
+                Ok(false) => (),
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+        }
+
+        self.leaves = Some(self.open_sidecar("leaves", true)?);
+        for position in leaves {
+            if self.add_leaves(position, true).is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Fail with [`OffsetOverflow`](NodeError::OffsetOverflow) if the leaf
+    /// table can't hold the entry of `position`. Checked before a node is
+    /// written.
+    pub(crate) fn check_leaf_entry(&self, position: u128) -> Result<(), NodeError> {
+        match &self.leaves {
+            Some(_) if leaf_entry_offset(position).is_none() => Err(NodeError::OffsetOverflow),
+            _ => Ok(()),
+        }
+    }
+
+    /// Update the leaf counts of the ancestors of a node that was written.
+    /// Only the node itself and its parent can have become a leaf or stopped
+    /// being one.
+    pub(crate) fn update_leaf_counts(
+        &mut self,
+        position: u128,
+        was_enabled: bool,
+        enabled: bool,
+    ) -> Result<(), NodeError> {
+        if self.leaves.is_none() || was_enabled == enabled {
+            return Ok(());
+        };
+
+        if !self.has_enabled_children(position)? {
+            self.add_leaves(position, enabled)?;
+        };
+
+        // The parent is a leaf while neither of its children is enabled.
+        if position > 0 {
+            let parent = positions::parent(position);
+            let sibling = match position % 2 {
+                1 => position + 1,
+                _ => position - 1,
+            };
+            if self.occupancy(parent..parent + 1)?.first() == Some(&true)
+                && self.occupancy(sibling..sibling + 1)?.first() != Some(&true)
+            {
+                self.add_leaves(parent, !enabled)?;
+            };
+        };
+
+        Ok(())
+    }
+
+    /// Add a leaf to (or remove one from) the count of `position` and of
+    /// each of its ancestors.
+    fn add_leaves(&mut self, position: u128, added: bool) -> Result<(), NodeError> {
+        let leaves = match &self.leaves {
+            Some(leaves) => leaves,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        let mut ancestor = position;
+        loop {
+            let offset = match leaf_entry_offset(ancestor) {
+                Some(offset) => offset,
+                None => return Err(NodeError::OffsetOverflow),
+            };
+            let count = read_leaf_count(leaves, ancestor)?;
+            let count = match added {
+                true => count.saturating_add(1),
+                false => count.saturating_sub(1),
+            };
+            match leaves.write_at(offset, &count.to_be_bytes()) {
+                Ok(_) => (),
+                Err(_) => return Err(NodeError::Unexistent),
+            };
+
+            if ancestor == 0 {
+                return Ok(());
+            };
+            ancestor = positions::parent(ancestor);
+        }
+    }
+
+    /// Whether either child of `position` holds an enabled node.
+    fn has_enabled_children(&self, position: u128) -> Result<bool, NodeError> {
+        match positions::child(position, 0) {
+            Some(first_child) => Ok(self
+                .occupancy(first_child..first_child.saturating_add(2))?
+                .contains(&true)),
+            None => Ok(false),
+        }
+    }
+}
+
+/// The offset of the entry of `position` in the leaf table, if it has one.
+fn leaf_entry_offset(position: u128) -> Option<u64> {
+    u64::try_from(position)
+        .ok()
+        .and_then(|position| position.checked_mul(LEAF_ENTRY_SIZE))
+}
+
+/// The amount of leaves in the subtree rooted at `position`, read from the
+/// leaf table. Missing entries at the end of the table are empty subtrees.
+fn read_leaf_count(leaves: &File, position: u128) -> Result<u128, NodeError> {
+    let offset = match leaf_entry_offset(position) {
+        Some(offset) => offset,
+        None => return Ok(0),
+    };
+    let size = match leaves.size() {
+        Ok(size) => size,
+        Err(_) => return Err(NodeError::Unexistent),
+    };
+    if offset.saturating_add(LEAF_ENTRY_SIZE) > size {
+        return Ok(0);
+    };
+
+    let mut entry = [0_u8; LEAF_ENTRY_SIZE as usize];
+    match leaves.read_at(offset, &mut entry) {
+        Ok(_) => Ok(u128::from_be_bytes(entry)),
+        Err(_) => Err(NodeError::Unexistent),
+    }
+}
@@ -0,0 +1,74 @@
+//! A read-only handle over a preloaded tree file, for contexts where blocking
+//! or locking is forbidden.
+
+use crate::{CreateOptions, NodeData, NodeError, TreeBytes, TreeFileError};
+use std::fs;
+
+/// A flat tree file loaded into memory. Reads never mutate, lock or reach the
+/// storage: every method takes `&self` and only reads the loaded bytes, so the
+/// handle can be shared freely across threads and used from signal handlers.
+///
+/// Writes to the tree file after it's loaded aren't seen by the handle.
+/// Persistent trees can't be loaded, as their versions are kept in other
+/// files.
+#[derive(Debug)]
+pub struct ReadOnlyTree {
+    tree: TreeBytes<Vec<u8>>,
+}
+
+// Sharing the handle is the point of it.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ReadOnlyTree>();
+};
+
+impl ReadOnlyTree {
+    /// Load a closed tree file into memory. Fails with
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown) if it's open for
+    /// writing.
+    pub fn open(file_path: &'static str) -> Result<Self, TreeFileError> {
+        match fs::read(file_path) {
+            Ok(bytes) => Self::from_bytes(bytes),
+            Err(_) => Err(TreeFileError::FileNotOpened),
+        }
+    }
+
+    /// Take the bytes of a tree file already in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, TreeFileError> {
+        Ok(ReadOnlyTree {
+            tree: TreeBytes::parse(bytes)?,
+        })
+    }
+
+    /// The options the tree was created with.
+    pub fn options(&self) -> &CreateOptions {
+        self.tree.options()
+    }
+
+    /// The amount of nodes in the tree.
+    pub fn nodes(&self) -> u64 {
+        self.tree.nodes()
+    }
+
+    /// Decode a node by its tranversal position.
+    pub fn node(&self, position: u128) -> Result<NodeData, NodeError> {
+        self.tree.node(position)
+    }
+
+    /// Decode the subitems of a node without allocating, like
+    /// [`TreeBytes::decode_node_into`].
+    pub fn decode_node_into(&self, position: u128, out: &mut [u64]) -> Result<usize, NodeError> {
+        self.tree.decode_node_into(position, out)
+    }
+
+    /// Copy one subitem of a node without allocating, like
+    /// [`TreeBytes::decode_subitem_into`].
+    pub fn decode_subitem_into(
+        &self,
+        position: u128,
+        index: usize,
+        out: &mut [u8],
+    ) -> Result<u32, NodeError> {
+        self.tree.decode_subitem_into(position, index, out)
+    }
+}
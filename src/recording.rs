@@ -0,0 +1,281 @@
+//! Traces: a record of the calls changing a tree, replayed on another tree
+//! to reproduce the state of a tree file, e.g. one reported as corrupted.
+//!
+//! A trace starts with its identifier (8 bytes), its format version (2
+//! bytes) and the headers of the tree file recorded, dirty flag cleared (2
+//! bytes of length and the headers). Each recorded call follows as its kind
+//! (1 byte), the length of its arguments (4 bytes) and its arguments:
+//!
+//! - Writes of a node: the position (16 bytes), whether it could overwrite
+//!   a node and whether it was disabled (1 byte each), whether it succeeded
+//!   (1 byte) and the subitems packed one after the other.
+//! - Garbage collections: whether they succeeded (1 byte) and the versions
+//!   retained (8 bytes each).
+//! - Rollbacks: whether they succeeded (1 byte) and the version rolled back
+//!   to (8 bytes).
+//! - Changes of the writer id: the id (4 bytes).
+
+use crate::{bitcodec, Tree, TreeFileError, DIRTY_FLAG};
+use std::fs::{self, File};
+use std::io::Write;
+
+/// The first bytes of every trace.
+const TRACE_IDENTIFIER: [u8; 8] = *b"DOTTRACE";
+
+/// The version of the trace format written.
+const TRACE_VERSION: u16 = 1;
+
+/// The kinds of calls recorded.
+const CALL_SET_NODE: u8 = 0;
+const CALL_GC: u8 = 1;
+const CALL_ROLLBACK: u8 = 2;
+const CALL_WRITER_ID: u8 = 3;
+
+/// Why a trace couldn't be replayed.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The trace couldn't be read.
+    Read,
+
+    /// The trace isn't a trace of a tree, or was written in a newer format.
+    Invalid,
+
+    /// The tree replaying the trace has other headers than the tree that
+    /// recorded it.
+    SchemaMismatch,
+
+    /// A call that succeeded when it was recorded failed when it was
+    /// replayed. `call` is its index in the trace, counting from 0.
+    Diverged { call: u64 },
+}
+
+impl Tree {
+    /// Start recording the calls changing the tree in a new trace at
+    /// `path`, replacing the trace being recorded, if any. Replay it with
+    /// [`replay_trace`](Tree::replay_trace).
+    ///
+    /// The writes of nodes (through [`set_node`](Tree::set_node) or a
+    /// [`Node`](crate::Node)), garbage collections, rollbacks and changes of
+    /// the writer id are recorded, along with whether they succeeded. Calls
+    /// rewriting the tree file as a whole (e.g.
+    /// [`enable_feature`](Tree::enable_feature) or
+    /// [`apply_backup`](Tree::apply_backup)) and writes through
+    /// [`SubtreeWriter`](crate::SubtreeWriter)s aren't. The recording stops
+    /// if the trace can't be written.
+    pub fn record_trace(&mut self, path: &str) -> Result<(), TreeFileError> {
+        let mut headers = vec![0_u8; self.header_size];
+        if self.storage.read_at(0, &mut headers).is_err() {
+            return Err(TreeFileError::MissingHeaders);
+        };
+        bitcodec::pack_bits_at(&mut headers[10..12], DIRTY_FLAG, &[false]);
+
+        let mut start = TRACE_IDENTIFIER.to_vec();
+        start.extend(TRACE_VERSION.to_be_bytes());
+        start.extend((headers.len() as u16).to_be_bytes());
+        start.extend(headers);
+
+        let mut trace = match File::create(path) {
+            Ok(trace) => trace,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        if trace.write_all(&start).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.recording = Some(trace);
+
+        Ok(())
+    }
+
+    /// Stop recording the calls changing the tree.
+    pub fn stop_trace(&mut self) {
+        self.recording = None;
+    }
+
+    /// Replay the calls recorded in the trace at `path` on `dest`, which
+    /// must have the headers of the tree that recorded it, e.g. a new tree
+    /// created like it. Calls that failed when they were recorded are
+    /// skipped. Returns the amount of calls replayed.
+    ///
+    /// Trees with the attribution feature are stamped with the time of the
+    /// replay, and the validator and write hooks of `dest` are called.
+    pub fn replay_trace(path: &str, dest: &mut Tree) -> Result<u64, ReplayError> {
+        let trace = match fs::read(path) {
+            Ok(trace) => trace,
+            Err(_) => return Err(ReplayError::Read),
+        };
+
+        if trace.len() < 12
+            || trace[..8] != TRACE_IDENTIFIER
+            || u16::from_be_bytes([trace[8], trace[9]]) > TRACE_VERSION
+        {
+            return Err(ReplayError::Invalid);
+        };
+        let header_size = u16::from_be_bytes([trace[10], trace[11]]) as usize;
+        let headers = trace
+            .get(12..12 + header_size)
+            .ok_or(ReplayError::Invalid)?;
+
+        let mut dest_headers = vec![0_u8; dest.header_size];
+        if dest.storage.read_at(0, &mut dest_headers).is_err() {
+            return Err(ReplayError::SchemaMismatch);
+        };
+        bitcodec::pack_bits_at(&mut dest_headers[10..12], DIRTY_FLAG, &[false]);
+        if headers != dest_headers {
+            return Err(ReplayError::SchemaMismatch);
+        };
+
+        let mut replayed = 0;
+
+        // A call cut short by a crash is dropped, as it was recorded last.
+        let mut offset = 12 + header_size;
+        let mut call = 0;
+        while offset + 5 <= trace.len() {
+            let kind = trace[offset];
+            let len = bitcodec::u8_array_to_u32(trace[offset + 1..offset + 5].try_into().unwrap());
+            let args = match trace.get(offset + 5..offset + 5 + len as usize) {
+                Some(args) => args,
+                None => break,
+            };
+
+            match dest.replay_call(kind, args)? {
+                Some(true) => replayed += 1,
+                Some(false) => return Err(ReplayError::Diverged { call }),
+                None => (),
+            };
+
+            offset += 5 + len as usize;
+            call += 1;
+        }
+
+        Ok(replayed)
+    }
+
+    /// Make a recorded call, returning whether it succeeded, or `None` if
+    /// it's skipped.
+    fn replay_call(&mut self, kind: u8, args: &[u8]) -> Result<Option<bool>, ReplayError> {
+        let succeeded = |args: &[u8], at: usize| match args.get(at) {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(ReplayError::Invalid),
+        };
+
+        match kind {
+            CALL_SET_NODE => {
+                if args.len() < 19 {
+                    return Err(ReplayError::Invalid);
+                };
+                if !succeeded(args, 18)? {
+                    return Ok(None);
+                };
+
+                let position = u128::from_be_bytes(args[..16].try_into().unwrap());
+                let bits = bitcodec::bytes_to_bits(&args[19..]);
+                let mut subitems = vec![];
+                let mut start = 0;
+                for size in &self.subitems {
+                    let end = start + *size as usize;
+                    match bits.get(start..end) {
+                        Some(subitem) => subitems.push(subitem.to_vec()),
+                        None => return Err(ReplayError::Invalid),
+                    };
+                    start = end;
+                }
+
+                let result =
+                    self.set_node_quiet(&subitems, &position, args[16] == 1, args[17] == 1);
+                Ok(Some(result.is_ok()))
+            }
+            CALL_GC => {
+                if !succeeded(args, 0)? {
+                    return Ok(None);
+                };
+                if !(args.len() - 1).is_multiple_of(8) {
+                    return Err(ReplayError::Invalid);
+                };
+
+                let versions: Vec<u64> = args[1..]
+                    .chunks(8)
+                    .map(|version| u64::from_be_bytes(version.try_into().unwrap()))
+                    .collect();
+                Ok(Some(self.gc(&versions).is_ok()))
+            }
+            CALL_ROLLBACK => {
+                if !succeeded(args, 0)? {
+                    return Ok(None);
+                };
+                let version = match args.get(1..9) {
+                    Some(version) if args.len() == 9 => {
+                        u64::from_be_bytes(version.try_into().unwrap())
+                    }
+                    _ => return Err(ReplayError::Invalid),
+                };
+
+                Ok(Some(self.roll_back_version(version).is_ok()))
+            }
+            CALL_WRITER_ID => {
+                let id = match args.try_into() {
+                    Ok(id) => bitcodec::u8_array_to_u32(id),
+                    Err(_) => return Err(ReplayError::Invalid),
+                };
+                self.set_writer_id(id);
+
+                Ok(Some(true))
+            }
+            _ => Err(ReplayError::Invalid),
+        }
+    }
+
+    /// Record a write of a node, if a trace is being recorded.
+    pub(crate) fn record_set_node(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        overwrite: bool,
+        disabled: bool,
+        succeeded: bool,
+    ) {
+        if self.recording.is_none() {
+            return;
+        };
+
+        let mut args = position.to_be_bytes().to_vec();
+        args.extend([overwrite as u8, disabled as u8, succeeded as u8]);
+        args.extend(bitcodec::bits_to_bytes(&subitems.concat()));
+        self.record_call(CALL_SET_NODE, &args);
+    }
+
+    /// Record a garbage collection, if a trace is being recorded.
+    pub(crate) fn record_gc(&mut self, retain_versions: &[u64], succeeded: bool) {
+        let mut args = vec![succeeded as u8];
+        for version in retain_versions {
+            args.extend(version.to_be_bytes());
+        }
+        self.record_call(CALL_GC, &args);
+    }
+
+    /// Record a rollback, if a trace is being recorded.
+    pub(crate) fn record_rollback(&mut self, version: u64, succeeded: bool) {
+        let mut args = vec![succeeded as u8];
+        args.extend(version.to_be_bytes());
+        self.record_call(CALL_ROLLBACK, &args);
+    }
+
+    /// Record a change of the writer id, if a trace is being recorded.
+    pub(crate) fn record_writer_id(&mut self, id: u32) {
+        self.record_call(CALL_WRITER_ID, &bitcodec::u32_to_u8_array(id));
+    }
+
+    fn record_call(&mut self, kind: u8, args: &[u8]) {
+        let trace = match &mut self.recording {
+            Some(trace) => trace,
+            None => return,
+        };
+
+        let mut call = vec![kind];
+        call.extend(bitcodec::u32_to_u8_array(args.len() as u32));
+        call.extend(args);
+        if trace.write_all(&call).is_err() {
+            self.recording = None;
+        };
+    }
+}
@@ -0,0 +1,171 @@
+//! Repairing a tree from a known-good replica, copying only the blocks that
+//! differ.
+
+use crate::cache::PAGE_SIZE;
+use crate::{sidecar_path, write_dirty, Storage, Tree, TreeFileError, TreeOpenMode};
+use std::fs::{self, File};
+
+/// The blocks copied from a replica by [`repair_from`](Tree::repair_from).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RepairReport {
+    /// The amount of blocks (of the tree file and of the files kept next to
+    /// it) that differed from the replica.
+    pub repaired_blocks: u64,
+
+    /// The amount of bytes copied from the replica.
+    pub repaired_bytes: u64,
+}
+
+/// The 64-bit FNV-1a hash of a block.
+fn block_digest(block: &[u8]) -> u64 {
+    block.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The blocks of `replica` whose digests differ from the same blocks of
+/// `storage`, with their offsets. Blocks past the end of `storage` always
+/// differ.
+fn divergent_blocks(
+    storage: &dyn Storage,
+    replica: &dyn Storage,
+) -> Result<Vec<(u64, Vec<u8>)>, TreeFileError> {
+    let (size, replica_size) = match (storage.size(), replica.size()) {
+        (Ok(size), Ok(replica_size)) => (size, replica_size),
+        _ => return Err(TreeFileError::FileNotOpened),
+    };
+
+    let mut blocks = vec![];
+    for block in 0..replica_size.div_ceil(PAGE_SIZE) {
+        let offset = block * PAGE_SIZE;
+        let len = PAGE_SIZE.min(replica_size - offset) as usize;
+
+        let mut expected = vec![0_u8; len];
+        if replica.read_at(offset, &mut expected).is_err() {
+            return Err(TreeFileError::FileNotOpened);
+        };
+
+        let mut current = vec![0_u8; len];
+        let readable = offset + len as u64 <= size && storage.read_at(offset, &mut current).is_ok();
+        if !readable || block_digest(&current) != block_digest(&expected) {
+            blocks.push((offset, expected));
+        };
+    }
+
+    Ok(blocks)
+}
+
+/// Copy the divergent blocks of `replica` into `storage`, and give it the
+/// size of `replica`.
+fn repair_storage(
+    storage: &dyn Storage,
+    replica: &dyn Storage,
+    report: &mut RepairReport,
+) -> Result<(), TreeFileError> {
+    for (offset, block) in divergent_blocks(storage, replica)? {
+        if storage.write_at(offset, &block).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        report.record(&block);
+    }
+
+    resize(storage, replica)
+}
+
+/// Give `storage` the size of `replica`.
+fn resize(storage: &dyn Storage, replica: &dyn Storage) -> Result<(), TreeFileError> {
+    let size = match replica.size() {
+        Ok(size) => size,
+        Err(_) => return Err(TreeFileError::FileNotOpened),
+    };
+
+    match storage.set_size(size) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TreeFileError::MissingPermissions),
+    }
+}
+
+impl RepairReport {
+    fn record(&mut self, block: &[u8]) {
+        self.repaired_blocks += 1;
+        self.repaired_bytes += block.len() as u64;
+    }
+}
+
+impl Tree {
+    /// Repair the tree from a known-good replica with the same schema, e.g.
+    /// after partial corruption. The tree file and the files kept next to it
+    /// are compared block by block, and only the blocks that differ are
+    /// copied, so the repaired tree ends up identical to the replica.
+    ///
+    /// Fails with [`SchemaMismatch`](TreeFileError::SchemaMismatch) if the
+    /// replica has other features, subitems, bit order or layout.
+    pub fn repair_from(&mut self, replica: &Tree) -> Result<RepairReport, TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        if self.features != replica.features
+            || self.subitems != replica.subitems
+            || self.bit_order != replica.bit_order
+            || self.layout != replica.layout
+        {
+            return Err(TreeFileError::SchemaMismatch);
+        };
+
+        // Pinned pages past the replica's end would outlive the truncation.
+        self.unpin_all();
+
+        // The tree file is written through the tree, so its reader handles
+        // and cache see the repaired blocks.
+        let mut report = RepairReport::default();
+        for (offset, block) in divergent_blocks(&*self.storage, &*replica.storage)? {
+            if self.bulk(|tree| tree.write_bytes(offset, &block)).is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+            report.record(&block);
+        }
+        let size = match replica.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        if self.set_storage_size(size).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        let sidecars: [(&Option<File>, &Option<File>); 7] = [
+            (&self.versions, &replica.versions),
+            (&self.timestamps, &replica.timestamps),
+            (&self.occupancy, &replica.occupancy),
+            (&self.levels, &replica.levels),
+            (&self.leaves, &replica.leaves),
+            (&self.audit, &replica.audit),
+            (&self.merkle, &replica.merkle),
+        ];
+        for (sidecar, replica_sidecar) in sidecars {
+            if let (Some(sidecar), Some(replica_sidecar)) = (sidecar, replica_sidecar) {
+                repair_storage(sidecar, replica_sidecar, &mut report)?;
+            };
+        }
+
+        let savepoints = sidecar_path(&self.path, "savepoints");
+        if let Ok(expected) = fs::read(sidecar_path(&replica.path, "savepoints")) {
+            if fs::read(&savepoints).ok().as_ref() != Some(&expected) {
+                if fs::write(&savepoints, &expected).is_err() {
+                    return Err(TreeFileError::MissingPermissions);
+                };
+                report.record(&expected);
+            };
+        };
+
+        // The index log is replayed when the tree is opened, so the indexes
+        // are rebuilt from the repaired nodes instead.
+        self.rebuild_indexes()?;
+
+        // The replica's headers are copied as they are, but the tree is
+        // still open for writing.
+        write_dirty(&*self.storage, true)?;
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,272 @@
+//! Retained values: the last values written to each position, kept to debug
+//! or audit how a node changed without making the tree persistent.
+//!
+//! The values are kept in a log next to the tree file (with the `.values`
+//! extension), starting with the amount of values retained per position (4
+//! bytes). Each entry is the position (16 bytes), whether the node was
+//! enabled (1 byte) and its subitems packed one after the other. The
+//! entries still retained are found when the tree is opened.
+
+use crate::{
+    bitcodec, sidecar_path, Node, NodeData, NodeError, Storage, Tree, TreeFileError, TreeOpenMode,
+};
+use std::fs;
+
+/// The size in bytes of the log before its first entry.
+const VALUE_LOG_HEADER_SIZE: u64 = 4;
+
+/// The size in bytes below which the log is never compacted.
+const VALUE_COMPACT_MIN_SIZE: u64 = 1 << 20;
+
+impl Tree {
+    /// Retain the last `depth` values written to each position, read with
+    /// [`node_history`](Tree::node_history). A depth of 0 stops retaining
+    /// them and removes the log. Lowering the depth drops the oldest values
+    /// of each position.
+    ///
+    /// Every write of a node is retained, disabled nodes included, so the
+    /// tree can't be [split](Tree::split_writers) and
+    /// [`SubitemWriter`](crate::SubitemWriter)s can't be opened. Like
+    /// annotations, the values belong to positions, and aren't versioned
+    /// with persistent trees.
+    pub fn set_retained_values(&mut self, depth: u32) -> Result<(), TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Trees opened from memory or an object store have no files next to
+        // them.
+        if self.path.as_os_str().is_empty() {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        if depth == 0 {
+            self.value_log = None;
+            self.retained.clear();
+            self.retained_depth = 0;
+            let _ = fs::remove_file(sidecar_path(&self.path, "values"));
+            return Ok(());
+        };
+
+        if self.value_log.is_none() {
+            self.value_log = Some(self.open_sidecar("values", true)?);
+        };
+        self.retained_depth = depth;
+        for values in self.retained.values_mut() {
+            while values.len() > depth as usize {
+                values.pop_front();
+            }
+        }
+
+        if let Some(log) = &self.value_log {
+            if log.write_at(0, &depth.to_be_bytes()).is_err() {
+                return Err(TreeFileError::MissingPermissions);
+            };
+        };
+
+        self.sync()
+    }
+
+    /// The amount of values retained per position. 0 if they aren't.
+    pub fn retained_values(&self) -> u32 {
+        self.retained_depth
+    }
+
+    /// The last values written to `position`, from the oldest to the newest
+    /// (see [`set_retained_values`](Tree::set_retained_values)). Fails with
+    /// [`MissingFeature`](NodeError::MissingFeature) if the tree doesn't
+    /// retain them.
+    pub fn node_history(&self, position: u128) -> Result<Vec<NodeData>, NodeError> {
+        let log = match &self.value_log {
+            Some(log) => log,
+            None => return Err(NodeError::MissingFeature),
+        };
+
+        let entries = match self.retained.get(&position) {
+            Some(entries) => entries,
+            None => return Ok(vec![]),
+        };
+
+        let size = self.value_entry_size();
+        let mut history = vec![];
+        for entry in entries {
+            let mut bytes = vec![0_u8; size as usize];
+            if log
+                .read_at(VALUE_LOG_HEADER_SIZE + entry * size, &mut bytes)
+                .is_err()
+            {
+                return Err(NodeError::Unexistent);
+            };
+            history.push(self.decode_value(&bytes));
+        }
+
+        Ok(history)
+    }
+
+    /// Open the log and find the entries still retained, if the tree retains
+    /// values. A new tree drops the values of the tree file it replaced.
+    pub(crate) fn open_retention(&mut self, create: bool) -> Result<(), TreeFileError> {
+        let path = sidecar_path(&self.path, "values");
+
+        if create {
+            let _ = fs::remove_file(path);
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        };
+
+        let log = self.open_sidecar("values", false)?;
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        let depth = match bytes.get(..VALUE_LOG_HEADER_SIZE as usize) {
+            Some(depth) => u32::from_be_bytes(depth.try_into().unwrap()),
+            None => return Err(TreeFileError::Corrupted),
+        };
+
+        // An entry cut short by a crash is dropped, as its write never
+        // finished.
+        let size = self.value_entry_size() as usize;
+        let entries = &bytes[VALUE_LOG_HEADER_SIZE as usize..];
+        for (index, entry) in entries.chunks_exact(size).enumerate() {
+            let position = u128::from_be_bytes(entry[..16].try_into().unwrap());
+            self.retain_entry(position, index as u64, depth);
+        }
+
+        self.value_log = Some(log);
+        self.retained_depth = depth;
+
+        Ok(())
+    }
+
+    /// Retain a value written to a node, if the tree retains values.
+    pub(crate) fn retain_value(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<(), NodeError> {
+        let log = match &self.value_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        let mut entry = position.to_be_bytes().to_vec();
+        entry.push(!disabled as u8);
+        entry.extend(bitcodec::bits_to_bytes(&subitems.concat()));
+
+        let size = self.value_entry_size();
+        let end = match log.size() {
+            Ok(end) => end,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+        let index = end.saturating_sub(VALUE_LOG_HEADER_SIZE) / size;
+        if log
+            .write_at(VALUE_LOG_HEADER_SIZE + index * size, &entry)
+            .is_err()
+        {
+            return Err(NodeError::Unexistent);
+        };
+        self.retain_entry(position, index, self.retained_depth);
+
+        // Compacted once most of the log would be dropped entries even if
+        // every position retained as many values as it may.
+        let live = self.retained.len() as u64 * self.retained_depth as u64;
+        let written = VALUE_LOG_HEADER_SIZE + (index + 1) * size;
+        if written >= VALUE_COMPACT_MIN_SIZE && written > live.saturating_mul(4 * size) {
+            return match self.compact_values() {
+                Ok(_) => Ok(()),
+                Err(_) => Err(NodeError::Unexistent),
+            };
+        };
+
+        Ok(())
+    }
+
+    /// Record the entry at `index` as the newest value of `position`,
+    /// dropping the oldest one past `depth`.
+    fn retain_entry(&mut self, position: u128, index: u64, depth: u32) {
+        let entries = self.retained.entry(position).or_default();
+        entries.push_back(index);
+        while entries.len() > depth as usize {
+            entries.pop_front();
+        }
+    }
+
+    /// Rewrite the log with the entries still retained, in the order they
+    /// were written. The new log is written next to it and renamed over it,
+    /// so a crash leaves one of them whole.
+    fn compact_values(&mut self) -> std::io::Result<()> {
+        let log = match &self.value_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        let mut live: Vec<(u64, u128)> = self
+            .retained
+            .iter()
+            .flat_map(|(position, entries)| entries.iter().map(|index| (*index, *position)))
+            .collect();
+        live.sort_unstable();
+
+        let size = self.value_entry_size();
+        let mut bytes = self.retained_depth.to_be_bytes().to_vec();
+        let mut entry = vec![0_u8; size as usize];
+        for (index, _) in &live {
+            log.read_at(VALUE_LOG_HEADER_SIZE + index * size, &mut entry)?;
+            bytes.extend(&entry);
+        }
+
+        let temp = sidecar_path(&self.path, "values.compact");
+        fs::write(&temp, bytes)?;
+        fs::rename(&temp, sidecar_path(&self.path, "values"))?;
+        self.value_log = Some(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(sidecar_path(&self.path, "values"))?,
+        );
+
+        self.retained.clear();
+        for (index, (_, position)) in live.into_iter().enumerate() {
+            self.retained
+                .entry(position)
+                .or_default()
+                .push_back(index as u64);
+        }
+
+        Ok(())
+    }
+
+    /// The size in bytes of each entry of the log.
+    fn value_entry_size(&self) -> u64 {
+        let bits: u64 = self.subitems.iter().map(|size| *size as u64).sum();
+        17 + bits.div_ceil(8)
+    }
+
+    fn decode_value(&self, entry: &[u8]) -> NodeData {
+        let bits = bitcodec::bytes_to_bits(&entry[17..]);
+
+        let mut subitems = vec![];
+        let mut start = 0;
+        for size in &self.subitems {
+            subitems.push(bits[start..start + *size as usize].to_vec());
+            start += *size as usize;
+        }
+
+        NodeData {
+            position: u128::from_be_bytes(entry[..16].try_into().unwrap()),
+            enabled: entry[16] == 1,
+            subitems,
+        }
+    }
+}
+
+impl Node<'_> {
+    /// The last values written to the node, like [`Tree::node_history`].
+    pub fn history(&self) -> Result<Vec<NodeData>, NodeError> {
+        self.tree.node_history(self.position)
+    }
+}
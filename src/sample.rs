@@ -0,0 +1,62 @@
+//! Uniform samples of the enabled nodes, to profile or validate large trees
+//! statistically without decoding every node.
+
+use crate::{NodeData, NodeError, Tree};
+
+/// A small seeded generator of pseudo-random numbers (SplitMix64), so that
+/// samples can be reproduced.
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        ((self.next() as u128 * bound as u128) >> 64) as u64
+    }
+}
+
+impl Tree {
+    /// `n` enabled nodes sampled uniformly, without replacement, sorted by
+    /// position. Trees with fewer enabled nodes return all of them. The same
+    /// `seed` returns the same sample of the same tree.
+    ///
+    /// The sample is drawn from the positions of the enabled nodes (reservoir
+    /// sampling over [`positions`](Tree::positions)), so only the occupancy
+    /// bitmap of trees with the occupancy feature, or the headers of the
+    /// nodes otherwise, are read. Only the sampled nodes are decoded.
+    pub fn sample(&self, n: usize, seed: u64) -> Result<Vec<NodeData>, NodeError> {
+        if n == 0 {
+            return Ok(vec![]);
+        };
+
+        let mut rng = SampleRng(seed);
+        // `n` may be far more than the nodes there are, so the reservoir grows
+        // as positions are pushed.
+        let mut reservoir: Vec<u128> = Vec::new();
+        for (seen, position) in self.positions()?.enumerate() {
+            let position = position?;
+            if reservoir.len() < n {
+                reservoir.push(position);
+                continue;
+            };
+
+            let replaced = rng.below(seen as u64 + 1) as usize;
+            if replaced < n {
+                reservoir[replaced] = position;
+            };
+        }
+        reservoir.sort_unstable();
+
+        reservoir
+            .into_iter()
+            .map(|position| self.read_node(position))
+            .collect()
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{bitcodec, sidecar_path, Feature, Operation, Tree, TreeFileError, TreeOpenMode};
+use std::fs;
+use std::io::ErrorKind;
+
+impl Tree {
+    /// Name the current version of a persistent tree, so that the tree can
+    /// be rolled back to it with [`rollback_to`](Tree::rollback_to). Naming
+    /// another version with an existing name moves the savepoint. Returns
+    /// the saved version.
+    ///
+    /// The garbage collector always retains the versions of the
+    /// savepoints.
+    pub fn savepoint(&mut self, name: &str) -> Result<u64, TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        let version = match self.version() {
+            Some(version) => version,
+            None => return Err(TreeFileError::UnexistentVersion),
+        };
+
+        let mut savepoints = self.savepoints()?;
+        savepoints.retain(|(saved, _)| saved != name);
+        savepoints.push((name.to_string(), version));
+        self.write_savepoints(&savepoints)?;
+
+        Ok(version)
+    }
+
+    /// Make the version of a savepoint the current version again. The
+    /// rollback is recorded as a new version, so the versions written after
+    /// the savepoint can still be opened. The validator and the write hooks
+    /// aren't called.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        let version = match self
+            .savepoints()?
+            .into_iter()
+            .find(|(saved, _)| saved == name)
+        {
+            Some((_, version)) => version,
+            None => return Err(TreeFileError::UnexistentVersion),
+        };
+
+        self.roll_back_version(version)
+    }
+
+    /// Make a version of a persistent tree the current version again, as a
+    /// new version.
+    pub(crate) fn roll_back_version(&mut self, version: u64) -> Result<(), TreeFileError> {
+        let result = self.restore_version(version);
+        self.record_rollback(version, result.is_ok());
+
+        result
+    }
+
+    fn restore_version(&mut self, version: u64) -> Result<(), TreeFileError> {
+        let root = match self.version_root(version)? {
+            Some(root) => root,
+            None => return Err(TreeFileError::UnexistentVersion),
+        };
+        let latest = match self.version() {
+            Some(latest) => latest,
+            None => return Err(TreeFileError::UnexistentVersion),
+        };
+
+        let changes = self.diff_versions(latest, version)?;
+
+        if self.push_version(root).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+        self.push_timestamp()?;
+
+        // The occupancy bitmap, the level table, the subtree hashes and the
+        // subitem indexes describe the latest version.
+        for change in changes {
+            let was_enabled = change.before.as_ref().is_some_and(|node| node.enabled);
+            let enabled = change.after.as_ref().is_some_and(|node| node.enabled);
+
+            let result = self
+                .mark_occupancy(change.position, enabled)
+                .and_then(|_| self.update_level_stats(change.position, was_enabled, enabled))
+                .and_then(|_| self.update_merkle(change.position))
+                .and_then(|_| self.update_indexes(change.position));
+            if result.is_err() {
+                return Err(TreeFileError::Corrupted);
+            };
+        }
+
+        match self.record_audit(Operation::Rollback, None, None, None) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::MissingPermissions),
+        }
+    }
+
+    /// Remove a savepoint. Its version is kept, but the garbage collector
+    /// no longer retains it.
+    pub fn release_savepoint(&mut self, name: &str) -> Result<(), TreeFileError> {
+        let mut savepoints = self.savepoints()?;
+        let count = savepoints.len();
+        savepoints.retain(|(saved, _)| saved != name);
+
+        if savepoints.len() == count {
+            return Err(TreeFileError::UnexistentVersion);
+        };
+
+        self.write_savepoints(&savepoints)
+    }
+
+    /// The name and version of every savepoint, in the order they were
+    /// created.
+    pub fn savepoints(&self) -> Result<Vec<(String, u64)>, TreeFileError> {
+        let bytes = match fs::read(sidecar_path(&self.path, "savepoints")) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        let mut savepoints = vec![];
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            if rest.len() < 12 {
+                return Err(TreeFileError::Corrupted);
+            };
+
+            let (version, after) = rest.split_at(8);
+            let (length, after) = after.split_at(4);
+            let length = bitcodec::u8_array_to_u32(length.try_into().unwrap()) as usize;
+            if after.len() < length {
+                return Err(TreeFileError::Corrupted);
+            };
+
+            let (name, after) = after.split_at(length);
+            let name = match String::from_utf8(name.to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(TreeFileError::Corrupted),
+            };
+            savepoints.push((name, bitcodec::u8_array_to_u64(version.try_into().unwrap())));
+            rest = after;
+        }
+
+        Ok(savepoints)
+    }
+
+    fn write_savepoints(&self, savepoints: &[(String, u64)]) -> Result<(), TreeFileError> {
+        let mut bytes = vec![];
+        for (name, version) in savepoints {
+            bytes.extend(bitcodec::u64_to_u8_array(*version));
+            bytes.extend(bitcodec::u32_to_u8_array(name.len() as u32));
+            bytes.extend(name.as_bytes());
+        }
+
+        match fs::write(sidecar_path(&self.path, "savepoints"), bytes) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::MissingPermissions),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Sequential scans of every stored slot, reading the tree file in large
+//! chunks.
+
+use crate::{node_header_size, Feature, NodeError, Tree};
+
+/// A slot decoded by [`scan`](Tree::scan).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScannedSlot {
+    /// The index of the slot in the tree file. Slots of trees stored in
+    /// level order hold the node at the same position, except in persistent
+    /// trees.
+    pub slot: u128,
+
+    /// Whether the slot holds an enabled node.
+    pub enabled: bool,
+
+    /// The subitems of the node.
+    pub subitems: Vec<Vec<bool>>,
+}
+
+impl Tree {
+    /// Read every stored slot in file order, `chunk_bytes` bytes of the tree
+    /// file (and at least one slot) at a time, and call `visit` with the
+    /// slots of each chunk. Returning false from `visit` stops the scan.
+    /// The size of the chunks depends on the [`MemoryMode`](crate::MemoryMode)
+    /// of the tree.
+    ///
+    /// The slots passed to `visit` are decoded into the same buffers every
+    /// time, so a scan only allocates when it starts. Columnar trees are read
+    /// one column at a time. Persistent trees pass the copies kept for older
+    /// versions too.
+    pub fn scan(
+        &self,
+        chunk_bytes: usize,
+        mut visit: impl FnMut(&[ScannedSlot]) -> bool,
+    ) -> Result<(), NodeError> {
+        let node_size = self.node_size() as u128;
+        let slots = self.nodes() as u128;
+        if node_size == 0 {
+            return Ok(());
+        };
+
+        let disabling = self.features.contains(&Feature::Disabling);
+        let header_size = node_header_size(&self.features) as usize;
+        let chunk_bytes = self.memory_mode.chunk_bytes(chunk_bytes);
+        let chunk = (chunk_bytes as u128 * 8 / node_size).clamp(1, slots.max(1));
+
+        // The start of each span in the node's bits, and its width.
+        let spans: Vec<(usize, usize)> = self
+            .slot_spans(0)
+            .iter()
+            .scan(0, |start, (_, width)| {
+                let span = (*start, *width as usize);
+                *start += *width as usize;
+                Some(span)
+            })
+            .collect();
+
+        let mut buffers: Vec<Vec<u8>> = vec![vec![]; spans.len()];
+        let mut scanned: Vec<ScannedSlot> = (0..chunk)
+            .map(|_| ScannedSlot {
+                subitems: self
+                    .subitems
+                    .iter()
+                    .map(|size| Vec::with_capacity(*size as usize))
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut first = 0;
+        while first < slots {
+            let count = (slots - first).min(chunk);
+
+            // The bits of every span of the chunk are contiguous, and start
+            // `pad` bits into their first byte.
+            let mut pads = vec![];
+            for ((offset, width), buffer) in self.slot_spans(first).iter().zip(&mut buffers) {
+                let start = self.header_size as u64 + (offset / 8) as u64;
+                let pad = (offset % 8) as usize;
+                buffer.resize(
+                    (pad as u128 + count * *width as u128).div_ceil(8) as usize,
+                    0,
+                );
+                if self.read_bytes(start, buffer).is_err() {
+                    return Err(NodeError::Unexistent);
+                };
+                pads.push(pad);
+            }
+
+            let bit = |index: usize, node_bit: usize| {
+                let span = spans
+                    .iter()
+                    .rposition(|(start, _)| *start <= node_bit)
+                    .unwrap_or_default();
+                let (start, width) = spans[span];
+                let at = pads[span] + index * width + node_bit - start;
+                buffers[span][at / 8] & self.bit_order.mask(at) != 0
+            };
+
+            for (index, slot) in scanned[..count as usize].iter_mut().enumerate() {
+                slot.slot = first + index as u128;
+                slot.enabled = !disabling || bit(index, 0);
+
+                let mut node_bit = header_size;
+                for (subitem, size) in slot.subitems.iter_mut().zip(&self.subitems) {
+                    subitem.clear();
+                    subitem.extend((node_bit..node_bit + *size as usize).map(|at| bit(index, at)));
+                    node_bit += *size as usize;
+                }
+            }
+
+            if !visit(&scanned[..count as usize]) {
+                return Ok(());
+            };
+            first += count;
+        }
+
+        Ok(())
+    }
+}
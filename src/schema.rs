@@ -0,0 +1,196 @@
+#[cfg(feature = "std")]
+use crate::GapFill;
+use crate::{node_header_size, Feature, Layout};
+use alloc::vec::Vec;
+
+/// The maximum amount of subitems each node can have.
+pub const MAX_SUBITEMS: usize = 1 << 16;
+
+/// The maximum total size in bits of each node (including headers).
+pub const MAX_NODE_SIZE: u64 = 1 << 24;
+
+/// Why the layout of a tree is invalid.
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    /// A subitem has a size of 0 bits.
+    ZeroWidthSubitem { index: usize },
+
+    /// The nodes have more subitems than [`MAX_SUBITEMS`].
+    TooManySubitems { count: usize, max: usize },
+
+    /// The nodes are bigger than [`MAX_NODE_SIZE`] bits.
+    NodeTooLarge { size: u64, max: u64 },
+
+    /// The nodes have no bits at all, as there are no subitems and no
+    /// feature adds a node header.
+    EmptyNode,
+
+    /// The layout can't be used with the tree's features (columnar trees
+    /// need the disabling feature), or has no levels or more than
+    /// [`Layout::MAX_LEVELS`].
+    InvalidLayout,
+
+    /// The attribution feature is enabled, but the nodes don't have two
+    /// subitems of at most 64 bits at the end to hold the writer id and the
+    /// last-modified time.
+    InvalidAttribution,
+
+    /// The gap fill isn't zeros, but the tree is persistent or columnar, or
+    /// the template doesn't have the tree's subitems.
+    InvalidGapFill,
+}
+
+/// Check that a tree with `features` and `subitems` can be stored.
+pub(crate) fn validate(features: &[Feature], subitems: &[u32]) -> Result<(), SchemaError> {
+    if subitems.len() > MAX_SUBITEMS {
+        return Err(SchemaError::TooManySubitems {
+            count: subitems.len(),
+            max: MAX_SUBITEMS,
+        });
+    };
+
+    if let Some(index) = subitems.iter().position(|subitem| *subitem == 0) {
+        return Err(SchemaError::ZeroWidthSubitem { index });
+    };
+
+    if features.contains(&Feature::Attribution)
+        && (subitems.len() < 2 || subitems[subitems.len() - 2..].iter().any(|size| *size > 64))
+    {
+        return Err(SchemaError::InvalidAttribution);
+    };
+
+    let size = node_header_size(features) as u64
+        + subitems.iter().map(|subitem| *subitem as u64).sum::<u64>();
+
+    if size == 0 {
+        return Err(SchemaError::EmptyNode);
+    };
+
+    if size > MAX_NODE_SIZE {
+        return Err(SchemaError::NodeTooLarge {
+            size,
+            max: MAX_NODE_SIZE,
+        });
+    };
+
+    Ok(())
+}
+
+/// Check that a tree with `features` can be stored with `layout`.
+pub(crate) fn validate_layout(features: &[Feature], layout: Layout) -> Result<(), SchemaError> {
+    match layout {
+        Layout::LevelOrder => Ok(()),
+        // Persistent trees store their slots in the order they're written.
+        Layout::VanEmdeBoas { .. } | Layout::Columnar { .. }
+            if features.contains(&Feature::Persistent) =>
+        {
+            Err(SchemaError::InvalidLayout)
+        }
+        Layout::VanEmdeBoas { levels } | Layout::Columnar { levels }
+            if levels == 0 || levels > Layout::MAX_LEVELS =>
+        {
+            Err(SchemaError::InvalidLayout)
+        }
+        Layout::VanEmdeBoas { .. } | Layout::Columnar { .. } => Ok(()),
+    }
+}
+
+#[cfg(feature = "std")]
+/// Check that the slots a tree with `features` reserves with `layout` read
+/// as missing nodes until they're written. Columnar trees reserve every
+/// slot when they're created, which only the disabling feature tells apart
+/// from nodes.
+pub(crate) fn validate_reserved_slots(
+    features: &[Feature],
+    layout: Layout,
+) -> Result<(), SchemaError> {
+    match layout {
+        Layout::Columnar { .. } if !features.contains(&Feature::Disabling) => {
+            Err(SchemaError::InvalidLayout)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "std")]
+/// Check that the gaps of a tree with `features`, `subitems` and `layout` can
+/// be filled with `gap_fill`.
+pub(crate) fn validate_gap_fill(
+    features: &[Feature],
+    subitems: &[u32],
+    layout: Layout,
+    gap_fill: &GapFill,
+) -> Result<(), SchemaError> {
+    match gap_fill {
+        GapFill::Zeros => return Ok(()),
+        _ if features.contains(&Feature::Persistent)
+            || matches!(layout, Layout::Columnar { .. }) =>
+        {
+            return Err(SchemaError::InvalidGapFill)
+        }
+        GapFill::Ones => (),
+        GapFill::Template(template) => {
+            if template.len() != subitems.len()
+                || template
+                    .iter()
+                    .zip(subitems)
+                    .any(|(subitem, size)| subitem.len() != *size as usize)
+            {
+                return Err(SchemaError::InvalidGapFill);
+            };
+        }
+    };
+
+    Ok(())
+}
+
+/// How a tree layout packs its nodes into bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaReport {
+    /// The size in bits of the headers added to each node by the features.
+    pub header_size: u32,
+
+    /// The total size in bits of each node.
+    pub node_size: u32,
+
+    /// Whether every node starts and ends at a byte boundary.
+    pub aligned: bool,
+
+    /// The average amount of bits of neighbouring nodes that are read (and
+    /// rewritten) along with each node.
+    pub wasted_bits_per_slot: f64,
+
+    /// The same subitems, plus a padding subitem that aligns the nodes to
+    /// byte boundaries. Equal to the analyzed subitems if they're aligned.
+    pub suggested_subitems: Vec<u32>,
+}
+
+/// Analyze how a tree with `subitems` and `features` would be stored, and
+/// suggest a padded layout whose nodes are aligned to byte boundaries.
+pub fn analyze_schema(subitems: &[u32], features: &[Feature]) -> Result<SchemaReport, SchemaError> {
+    validate(features, subitems)?;
+
+    let header_size = node_header_size(features);
+    let node_size = header_size + subitems.iter().sum::<u32>();
+
+    // Nodes start at the same bit of a byte every 8 nodes, so 8 consecutive
+    // nodes cover every way a node can be placed.
+    let touched_bits: u64 = (0..8_u64)
+        .map(|slot| (slot * node_size as u64 % 8 + node_size as u64).div_ceil(8) * 8)
+        .sum();
+    let wasted_bits_per_slot = (touched_bits as f64 / 8.0) - node_size as f64;
+
+    let padding = (8 - node_size % 8) % 8;
+    let mut suggested_subitems = subitems.to_vec();
+    if padding != 0 {
+        suggested_subitems.push(padding);
+    };
+
+    Ok(SchemaReport {
+        header_size,
+        node_size,
+        aligned: padding == 0,
+        wasted_bits_per_slot,
+        suggested_subitems,
+    })
+}
@@ -0,0 +1,65 @@
+use crate::{positions, NodeData, NodeError, Tree};
+use std::cmp::Ordering;
+
+impl Tree {
+    /// Binary search the deepest level of a complete tree (every level full
+    /// except the deepest, which is filled from the left) whose leaves are
+    /// sorted from left to right. `cmp` returns how a leaf compares to the
+    /// searched one, like [`slice::binary_search_by`].
+    ///
+    /// Returns the position of a matching leaf, or `None` if no leaf
+    /// matches. If several leaves match, the one returned is the first one
+    /// the search reaches: always the same one for the same leaves, but not
+    /// necessarily the leftmost one.
+    pub fn search_leaves(
+        &self,
+        mut cmp: impl FnMut(&NodeData) -> Ordering,
+    ) -> Result<Option<u128>, NodeError> {
+        // The leftmost path of a complete tree reaches the deepest level.
+        let mut first = 0;
+        if !self.exists(first)? {
+            return Ok(None);
+        };
+        while let Some(child) = positions::child(first, 0) {
+            if !self.exists(child)? {
+                break;
+            };
+            first = child;
+        }
+
+        // The deepest level is filled from the left, so the leaves that exist
+        // come before those that don't. It ends where the next level starts.
+        let end = positions::child(first, 0).unwrap_or(u128::MAX);
+        let (mut low, mut high) = (first + 1, end);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.exists(middle)? {
+                true => low = middle + 1,
+                false => high = middle,
+            };
+        }
+
+        let (mut low, mut high) = (first, low);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let leaf = self.read_node(middle)?;
+
+            match cmp(&leaf) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(Some(middle)),
+            };
+        }
+
+        Ok(None)
+    }
+
+    /// Whether a position holds an enabled node.
+    fn exists(&self, position: u128) -> Result<bool, NodeError> {
+        match self.read_node(position) {
+            Ok(_) => Ok(true),
+            Err(NodeError::Unexistent) | Err(NodeError::Disabled) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
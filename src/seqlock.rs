@@ -0,0 +1,188 @@
+//! Sequence counters letting reader handles read the tree file without
+//! locking while a writer changes it, retrying the reads that a write
+//! overlapped.
+
+use crate::cache::PAGE_SIZE;
+use crate::{CreateOptions, Feature, Tree, TreeFileError, TreeOpenMode};
+use std::hint;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The amount of sequence counters. Blocks share counters when the tree file
+/// has more blocks, which only makes some reads retry needlessly.
+const SEQUENCE_COUNT: u64 = 1024;
+
+/// The writes in flight to the blocks sharing a counter, and how many of
+/// them finished. Several writers (like [`SubtreeWriter`](crate::SubtreeWriter)s)
+/// may write the same blocks at once, so a single counter made odd while
+/// a block is written would be even again while two writes are in flight.
+#[derive(Debug, Default)]
+struct BlockSequence {
+    writers: AtomicU64,
+    sequence: AtomicU64,
+}
+
+/// A sequence counter for every block of the tree file.
+#[derive(Debug)]
+pub(crate) struct BlockSequences {
+    counters: Vec<BlockSequence>,
+}
+
+impl BlockSequences {
+    fn new() -> Self {
+        Self {
+            counters: (0..SEQUENCE_COUNT)
+                .map(|_| BlockSequence::default())
+                .collect(),
+        }
+    }
+
+    /// The counters of the blocks holding `len` bytes at `offset`, each at
+    /// most once.
+    fn counters(&self, offset: u64, len: usize) -> impl Iterator<Item = &BlockSequence> {
+        let first = offset / PAGE_SIZE;
+        let end = (offset + len as u64).div_ceil(PAGE_SIZE).max(first + 1);
+
+        (first..end)
+            .take(SEQUENCE_COUNT as usize)
+            .map(|block| &self.counters[(block % SEQUENCE_COUNT) as usize])
+    }
+
+    /// Write the blocks holding `len` bytes at `offset`.
+    pub(crate) fn write<T>(&self, offset: u64, len: usize, write: impl FnOnce() -> T) -> T {
+        for counter in self.counters(offset, len) {
+            counter.writers.fetch_add(1, Ordering::AcqRel);
+        }
+        let result = write();
+        // The sequence grows before the writer leaves, so a reader that
+        // doesn't see the writer anymore sees the write finished.
+        for counter in self.counters(offset, len) {
+            counter.sequence.fetch_add(1, Ordering::AcqRel);
+            counter.writers.fetch_sub(1, Ordering::AcqRel);
+        }
+
+        result
+    }
+
+    /// Read the blocks holding `len` bytes at `offset`, reading again until
+    /// no write overlapped the read.
+    pub(crate) fn read(
+        &self,
+        offset: u64,
+        len: usize,
+        mut read: impl FnMut() -> io::Result<()>,
+    ) -> io::Result<()> {
+        loop {
+            // Sequences only grow, so their sum only stays the same if no
+            // write finished.
+            let before = match self.sum(offset, len) {
+                Some(sum) => sum,
+                None => {
+                    hint::spin_loop();
+                    continue;
+                }
+            };
+
+            let result = read();
+            if self.sum(offset, len) == Some(before) {
+                return result;
+            };
+        }
+    }
+
+    /// The sum of the sequences of the blocks, or `None` if any of them is
+    /// being written.
+    fn sum(&self, offset: u64, len: usize) -> Option<u64> {
+        let mut sum: u64 = 0;
+        for counter in self.counters(offset, len) {
+            // The writers are loaded first: a write finished after the load
+            // grows the sequence loaded next.
+            if counter.writers.load(Ordering::Acquire) > 0 {
+                return None;
+            };
+            sum = sum.wrapping_add(counter.sequence.load(Ordering::Acquire));
+        }
+
+        Some(sum)
+    }
+}
+
+impl Tree {
+    /// Open a read-only handle over the tree's storage, e.g. to send to a
+    /// reader thread while this tree keeps writing. Reads through the handle
+    /// never wait for the writes made through this tree: they're read again
+    /// if a write changed the same blocks of the tree file meanwhile.
+    ///
+    /// The handle sees every write once it's made, without flushing. Only
+    /// the tree file is covered, so persistent trees (whose versions are
+    /// kept in another file) can't have readers. For the same reason, the
+    /// handle never keeps pages in memory: [`pin`](Tree::pin),
+    /// [`prefetch`](Tree::prefetch) and [`warm`](Tree::warm) do nothing
+    /// through it, as the pages kept wouldn't see later writes.
+    pub fn reader(&mut self) -> Result<Tree, TreeFileError> {
+        if self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let sequences = Arc::clone(
+            self.sequences
+                .get_or_insert_with(|| Arc::new(BlockSequences::new())),
+        );
+
+        let options = CreateOptions {
+            features: self.features.clone(),
+            subitems: self.subitems.clone(),
+            bit_order: self.bit_order,
+            layout: self.layout,
+            gap_fill: self.gap_fill.clone(),
+        };
+        let path = self.path.to_string_lossy().into_owned();
+
+        let mut reader = Tree::from_parts(
+            Arc::clone(&self.storage),
+            TreeOpenMode::Read,
+            &path,
+            options,
+            false,
+        )?;
+        reader.sequences = Some(sequences);
+
+        Ok(reader)
+    }
+
+    /// Whether the tree is a handle opened with [`reader`](Tree::reader).
+    pub(crate) fn is_reader(&self) -> bool {
+        self.sequences.is_some() && self.mode == TreeOpenMode::Read
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_every_writer_in_flight() {
+        let sequences = BlockSequences::new();
+        let before = sequences.sum(0, 1);
+
+        sequences.write(0, 1, || {
+            assert_eq!(sequences.sum(0, 1), None);
+            sequences.write(1, 1, || assert_eq!(sequences.sum(0, 1), None));
+            // The other write finished, but this one is still in flight.
+            assert_eq!(sequences.sum(0, 1), None);
+        });
+
+        assert!(sequences.sum(0, 1).is_some());
+        assert_ne!(sequences.sum(0, 1), before);
+    }
+
+    #[test]
+    fn skips_the_blocks_not_written() {
+        let sequences = BlockSequences::new();
+
+        sequences.write(0, 1, || {
+            assert_eq!(sequences.sum(PAGE_SIZE, 1), Some(0));
+        });
+    }
+}
@@ -0,0 +1,540 @@
+//! A small HTTP/JSON API over a tree, so that services in other languages
+//! can read and write a centrally hosted tree.
+//!
+//! | Request                            | Response                             |
+//! | ---------------------------------- | ------------------------------------ |
+//! | `GET /nodes/{position}`            | The node.                            |
+//! | `PUT /nodes/{position}`            | `204`, after setting the node.       |
+//! | `GET /nodes/{position}/subtree`    | The nodes of the subtree, pre-order. |
+//! | `GET /stats`                       | The shape and I/O stats of the tree. |
+//!
+//! Nodes are sent as `{"position": 0, "enabled": true, "subitems": [...]}`,
+//! with subitems of at most 64 bits as numbers and longer ones as strings of
+//! `0`s and `1`s. `PUT` takes the same object, without the position, and
+//! `enabled` defaults to true. The subtree takes the `depth` and `disabled`
+//! (`true` to include disabled nodes) query parameters. Errors are sent as
+//! `{"error": "..."}`, and bodies nesting arrays and objects more than 32
+//! deep are rejected as `InvalidJson`. Requests whose line and headers take
+//! more than 64 KiB, or that have more than 100 headers, are answered with
+//! `431`.
+
+use crate::{bitcodec, NodeData, NodeError, TraversalOptions, Tree};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The largest request body accepted, in bytes.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The largest request line and headers accepted, in bytes all together.
+const MAX_HEAD_SIZE: u64 = 64 * 1024;
+
+/// The most headers accepted in a request.
+const MAX_HEADERS: usize = 100;
+
+/// The deepest nesting of arrays and objects accepted in a request body.
+const MAX_JSON_DEPTH: usize = 32;
+
+/// How long reading a request or writing a response may stall before the
+/// connection is dropped, unless set with
+/// [`set_timeout`](TreeServer::set_timeout).
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server exposing a tree over HTTP. Requests are handled one at a time,
+/// one per connection.
+#[derive(Debug)]
+pub struct TreeServer {
+    tree: Tree,
+    listener: TcpListener,
+    timeout: Duration,
+}
+
+/// A response, as its status and JSON body.
+type Response = (u16, Option<String>);
+
+/// The method, target and body length of a request.
+type Head = (String, String, u64);
+
+impl TreeServer {
+    /// Listen for requests on `address`.
+    pub fn bind(tree: Tree, address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            tree,
+            listener: TcpListener::bind(address)?,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Set how long reading a request or writing a response may stall
+    /// before the connection is dropped, so a slow client can't hold the
+    /// server (30 seconds by default). Fails if `timeout` is zero.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        if timeout.is_zero() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// The address the server listens on, e.g. to find the port it was
+    /// given when bound to port `0`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle requests forever. Failed connections are skipped.
+    pub fn run(mut self) -> io::Result<()> {
+        loop {
+            let _ = self.handle_next();
+        }
+    }
+
+    /// Wait for a connection and handle its request.
+    pub fn handle_next(&mut self) -> io::Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        self.handle(stream)
+    }
+
+    /// Stop serving and take the tree back.
+    pub fn into_tree(self) -> Tree {
+        self.tree
+    }
+
+    fn handle(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let (method, target, length) = match read_head(&mut reader)? {
+            Ok(head) => head,
+            Err(response) => return respond(&mut stream, response),
+        };
+
+        if length > MAX_BODY_SIZE {
+            return respond(&mut stream, failure(413, "BodyTooLarge"));
+        };
+        let mut body = vec![0_u8; length as usize];
+        reader.read_exact(&mut body)?;
+
+        let response = self.route(&method, &target, &body);
+        respond(&mut stream, response)
+    }
+
+    fn route(&mut self, method: &str, target: &str, body: &[u8]) -> Response {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (target, ""),
+        };
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", ["stats"]) => (200, Some(self.stats())),
+            ("GET", ["nodes", position]) => match position.parse() {
+                Ok(position) => match self.tree.read_node(position) {
+                    Ok(node) => (200, Some(node_json(&node))),
+                    Err(error) => node_error(error),
+                },
+                Err(_) => failure(400, "InvalidPosition"),
+            },
+            ("PUT", ["nodes", position]) => match position.parse() {
+                Ok(position) => self.set_node(position, body),
+                Err(_) => failure(400, "InvalidPosition"),
+            },
+            ("GET", ["nodes", position, "subtree"]) => match position.parse() {
+                Ok(position) => self.subtree(position, query),
+                Err(_) => failure(400, "InvalidPosition"),
+            },
+            (_, ["stats"] | ["nodes", _] | ["nodes", _, "subtree"]) => {
+                failure(405, "MethodNotAllowed")
+            }
+            _ => failure(404, "NotFound"),
+        }
+    }
+
+    fn set_node(&mut self, position: u128, body: &[u8]) -> Response {
+        let fields = match std::str::from_utf8(body).ok().and_then(parse_json) {
+            Some(Json::Object(fields)) => fields,
+            _ => return failure(400, "InvalidJson"),
+        };
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+        };
+
+        let enabled = match field("enabled") {
+            Some(Json::Bool(enabled)) => *enabled,
+            None => true,
+            Some(_) => return failure(400, "InvalidEnabled"),
+        };
+        let values = match field("subitems") {
+            Some(Json::Array(values)) if values.len() == self.tree.subitems.len() => values,
+            _ => return failure(400, "InvalidSubitems"),
+        };
+
+        let mut subitems = vec![];
+        for (value, width) in values.iter().zip(&self.tree.subitems) {
+            subitems.push(match value {
+                Json::Number(number) if *width <= 64 => {
+                    if *width < 64 && *number >> width != 0 {
+                        return failure(400, "InvalidSubitems");
+                    };
+                    bitcodec::u64_to_bits(*number, *width)
+                }
+                Json::String(bits) if bits.chars().all(|bit| bit == '0' || bit == '1') => {
+                    bits.chars().map(|bit| bit == '1').collect()
+                }
+                _ => return failure(400, "InvalidSubitems"),
+            });
+        }
+
+        match self
+            .tree
+            .set_node_quiet(&subitems, &position, true, !enabled)
+        {
+            Ok(_) => (204, None),
+            Err(error) => node_error(error),
+        }
+    }
+
+    fn subtree(&self, position: u128, query: &str) -> Response {
+        let mut options = TraversalOptions::default();
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            match parameter.split_once('=') {
+                Some(("depth", depth)) => match depth.parse() {
+                    Ok(depth) => options.max_depth = Some(depth),
+                    Err(_) => return failure(400, "InvalidDepth"),
+                },
+                Some(("disabled", disabled)) => options.include_disabled = disabled == "true",
+                _ => return failure(400, "InvalidQuery"),
+            };
+        }
+
+        let mut nodes = vec![];
+        for node in self.tree.traverse(position, options) {
+            match node {
+                Ok(node) => nodes.push(node_json(&node)),
+                Err(error) => return node_error(error),
+            };
+        }
+
+        (200, Some(format!("[{}]", nodes.join(","))))
+    }
+
+    fn stats(&self) -> String {
+        let io = self.tree.io_stats();
+        let features: Vec<String> = self
+            .tree
+            .features
+            .iter()
+            .map(|feature| format!("\"{:?}\"", feature))
+            .collect();
+        let subitems: Vec<String> = self.tree.subitems.iter().map(u32::to_string).collect();
+
+        format!(
+            "{{\"nodes\":{},\"levels\":{},\"node_size\":{},\"features\":[{}],\"subitems\":[{}],\"bytes_read\":{},\"bytes_written\":{}}}",
+            self.tree.nodes(),
+            self.tree.levels(),
+            self.tree.node_size(),
+            features.join(","),
+            subitems.join(","),
+            io.bytes_read,
+            io.bytes_written,
+        )
+    }
+}
+
+/// Read the request line and the headers, failing with the response to send
+/// if they're invalid or larger than [`MAX_HEAD_SIZE`] and [`MAX_HEADERS`]
+/// allow, so a client sending a line without end can't fill the memory.
+fn read_head(reader: &mut impl BufRead) -> io::Result<Result<Head, Response>> {
+    let mut head = reader.take(MAX_HEAD_SIZE);
+
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    if !line.ends_with('\n') && head.limit() == 0 {
+        return Ok(Err(failure(431, "HeadersTooLarge")));
+    };
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Ok(Err(failure(400, "InvalidRequest"))),
+    };
+
+    let mut length = 0;
+    for headers in 0.. {
+        line.clear();
+        if head.read_line(&mut line)? == 0 {
+            return match head.limit() {
+                0 => Ok(Err(failure(431, "HeadersTooLarge"))),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        };
+        if !line.ends_with('\n') && head.limit() == 0 {
+            return Ok(Err(failure(431, "HeadersTooLarge")));
+        };
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        };
+        if headers == MAX_HEADERS {
+            return Ok(Err(failure(431, "HeadersTooLarge")));
+        };
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(u64::MAX);
+            };
+        };
+    }
+
+    Ok(Ok((method, target, length)))
+}
+
+fn respond(stream: &mut TcpStream, (status, body): Response) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    let body = body.unwrap_or_default();
+
+    let mut response = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", status, reason);
+    if status != 204 {
+        response += &format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+    };
+    response += "\r\n";
+    response += &body;
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+fn failure(status: u16, name: &str) -> Response {
+    (status, Some(format!("{{\"error\":\"{}\"}}", name)))
+}
+
+fn node_error(error: NodeError) -> Response {
+    let status = match error {
+        NodeError::Disabled | NodeError::Unexistent => 404,
+        NodeError::NodeAlreadyExists
+        | NodeError::WouldLeaveGap
+        | NodeError::InvalidTransactionState => 409,
+        NodeError::InvalidIndex
+        | NodeError::InvalidSubitem
+        | NodeError::SubitemCountMismatch { .. }
+        | NodeError::InvalidToken => 400,
+        _ => 500,
+    };
+
+    // Struct variants are named without their fields.
+    let name = format!("{:?}", error);
+    let name = name.split([' ', '(', '{']).next().unwrap_or_default();
+    failure(status, name)
+}
+
+fn node_json(node: &NodeData) -> String {
+    let subitems: Vec<String> = node
+        .subitems
+        .iter()
+        .map(|subitem| match subitem.len() {
+            0..=64 => bitcodec::bits_to_u64(subitem).to_string(),
+            _ => format!(
+                "\"{}\"",
+                subitem
+                    .iter()
+                    .map(|bit| if *bit { '1' } else { '0' })
+                    .collect::<String>()
+            ),
+        })
+        .collect();
+
+    format!(
+        "{{\"position\":{},\"enabled\":{},\"subitems\":[{}]}}",
+        node.position,
+        node.enabled,
+        subitems.join(",")
+    )
+}
+
+/// The JSON values taken by the API. Numbers are unsigned integers.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+fn parse_json(text: &str) -> Option<Json> {
+    let mut chars = text.chars().peekable();
+    let value = parse_value(&mut chars, 0)?;
+
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        Some(_) => None,
+        None => Some(value),
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|char| char.is_whitespace()).is_some() {}
+}
+
+/// Parse the value at `chars`, nested in `depth` arrays and objects. Values
+/// nested deeper than [`MAX_JSON_DEPTH`] are rejected.
+fn parse_value(chars: &mut Chars, depth: usize) -> Option<Json> {
+    skip_whitespace(chars);
+
+    if matches!(chars.peek()?, '{' | '[') && depth == MAX_JSON_DEPTH {
+        return None;
+    };
+
+    match chars.peek()? {
+        '{' => {
+            chars.next();
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Some(Json::Object(fields));
+            };
+            loop {
+                skip_whitespace(chars);
+                let key = match parse_value(chars, depth + 1)? {
+                    Json::String(key) => key,
+                    _ => return None,
+                };
+                skip_whitespace(chars);
+                chars.next_if_eq(&':')?;
+                fields.push((key, parse_value(chars, depth + 1)?));
+
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => return Some(Json::Object(fields)),
+                    _ => return None,
+                };
+            }
+        }
+        '[' => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Some(Json::Array(values));
+            };
+            loop {
+                values.push(parse_value(chars, depth + 1)?);
+
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    ']' => return Some(Json::Array(values)),
+                    _ => return None,
+                };
+            }
+        }
+        '"' => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => return Some(Json::String(string)),
+                    '\\' => string.push(match chars.next()? {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        char @ ('"' | '\\' | '/') => char,
+                        _ => return None,
+                    }),
+                    char => string.push(char),
+                };
+            }
+        }
+        '0'..='9' => {
+            let mut number: u64 = 0;
+            while let Some(digit) = chars.peek().and_then(|char| char.to_digit(10)) {
+                chars.next();
+                number = number.checked_mul(10)?.checked_add(digit as u64)?;
+            }
+            Some(Json::Number(number))
+        }
+        _ => {
+            let word: String =
+                std::iter::from_fn(|| chars.next_if(char::is_ascii_alphabetic)).collect();
+            match word.as_str() {
+                "true" => Some(Json::Bool(true)),
+                "false" => Some(Json::Bool(false)),
+                "null" => Some(Json::Null),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_deeply_nested_bodies() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+
+        assert!(parse_json(&nested(MAX_JSON_DEPTH)).is_some());
+        assert!(parse_json(&nested(MAX_JSON_DEPTH + 1)).is_none());
+        assert!(parse_json(&"[".repeat(1_000_000)).is_none());
+    }
+
+    #[test]
+    fn rejects_heads_without_end() {
+        let status = |request: String| match read_head(&mut request.as_bytes()) {
+            Ok(Err((status, _))) => Some(status),
+            _ => None,
+        };
+
+        let head =
+            read_head(&mut "PUT /nodes/3 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}".as_bytes());
+        assert_eq!(
+            head.unwrap().unwrap(),
+            ("PUT".to_string(), "/nodes/3".to_string(), 2)
+        );
+
+        // A request line, or a header, without end.
+        assert_eq!(
+            status("GET /".to_string() + &"a".repeat(1 << 20)),
+            Some(431)
+        );
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nX: ".to_string() + &"a".repeat(1 << 20)),
+            Some(431)
+        );
+
+        // Too many headers, each of them short.
+        let headers = "X: a\r\n".repeat(MAX_HEADERS + 1);
+        assert_eq!(
+            status(format!("GET / HTTP/1.1\r\n{}\r\n", headers)),
+            Some(431)
+        );
+        let headers = "X: a\r\n".repeat(MAX_HEADERS);
+        assert!(
+            read_head(&mut format!("GET / HTTP/1.1\r\n{}\r\n", headers).as_bytes())
+                .unwrap()
+                .is_ok()
+        );
+    }
+}
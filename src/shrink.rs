@@ -0,0 +1,100 @@
+//! Dropping the disabled slots at the end of the tree file, e.g. after
+//! disabling the deepest levels of the tree.
+
+use crate::{Feature, Layout, Tree, TreeFileError, TreeOpenMode};
+
+/// The most bits read at once while looking for the last enabled slot.
+const SHRINK_CHUNK_BITS: u128 = 1 << 19;
+
+/// The result of dropping the disabled slots at the end of a tree file.
+#[derive(Debug)]
+pub struct ShrinkReport {
+    /// The amount of slots that were removed from the tree file.
+    pub reclaimed_slots: u64,
+
+    /// The amount of bytes the tree file shrunk.
+    pub reclaimed_bytes: u64,
+}
+
+impl Tree {
+    /// Truncate the tree file after its last enabled slot, dropping the
+    /// disabled slots after it. The positions of the dropped slots read as
+    /// unexistent rather than disabled, and every other node keeps its slot,
+    /// so nothing else is rewritten.
+    ///
+    /// Trees without the disabling feature have no disabled slots to drop.
+    /// Columnar trees have a fixed size, and persistent trees reclaim their
+    /// slots through [`gc`](Tree::gc), so both fail with
+    /// [`UnsupportedFeature`](TreeFileError::UnsupportedFeature).
+    pub fn shrink_to_fit(&mut self) -> Result<ShrinkReport, TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        if self.features.contains(&Feature::Persistent)
+            || matches!(self.layout, Layout::Columnar { .. })
+        {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        let slots = self.nodes();
+        let kept = match self.features.contains(&Feature::Disabling) {
+            true => self.bulk(|tree| tree.enabled_slots_end(slots as u128))?,
+            false => slots as u128,
+        };
+
+        let old_size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+        let new_size =
+            self.header_size as u64 + (kept as u64 * self.node_size() as u64).div_ceil(8);
+        if new_size >= old_size {
+            return Ok(ShrinkReport {
+                reclaimed_slots: 0,
+                reclaimed_bytes: 0,
+            });
+        };
+
+        if self.set_storage_size(new_size).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        Ok(ShrinkReport {
+            reclaimed_slots: slots - kept as u64,
+            reclaimed_bytes: old_size - new_size,
+        })
+    }
+
+    /// The slot after the last enabled one of the first `slots` slots, read
+    /// from the end in chunks.
+    fn enabled_slots_end(&self, slots: u128) -> Result<u128, TreeFileError> {
+        let node_size = self.node_size() as u128;
+        let chunk = (SHRINK_CHUNK_BITS / node_size).max(1);
+
+        let mut end = slots;
+        while end > 0 {
+            let start = end.saturating_sub(chunk);
+            let first_byte = start * node_size / 8;
+            let end_byte = (end * node_size).div_ceil(8);
+
+            let mut bytes = vec![0_u8; (end_byte - first_byte) as usize];
+            let offset = self.header_size as u64 + first_byte as u64;
+            if self.read_bytes(offset, &mut bytes).is_err() {
+                return Err(TreeFileError::FileNotOpened);
+            };
+
+            // The enabled bit comes first in every slot.
+            for slot in (start..end).rev() {
+                let bit = (slot * node_size - first_byte * 8) as usize;
+                if bytes[bit / 8] & self.bit_order.mask(bit) != 0 {
+                    return Ok(slot + 1);
+                };
+            }
+
+            end = start;
+        }
+
+        Ok(0)
+    }
+}
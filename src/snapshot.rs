@@ -0,0 +1,153 @@
+use crate::{NodeData, NodeError, Tree};
+use std::collections::BTreeMap;
+
+/// The expected contents of a tree, kept in memory.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Snapshot {
+    /// The size of each node subitem in bits.
+    pub subitems: Vec<u32>,
+
+    /// The nodes of the tree by their tranversal position.
+    pub nodes: BTreeMap<u128, NodeData>,
+}
+
+/// What to ignore when comparing a tree against a snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct MatchOptions {
+    /// Skip disabled nodes, both in the tree and in the snapshot.
+    pub ignore_disabled: bool,
+
+    /// The indexes of the subitems that aren't compared.
+    pub ignore_subitems: Vec<usize>,
+}
+
+/// A difference between a tree and a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    /// The tree's subitems have different sizes than the snapshot's.
+    Schema { expected: Vec<u32>, got: Vec<u32> },
+
+    /// A node of the snapshot isn't in the tree.
+    MissingNode { position: u128 },
+
+    /// A node of the tree isn't in the snapshot.
+    UnexpectedNode { position: u128 },
+
+    /// A node is enabled in only one of the tree and the snapshot.
+    Enabled { position: u128, expected: bool },
+
+    /// A subitem of a node has different bits in the tree.
+    Subitem {
+        position: u128,
+        index: usize,
+        expected: Vec<bool>,
+        got: Vec<bool>,
+    },
+}
+
+impl Snapshot {
+    /// An empty snapshot of a tree with `subitems`.
+    pub fn new(subitems: Vec<u32>) -> Self {
+        Self {
+            subitems,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Add (or replace) a node.
+    pub fn insert(&mut self, position: u128, subitems: Vec<Vec<bool>>, enabled: bool) {
+        self.nodes.insert(
+            position,
+            NodeData {
+                position,
+                enabled,
+                subitems,
+            },
+        );
+    }
+}
+
+impl Tree {
+    /// Capture every stored node of the tree in a snapshot.
+    pub fn snapshot(&self) -> Result<Snapshot, NodeError> {
+        let nodes = self.stored_nodes()?;
+
+        Ok(Snapshot {
+            subitems: self.subitems.clone(),
+            nodes: nodes
+                .into_iter()
+                .map(|node| (node.position, node))
+                .collect(),
+        })
+    }
+
+    /// Compare the tree against a snapshot, returning every difference
+    /// found. The tree matches the snapshot if the list is empty.
+    pub fn assert_matches(
+        &self,
+        expected: &Snapshot,
+        options: &MatchOptions,
+    ) -> Result<Vec<Mismatch>, NodeError> {
+        if expected.subitems != self.subitems {
+            return Ok(vec![Mismatch::Schema {
+                expected: expected.subitems.clone(),
+                got: self.subitems.clone(),
+            }]);
+        };
+
+        let keep = |node: &NodeData| node.enabled || !options.ignore_disabled;
+
+        let got: BTreeMap<u128, NodeData> = self
+            .stored_nodes()?
+            .into_iter()
+            .filter(keep)
+            .map(|node| (node.position, node))
+            .collect();
+
+        let mut mismatches = vec![];
+        for node in expected.nodes.values().filter(|node| keep(node)) {
+            let stored = match got.get(&node.position) {
+                Some(stored) => stored,
+                None => {
+                    mismatches.push(Mismatch::MissingNode {
+                        position: node.position,
+                    });
+                    continue;
+                }
+            };
+
+            if stored.enabled != node.enabled {
+                mismatches.push(Mismatch::Enabled {
+                    position: node.position,
+                    expected: node.enabled,
+                });
+            };
+
+            for (index, subitem) in node.subitems.iter().enumerate() {
+                if options.ignore_subitems.contains(&index) {
+                    continue;
+                };
+
+                let stored_subitem = stored.subitems.get(index).cloned().unwrap_or_default();
+                if *subitem != stored_subitem {
+                    mismatches.push(Mismatch::Subitem {
+                        position: node.position,
+                        index,
+                        expected: subitem.clone(),
+                        got: stored_subitem,
+                    });
+                };
+            }
+        }
+
+        for position in got.keys() {
+            if !expected.nodes.get(position).is_some_and(keep) {
+                mismatches.push(Mismatch::UnexpectedNode {
+                    position: *position,
+                });
+            };
+        }
+
+        Ok(mismatches)
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{
+    create_file, lease, node_size, read_dirty, read_headers, schema, write_headers, CreateOptions,
+    Feature, Tree, TreeFileError, TreeOpenMode,
+};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A byte store holding a tree file. Reads and writes are positioned, so a
+/// storage can be shared between handles without a common cursor.
+pub trait Storage: Debug + Send + Sync {
+    /// Read exactly `buf.len()` bytes starting at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write the whole buffer starting at `offset`, growing the storage if
+    /// needed. Gaps are filled with zeros.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// The size of the stored data in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncate or extend (with zeros) the stored data.
+    fn set_size(&self, size: u64) -> io::Result<()>;
+
+    /// Flush the changes to disk.
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut read = 0;
+        while read < buf.len() {
+            match std::os::windows::fs::FileExt::seek_read(
+                self,
+                &mut buf[read..],
+                offset + read as u64,
+            ) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match std::os::windows::fs::FileExt::seek_write(
+                self,
+                &buf[written..],
+                offset + written as u64,
+            ) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// A read-only storage over bytes kept in memory.
+#[derive(Debug)]
+pub(crate) struct ByteStorage(pub(crate) Vec<u8>);
+
+impl Storage for ByteStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = match usize::try_from(offset) {
+            Ok(start) if start + buf.len() <= self.0.len() => start,
+            _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+
+        buf.copy_from_slice(&self.0[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn set_size(&self, _size: u64) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A storage that keeps the first bytes of a tree (the headers and its top
+/// levels) in a hot file, and the rest in a cold file. Accesses are routed
+/// to the right file transparently.
+#[derive(Debug)]
+pub struct TieredStorage {
+    /// The file holding the bytes before `split`.
+    pub hot: File,
+
+    /// The file holding the bytes from `split` onwards.
+    pub cold: File,
+
+    /// The offset where the cold file starts.
+    pub split: u64,
+}
+
+impl TieredStorage {
+    /// Split a byte range into the parts stored in the hot file and in the
+    /// cold file.
+    fn route(&self, offset: u64, len: usize) -> (usize, u64) {
+        let hot_len = self.split.saturating_sub(offset).min(len as u64) as usize;
+        let cold_offset = (offset + hot_len as u64).saturating_sub(self.split);
+
+        (hot_len, cold_offset)
+    }
+}
+
+impl Storage for TieredStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (hot_len, cold_offset) = self.route(offset, buf.len());
+        let (hot, cold) = buf.split_at_mut(hot_len);
+
+        if !hot.is_empty() {
+            self.hot.read_at(offset, hot)?;
+        }
+        if !cold.is_empty() {
+            self.cold.read_at(cold_offset, cold)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let (hot_len, cold_offset) = self.route(offset, buf.len());
+        let (hot, cold) = buf.split_at(hot_len);
+
+        if !hot.is_empty() {
+            Storage::write_at(&self.hot, offset, hot)?;
+        }
+        if !cold.is_empty() {
+            if self.hot.size()? < self.split {
+                self.hot.set_len(self.split)?;
+            }
+            Storage::write_at(&self.cold, cold_offset, cold)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        match self.cold.size()? {
+            0 => self.hot.size(),
+            cold => Ok(self.split + cold),
+        }
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.hot.set_len(size.min(self.split))?;
+        self.cold.set_len(size.saturating_sub(self.split))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.hot.sync_all()?;
+        self.cold.sync_all()
+    }
+}
+
+/// The offset of the first byte not needed by the top `levels` levels of a
+/// tree.
+fn tier_split(features: &[Feature], subitems: &[u32], levels: u32) -> u64 {
+    let header_size = 16 + subitems.len() as u128 * 4;
+    let node_size = node_size(features, subitems) as u128;
+
+    let nodes = 1_u128.checked_shl(levels).unwrap_or(0).wrapping_sub(1);
+
+    nodes
+        .checked_mul(node_size)
+        .map(|bits| header_size + bits.div_ceil(8))
+        .unwrap_or(u64::MAX as u128)
+        .min(u64::MAX as u128) as u64
+}
+
+impl Tree {
+    /// Open an existent tree file held by `storage`, e.g. a
+    /// [`FaultyStorage`](crate::FaultyStorage) over the tree file. The files
+    /// kept next to the tree file are opened next to `file_path`, as if the
+    /// storage held the tree file there. Fails with
+    /// [`UncleanShutdown`](TreeFileError::UncleanShutdown) if the tree file
+    /// wasn't closed the last time it was opened for writing, or
+    /// [`LeaseHeld`](TreeFileError::LeaseHeld) if its writer still holds a
+    /// lease on it.
+    pub fn with_storage(
+        storage: Arc<dyn Storage>,
+        file_path: &'static str,
+        mode: TreeOpenMode,
+    ) -> Result<Self, TreeFileError> {
+        let options = read_headers(&*storage)?;
+
+        if read_dirty(&*storage)? {
+            return Err(lease::unclean_error(Path::new(file_path)));
+        };
+
+        Self::from_parts(storage, mode, file_path, options, false)
+    }
+
+    /// Open an existent tree file split in two by
+    /// [`create_tiered`](Tree::create_tiered). `hot_levels` must be the same
+    /// amount of levels the tree was created with.
+    pub fn open_tiered(
+        hot_path: &'static str,
+        cold_path: &'static str,
+        hot_levels: u32,
+        mode: TreeOpenMode,
+    ) -> Result<Self, TreeFileError> {
+        Self::from_tiers(hot_path, cold_path, hot_levels, mode, false)
+    }
+
+    fn from_tiers(
+        hot_path: &str,
+        cold_path: &str,
+        hot_levels: u32,
+        mode: TreeOpenMode,
+        created: bool,
+    ) -> Result<Self, TreeFileError> {
+        let open = |path: &str| match OpenOptions::new()
+            .read(true)
+            .write(mode == TreeOpenMode::ReadWrite)
+            .open(path)
+        {
+            Ok(file) => Ok(file),
+            Err(_) => Err(TreeFileError::FileNotOpened),
+        };
+
+        let hot = open(hot_path)?;
+        let cold = open(cold_path)?;
+        let options = read_headers(&hot)?;
+
+        if !created && read_dirty(&hot)? {
+            return Err(TreeFileError::UncleanShutdown);
+        };
+
+        let storage = TieredStorage {
+            hot,
+            cold,
+            split: tier_split(&options.features, &options.subitems, hot_levels),
+        };
+
+        Self::from_parts(Arc::new(storage), mode, hot_path, options, created)
+    }
+
+    /// Create a new tree file whose headers and top `hot_levels` levels are
+    /// stored in `hot_path`, and whose deeper levels are stored in
+    /// `cold_path`. Persistent trees are split by slot instead, as their
+    /// slots aren't ordered by level.
+    pub fn create_tiered(
+        hot_path: &'static str,
+        cold_path: &'static str,
+        hot_levels: u32,
+        mode: TreeOpenMode,
+        features: Vec<Feature>,
+        subitems: Vec<u32>,
+    ) -> Result<Self, TreeFileError> {
+        match schema::validate(&features, &subitems) {
+            Ok(_) => (),
+            Err(error) => return Err(TreeFileError::InvalidSchema(error)),
+        };
+
+        let hot = create_file(hot_path)?;
+        create_file(cold_path)?;
+        write_headers(
+            &hot,
+            &CreateOptions {
+                features,
+                subitems,
+                ..Default::default()
+            },
+        )?;
+
+        Self::from_tiers(hot_path, cold_path, hot_levels, mode, true)
+    }
+}
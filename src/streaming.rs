@@ -0,0 +1,221 @@
+//! Streaming subitems from and to the tree file, for subitems holding blobs
+//! (e.g. images or serialized models) too large to decode at once.
+//!
+//! The bits of a subitem are streamed as bytes, most significant bit first.
+//! The last byte of a subitem whose size isn't a multiple of 8 bits is padded
+//! with zeros when read, and only its first bits are written.
+
+use crate::{bitcodec, Feature, LazyNode, Node, NodeError, Tree};
+use std::io::{self, Read, Write};
+
+/// The most bytes streamed by each read or write of the tree file.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads a subitem from the tree file. Returned by
+/// [`Node::subitem_reader`] and [`LazyNode::subitem_reader`].
+#[derive(Debug)]
+pub struct SubitemReader<'a> {
+    tree: &'a Tree,
+
+    /// The subitem's offset in bits after the file headers.
+    start: u128,
+
+    /// The subitem's size in bits.
+    width: u32,
+
+    /// The amount of bits read so far.
+    read: u32,
+}
+
+/// Writes a subitem to the tree file in place. Returned by
+/// [`Node::subitem_writer`].
+///
+/// The subitem hashes and indexes of the tree are updated by
+/// [`finish`](SubitemWriter::finish), or when the writer is dropped,
+/// ignoring failures.
+#[derive(Debug)]
+pub struct SubitemWriter<'a> {
+    tree: &'a mut Tree,
+    position: u128,
+
+    /// The subitem's offset in bits after the file headers.
+    start: u128,
+
+    /// The subitem's size in bits.
+    width: u32,
+
+    /// The amount of bits written so far.
+    written: u32,
+
+    /// Whether the data kept about the node was updated after the last
+    /// write.
+    finished: bool,
+}
+
+impl Tree {
+    /// A reader of the subitem `index` of the enabled node at `position`.
+    fn subitem_reader(&self, position: u128, index: usize) -> Result<SubitemReader<'_>, NodeError> {
+        let (start, width) = self.subitem_span(position, index)?;
+
+        Ok(SubitemReader {
+            tree: self,
+            start,
+            width,
+            read: 0,
+        })
+    }
+
+    /// The offset in bits after the file headers and the size of the subitem
+    /// `index` of the enabled node at `position`.
+    fn subitem_span(&self, position: u128, index: usize) -> Result<(u128, u32), NodeError> {
+        let width = match self.subitems.get(index) {
+            Some(width) => *width,
+            None => return Err(NodeError::InvalidIndex),
+        };
+
+        let slot = self.resolve(position)?;
+        if !self.read_slot_header(slot)?.enabled {
+            return Err(NodeError::Disabled);
+        };
+
+        let within = self.node_header_size() + self.subitems[..index].iter().sum::<u32>();
+
+        Ok((self.slot_bit_offset(slot, within), width))
+    }
+}
+
+impl Node<'_> {
+    /// A reader of the subitem `index` of the node, reading it from the tree
+    /// file as it goes rather than from the node's subitems.
+    pub fn subitem_reader(&self, index: usize) -> Result<SubitemReader<'_>, NodeError> {
+        self.tree.subitem_reader(self.position, index)
+    }
+
+    /// A writer of the subitem `index` of the node, writing the bytes to the
+    /// node's slot as they come. Bytes past the end of the subitem aren't
+    /// written, and the bits that aren't written keep their contents.
+    ///
+    /// The node's subitems aren't updated: [`refresh`](Node::refresh) it
+    /// afterwards. The writes aren't validated nor passed to the write
+    /// hooks, and they aren't recorded in traces. Persistent trees can't
+    /// write nodes in place, and trees with the audit or attribution features
+    /// (or retaining values) record the contents of every write, so they
+    /// fail with [`UnsupportedFeature`](NodeError::UnsupportedFeature), as
+    /// do trees with a validator or write hooks.
+    pub fn subitem_writer(&mut self, index: usize) -> Result<SubitemWriter<'_>, NodeError> {
+        let tree = &mut *self.tree;
+        if tree.features.contains(&Feature::Persistent)
+            || tree.features.contains(&Feature::Audit)
+            || tree.features.contains(&Feature::Attribution)
+            || tree.value_log.is_some()
+            || tree.validator.is_some()
+            || !tree.write_hooks.is_empty()
+        {
+            return Err(NodeError::UnsupportedFeature);
+        };
+
+        let (start, width) = tree.subitem_span(self.position, index)?;
+
+        Ok(SubitemWriter {
+            tree,
+            position: self.position,
+            start,
+            width,
+            written: 0,
+            finished: true,
+        })
+    }
+}
+
+impl LazyNode<'_> {
+    /// A reader of the subitem `index` of the node, like
+    /// [`Node::subitem_reader`].
+    pub fn subitem_reader(&self, index: usize) -> Result<SubitemReader<'_>, NodeError> {
+        self.tree.subitem_reader(self.position, index)
+    }
+}
+
+impl SubitemReader<'_> {
+    /// The subitem's size in bits.
+    pub fn size(&self) -> u32 {
+        self.width
+    }
+}
+
+impl Read for SubitemReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = (self.width - self.read).div_ceil(8) as usize;
+        let len = buf.len().min(left).min(STREAM_CHUNK_SIZE);
+        if len == 0 {
+            return Ok(0);
+        };
+
+        let bits = (len as u32 * 8).min(self.width - self.read);
+        let read = match self.tree.read_bits(self.start + self.read as u128, bits) {
+            Ok(read) => read,
+            Err(error) => return Err(io::Error::other(format!("{:?}", error))),
+        };
+
+        buf[..len].fill(0);
+        bitcodec::pack_bits_at(&mut buf[..len], 0, &read);
+        self.read += bits;
+
+        Ok(len)
+    }
+}
+
+impl SubitemWriter<'_> {
+    /// The subitem's size in bits.
+    pub fn size(&self) -> u32 {
+        self.width
+    }
+
+    /// Update the data kept about the node (its subitem hash and the
+    /// indexes of the tree), which re-reads it whole if the tree keeps any.
+    pub fn finish(mut self) -> Result<(), NodeError> {
+        self.update()
+    }
+
+    fn update(&mut self) -> Result<(), NodeError> {
+        if self.finished {
+            return Ok(());
+        };
+        self.finished = true;
+
+        self.tree.update_merkle(self.position)?;
+        self.tree.update_indexes(self.position)
+    }
+}
+
+impl Write for SubitemWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let left = (self.width - self.written).div_ceil(8) as usize;
+        let len = buf.len().min(left).min(STREAM_CHUNK_SIZE);
+        if len == 0 {
+            return Ok(0);
+        };
+
+        let bits = (len as u32 * 8).min(self.width - self.written) as usize;
+        let written = &bitcodec::bytes_to_bits(&buf[..len])[..bits];
+        self.finished = false;
+        if let Err(error) = self
+            .tree
+            .write_bits(self.start + self.written as u128, written)
+        {
+            return Err(io::Error::other(format!("{:?}", error)));
+        };
+        self.written += bits as u32;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SubitemWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.update();
+    }
+}
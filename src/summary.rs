@@ -0,0 +1,173 @@
+//! A summary of a tree's structure, also shown when a tree is displayed.
+
+use crate::{positions, BitOrder, Feature, Layout, NodeError, Tree, TreeOpenMode};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a tree holds and how it's stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeSummary {
+    /// The path of the tree file. `None` for trees opened from memory or an
+    /// object store.
+    pub path: Option<PathBuf>,
+
+    /// Whether the tree was opened for writing.
+    pub writable: bool,
+
+    /// The version of the format of the tree file, as numbered in the
+    /// README (version 2 is stored as `00 01`).
+    pub format_version: u16,
+
+    /// The version read, and the amount of versions recorded. `None` if the
+    /// tree isn't persistent or has no versions yet.
+    pub version: Option<(u64, u64)>,
+
+    /// The features of the tree file.
+    pub features: Vec<Feature>,
+
+    /// The size in bits of each subitem.
+    pub subitems: Vec<u32>,
+
+    /// The order the nodes are stored in.
+    pub layout: Layout,
+
+    /// The order bits are packed into each byte in.
+    pub bit_order: BitOrder,
+
+    /// The amount of enabled nodes.
+    pub live_nodes: u128,
+
+    /// The amount of stored slots, disabled nodes (and the copies kept for
+    /// older versions) included.
+    pub slots: u64,
+
+    /// The level of the deepest enabled node. `None` if the tree has no
+    /// enabled node.
+    pub depth: Option<u32>,
+
+    /// The size in bytes of the tree file.
+    pub size: u64,
+
+    /// When the tree file was last modified, if it's known.
+    pub modified: Option<SystemTime>,
+}
+
+impl Tree {
+    /// Summarize what the tree holds and how it's stored. The enabled nodes
+    /// are counted from the occupancy bitmap if the tree has one, or from
+    /// the header of every node otherwise.
+    pub fn summary(&self) -> Result<TreeSummary, NodeError> {
+        let mut live_nodes = 0;
+        let mut depth = None;
+        for position in self.positions()? {
+            live_nodes += 1;
+            depth = depth.max(Some(positions::level(position?)));
+        }
+
+        let size = match self.storage.size() {
+            Ok(size) => size,
+            Err(_) => return Err(NodeError::Unexistent),
+        };
+
+        let path = Some(self.path.clone()).filter(|path| !path.as_os_str().is_empty());
+        let modified = path
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok());
+
+        Ok(TreeSummary {
+            path,
+            writable: self.mode == TreeOpenMode::ReadWrite,
+            format_version: u16::from_be_bytes(crate::FORMAT_VERSION) + 1,
+            version: self
+                .version()
+                .map(|version| (version, self.version_count())),
+            features: self.features.clone(),
+            subitems: self.subitems.clone(),
+            layout: self.layout,
+            bit_order: self.bit_order,
+            live_nodes,
+            slots: self.nodes(),
+            depth,
+            size,
+            modified,
+        })
+    }
+}
+
+impl fmt::Display for TreeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "tree {}", path.display())?,
+            None => write!(f, "tree in memory")?,
+        };
+        writeln!(
+            f,
+            " ({}, format version {})",
+            match self.writable {
+                true => "read-write",
+                false => "read-only",
+            },
+            self.format_version
+        )?;
+
+        if let Some((version, versions)) = self.version {
+            writeln!(f, "  version: {} of {}", version, versions)?;
+        };
+
+        let features: Vec<String> = self
+            .features
+            .iter()
+            .map(|feature| format!("{:?}", feature))
+            .collect();
+        match features.is_empty() {
+            true => writeln!(f, "  features: none")?,
+            false => writeln!(f, "  features: {}", features.join(", "))?,
+        };
+
+        let subitems: Vec<String> = self
+            .subitems
+            .iter()
+            .enumerate()
+            .map(|(index, size)| format!("subitem{}: {} bits", index, size))
+            .collect();
+        writeln!(f, "  schema: {}", subitems.join(", "))?;
+        writeln!(f, "  layout: {:?}, {:?}", self.layout, self.bit_order)?;
+
+        writeln!(
+            f,
+            "  nodes: {} live of {} slots",
+            self.live_nodes, self.slots
+        )?;
+        match self.depth {
+            Some(depth) => writeln!(f, "  depth: {}", depth)?,
+            None => writeln!(f, "  depth: empty")?,
+        };
+        writeln!(f, "  size: {} bytes", self.size)?;
+
+        match self
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        {
+            Some(modified) => writeln!(
+                f,
+                "  last modified: {} ms after the Unix epoch",
+                modified.as_millis()
+            ),
+            None => writeln!(f, "  last modified: unknown"),
+        }
+    }
+}
+
+impl fmt::Display for Tree {
+    /// The [`summary`](Tree::summary) of the tree, or why it couldn't be
+    /// read.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.summary() {
+            Ok(summary) => write!(f, "{}", summary),
+            Err(error) => writeln!(f, "tree {} (unreadable: {:?})", self.path.display(), error),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Export of the nodes of a tree as a table, one row per node.
+
+use crate::{bitcodec, positions, NodeError, TraversalOptions, Tree};
+use std::io::Write;
+
+/// The format of an exported table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableFormat {
+    /// Comma-separated values.
+    Csv,
+
+    /// Tab-separated values.
+    Tsv,
+}
+
+/// Why a table couldn't be exported.
+#[derive(Debug)]
+pub enum TableError {
+    /// The table couldn't be written.
+    Write,
+
+    /// A node couldn't be read.
+    Node(NodeError),
+}
+
+impl Tree {
+    /// Export every stored node, disabled ones included, as a table with a
+    /// header row and the columns `position`, `level`, `enabled` and one
+    /// column per subitem (`subitem0`, `subitem1`, ...). Nodes are written
+    /// in pre-order, as they're read.
+    ///
+    /// Subitems of at most 64 bits are written as unsigned decimal numbers,
+    /// and longer ones as strings of `0`s and `1`s. Returns the amount of
+    /// rows written, without the header.
+    pub fn export_table(
+        &self,
+        mut writer: impl Write,
+        format: TableFormat,
+    ) -> Result<u64, TableError> {
+        let delimiter = match format {
+            TableFormat::Csv => ",",
+            TableFormat::Tsv => "\t",
+        };
+
+        let mut header = vec![
+            "position".to_string(),
+            "level".to_string(),
+            "enabled".to_string(),
+        ];
+        header.extend((0..self.subitems.len()).map(|index| format!("subitem{}", index)));
+        if writeln!(writer, "{}", header.join(delimiter)).is_err() {
+            return Err(TableError::Write);
+        };
+
+        let mut rows = 0;
+        let options = TraversalOptions {
+            include_disabled: true,
+            ..Default::default()
+        };
+        for node in self.traverse(0, options) {
+            let node = match node {
+                Ok(node) => node,
+                Err(error) => return Err(TableError::Node(error)),
+            };
+
+            let mut row = vec![
+                node.position.to_string(),
+                positions::level(node.position).to_string(),
+                node.enabled.to_string(),
+            ];
+            for subitem in &node.subitems {
+                row.push(match subitem.len() {
+                    0..=64 => bitcodec::bits_to_u64(subitem).to_string(),
+                    _ => subitem
+                        .iter()
+                        .map(|bit| if *bit { '1' } else { '0' })
+                        .collect(),
+                });
+            }
+
+            if writeln!(writer, "{}", row.join(delimiter)).is_err() {
+                return Err(TableError::Write);
+            };
+            rows += 1;
+        }
+
+        match writer.flush() {
+            Ok(_) => Ok(rows),
+            Err(_) => Err(TableError::Write),
+        }
+    }
+}
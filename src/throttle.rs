@@ -0,0 +1,99 @@
+//! A budget of bytes per second for the writes of bulk operations, so that
+//! rewriting a whole tree doesn't take all the bandwidth of the storage from
+//! the readers of the tree file.
+
+use crate::Tree;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The bytes bulk operations can write before they have to wait, refilled
+/// over time up to a second's worth.
+#[derive(Debug)]
+pub(crate) struct IoBudget {
+    bytes_per_sec: u64,
+
+    /// The bytes that can be written right away.
+    available: f64,
+
+    /// When `available` was last refilled.
+    refilled: Instant,
+}
+
+impl IoBudget {
+    fn new(bytes_per_sec: u64) -> Self {
+        IoBudget {
+            bytes_per_sec,
+            available: bytes_per_sec as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` from the budget, and return how long to wait before
+    /// writing them, if they're more than the budget has left.
+    fn take(&mut self, bytes: u64) -> Option<Duration> {
+        let now = Instant::now();
+        let rate = self.bytes_per_sec as f64;
+        self.available =
+            (self.available + now.duration_since(self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+        self.available -= bytes as f64;
+
+        if self.available >= 0.0 {
+            return None;
+        };
+
+        Some(Duration::from_secs_f64(-self.available / rate))
+    }
+}
+
+impl Tree {
+    /// Limit the writes of bulk operations rewriting the tree file
+    /// ([`gc`](Tree::gc), [`enable_feature`](Tree::enable_feature) and
+    /// [`disable_feature`](Tree::disable_feature),
+    /// [`apply_backup`](Tree::apply_backup),
+    /// [`apply_patch`](crate::apply_patch),
+    /// [`repair_from`](Tree::repair_from),
+    /// [`import_edges`](Tree::import_edges) and
+    /// [`import_newick`](Tree::import_newick)) to `bytes_per_sec` bytes per
+    /// second, at least 1. Bulk operations wait before the writes that go
+    /// over the budget, and the waits are counted in
+    /// [`io_stats`](Tree::io_stats). Other writes are never throttled.
+    pub fn set_io_budget(&mut self, bytes_per_sec: u64) {
+        self.budget = Some(IoBudget::new(bytes_per_sec.max(1)));
+    }
+
+    /// Remove the limit of the writes of bulk operations.
+    pub fn clear_io_budget(&mut self) {
+        self.budget = None;
+    }
+
+    /// Run a bulk operation, throttling its writes to the I/O budget.
+    pub(crate) fn bulk<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let bulk = self.bulk;
+        self.bulk = true;
+        let result = f(self);
+        self.bulk = bulk;
+
+        result
+    }
+
+    /// Wait until `bytes` fit in the I/O budget, if a bulk operation is
+    /// writing them.
+    pub(crate) fn throttle(&mut self, bytes: u64) {
+        if !self.bulk {
+            return;
+        };
+
+        let wait = match &mut self.budget {
+            Some(budget) => budget.take(bytes),
+            None => None,
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+
+            let mut io = self.io();
+            io.throttle_events += 1;
+            io.throttle_wait += wait;
+        };
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{Tree, TreeFileError};
+use std::fmt;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+/// An operation performed on a tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    ReadNode,
+    WriteNode,
+    Flush,
+    Gc,
+    Verify,
+    Rollback,
+}
+
+/// An operation that took longer than the threshold of the trace hook.
+#[derive(Debug)]
+pub struct SlowOperation {
+    /// The operation that was performed.
+    pub operation: Operation,
+
+    /// The tranversal position of the node involved, if any.
+    pub position: Option<u128>,
+
+    /// The amount of bytes read from the storage by the operation.
+    pub bytes_read: u64,
+
+    /// The amount of bytes written to the storage by the operation.
+    pub bytes_written: u64,
+
+    /// The time the operation took.
+    pub elapsed: Duration,
+}
+
+/// An event reported to the trace hook.
+#[derive(Debug)]
+pub enum TraceEvent {
+    SlowOperation(SlowOperation),
+
+    /// A tree opened for writing was dropped without calling
+    /// [`Tree::close`], and its changes couldn't be flushed.
+    CloseFailed(TreeFileError),
+}
+
+/// The hook receiving a tree's trace events.
+pub(crate) struct TraceHook {
+    threshold: Duration,
+    hook: Box<dyn Fn(&TraceEvent) + Send + Sync>,
+}
+
+impl fmt::Debug for TraceHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceHook")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The amount of bytes moved to and from a tree's storage.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct IoCounters {
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+
+    /// The amount of node bits written, without the bits of neighbouring
+    /// nodes rewritten along with them.
+    pub(crate) logical_bits_written: u64,
+
+    /// The amount of times bulk operations waited for the I/O budget, and
+    /// for how long in total.
+    pub(crate) throttle_events: u64,
+    pub(crate) throttle_wait: Duration,
+}
+
+/// The amount of data a tree moved to and from its storage since it was
+/// opened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoStats {
+    /// The amount of bytes read from the storage.
+    pub bytes_read: u64,
+
+    /// The amount of bytes written to the storage.
+    pub bytes_written: u64,
+
+    /// The amount of bytes of node data written, which can be less than a
+    /// byte per node.
+    pub logical_bytes_written: f64,
+
+    /// The amount of times a bulk operation waited before writing, to stay
+    /// within the I/O budget (see [`Tree::set_io_budget`]).
+    pub throttle_events: u64,
+
+    /// The total time bulk operations waited for the I/O budget.
+    pub throttled: Duration,
+}
+
+impl IoStats {
+    /// How many bytes were written to the storage for each byte of node data
+    /// written. 1 means no amplification at all.
+    pub fn write_amplification(&self) -> f64 {
+        match self.logical_bytes_written {
+            0.0 => 1.0,
+            logical => self.bytes_written as f64 / logical,
+        }
+    }
+}
+
+impl Tree {
+    /// Set a hook that is called with a [`TraceEvent::SlowOperation`] every
+    /// time a single operation takes `threshold` or longer.
+    pub fn set_trace_hook(
+        &mut self,
+        threshold: Duration,
+        hook: impl Fn(&TraceEvent) + Send + Sync + 'static,
+    ) {
+        self.trace = Some(TraceHook {
+            threshold,
+            hook: Box::new(hook),
+        });
+    }
+
+    /// The amount of node data moved to and from the storage since the tree
+    /// was opened.
+    pub fn io_stats(&self) -> IoStats {
+        let io = *self.io();
+        IoStats {
+            bytes_read: io.bytes_read,
+            bytes_written: io.bytes_written,
+            logical_bytes_written: io.logical_bits_written as f64 / 8.0,
+            throttle_events: io.throttle_events,
+            throttled: io.throttle_wait,
+        }
+    }
+
+    /// Remove the trace hook.
+    pub fn clear_trace_hook(&mut self) {
+        self.trace = None;
+    }
+
+    /// Send an event to the trace hook, if there's one.
+    pub(crate) fn report(&self, event: TraceEvent) {
+        if let Some(trace) = &self.trace {
+            (trace.hook)(&event);
+        };
+    }
+
+    /// Run an operation, reporting it to the trace hook if it's slow.
+    pub(crate) fn traced<T>(
+        &mut self,
+        operation: Operation,
+        position: Option<u128>,
+        f: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        let start = self.trace_start();
+        let result = f(self);
+        self.trace_end(operation, position, start);
+
+        result
+    }
+
+    /// Run an operation that only reads, like [`traced`](Tree::traced).
+    pub(crate) fn traced_read<T>(
+        &self,
+        operation: Operation,
+        position: Option<u128>,
+        f: impl FnOnce(&Self) -> T,
+    ) -> T {
+        let start = self.trace_start();
+        let result = f(self);
+        self.trace_end(operation, position, start);
+
+        result
+    }
+
+    /// The I/O counters and the time at the start of a traced operation.
+    /// `None` if there's no trace hook.
+    fn trace_start(&self) -> Option<(IoCounters, Instant)> {
+        self.trace.as_ref().map(|_| (*self.io(), Instant::now()))
+    }
+
+    /// Report a traced operation to the trace hook if it was slow.
+    fn trace_end(
+        &self,
+        operation: Operation,
+        position: Option<u128>,
+        start: Option<(IoCounters, Instant)>,
+    ) {
+        let (io, start) = match start {
+            Some(start) => start,
+            None => return,
+        };
+        let elapsed = start.elapsed();
+
+        if let Some(trace) = &self.trace {
+            if elapsed >= trace.threshold {
+                let now = *self.io();
+                (trace.hook)(&TraceEvent::SlowOperation(SlowOperation {
+                    operation,
+                    position,
+                    bytes_read: now.bytes_read - io.bytes_read,
+                    bytes_written: now.bytes_written - io.bytes_written,
+                    elapsed,
+                }));
+            };
+        };
+    }
+
+    /// The amount of bytes moved to and from the storage so far.
+    pub(crate) fn io(&self) -> MutexGuard<'_, IoCounters> {
+        self.io.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
@@ -0,0 +1,201 @@
+//! Transactions on persistent trees that can take part in a two-phase
+//! commit with other systems, e.g. a database or a message queue.
+//!
+//! The state of the transaction is kept next to the tree file (with the
+//! `.transaction` extension) as whether it's prepared (1 byte), the version
+//! it started from (8 bytes) and the id it was prepared with.
+
+use crate::{bitcodec, sidecar_path, Feature, Tree, TreeFileError, TreeOpenMode};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+
+/// A transaction written to the marker file.
+struct Marker {
+    prepared: bool,
+    start: u64,
+    id: String,
+}
+
+impl Tree {
+    /// Start a transaction on a persistent tree. The nodes written until
+    /// it's committed or aborted are part of it.
+    ///
+    /// Only one transaction can be active at a time, and the tree must have
+    /// a version to roll back to. The garbage collector retains the version
+    /// the transaction started from.
+    pub fn begin_transaction(&mut self) -> Result<(), TreeFileError> {
+        self.check_transactions()?;
+
+        if self.read_marker()?.is_some() {
+            return Err(TreeFileError::InvalidTransactionState);
+        };
+
+        let start = match self.version() {
+            Some(version) => version,
+            None => return Err(TreeFileError::UnexistentVersion),
+        };
+
+        self.write_marker(&Marker {
+            prepared: false,
+            start,
+            id: String::new(),
+        })
+    }
+
+    /// Prepare the active transaction to be committed, as the first phase of
+    /// a two-phase commit. Its writes are flushed to disk, and the
+    /// transaction is recorded as prepared with `id`, the id of the global
+    /// transaction, before returning.
+    ///
+    /// A prepared transaction is kept if the process crashes, until it's
+    /// committed or aborted, e.g. once the coordinator's decision is known.
+    /// Writing a node while the transaction is prepared fails with
+    /// [`InvalidTransactionState`](crate::NodeError::InvalidTransactionState).
+    pub fn prepare(&mut self, id: &str) -> Result<(), TreeFileError> {
+        self.check_transactions()?;
+
+        let start = match self.read_marker()? {
+            Some(marker) if !marker.prepared => marker.start,
+            _ => return Err(TreeFileError::InvalidTransactionState),
+        };
+
+        self.sync()?;
+        self.write_marker(&Marker {
+            prepared: true,
+            start,
+            id: id.to_string(),
+        })?;
+        self.prepared = true;
+
+        Ok(())
+    }
+
+    /// Commit the prepared transaction, keeping its writes.
+    pub fn commit(&mut self) -> Result<(), TreeFileError> {
+        self.check_transactions()?;
+
+        match self.read_marker()? {
+            Some(marker) if marker.prepared => (),
+            _ => return Err(TreeFileError::InvalidTransactionState),
+        };
+
+        self.remove_marker()?;
+        self.prepared = false;
+
+        Ok(())
+    }
+
+    /// Abort the active (or prepared) transaction, rolling the tree back to
+    /// the version it started from. The rollback is recorded as a new
+    /// version, like with [`rollback_to`](Tree::rollback_to).
+    pub fn abort(&mut self) -> Result<(), TreeFileError> {
+        self.check_transactions()?;
+
+        let start = match self.read_marker()? {
+            Some(marker) => marker.start,
+            None => return Err(TreeFileError::InvalidTransactionState),
+        };
+
+        self.roll_back_version(start)?;
+        self.sync()?;
+        self.remove_marker()?;
+        self.prepared = false;
+
+        Ok(())
+    }
+
+    /// The id of the prepared transaction, if there's one, e.g. to ask the
+    /// coordinator whether to commit or abort it after a crash.
+    pub fn prepared_transaction(&self) -> Result<Option<String>, TreeFileError> {
+        match self.read_marker()? {
+            Some(marker) if marker.prepared => Ok(Some(marker.id)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The version the active transaction started from, if there's one.
+    pub(crate) fn transaction_start(&self) -> Result<Option<u64>, TreeFileError> {
+        Ok(self.read_marker()?.map(|marker| marker.start))
+    }
+
+    /// Abort the transaction that was active when the tree was last closed,
+    /// unless it was prepared, as its writes were never promised to anyone.
+    /// A new tree drops the transaction of the tree file it replaced.
+    pub(crate) fn open_transaction(&mut self, create: bool) -> Result<(), TreeFileError> {
+        if create {
+            let _ = fs::remove_file(sidecar_path(&self.path, "transaction"));
+            return Ok(());
+        };
+
+        if !self.features.contains(&Feature::Persistent) || self.mode != TreeOpenMode::ReadWrite {
+            return Ok(());
+        };
+
+        match self.read_marker()? {
+            Some(marker) if !marker.prepared => self.abort(),
+            Some(_) => {
+                self.prepared = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_transactions(&self) -> Result<(), TreeFileError> {
+        if !self.features.contains(&Feature::Persistent) {
+            return Err(TreeFileError::MissingFeature);
+        };
+
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        Ok(())
+    }
+
+    fn read_marker(&self) -> Result<Option<Marker>, TreeFileError> {
+        let bytes = match fs::read(sidecar_path(&self.path, "transaction")) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(_) => return Err(TreeFileError::FileNotOpened),
+        };
+
+        if bytes.len() < 9 || bytes[0] > 1 {
+            return Err(TreeFileError::Corrupted);
+        };
+        let id = match String::from_utf8(bytes[9..].to_vec()) {
+            Ok(id) => id,
+            Err(_) => return Err(TreeFileError::Corrupted),
+        };
+
+        Ok(Some(Marker {
+            prepared: bytes[0] == 1,
+            start: bitcodec::u8_array_to_u64(bytes[1..9].try_into().unwrap()),
+            id,
+        }))
+    }
+
+    /// Write the marker file and flush it to disk.
+    fn write_marker(&self, marker: &Marker) -> Result<(), TreeFileError> {
+        let mut bytes = vec![marker.prepared as u8];
+        bytes.extend(bitcodec::u64_to_u8_array(marker.start));
+        bytes.extend(marker.id.as_bytes());
+
+        let path = sidecar_path(&self.path, "transaction");
+        if fs::write(&path, bytes).is_err() {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        match File::open(path).and_then(|file| file.sync_all()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::SyncFailed),
+        }
+    }
+
+    fn remove_marker(&self) -> Result<(), TreeFileError> {
+        match fs::remove_file(sidecar_path(&self.path, "transaction")) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TreeFileError::MissingPermissions),
+        }
+    }
+}
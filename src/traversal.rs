@@ -0,0 +1,214 @@
+use crate::{positions, Feature, Layout, Node, NodeData, NodeError, Operation, Tree};
+
+/// How far a traversal descends, and which nodes it yields.
+#[derive(Debug, Clone, Default)]
+pub struct TraversalOptions {
+    /// The deepest level below the starting node that is visited. `None`
+    /// visits every level.
+    pub max_depth: Option<u32>,
+
+    /// Called with every visited node. The children of the nodes it returns
+    /// true for aren't visited.
+    pub prune: Option<fn(&NodeData) -> bool>,
+
+    /// Yield disabled nodes too. Their children are visited either way.
+    pub include_disabled: bool,
+}
+
+/// A depth-first, pre-order traversal of a subtree, from left to right:
+/// each node comes before its left subtree, which comes before its right
+/// subtree.
+///
+/// Disabled nodes are skipped (unless
+/// [`include_disabled`](TraversalOptions::include_disabled) is set), but
+/// their subtrees aren't. Positions without a stored node end their branch,
+/// as nothing is stored below them. The order only depends on the positions
+/// visited, so it's the same for every layout and kind of tree.
+#[derive(Debug)]
+pub struct Traversal<'a> {
+    pub(crate) tree: &'a Tree,
+    options: TraversalOptions,
+
+    /// The nodes left to visit, as their position, depth and slot.
+    pending: Vec<(u128, u32, u128)>,
+}
+
+impl Tree {
+    /// Traverse the subtree rooted at `position`.
+    pub fn traverse(&self, position: u128, options: TraversalOptions) -> Traversal<'_> {
+        let pending = match self.resolve(position) {
+            Ok(slot) => vec![(position, 0, slot)],
+            Err(_) => vec![],
+        };
+
+        Traversal {
+            tree: self,
+            options,
+            pending,
+        }
+    }
+
+    /// Resume a pre-order traversal of the whole tree with the nodes left to
+    /// visit, as their position, depth and slot.
+    pub(crate) fn resume_traversal(&self, pending: Vec<(u128, u32, u128)>) -> Traversal<'_> {
+        Traversal {
+            tree: self,
+            options: TraversalOptions::default(),
+            pending,
+        }
+    }
+
+    /// The slot of the child `index` at `child`, of a node with the child
+    /// pointers `children`. `None` if it can't be stored.
+    pub(crate) fn child_slot(
+        &self,
+        children: &[Option<u128>; 2],
+        child: u128,
+        index: u8,
+    ) -> Option<u128> {
+        match self.features.contains(&Feature::Persistent) {
+            true => children[index as usize],
+            false => self.layout.slot(child),
+        }
+    }
+}
+
+impl Iterator for Traversal<'_> {
+    type Item = Result<NodeData, NodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.pending.is_empty() {
+            if let Err(error) = self.tree.check_node_bytes() {
+                self.pending.clear();
+                return Some(Err(error));
+            };
+        };
+
+        while let Some((position, depth, slot)) = self.pending.pop() {
+            let contents = match self.tree.read_slot(slot) {
+                Ok(contents) => contents,
+                Err(NodeError::Unexistent) => continue,
+                Err(error) => return Some(Err(error)),
+            };
+
+            let node = NodeData {
+                position,
+                enabled: contents.enabled,
+                subitems: contents.subitems,
+            };
+
+            let pruned = match self.options.prune {
+                Some(prune) => prune(&node),
+                None => false,
+            };
+            let deeper = match self.options.max_depth {
+                Some(max_depth) => depth < max_depth,
+                None => true,
+            };
+
+            if !pruned && deeper {
+                // Pushed right first, so that the left child is visited first.
+                for index in [1, 0] {
+                    let child = match positions::child(position, index) {
+                        Some(child) => child,
+                        None => continue,
+                    };
+                    if let Some(child_slot) = self.tree.child_slot(&contents.children, child, index)
+                    {
+                        self.pending.push((child, depth + 1, child_slot));
+                    };
+                }
+            };
+
+            if node.enabled || self.options.include_disabled {
+                return Some(Ok(node));
+            };
+        }
+
+        None
+    }
+}
+
+impl Tree {
+    /// The enabled children of the node at `position`, the left one first,
+    /// or `None` for the children that are missing or disabled.
+    ///
+    /// The children of flat trees stored in level order are in adjacent
+    /// slots, so they're read at once. The children of other trees are read
+    /// one after the other.
+    pub fn children(
+        &self,
+        position: u128,
+    ) -> Result<(Option<NodeData>, Option<NodeData>), NodeError> {
+        // The deepest positions have no children that can be addressed.
+        let left = positions::child(position, 0);
+        let right = positions::child(position, 1);
+
+        if self.features.contains(&Feature::Persistent) || self.layout != Layout::LevelOrder {
+            return Ok((self.enabled_child(left)?, self.enabled_child(right)?));
+        };
+
+        // Flat trees store fewer slots than could be addressed.
+        let (left, right) = match (left, right) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Ok((None, None)),
+        };
+
+        self.check_node_bytes()?;
+        self.record_access(left);
+        self.record_access(right);
+
+        let slots = self.nodes() as u128;
+        if left >= slots {
+            return Ok((None, None));
+        };
+        let count = match right < slots {
+            true => 2,
+            false => 1,
+        };
+
+        let node_size = self.node_size();
+        let bits = self.traced_read(Operation::ReadNode, Some(left), |tree| {
+            tree.read_bits(left * node_size as u128, count * node_size)
+        })?;
+
+        let mut children = [None, None];
+        for (index, bits) in bits.chunks(node_size as usize).enumerate() {
+            let position = left + index as u128;
+            children[index] = match self.decode_slot(bits) {
+                Ok(contents) if contents.enabled => Some(NodeData {
+                    position,
+                    enabled: true,
+                    subitems: contents.subitems,
+                }),
+                Ok(_) => None,
+                Err(detail) => return Err(self.corruption(position, 0, detail)),
+            };
+        }
+
+        let [left, right] = children;
+        Ok((left, right))
+    }
+
+    /// The node at `position`, or `None` if it's missing, disabled or can't
+    /// be addressed.
+    fn enabled_child(&self, position: Option<u128>) -> Result<Option<NodeData>, NodeError> {
+        let position = match position {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        match self.read_node(position) {
+            Ok(node) => Ok(Some(node)),
+            Err(NodeError::Unexistent | NodeError::Disabled) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Node<'_> {
+    /// The enabled children of the node, like [`Tree::children`].
+    pub fn children(&self) -> Result<(Option<NodeData>, Option<NodeData>), NodeError> {
+        self.tree.children(self.position)
+    }
+}
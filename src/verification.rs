@@ -0,0 +1,52 @@
+//! Reading every write back from the storage, to catch storage that loses or
+//! corrupts writes, or bugs while changing the format.
+
+use crate::{NodeError, Tree};
+
+/// How the bytes written to the tree file are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WriteVerification {
+    /// The writes aren't checked.
+    #[default]
+    Off,
+
+    /// Every write of a node is read back from the storage, bypassing the
+    /// cache, and compared with the bytes written.
+    ReadBack,
+}
+
+impl Tree {
+    /// Set how the writes of nodes are checked. With
+    /// [`ReadBack`](WriteVerification::ReadBack), every write of a slot
+    /// (including path copies, child hints and gap fills) reads its bytes
+    /// back, and fails with [`VerifyFailed`](NodeError::VerifyFailed) if
+    /// they don't match. The write isn't undone: the slot is left with
+    /// whatever the storage holds.
+    pub fn set_write_verification(&mut self, verification: WriteVerification) {
+        self.verification = verification;
+    }
+
+    /// How the writes of nodes are checked.
+    pub fn write_verification(&self) -> WriteVerification {
+        self.verification
+    }
+
+    /// Check that the storage holds `written` at `offset`, if writes are
+    /// verified.
+    pub(crate) fn verify_written(&self, offset: u64, written: &[u8]) -> Result<(), NodeError> {
+        if self.verification == WriteVerification::Off {
+            return Ok(());
+        };
+
+        let mut stored = vec![0_u8; written.len()];
+        if self.storage.read_at(offset, &mut stored).is_err() {
+            return Err(NodeError::VerifyFailed);
+        };
+        self.io().bytes_read += stored.len() as u64;
+
+        match stored == written {
+            true => Ok(()),
+            false => Err(NodeError::VerifyFailed),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{positions, Feature, GapFill, NodeError, Tree, TreeFileError, TreeOpenMode};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A handle that writes the nodes of a single subtree of a tree. Handles of
+/// disjoint subtrees can write at the same time from different threads.
+#[derive(Debug)]
+pub struct SubtreeWriter {
+    tree: Tree,
+    root: u128,
+}
+
+impl SubtreeWriter {
+    /// The position of the root of the subtree.
+    pub fn root(&self) -> u128 {
+        self.root
+    }
+
+    /// Set a node of the subtree, overwriting it if it already exists.
+    pub fn set_node(
+        &mut self,
+        subitems: &[Vec<bool>],
+        position: u128,
+        disabled: bool,
+    ) -> Result<(), NodeError> {
+        if !positions::is_descendant(position, self.root) {
+            return Err(NodeError::OutsideSubtree);
+        };
+
+        self.tree
+            .set_node_quiet(subitems, &position, true, disabled)
+    }
+
+    /// The amount of bytes written by the handle.
+    pub fn bytes_written(&self) -> u64 {
+        self.tree.io().bytes_written
+    }
+}
+
+impl Tree {
+    /// Split the tree into one writer handle for each subtree rooted at
+    /// `level`, so that the subtrees can be built in parallel. The nodes above
+    /// `level` are still written through the tree.
+    ///
+    /// The handles share the tree's storage, so they only wait for each other
+    /// to write the bytes shared by nodes of different subtrees. Flush the
+    /// tree once every handle is done. Trees with write hooks, the audit or
+    /// child hints features, subtree hashes, subitem indexes, retained
+    /// values or a gap fill other than zeros can't be split.
+    pub fn split_writers(&mut self, level: u32) -> Result<Vec<SubtreeWriter>, TreeFileError> {
+        if self.mode != TreeOpenMode::ReadWrite {
+            return Err(TreeFileError::MissingPermissions);
+        };
+
+        // Persistent writes copy the path from the root, level stats are
+        // updated per level, subtree hashes up to the root and the audit log,
+        // the index log and the value log are appended in order, all of which
+        // every subtree shares. Child hints are written to the parent, which
+        // the roots of sibling subtrees share.
+        if self.features.contains(&Feature::Persistent)
+            || self.features.contains(&Feature::LevelStats)
+            || self.features.contains(&Feature::ChildHints)
+            || self.merkle.is_some()
+            || self.index_log.is_some()
+            || self.value_log.is_some()
+            || self.features.contains(&Feature::Audit)
+        {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        // Rolling a write back could truncate the nodes of other writers, and
+        // filling a gap could overwrite them.
+        if !self.write_hooks.is_empty() || self.gap_fill != GapFill::Zeros {
+            return Err(TreeFileError::UnsupportedFeature);
+        };
+
+        // No position can be addressed at that level.
+        let first = match 1_u128.checked_shl(level) {
+            Some(width) => width - 1,
+            None => return Ok(vec![]),
+        };
+
+        let mut writers = vec![];
+        for root in first..=first * 2 {
+            let occupancy = match &self.occupancy {
+                Some(bitmap) => match bitmap.try_clone() {
+                    Ok(bitmap) => Some(bitmap),
+                    Err(_) => return Err(TreeFileError::FileNotOpened),
+                },
+                None => None,
+            };
+
+            let revisions = match &self.revisions {
+                Some(table) => match table.try_clone() {
+                    Ok(table) => Some(table),
+                    Err(_) => return Err(TreeFileError::FileNotOpened),
+                },
+                None => None,
+            };
+
+            writers.push(SubtreeWriter {
+                tree: Tree {
+                    storage: Arc::clone(&self.storage),
+                    mode: TreeOpenMode::ReadWrite,
+                    header_size: self.header_size,
+                    features: self.features.clone(),
+                    subitems: self.subitems.clone(),
+                    bit_order: self.bit_order,
+                    layout: self.layout,
+                    gap_fill: self.gap_fill.clone(),
+                    path: self.path.clone(),
+                    versions: None,
+                    timestamps: None,
+                    version: None,
+                    occupancy,
+                    levels: None,
+                    leaves: None,
+                    audit: None,
+                    merkle: None,
+                    index_log: None,
+                    indexes: BTreeMap::new(),
+                    revisions,
+                    annotation_log: None,
+                    annotations: BTreeMap::new(),
+                    value_log: None,
+                    retained: BTreeMap::new(),
+                    retained_depth: 0,
+                    writer_id: self.writer_id,
+                    trace: None,
+                    validator: self.validator.clone(),
+                    write_hooks: vec![],
+                    io: Default::default(),
+                    budget: None,
+                    bulk: false,
+                    prepared: false,
+                    recording: None,
+                    verification: self.verification,
+                    max_node_bytes: self.max_node_bytes,
+                    lease: self.lease,
+                    memory_mode: self.memory_mode,
+                    // The tree file is flushed and closed through the tree.
+                    closed: true,
+                    boundary: Arc::clone(&self.boundary),
+                    cache: Default::default(),
+                    accesses: Default::default(),
+                    sequences: self.sequences.clone(),
+                },
+                root,
+            });
+        }
+
+        Ok(writers)
+    }
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use dot_tree::{BackupError, CreateOptions};
+
+#[test]
+fn applies_differential_backups_only_in_order() {
+    let options = || CreateOptions {
+        subitems: vec![8],
+        ..Default::default()
+    };
+    let mut source = common::create("backups-source", options());
+    let mut restored = common::create("backups-restored", options());
+
+    source
+        .set_node_quiet(&[common::bits(1, 8)], &0, true, false)
+        .unwrap();
+    let mut full = vec![];
+    let first = source.backup_diff(0, &mut full).unwrap();
+
+    source
+        .set_node_quiet(&[common::bits(2, 8)], &1, true, false)
+        .unwrap();
+    let mut second = vec![];
+    let second_revision = source.backup_diff(first, &mut second).unwrap();
+
+    source
+        .set_node_quiet(&[common::bits(3, 8)], &2, true, false)
+        .unwrap();
+    let mut third = vec![];
+    source.backup_diff(second_revision, &mut third).unwrap();
+
+    // A differential backup needs the backups before it applied first.
+    assert!(matches!(
+        restored.apply_backup(&second[..]),
+        Err(BackupError::BaseMismatch)
+    ));
+    assert_eq!(restored.apply_backup(&full[..]).unwrap(), first);
+    assert!(matches!(
+        restored.apply_backup(&third[..]),
+        Err(BackupError::BaseMismatch)
+    ));
+    assert_eq!(restored.apply_backup(&second[..]).unwrap(), second_revision);
+    restored.apply_backup(&third[..]).unwrap();
+
+    for position in 0..3 {
+        assert_eq!(
+            restored.read_node(position).unwrap().subitems,
+            vec![common::bits(position as u64 + 1, 8)]
+        );
+    }
+
+    // Full backups apply whatever was restored before.
+    restored.apply_backup(&full[..]).unwrap();
+}
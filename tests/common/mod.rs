@@ -0,0 +1,42 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use dot_tree::{CreateOptions, Tree, TreeOpenMode};
+use std::path::PathBuf;
+
+/// A path for a tree file of the test `name`, removing the files left there
+/// by an earlier run. The path is leaked, as trees keep a `&'static str`.
+pub fn tree_path(name: &str) -> &'static str {
+    let dir = std::env::temp_dir().join("dot_tree-tests");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let path: PathBuf = dir.join(format!("{}.tree", name));
+    for entry in std::fs::read_dir(&dir).unwrap().flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if file_name == format!("{}.tree", name)
+            || file_name.starts_with(&format!("{}.tree.", name))
+        {
+            let _ = std::fs::remove_file(entry.path());
+        };
+    }
+
+    Box::leak(path.to_string_lossy().into_owned().into_boxed_str())
+}
+
+/// Create a tree for the test `name` with `options`.
+pub fn create(name: &str, options: CreateOptions) -> Tree {
+    Tree::create_with_options(tree_path(name), TreeOpenMode::ReadWrite, options).unwrap()
+}
+
+/// The bits of `value`, most significant first, as a subitem of `size`
+/// bits.
+pub fn bits(value: u64, size: u32) -> Vec<bool> {
+    (0..size).rev().map(|bit| (value >> bit) & 1 == 1).collect()
+}
+
+/// The path of the tree file of `tree`, to open it again.
+pub fn tree_path_of(tree: &Tree) -> &'static str {
+    let path = tree.summary().unwrap().path.unwrap();
+    Box::leak(path.to_string_lossy().into_owned().into_boxed_str())
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use dot_tree::{BitOrder, CreateOptions, Feature, MemoryMode, Tree, TreeOpenMode};
+
+const NODES: u128 = 64;
+
+/// The subitems written to `position` in `round`, different for every
+/// neighbour so that a write spilling into the bytes of another node shows.
+fn subitems(sizes: &[u32], position: u128, round: u64) -> Vec<Vec<bool>> {
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(index, size)| {
+            let value = (position as u64 * 7 + index as u64 * 3 + round * 11) ^ 0b10101;
+            common::bits(value & ((1 << size) - 1), *size)
+        })
+        .collect()
+}
+
+/// Write every node twice, reading each one back right after writing it,
+/// through trees whose nodes straddle byte boundaries.
+fn reads_its_own_writes(name: &str, sizes: Vec<u32>) {
+    let modes = [
+        MemoryMode::Minimal,
+        MemoryMode::Balanced,
+        MemoryMode::Aggressive,
+    ];
+    let bit_orders = [BitOrder::MsbFirst, BitOrder::LsbFirst];
+
+    for (mode_index, mode) in modes.into_iter().enumerate() {
+        for (order_index, bit_order) in bit_orders.into_iter().enumerate() {
+            let mut tree = common::create(
+                &format!("{}-{}-{}", name, mode_index, order_index),
+                CreateOptions {
+                    features: vec![Feature::Disabling],
+                    subitems: sizes.clone(),
+                    bit_order,
+                    ..Default::default()
+                },
+            );
+            tree.set_memory_mode(mode);
+            for position in 0..NODES {
+                tree.set_node_quiet(&subitems(&sizes, position, 0), &position, true, false)
+                    .unwrap();
+            }
+
+            // The pages are kept in memory before they're written again.
+            tree.warm(8).unwrap();
+            for position in 0..NODES {
+                let _ = tree.read_node(position).unwrap();
+            }
+
+            for position in (0..NODES).rev() {
+                let written = subitems(&sizes, position, 1);
+                let node = tree.set_node(&written, &position, true, false).unwrap();
+                assert_eq!(node.subitems, written);
+                assert_eq!(tree.node(position).unwrap().subitems, written);
+                assert_eq!(tree.read_node(position).unwrap().subitems, written);
+
+                // Its neighbours share bytes with it: the next node was just
+                // written, and the previous one still holds the first round.
+                if position + 1 < NODES {
+                    assert_eq!(
+                        tree.read_node(position + 1).unwrap().subitems,
+                        subitems(&sizes, position + 1, 1)
+                    );
+                };
+                if position > 0 {
+                    assert_eq!(
+                        tree.read_node(position - 1).unwrap().subitems,
+                        subitems(&sizes, position - 1, 0)
+                    );
+                };
+            }
+
+            let path = common::tree_path_of(&tree);
+            tree.close().unwrap();
+            let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+            for position in 0..NODES {
+                assert_eq!(
+                    tree.read_node(position).unwrap().subitems,
+                    subitems(&sizes, position, 1)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn reads_five_bit_nodes_right_after_writing_them() {
+    // With the enabled bit, nodes are 6 bits long.
+    reads_its_own_writes("consistency-5", vec![5]);
+}
+
+#[test]
+fn reads_thirteen_bit_nodes_right_after_writing_them() {
+    // With the enabled bit, nodes are 14 bits long.
+    reads_its_own_writes("consistency-13", vec![13]);
+}
+
+#[test]
+fn reads_nodes_with_both_sizes_right_after_writing_them() {
+    reads_its_own_writes("consistency-5-13", vec![5, 13]);
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use dot_tree::{
+    CreateOptions, Feature, Layout, NodeError, SchemaError, Tree, TreeFileError, TreeOpenMode,
+};
+
+#[test]
+fn fails_persistent_writes_that_would_store_phantom_ancestors() {
+    let mut tree = common::create(
+        "gaps-persistent",
+        CreateOptions {
+            features: vec![Feature::Persistent],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+
+    assert!(matches!(
+        tree.set_node_quiet(&[common::bits(5, 8)], &5, true, false),
+        Err(NodeError::WouldLeaveGap)
+    ));
+    for position in [0, 2, 5] {
+        assert!(matches!(
+            tree.read_node(position),
+            Err(NodeError::Unexistent)
+        ));
+    }
+
+    // Written from the root down, every ancestor is a node written before.
+    for position in [0, 2, 5] {
+        tree.set_node_quiet(&[common::bits(position as u64, 8)], &position, true, false)
+            .unwrap();
+    }
+    assert_eq!(
+        tree.read_node(5).unwrap().subitems,
+        vec![common::bits(5, 8)]
+    );
+    assert!(matches!(tree.read_node(1), Err(NodeError::Unexistent)));
+}
+
+#[test]
+fn requires_disabling_for_columnar_trees() {
+    let result = Tree::create_with_options(
+        common::tree_path("gaps-columnar"),
+        TreeOpenMode::ReadWrite,
+        CreateOptions {
+            subitems: vec![8],
+            layout: Layout::Columnar { levels: 4 },
+            ..Default::default()
+        },
+    );
+    assert!(matches!(
+        result,
+        Err(TreeFileError::InvalidSchema(SchemaError::InvalidLayout))
+    ));
+
+    // With it, the reserved slots read as disabled until they're written.
+    let mut tree = common::create(
+        "gaps-columnar-disabling",
+        CreateOptions {
+            features: vec![Feature::Disabling],
+            subitems: vec![8],
+            layout: Layout::Columnar { levels: 4 },
+            ..Default::default()
+        },
+    );
+    tree.set_node_quiet(&[common::bits(1, 8)], &5, true, false)
+        .unwrap();
+    assert!(matches!(tree.read_node(0), Err(NodeError::Disabled)));
+    assert!(matches!(
+        tree.disable_feature(Feature::Disabling),
+        Err(TreeFileError::InvalidSchema(SchemaError::InvalidLayout))
+    ));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, Tree, TreeFileError, TreeOpenMode};
+
+fn persistent(name: &str) -> Tree {
+    common::create(
+        name,
+        CreateOptions {
+            features: vec![Feature::Persistent],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    )
+}
+
+/// Write the root and its children again and again, so older versions
+/// hold slots the latest one doesn't reach.
+fn write_versions(tree: &mut Tree, rounds: u64) {
+    for round in 0..rounds {
+        for position in 0..3 {
+            tree.set_node_quiet(&[common::bits(round, 8)], &position, true, false)
+                .unwrap();
+        }
+    }
+}
+
+fn value(tree: &Tree, position: u128) -> u64 {
+    dot_tree::bitcodec::bits_to_u64(&tree.read_node(position).unwrap().subitems[0])
+}
+
+#[test]
+fn keeps_open_versions_readable() {
+    let mut tree = persistent("gc-open-versions");
+    write_versions(&mut tree, 4);
+    let path = common::tree_path_of(&tree);
+
+    // The version after writing every node of the second round.
+    let pinned = Tree::open_version(path, 5).unwrap();
+    assert_eq!(value(&pinned, 0), 1);
+
+    let report = tree.gc(&[]).unwrap();
+    assert!(report.reclaimed_slots > 0);
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(report.retained_versions, 1);
+
+    assert_eq!(value(&pinned, 0), 1);
+    assert_eq!(value(&pinned, 2), 1);
+    assert_eq!(value(&tree, 0), 3);
+    assert!(matches!(
+        Tree::open_version(path, 5),
+        Err(TreeFileError::UnexistentVersion)
+    ));
+
+    // The compacted tree is still written through the same handle.
+    tree.set_node_quiet(&[common::bits(9, 8)], &1, true, false)
+        .unwrap();
+    tree.close().unwrap();
+
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert_eq!((value(&tree, 0), value(&tree, 1)), (3, 9));
+}
+
+#[test]
+fn refuses_to_collect_a_shared_storage() {
+    let mut tree = persistent("gc-shared");
+    write_versions(&mut tree, 2);
+
+    let shared = tree.storage.clone();
+    assert!(matches!(
+        tree.gc(&[]),
+        Err(TreeFileError::UnsupportedFeature)
+    ));
+
+    drop(shared);
+    assert!(tree.gc(&[]).is_ok());
+}
+
+#[test]
+fn drops_a_collection_interrupted_before_replacing_the_tree_file() {
+    let mut tree = persistent("gc-interrupted");
+    write_versions(&mut tree, 3);
+    let path = common::tree_path_of(&tree);
+    tree.close().unwrap();
+
+    std::fs::write(format!("{}.gc", path), b"partial").unwrap();
+    std::fs::write(format!("{}.versions.gc", path), b"partial").unwrap();
+
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert_eq!(tree.version_count(), 9);
+    assert_eq!(value(&tree, 2), 2);
+    assert!(!std::path::Path::new(&format!("{}.gc", path)).exists());
+    assert!(!std::path::Path::new(&format!("{}.versions.gc", path)).exists());
+}
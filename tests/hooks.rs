@@ -0,0 +1,41 @@
+mod common;
+
+use dot_tree::{CreateOptions, Tree, TreeOpenMode};
+
+#[test]
+fn leaves_no_retained_values_for_rolled_back_writes() {
+    let mut tree = common::create(
+        "hooks-retained",
+        CreateOptions {
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    let path = common::tree_path_of(&tree);
+    tree.set_retained_values(2).unwrap();
+    tree.after_write(|change| match &change.after {
+        Some(node) if node.subitems == vec![common::bits(3, 8)] => Err("rejected".to_string()),
+        _ => Ok(()),
+    });
+
+    for value in [1, 2, 3] {
+        let _ = tree.set_node_quiet(&[common::bits(value, 8)], &0, true, false);
+    }
+    let history = |tree: &Tree| {
+        tree.node_history(0)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.subitems)
+            .collect::<Vec<_>>()
+    };
+
+    // The rejected write and its rollback add no values, so the oldest one
+    // is still retained.
+    let expected = vec![vec![common::bits(1, 8)], vec![common::bits(2, 8)]];
+    assert_eq!(tree.read_node(0).unwrap().subitems, expected[1]);
+    assert_eq!(history(&tree), expected);
+
+    tree.close().unwrap();
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert_eq!(history(&tree), expected);
+}
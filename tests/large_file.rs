@@ -0,0 +1,130 @@
+//! Trees whose files are larger than 4 GiB, kept sparse so they take little
+//! disk space. They're slow on file systems without sparse files, so they
+//! only run when asked for:
+//!
+//! ```sh
+//! cargo test --test large_file -- --ignored
+//! ```
+
+mod common;
+
+use dot_tree::{CreateOptions, Feature, NodeError, Tree, TreeOpenMode};
+use std::fs::{self, OpenOptions};
+
+/// 5 GiB, past what 32-bit offsets can address.
+const LARGE_SIZE: u64 = 5 << 30;
+
+/// The size in bits of the nodes of the test trees: the enabled bit and a
+/// 32-bit subitem.
+const NODE_BITS: u64 = 33;
+
+fn large_tree(name: &str, features: Vec<Feature>) -> Tree {
+    let mut features = features;
+    features.push(Feature::Disabling);
+
+    common::create(
+        name,
+        CreateOptions {
+            features,
+            subitems: vec![32],
+            ..Default::default()
+        },
+    )
+}
+
+/// The first position whose node starts past `offset` bytes.
+fn position_past(offset: u64) -> u128 {
+    (offset * 8).div_ceil(NODE_BITS) as u128
+}
+
+/// Remove the files of the test `name`, so they don't take disk space
+/// until the next run.
+fn remove(name: &str) {
+    common::tree_path(name);
+}
+
+#[test]
+#[ignore]
+fn writes_and_reads_nodes_past_4_gib() {
+    let mut tree = large_tree("large-write", vec![]);
+    let path = common::tree_path_of(&tree);
+
+    let position = position_past(LARGE_SIZE);
+    tree.set_node_quiet(&[common::bits(0xdead_beef, 32)], &position, true, false)
+        .unwrap();
+    tree.set_node_quiet(&[common::bits(7, 32)], &0, true, false)
+        .unwrap();
+    assert_eq!(tree.nodes() as u128, position + 1);
+    tree.close().unwrap();
+
+    assert!(fs::metadata(path).unwrap().len() > LARGE_SIZE);
+
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert_eq!(
+        tree.read_node(position).unwrap().subitems,
+        vec![common::bits(0xdead_beef, 32)]
+    );
+    assert_eq!(
+        tree.read_node(0).unwrap().subitems,
+        vec![common::bits(7, 32)]
+    );
+    // The gap before the last node reads as disabled nodes.
+    assert!(matches!(
+        tree.read_node(position - 1),
+        Err(NodeError::Disabled)
+    ));
+    assert!(matches!(
+        tree.read_node(position + 1),
+        Err(NodeError::Unexistent)
+    ));
+
+    remove("large-write");
+}
+
+#[test]
+#[ignore]
+fn opens_a_sparse_fixture_past_4_gib() {
+    let tree = large_tree("large-fixture", vec![]);
+    let path = common::tree_path_of(&tree);
+    tree.close().unwrap();
+
+    // Grown without writing, so every node of the fixture is disabled.
+    let file = OpenOptions::new().write(true).open(path).unwrap();
+    file.set_len(LARGE_SIZE).unwrap();
+    drop(file);
+
+    let mut tree = Tree::open(path, TreeOpenMode::ReadWrite).unwrap();
+    let header_size = LARGE_SIZE - tree.nodes() * NODE_BITS / 8;
+    let last = tree.nodes() as u128 - 1;
+    assert!(header_size < 1024);
+    assert!(matches!(tree.read_node(last), Err(NodeError::Disabled)));
+
+    tree.set_node_quiet(&[common::bits(1, 32)], &last, true, false)
+        .unwrap();
+    assert_eq!(
+        tree.read_node(last).unwrap().subitems,
+        vec![common::bits(1, 32)]
+    );
+    tree.close().unwrap();
+    assert_eq!(fs::metadata(path).unwrap().len(), LARGE_SIZE);
+
+    remove("large-fixture");
+}
+
+#[test]
+#[ignore]
+fn keeps_the_occupancy_of_nodes_past_4_gib() {
+    let mut tree = large_tree("large-occupancy", vec![Feature::Occupancy]);
+
+    let position = position_past(LARGE_SIZE);
+    tree.set_node_quiet(&[common::bits(3, 32)], &position, true, false)
+        .unwrap();
+    assert_eq!(
+        tree.occupancy(position - 1..position + 2).unwrap(),
+        vec![false, true, false]
+    );
+    assert_eq!(tree.subtree_size(0).unwrap(), 1);
+    tree.close().unwrap();
+
+    remove("large-occupancy");
+}
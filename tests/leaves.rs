@@ -0,0 +1,78 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, Tree, TreeOpenMode};
+
+fn disabling(name: &str, features: Vec<Feature>) -> Tree {
+    common::create(
+        name,
+        CreateOptions {
+            features,
+            subitems: vec![8],
+            ..Default::default()
+        },
+    )
+}
+
+/// Enable and disable pseudo-random nodes of the first levels of `trees`.
+fn write_randomly(trees: &mut [&mut Tree], writes: usize) {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..writes {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        let position = (state % 127) as u128;
+        let disabled = (state >> 32).is_multiple_of(3);
+        for tree in trees.iter_mut() {
+            tree.set_node_quiet(&[common::bits(state & 0xff, 8)], &position, true, disabled)
+                .unwrap();
+        }
+    }
+}
+
+fn assert_same_leaves(tree: &Tree, expected: &Tree) {
+    for position in 0..255 {
+        assert_eq!(
+            tree.subtree_leaves(position).unwrap(),
+            expected.subtree_leaves(position).unwrap(),
+            "subtree of {}",
+            position
+        );
+    }
+
+    let leaves = expected.subtree_leaves(0).unwrap();
+    for k in 0..=leaves {
+        assert_eq!(tree.kth_leaf(k).ok(), expected.kth_leaf(k).ok());
+    }
+}
+
+#[test]
+fn keeps_leaf_counts_through_writes() {
+    let mut tree = disabling(
+        "leaves-writes",
+        vec![Feature::Disabling, Feature::LevelStats],
+    );
+    let mut expected = disabling("leaves-writes-expected", vec![Feature::Disabling]);
+
+    write_randomly(&mut [&mut tree, &mut expected], 2000);
+
+    assert_same_leaves(&tree, &expected);
+}
+
+#[test]
+fn builds_leaf_counts_when_the_feature_is_enabled() {
+    let mut tree = disabling("leaves-enabled", vec![Feature::Disabling]);
+    let mut expected = disabling("leaves-enabled-expected", vec![Feature::Disabling]);
+    write_randomly(&mut [&mut tree, &mut expected], 500);
+
+    tree.enable_feature(Feature::LevelStats).unwrap();
+    assert_same_leaves(&tree, &expected);
+
+    // Kept up to date by later writes, and read again once reopened.
+    write_randomly(&mut [&mut tree, &mut expected], 500);
+    let path = common::tree_path_of(&tree);
+    tree.close().unwrap();
+
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert_same_leaves(&tree, &expected);
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, NodeError};
+
+#[test]
+fn reports_failed_node_updates() {
+    let mut tree = common::create(
+        "nodes-update",
+        CreateOptions {
+            features: vec![Feature::Disabling],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    tree.set_node_quiet(&[common::bits(1, 8)], &0, true, false)
+        .unwrap();
+
+    // Updating overwrites the node it was read from.
+    tree.node(0)
+        .unwrap()
+        .update(vec![common::bits(2, 8)])
+        .unwrap();
+    assert_eq!(
+        tree.read_node(0).unwrap().subitems,
+        vec![common::bits(2, 8)]
+    );
+
+    let mut node = tree.node(0).unwrap();
+    assert!(matches!(
+        node.update(vec![common::bits(3, 4)]),
+        Err(NodeError::InvalidSubitem)
+    ));
+    assert_eq!(node.subitems, vec![common::bits(2, 8)]);
+
+    tree.set_validator(|_, _| Err("read only".to_string()));
+    let mut node = tree.node(0).unwrap();
+    for result in [
+        node.update(vec![common::bits(3, 8)]),
+        node.disable(),
+        node.enable(),
+    ] {
+        assert!(matches!(result, Err(NodeError::ValidationFailed(_))));
+    }
+    assert!(matches!(tree.read_node(0), Ok(node) if node.subitems == vec![common::bits(2, 8)]));
+}
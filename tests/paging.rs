@@ -0,0 +1,129 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, Layout, TraversalOptions, TraversalOrder, Tree};
+
+/// The enabled positions of the sparse tree: the root's left child is
+/// disabled but has enabled descendants, and most positions are gaps.
+const ENABLED: [u128; 8] = [0, 2, 4, 5, 9, 13, 14, 30];
+
+/// The positions written disabled.
+const DISABLED: [u128; 2] = [1, 6];
+
+fn sparse(name: &str, features: Vec<Feature>, layout: Layout) -> Tree {
+    let mut tree = common::create(
+        name,
+        CreateOptions {
+            features,
+            subitems: vec![8],
+            layout,
+            ..Default::default()
+        },
+    );
+
+    // Written out of order, so the order of the pages only depends on the
+    // positions.
+    for position in ENABLED.iter().rev() {
+        tree.set_node_quiet(&[common::bits(*position as u64, 8)], position, true, false)
+            .unwrap();
+    }
+    for position in DISABLED {
+        tree.set_node_quiet(&[common::bits(0, 8)], &position, true, true)
+            .unwrap();
+    }
+
+    tree
+}
+
+/// The enabled positions below `position` in pre-order, each node before
+/// its left subtree and its left subtree before its right one. Disabled
+/// nodes and gaps are skipped, but not what's below them.
+fn pre_order(position: u128, limit: u128, order: &mut Vec<u128>) {
+    if position >= limit {
+        return;
+    };
+    if ENABLED.contains(&position) {
+        order.push(position);
+    };
+    pre_order(position * 2 + 1, limit, order);
+    pre_order(position * 2 + 2, limit, order);
+}
+
+/// The positions of every page of a traversal, read `limit` nodes at a
+/// time.
+fn paged_positions(tree: &Tree, order: TraversalOrder, limit: usize) -> Vec<u128> {
+    let mut positions = vec![];
+    let mut token: Option<String> = None;
+    loop {
+        let page = tree.traverse_page(order, token.as_deref(), limit).unwrap();
+        assert!(page.nodes.len() <= limit);
+        for node in &page.nodes {
+            assert!(node.enabled);
+            assert_eq!(node.subitems, vec![common::bits(node.position as u64, 8)]);
+        }
+        positions.extend(page.nodes.iter().map(|node| node.position));
+
+        match page.next {
+            Some(next) => token = Some(next),
+            None => return positions,
+        };
+    }
+}
+
+fn assert_orders(tree: &Tree) {
+    let mut pre = vec![];
+    pre_order(0, 31, &mut pre);
+    assert_eq!(pre, vec![0, 4, 9, 2, 5, 13, 14, 30]);
+
+    let traversed: Vec<u128> = tree
+        .traverse(0, TraversalOptions::default())
+        .map(|node| node.unwrap().position)
+        .collect();
+    assert_eq!(traversed, pre);
+
+    let mut level = ENABLED.to_vec();
+    level.sort_unstable();
+
+    for limit in [1, 2, 3, 8, 100] {
+        assert_eq!(paged_positions(tree, TraversalOrder::PreOrder, limit), pre);
+        assert_eq!(
+            paged_positions(tree, TraversalOrder::LevelOrder, limit),
+            level
+        );
+    }
+}
+
+#[test]
+fn pages_a_sparse_tree_in_order() {
+    let tree = sparse(
+        "paging-sparse",
+        vec![Feature::Disabling],
+        Layout::LevelOrder,
+    );
+    assert_orders(&tree);
+}
+
+#[test]
+fn pages_a_sparse_tree_in_order_with_every_layout() {
+    let layouts = [
+        Layout::VanEmdeBoas { levels: 5 },
+        Layout::Columnar { levels: 5 },
+    ];
+    for (index, layout) in layouts.into_iter().enumerate() {
+        let tree = sparse(
+            &format!("paging-sparse-layout-{}", index),
+            vec![Feature::Disabling],
+            layout,
+        );
+        assert_orders(&tree);
+    }
+}
+
+#[test]
+fn pages_a_sparse_persistent_tree_in_order() {
+    let tree = sparse(
+        "paging-sparse-persistent",
+        vec![Feature::Disabling, Feature::Persistent],
+        Layout::LevelOrder,
+    );
+    assert_orders(&tree);
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use dot_tree::{
+    AppendOnlyPlacement, CreateOptions, Feature, FreeListPlacement, Layout, LevelOrderPlacement,
+    NodeError, PlacementStrategy, Tree,
+};
+
+fn tree(name: &str, features: Vec<Feature>, layout: Layout) -> Tree {
+    common::create(
+        name,
+        CreateOptions {
+            features,
+            subitems: vec![8],
+            layout,
+            ..Default::default()
+        },
+    )
+}
+
+/// Insert a node for each value, returning the positions they got.
+fn insert(tree: &mut Tree, strategy: &mut impl PlacementStrategy, values: &[u64]) -> Vec<u128> {
+    values
+        .iter()
+        .map(|value| {
+            tree.insert_node(strategy, &[common::bits(*value, 8)])
+                .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn places_nodes_in_level_order() {
+    let mut tree = tree(
+        "placement-level-order",
+        vec![Feature::Disabling],
+        Layout::LevelOrder,
+    );
+    let mut strategy = LevelOrderPlacement::default();
+
+    assert_eq!(
+        insert(&mut tree, &mut strategy, &[0, 1, 2, 3, 4]),
+        vec![0, 1, 2, 3, 4]
+    );
+    tree.remove_node(&mut strategy, 4).unwrap();
+    tree.remove_node(&mut strategy, 3).unwrap();
+    assert!(matches!(tree.read_node(3), Err(NodeError::Disabled)));
+
+    // Removed positions are reused shallowest first.
+    assert_eq!(insert(&mut tree, &mut strategy, &[5, 6, 7]), vec![3, 4, 5]);
+    assert_eq!(
+        tree.read_node(3).unwrap().subitems,
+        vec![common::bits(5, 8)]
+    );
+
+    // A new strategy finds the first free position by reading the tree.
+    tree.remove_node(&mut strategy, 1).unwrap();
+    let mut strategy = LevelOrderPlacement::default();
+    assert_eq!(insert(&mut tree, &mut strategy, &[8, 9]), vec![1, 6]);
+}
+
+#[test]
+fn places_nodes_past_the_last_one() {
+    let mut tree = tree(
+        "placement-append",
+        vec![Feature::Disabling],
+        Layout::LevelOrder,
+    );
+    let mut strategy = AppendOnlyPlacement::default();
+
+    assert_eq!(insert(&mut tree, &mut strategy, &[0, 1, 2]), vec![0, 1, 2]);
+    tree.remove_node(&mut strategy, 1).unwrap();
+    assert_eq!(insert(&mut tree, &mut strategy, &[3]), vec![3]);
+
+    // A new strategy starts past the last node stored.
+    tree.set_node_quiet(&[common::bits(9, 8)], &9, true, false)
+        .unwrap();
+    let mut strategy = AppendOnlyPlacement::default();
+    assert_eq!(insert(&mut tree, &mut strategy, &[4, 5]), vec![10, 11]);
+}
+
+#[test]
+fn places_nodes_at_removed_positions_first() {
+    let mut tree = tree(
+        "placement-free-list",
+        vec![Feature::Disabling],
+        Layout::LevelOrder,
+    );
+    let mut strategy = FreeListPlacement::default();
+
+    assert_eq!(
+        insert(&mut tree, &mut strategy, &[0, 1, 2, 3, 4, 5]),
+        vec![0, 1, 2, 3, 4, 5]
+    );
+    tree.remove_node(&mut strategy, 5).unwrap();
+    tree.remove_node(&mut strategy, 3).unwrap();
+    assert_eq!(insert(&mut tree, &mut strategy, &[6, 7, 8]), vec![3, 5, 6]);
+}
+
+#[test]
+fn places_nodes_within_the_layout() {
+    let mut tree = tree(
+        "placement-layout",
+        vec![Feature::Disabling],
+        Layout::Columnar { levels: 2 },
+    );
+    let mut strategy = AppendOnlyPlacement::default();
+
+    assert_eq!(insert(&mut tree, &mut strategy, &[0, 1, 2]), vec![0, 1, 2]);
+    assert!(matches!(
+        tree.insert_node(&mut strategy, &[common::bits(3, 8)]),
+        Err(NodeError::OutsideLayout)
+    ));
+}
+
+#[test]
+fn removes_nodes_of_trees_with_disabling_only() {
+    let mut tree = tree("placement-no-disabling", vec![], Layout::LevelOrder);
+    let mut strategy = LevelOrderPlacement::default();
+
+    assert_eq!(insert(&mut tree, &mut strategy, &[0, 1, 2]), vec![0, 1, 2]);
+    assert!(matches!(
+        tree.remove_node(&mut strategy, 2),
+        Err(NodeError::MissingFeature)
+    ));
+}
+
+#[test]
+fn places_nodes_of_persistent_trees() {
+    let mut tree = tree(
+        "placement-persistent",
+        vec![Feature::Persistent],
+        Layout::LevelOrder,
+    );
+    let mut strategy = LevelOrderPlacement::default();
+
+    // Each node's parent is placed before it, so no ancestor is missing.
+    assert_eq!(
+        insert(&mut tree, &mut strategy, &[0, 1, 2, 3]),
+        vec![0, 1, 2, 3]
+    );
+    assert_eq!(tree.version_count(), 4);
+    assert_eq!(
+        tree.read_node(3).unwrap().subitems,
+        vec![common::bits(3, 8)]
+    );
+}
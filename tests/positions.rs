@@ -0,0 +1,67 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, Layout, NodeError};
+
+/// Trees whose positions are mapped to slots in every way there is.
+fn trees(name: &str) -> Vec<dot_tree::Tree> {
+    let options = [
+        (vec![], Layout::LevelOrder),
+        (
+            vec![Feature::Disabling, Feature::Occupancy],
+            Layout::LevelOrder,
+        ),
+        (vec![], Layout::VanEmdeBoas { levels: 8 }),
+        (vec![Feature::Disabling], Layout::Columnar { levels: 8 }),
+        (
+            vec![Feature::Disabling, Feature::Persistent],
+            Layout::LevelOrder,
+        ),
+    ];
+
+    options
+        .into_iter()
+        .enumerate()
+        .map(|(index, (features, layout))| {
+            let mut tree = common::create(
+                &format!("{}-{}", name, index),
+                CreateOptions {
+                    features,
+                    subitems: vec![8],
+                    layout,
+                    ..Default::default()
+                },
+            );
+            tree.set_node_quiet(&[common::bits(1, 8)], &0, true, false)
+                .unwrap();
+            tree
+        })
+        .collect()
+}
+
+#[test]
+fn reads_the_deepest_positions_as_unexistent() {
+    for tree in trees("deepest-reads") {
+        for position in [u128::MAX, u128::MAX - 1, u128::MAX / 2 + 1] {
+            assert!(matches!(
+                tree.read_node(position),
+                Err(NodeError::Unexistent)
+            ));
+            assert_eq!(tree.children(position).unwrap(), (None, None));
+        }
+    }
+}
+
+#[test]
+fn fails_writes_past_the_largest_offset_without_panicking() {
+    for mut tree in trees("deepest-writes") {
+        let persistent = tree
+            .summary()
+            .unwrap()
+            .features
+            .contains(&Feature::Persistent);
+        for position in [u128::MAX, u128::MAX / 2] {
+            let result = tree.set_node_quiet(&[common::bits(2, 8)], &position, true, false);
+            assert_eq!(result.is_ok(), persistent, "{:?}", result);
+        }
+    }
+}
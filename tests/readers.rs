@@ -0,0 +1,29 @@
+mod common;
+
+use dot_tree::{CreateOptions, Tree};
+
+fn value(tree: &Tree, position: u128) -> u64 {
+    dot_tree::bitcodec::bits_to_u64(&tree.read_node(position).unwrap().subitems[0])
+}
+
+#[test]
+fn sees_writes_made_after_pinning() {
+    let mut tree = common::create(
+        "readers-pinning",
+        CreateOptions {
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    tree.set_node_quiet(&[common::bits(1, 8)], &0, true, false)
+        .unwrap();
+
+    let reader = tree.reader().unwrap();
+    reader.pin(&[0]).unwrap();
+    reader.warm(4).unwrap();
+    assert_eq!(reader.pinned_pages(), 0);
+
+    tree.set_node_quiet(&[common::bits(2, 8)], &0, true, false)
+        .unwrap();
+    assert_eq!(value(&reader, 0), 2);
+}
@@ -0,0 +1,26 @@
+mod common;
+
+use dot_tree::CreateOptions;
+
+#[test]
+fn samples_every_node_when_asked_for_more() {
+    let mut tree = common::create(
+        "sample-more",
+        CreateOptions {
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    for position in 0..5 {
+        tree.set_node_quiet(&[common::bits(position as u64, 8)], &position, true, false)
+            .unwrap();
+    }
+
+    for n in [5, 6, usize::MAX] {
+        let sample = tree.sample(n, 7).unwrap();
+        assert_eq!(
+            sample.iter().map(|node| node.position).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use dot_tree::{
+    CreateOptions, Feature, NodeError, SchemaError, TraversalOptions, Tree, TreeFileError,
+    TreeOpenMode,
+};
+
+/// A tree storing only which nodes are enabled, in a single bit per node.
+fn structural(name: &str, features: Vec<Feature>) -> Tree {
+    common::create(
+        name,
+        CreateOptions {
+            features,
+            subitems: vec![],
+            ..Default::default()
+        },
+    )
+}
+
+/// The shape written to the structural trees: the root, its left subtree
+/// down to position 7, and a disabled right child.
+fn write_shape(tree: &mut Tree) {
+    for position in [0, 1, 3, 7, 4] {
+        tree.set_node_quiet(&[], &position, true, false).unwrap();
+    }
+    tree.set_node_quiet(&[], &2, true, true).unwrap();
+}
+
+#[test]
+fn creates_a_tree_without_subitems() {
+    let tree = structural("structure-create", vec![Feature::Disabling]);
+
+    assert_eq!(tree.nodes(), 0);
+    assert_eq!(tree.summary().unwrap().subitems, Vec::<u32>::new());
+    assert!(matches!(tree.read_node(0), Err(NodeError::Unexistent)));
+}
+
+#[test]
+fn sets_and_reads_nodes_without_subitems() {
+    let mut tree = structural("structure-set", vec![Feature::Disabling]);
+    write_shape(&mut tree);
+
+    for position in [0, 1, 3, 4, 7] {
+        let node = tree.read_node(position).unwrap();
+        assert!(node.enabled);
+        assert!(node.subitems.is_empty());
+    }
+    assert!(matches!(tree.read_node(2), Err(NodeError::Disabled)));
+    assert!(matches!(tree.read_node(5), Err(NodeError::Disabled)));
+    assert_eq!(tree.nodes(), 8);
+
+    // Subitems can't be passed to a tree without them.
+    assert!(matches!(
+        tree.set_node_quiet(&[vec![true]], &0, true, false),
+        Err(NodeError::SubitemCountMismatch {
+            expected: 0,
+            got: 1
+        })
+    ));
+
+    // Read back the same once reopened.
+    let path = common::tree_path_of(&tree);
+    tree.close().unwrap();
+    let tree = Tree::open(path, TreeOpenMode::Read).unwrap();
+    assert!(tree.read_node(7).unwrap().enabled);
+    assert!(matches!(tree.read_node(2), Err(NodeError::Disabled)));
+}
+
+#[test]
+fn traverses_a_tree_without_subitems() {
+    let mut tree = structural(
+        "structure-traverse",
+        vec![Feature::Disabling, Feature::Occupancy],
+    );
+    write_shape(&mut tree);
+
+    let positions: Vec<u128> = tree
+        .traverse(0, TraversalOptions::default())
+        .map(|node| node.unwrap().position)
+        .collect();
+    assert_eq!(positions, vec![0, 1, 3, 7, 4]);
+
+    assert_eq!(tree.subtree_size(1).unwrap(), 4);
+    assert_eq!(tree.subtree_leaves(0).unwrap(), 2);
+}
+
+#[test]
+fn verifies_a_tree_without_subitems() {
+    let mut tree = structural(
+        "structure-verify",
+        vec![Feature::Disabling, Feature::Occupancy],
+    );
+    write_shape(&mut tree);
+
+    let report = tree.verify().unwrap();
+    assert!(report.findings.is_empty(), "{}", report);
+}
+
+#[test]
+fn verifies_a_persistent_tree_without_subitems() {
+    let mut tree = structural(
+        "structure-verify-persistent",
+        vec![Feature::Disabling, Feature::Persistent],
+    );
+    write_shape(&mut tree);
+
+    assert!(tree.read_node(7).unwrap().enabled);
+    assert!(tree.verify().unwrap().is_ok());
+}
+
+#[test]
+fn rejects_nodes_without_bits() {
+    // Without a feature adding a header to each node, nodes would have no
+    // bits.
+    let result = Tree::create_with_options(
+        common::tree_path("structure-empty-node"),
+        TreeOpenMode::ReadWrite,
+        CreateOptions::default(),
+    );
+    assert!(matches!(
+        result,
+        Err(TreeFileError::InvalidSchema(SchemaError::EmptyNode))
+    ));
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, NodeError, Tree};
+
+fn two_subitems(name: &str) -> Tree {
+    common::create(
+        name,
+        CreateOptions {
+            features: vec![Feature::Disabling],
+            subitems: vec![4, 12],
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn rejects_too_few_subitems() {
+    let mut tree = two_subitems("subitems-too-few");
+
+    assert!(matches!(
+        tree.set_node(&[common::bits(3, 4)], &0, true, false),
+        Err(NodeError::SubitemCountMismatch {
+            expected: 2,
+            got: 1
+        })
+    ));
+    assert!(matches!(
+        tree.set_node_quiet(&[], &0, true, false),
+        Err(NodeError::SubitemCountMismatch {
+            expected: 2,
+            got: 0
+        })
+    ));
+    assert!(matches!(tree.read_node(0), Err(NodeError::Unexistent)));
+}
+
+#[test]
+fn rejects_too_many_subitems() {
+    let mut tree = two_subitems("subitems-too-many");
+
+    let subitems = [common::bits(3, 4), common::bits(7, 12), common::bits(1, 4)];
+    assert!(matches!(
+        tree.set_node(&subitems, &0, true, false),
+        Err(NodeError::SubitemCountMismatch {
+            expected: 2,
+            got: 3
+        })
+    ));
+    assert!(matches!(tree.read_node(0), Err(NodeError::Unexistent)));
+}
+
+#[test]
+fn rejects_an_oversized_subitem() {
+    let mut tree = two_subitems("subitems-oversized");
+    tree.set_node_quiet(&[common::bits(3, 4), common::bits(7, 12)], &0, true, false)
+        .unwrap();
+
+    assert!(matches!(
+        tree.set_node_quiet(&[common::bits(3, 5), common::bits(7, 12)], &0, true, false),
+        Err(NodeError::InvalidSubitem)
+    ));
+    assert!(matches!(
+        tree.set_node_quiet(&[common::bits(3, 4), common::bits(7, 13)], &0, true, false),
+        Err(NodeError::InvalidSubitem)
+    ));
+
+    // The node written before is left as it was.
+    assert_eq!(
+        tree.read_node(0).unwrap().subitems,
+        vec![common::bits(3, 4), common::bits(7, 12)]
+    );
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, NodeError, Tree, TreeOpenMode};
+
+#[test]
+fn fails_writes_while_the_transaction_is_prepared() {
+    let mut tree = common::create(
+        "transactions-prepared",
+        CreateOptions {
+            features: vec![Feature::Persistent],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    let path = common::tree_path_of(&tree);
+
+    tree.set_node_quiet(&[common::bits(0, 8)], &0, true, false)
+        .unwrap();
+    tree.begin_transaction().unwrap();
+    tree.set_node_quiet(&[common::bits(1, 8)], &1, true, false)
+        .unwrap();
+    tree.prepare("global").unwrap();
+
+    assert!(matches!(
+        tree.set_node_quiet(&[common::bits(2, 8)], &2, true, false),
+        Err(NodeError::InvalidTransactionState)
+    ));
+
+    // The transaction is still prepared once the tree is opened again.
+    tree.close().unwrap();
+    let mut tree = Tree::open(path, TreeOpenMode::ReadWrite).unwrap();
+    assert_eq!(
+        tree.prepared_transaction().unwrap(),
+        Some("global".to_string())
+    );
+    assert!(matches!(
+        tree.set_node_quiet(&[common::bits(2, 8)], &2, true, false),
+        Err(NodeError::InvalidTransactionState)
+    ));
+    assert!(matches!(tree.read_node(2), Err(NodeError::Unexistent)));
+
+    tree.commit().unwrap();
+    tree.set_node_quiet(&[common::bits(2, 8)], &2, true, false)
+        .unwrap();
+    assert_eq!(
+        tree.read_node(1).unwrap().subitems,
+        vec![common::bits(1, 8)]
+    );
+    assert_eq!(
+        tree.read_node(2).unwrap().subitems,
+        vec![common::bits(2, 8)]
+    );
+}
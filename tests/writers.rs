@@ -0,0 +1,53 @@
+mod common;
+
+use dot_tree::{CreateOptions, Feature, TreeFileError};
+
+#[test]
+fn refuses_to_split_trees_with_child_hints() {
+    let mut tree = common::create(
+        "writers-child-hints",
+        CreateOptions {
+            features: vec![Feature::Disabling, Feature::ChildHints],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+
+    assert!(matches!(
+        tree.split_writers(1),
+        Err(TreeFileError::UnsupportedFeature)
+    ));
+}
+
+#[test]
+fn refuses_to_split_trees_retaining_values() {
+    let mut tree = common::create(
+        "writers-retained",
+        CreateOptions {
+            features: vec![Feature::Disabling],
+            subitems: vec![8],
+            ..Default::default()
+        },
+    );
+    tree.set_retained_values(2).unwrap();
+
+    assert!(matches!(
+        tree.split_writers(1),
+        Err(TreeFileError::UnsupportedFeature)
+    ));
+
+    // Once values aren't retained, the subtrees are written apart.
+    tree.set_retained_values(0).unwrap();
+    let mut writers = tree.split_writers(1).unwrap();
+    for writer in &mut writers {
+        let root = writer.root();
+        writer
+            .set_node(&[common::bits(root as u64, 8)], root, false)
+            .unwrap();
+    }
+    drop(writers);
+    assert_eq!(
+        tree.read_node(2).unwrap().subitems,
+        vec![common::bits(2, 8)]
+    );
+}